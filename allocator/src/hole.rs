@@ -5,10 +5,51 @@ use core::ptr::NonNull;
 
 use super::align_up;
 
+/// Number of size classes kept by [`Strategy::Segregated`]. Class `i` holds blocks of
+/// `HoleList::min_size() + i * align_of::<Hole>()` bytes.
+const SIZE_CLASSES: usize = 16;
+
+const NO_HOLE: Option<&'static mut Hole> = None;
+
+/// How the [`HoleList`] picks the hole an allocation is made from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Use the first hole that is big enough, starting at the lowest address.
+    FirstFit,
+    /// Use the smallest hole that is big enough. Slower, since every hole has to be looked at,
+    /// but it keeps big holes intact for big allocations.
+    BestFit,
+    /// Like first fit, but start searching where the last allocation was made instead of at the
+    /// lowest address, which spreads allocations out instead of piling small blocks up at the start.
+    NextFit,
+    /// Keep freed small blocks on a separate list per size, so allocations of the same size can
+    /// reuse them without searching. They aren't merged with their neighbours until an allocation
+    /// can't be satisfied otherwise or the strategy is changed. Everything else is first fit.
+    Segregated,
+}
+
+/// Fragmentation of a [`HoleList`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HoleStats {
+    /// Number of holes
+    pub holes: usize,
+    /// Size of the biggest hole
+    pub largest: usize,
+    /// Free bytes in all holes
+    pub free: usize,
+    /// Free bytes kept on the size class lists of [`Strategy::Segregated`], not counted in `free`
+    pub cached: usize,
+}
+
 /// A sorted list of holes. It uses the the holes itself to store its nodes.
 #[derive(Debug)]
 pub struct HoleList {
     first: Hole, // dummy
+    strategy: Strategy,
+    /// Where the last allocation ended, for `Strategy::NextFit`
+    cursor: usize,
+    /// Freed blocks by size class, for `Strategy::Segregated`. These aren't sorted.
+    classes: [Option<&'static mut Hole>; SIZE_CLASSES],
 }
 
 impl HoleList {
@@ -20,6 +61,9 @@ impl HoleList {
                 size: 0,
                 next: None,
             },
+            strategy: Strategy::FirstFit,
+            cursor: 0,
+            classes: [NO_HOLE; SIZE_CLASSES],
         }
     }
 
@@ -31,6 +75,9 @@ impl HoleList {
                 size: 0,
                 next: None,
             },
+            strategy: Strategy::FirstFit,
+            cursor: 0,
+            classes: [NO_HOLE; SIZE_CLASSES],
         }
     }
 
@@ -58,6 +105,9 @@ impl HoleList {
                 size: 0,
                 next: Some(&mut *ptr),
             },
+            strategy: Strategy::FirstFit,
+            cursor: 0,
+            classes: [NO_HOLE; SIZE_CLASSES],
         }
     }
 
@@ -100,6 +150,108 @@ impl HoleList {
         })
     }
 
+    /// Returns the strategy used by [`allocate`][HoleList::allocate].
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Changes the strategy used by [`allocate`][HoleList::allocate]. Any blocks kept on size
+    /// class lists are merged back into the list of holes.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.flush_classes();
+        self.strategy = strategy;
+    }
+
+    /// Searches the list for a big enough hole using the current [`Strategy`], and allocates a
+    /// block from it. Returns the start address of the block and the aligned layout, like
+    /// [`allocate_first_fit`][HoleList::allocate_first_fit].
+    #[allow(clippy::result_unit_err)]
+    pub fn allocate(&mut self, layout: Layout) -> Result<(NonNull<u8>, Layout), ()> {
+        let aligned_layout = Self::align_layout(layout);
+
+        let result = match self.strategy {
+            Strategy::FirstFit => allocate_first_fit(&mut self.first, aligned_layout),
+            Strategy::BestFit => allocate_best_fit(&mut self.first, aligned_layout),
+            Strategy::NextFit => {
+                let previous = previous_hole(&mut self.first, self.cursor);
+                match allocate_first_fit(previous, aligned_layout) {
+                    Ok(info) => Ok(info),
+                    // wrap around
+                    Err(()) => allocate_first_fit(&mut self.first, aligned_layout),
+                }
+            }
+            Strategy::Segregated => match self.pop_class(aligned_layout) {
+                Some(info) => Ok(info),
+                None => match allocate_first_fit(&mut self.first, aligned_layout) {
+                    Ok(info) => Ok(info),
+                    Err(()) if self.flush_classes() => {
+                        allocate_first_fit(&mut self.first, aligned_layout)
+                    }
+                    Err(()) => Err(()),
+                },
+            },
+        };
+
+        result.map(|holeinfo| {
+            self.cursor = holeinfo.addr + holeinfo.size;
+            (
+                NonNull::new(holeinfo.addr as *mut u8).unwrap(),
+                aligned_layout,
+            )
+        })
+    }
+
+    /// Tries to resize the allocation given by `ptr` and `layout` to `new_size` bytes without
+    /// moving it. Growing takes memory from a hole right behind the allocation, shrinking gives the
+    /// end of the allocation back. Returns the aligned new layout, or an error if there isn't
+    /// enough free memory behind the allocation or the freed part would be too small to become a
+    /// hole of its own.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by a call to [`allocate`][HoleList::allocate] or
+    /// [`allocate_first_fit`][HoleList::allocate_first_fit] with identical layout.
+    #[allow(clippy::result_unit_err)]
+    pub unsafe fn resize_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<Layout, ()> {
+        let old_size = Self::align_layout(layout).size();
+        let new_layout = Layout::from_size_align(new_size, layout.align()).map_err(|_| ())?;
+        let new_layout = Self::align_layout(new_layout);
+        let new_size = new_layout.size();
+        let addr = ptr.as_ptr() as usize;
+
+        if new_size > old_size {
+            grow_in_place(&mut self.first, addr + old_size, new_size - old_size)?;
+        } else if new_size < old_size {
+            shrink_in_place(&mut self.first, addr + new_size, old_size - new_size)?;
+        }
+        Ok(new_layout)
+    }
+
+    /// Returns the number of holes, the biggest one and how much memory is free in total.
+    pub fn stats(&self) -> HoleStats {
+        let mut stats = HoleStats::default();
+        let mut hole = self.first.next.as_deref();
+        while let Some(current) = hole {
+            stats.holes += 1;
+            stats.largest = stats.largest.max(current.size);
+            stats.free += current.size;
+            hole = current.next.as_deref();
+        }
+        for class in self.classes.iter() {
+            let mut block = class.as_deref();
+            while let Some(current) = block {
+                stats.cached += current.size;
+                block = current.next.as_deref();
+            }
+        }
+        stats
+    }
+
     /// Frees the allocation given by `ptr` and `layout`.
     ///
     /// This function walks the list and inserts the given block at the correct place. If the freed
@@ -116,6 +268,17 @@ impl HoleList {
     /// [`allocate_first_fit`]: HoleList::allocate_first_fit
     pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> Layout {
         let aligned_layout = Self::align_layout(layout);
+        if self.strategy == Strategy::Segregated {
+            if let Some(class) = size_class(aligned_layout.size()) {
+                let block = ptr.as_ptr() as *mut Hole;
+                block.write(Hole {
+                    size: aligned_layout.size(),
+                    next: self.classes[class].take(),
+                });
+                self.classes[class] = Some(&mut *block);
+                return aligned_layout;
+            }
+        }
         deallocate(
             &mut self.first,
             ptr.as_ptr() as usize,
@@ -129,6 +292,69 @@ impl HoleList {
         size_of::<usize>() * 2
     }
 
+    /// Returns the address and size of the hole with the highest address.
+    pub fn last_hole(&self) -> Option<(usize, usize)> {
+        let mut hole = self.first.next.as_ref()?;
+        while let Some(next) = hole.next.as_ref() {
+            hole = next;
+        }
+        Some(((*hole) as *const Hole as usize, hole.size))
+    }
+
+    /// Removes `size` bytes from the end of the last hole, if that hole ends at `top` and what's
+    /// left of it is either nothing or at least `HoleList::min_size` big. Returns whether the
+    /// memory was removed; the list is left alone otherwise.
+    pub fn shrink_last(&mut self, top: usize, size: usize) -> bool {
+        self.flush_classes();
+        // find the hole before the last one (or the dummy)
+        let mut previous = &mut self.first;
+        while previous.next.as_ref().map_or(false, |hole| hole.next.is_some()) {
+            previous = move_helper(previous).next.as_mut().unwrap();
+        }
+        let last = match previous.next.as_ref() {
+            Some(hole) => hole.info(),
+            None => return false,
+        };
+        if last.addr + last.size != top || last.size < size {
+            return false;
+        }
+        let remaining = last.size - size;
+        if remaining == 0 {
+            previous.next = None;
+        } else if remaining >= Self::min_size() {
+            previous.next.as_mut().unwrap().size = remaining;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Takes a block off the size class list for `layout`, if there is one with the right alignment.
+    fn pop_class(&mut self, layout: Layout) -> Option<HoleInfo> {
+        let head = &mut self.classes[size_class(layout.size())?];
+        let info = head.as_ref()?.info();
+        if info.addr % layout.align() != 0 {
+            return None;
+        }
+        *head = head.as_mut().unwrap().next.take();
+        Some(info)
+    }
+
+    /// Merges every block on the size class lists back into the list of holes. Returns whether
+    /// there were any.
+    fn flush_classes(&mut self) -> bool {
+        let mut flushed = false;
+        for class in 0..SIZE_CLASSES {
+            while let Some(block) = self.classes[class].take() {
+                self.classes[class] = block.next.take();
+                let info = block.info();
+                deallocate(&mut self.first, info.addr, info.size);
+                flushed = true;
+            }
+        }
+        flushed
+    }
+
     /// Returns information about the first hole for test purposes.
     #[cfg(test)]
     pub fn first_hole(&self) -> Option<(usize, usize)> {
@@ -148,6 +374,7 @@ struct Hole {
 }
 
 #[cfg(test)]
+#[derive(Debug)]
 pub struct Hole {
     pub size: usize,
     pub next: Option<&'static mut Hole>,
@@ -292,6 +519,100 @@ fn allocate_first_fit(mut previous: &mut Hole, layout: Layout) -> Result<HoleInf
     }
 }
 
+/// Like `allocate_first_fit`, but uses the smallest hole that is big enough.
+fn allocate_best_fit(first: &mut Hole, layout: Layout) -> Result<HoleInfo, ()> {
+    // (address, size) of the best hole so far
+    let mut best: Option<(usize, usize)> = None;
+    let mut hole = first.next.as_deref();
+    while let Some(current) = hole {
+        let info = current.info();
+        if best.map_or(true, |(_, size)| info.size < size) && split_hole(info, layout).is_some() {
+            best = Some((info.addr, info.size));
+            if info.size == layout.size() {
+                // can't do better than that
+                break;
+            }
+        }
+        hole = current.next.as_deref();
+    }
+    let (addr, _) = best.ok_or(())?;
+    // the best hole is the first one that fits from here on
+    allocate_first_fit(previous_hole(first, addr), layout)
+}
+
+/// Returns the hole in front of the first hole that ends after `addr`, or the dummy `first` if
+/// there's none.
+fn previous_hole(mut previous: &mut Hole, addr: usize) -> &mut Hole {
+    while previous
+        .next
+        .as_ref()
+        .map_or(false, |next| next.info().addr + next.size <= addr)
+    {
+        previous = move_helper(previous).next.as_mut().unwrap();
+    }
+    previous
+}
+
+/// Takes `size` bytes from the front of the hole starting at `addr`, if there is one that big.
+/// What's left of the hole must be either nothing or at least `HoleList::min_size` big.
+fn grow_in_place(first: &mut Hole, addr: usize, size: usize) -> Result<(), ()> {
+    let previous = previous_hole(first, addr);
+    let next = match previous.next.as_ref() {
+        Some(next) if next.info().addr == addr => next.info(),
+        _ => return Err(()),
+    };
+    if next.size == size {
+        previous.next = previous.next.as_mut().unwrap().next.take();
+    } else if next.size >= size + HoleList::min_size() {
+        let ptr = (addr + size) as *mut Hole;
+        unsafe {
+            ptr.write(Hole {
+                size: next.size - size,
+                next: previous.next.as_mut().unwrap().next.take(),
+            })
+        }
+        previous.next = Some(unsafe { &mut *ptr });
+    } else {
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Gives back the `size` bytes at `addr`, which are the end of an allocation. They're freed
+/// normally if they're big enough to be a hole, otherwise they can only be given back by merging
+/// them with a hole right behind them.
+fn shrink_in_place(first: &mut Hole, addr: usize, size: usize) -> Result<(), ()> {
+    if size >= HoleList::min_size() {
+        deallocate(first, addr, size);
+        return Ok(());
+    }
+    let previous = previous_hole(first, addr);
+    let next = match previous.next.as_ref() {
+        Some(next) if next.info().addr == addr + size => next.info(),
+        _ => return Err(()),
+    };
+    // move the next hole's header down
+    let ptr = addr as *mut Hole;
+    unsafe {
+        ptr.write(Hole {
+            size: next.size + size,
+            next: previous.next.as_mut().unwrap().next.take(),
+        })
+    }
+    previous.next = Some(unsafe { &mut *ptr });
+    Ok(())
+}
+
+/// Returns the size class list for blocks of `size` bytes (already aligned), if there is one.
+fn size_class(size: usize) -> Option<usize> {
+    let class = (size.checked_sub(HoleList::min_size())?) / align_of::<Hole>();
+    if class < SIZE_CLASSES {
+        Some(class)
+    } else {
+        None
+    }
+}
+
 /// Frees the allocation given by `(addr, size)`. It starts at the given hole and walks the list to
 /// find the correct place (the list is sorted by address).
 fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
@@ -24,6 +24,7 @@ use core::ptr::NonNull;
 #[cfg(test)]
 use hole::Hole;
 use hole::HoleList;
+pub use hole::{HoleStats, Strategy};
 #[cfg(feature = "use_spin")]
 use spinning_top::Spinlock;
 
@@ -69,10 +70,12 @@ impl Heap {
     /// This function must be called at most once and must only be used on an
     /// empty heap.
     pub unsafe fn init(&mut self, heap_bottom: usize, heap_size: usize) {
+        let strategy = self.holes.strategy();
         self.bottom = heap_bottom;
         self.size = heap_size;
         self.used = 0;
         self.holes = HoleList::new(heap_bottom, heap_size);
+        self.holes.set_strategy(strategy);
     }
 
     /// Initialize an empty heap with provided memory.
@@ -149,6 +152,66 @@ impl Heap {
         }
     }
 
+    /// Allocates a chunk of the given size with the given alignment, picking the block with the
+    /// heap's [`Strategy`]. Returns a pointer to the beginning of that chunk if it was successful.
+    #[allow(clippy::result_unit_err)]
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        match self.holes.allocate(layout) {
+            Ok((ptr, aligned_layout)) => {
+                self.used += aligned_layout.size();
+                Ok(ptr)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the strategy used by [`allocate`][Heap::allocate].
+    pub fn strategy(&self) -> Strategy {
+        self.holes.strategy()
+    }
+
+    /// Changes the strategy used by [`allocate`][Heap::allocate]. This can be done at any time.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.holes.set_strategy(strategy);
+    }
+
+    /// Changes the size of the given allocation to `new_size`. The allocation is resized in place
+    /// if there's room for it, otherwise a new one is made with [`allocate`][Heap::allocate], the
+    /// contents are copied over and the old one is freed. On failure the old allocation is left
+    /// alone.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by this heap for an allocation with identical size and
+    /// alignment, and `new_size` rounded up to the alignment must not overflow.
+    #[allow(clippy::result_unit_err)]
+    pub unsafe fn reallocate(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ()> {
+        let old_size = HoleList::align_layout(layout).size();
+        if let Ok(new_layout) = self.holes.resize_in_place(ptr, layout, new_size) {
+            self.used = self.used - old_size + new_layout.size();
+            return Ok(ptr);
+        }
+        let new_layout = Layout::from_size_align(new_size, layout.align()).map_err(|_| ())?;
+        let new_ptr = self.allocate(new_layout)?;
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.as_ptr(),
+            core::cmp::min(layout.size(), new_size),
+        );
+        self.deallocate(ptr, layout);
+        Ok(new_ptr)
+    }
+
+    /// Returns how fragmented the free memory is.
+    pub fn hole_stats(&self) -> HoleStats {
+        self.holes.stats()
+    }
+
     /// Frees the given allocation.
     ///
     /// This function walks the list of free memory blocks and inserts the freed block at the
@@ -157,8 +220,9 @@ impl Heap {
     ///
     /// # Safety
     ///
-    /// `ptr` must be a pointer returned by a call to the `allocate_first_fit` function with
-    /// identical size and alignment. Undefined behavior may occur for invalid arguments.
+    /// `ptr` must be a pointer returned by a call to the `allocate` or `allocate_first_fit`
+    /// functions with identical size and alignment. Undefined behavior may occur for invalid
+    /// arguments.
     pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
         self.used -= self.holes.deallocate(ptr, layout).size();
     }
@@ -200,6 +264,25 @@ impl Heap {
             .deallocate(NonNull::new_unchecked(top as *mut u8), layout);
         self.size += by;
     }
+
+    /// Returns how many bytes at the top of the heap are free
+    pub fn free_at_top(&self) -> usize {
+        match self.holes.last_hole() {
+            Some((addr, size)) if addr + size == self.top() => size,
+            _ => 0,
+        }
+    }
+
+    /// Shrinks the heap by removing `by` free bytes from its top, the opposite of `extend`.
+    /// Returns false and leaves the heap alone if that memory isn't free or the remaining
+    /// hole at the top would be too small to keep track of.
+    pub fn shrink(&mut self, by: usize) -> bool {
+        if by > self.size - self.used || !self.holes.shrink_last(self.top(), by) {
+            return false;
+        }
+        self.size -= by;
+        true
+    }
 }
 
 #[cfg(all(feature = "alloc_ref", feature = "use_spin"))]
@@ -208,7 +291,7 @@ unsafe impl Allocator for LockedHeap {
         if layout.size() == 0 {
             return Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0));
         }
-        match self.0.lock().allocate_first_fit(layout) {
+        match self.0.lock().allocate(layout) {
             Ok(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, layout.size())),
             Err(()) => Err(AllocError),
         }
@@ -269,7 +352,7 @@ unsafe impl GlobalAlloc for LockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.0
             .lock()
-            .allocate_first_fit(layout)
+            .allocate(layout)
             .ok()
             .map_or(core::ptr::null_mut::<u8>(), |allocation| allocation.as_ptr())
     }
@@ -279,6 +362,14 @@ unsafe impl GlobalAlloc for LockedHeap {
             .lock()
             .deallocate(NonNull::new_unchecked(ptr), layout)
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.0
+            .lock()
+            .reallocate(NonNull::new_unchecked(ptr), layout, new_size)
+            .ok()
+            .map_or(core::ptr::null_mut::<u8>(), |allocation| allocation.as_ptr())
+    }
 }
 
 /// Align downwards. Returns the greatest x with alignment `align`
@@ -290,3 +290,220 @@ fn extend_fragmented_heap() {
     // Try to allocate there
     assert!(heap.allocate_first_fit(layout_2.clone()).is_ok());
 }
+
+#[test]
+fn shrink_extended_heap() {
+    let mut heap = new_max_heap();
+
+    unsafe {
+        heap.extend(1024);
+    }
+    assert_eq!(heap.free_at_top(), 2048);
+
+    // give the extension back, the rest of the heap is still usable
+    assert!(heap.shrink(1024));
+    assert_eq!(heap.size(), 1024);
+    assert_eq!(heap.free_at_top(), 1024);
+    let layout = Layout::from_size_align(1024, 1).unwrap();
+    assert!(heap.allocate_first_fit(layout.clone()).is_ok());
+    assert_eq!(heap.free_at_top(), 0);
+}
+
+#[test]
+fn shrink_in_use_heap() {
+    let mut heap = new_max_heap();
+
+    let layout = Layout::from_size_align(512, 1).unwrap();
+    let low = heap.allocate_first_fit(layout.clone()).unwrap();
+    let high = heap.allocate_first_fit(layout.clone()).unwrap();
+
+    // the top of the heap is allocated
+    assert_eq!(heap.free_at_top(), 0);
+    assert!(!heap.shrink(512));
+
+    unsafe {
+        heap.deallocate(low, layout.clone());
+    }
+    // freeing the bottom doesn't help
+    assert!(!heap.shrink(512));
+
+    unsafe {
+        heap.deallocate(high, layout.clone());
+    }
+    // leftovers too small for a hole are refused
+    assert!(!heap.shrink(1024 - size_of::<usize>()));
+    assert!(heap.shrink(1024));
+    assert_eq!(heap.size(), 0);
+}
+
+/// Allocates four blocks, frees the first and third one and returns the heap along with the
+/// remaining blocks. Leaves holes of 64 and 32 bytes and the rest of the heap behind the last block.
+fn fragmented_heap(
+    strategy: Strategy,
+) -> (Heap, NonNull<u8>, NonNull<u8>, NonNull<u8>, NonNull<u8>) {
+    let mut heap = new_heap();
+    heap.set_strategy(strategy);
+    let big = Layout::from_size_align(64, align_of::<usize>()).unwrap();
+    let small = Layout::from_size_align(32, align_of::<usize>()).unwrap();
+    let a = heap.allocate(big).unwrap();
+    let b = heap.allocate(small).unwrap();
+    let c = heap.allocate(small).unwrap();
+    let d = heap.allocate(small).unwrap();
+    unsafe {
+        heap.deallocate(a, big);
+        heap.deallocate(c, small);
+    }
+    (heap, a, b, c, d)
+}
+
+#[test]
+fn first_fit_strategy() {
+    let (mut heap, a, _, _, _) = fragmented_heap(Strategy::FirstFit);
+    assert_eq!(heap.strategy(), Strategy::FirstFit);
+    let layout = Layout::from_size_align(32, align_of::<usize>()).unwrap();
+    // the 64 byte hole comes first
+    assert_eq!(heap.allocate(layout).unwrap(), a);
+}
+
+#[test]
+fn best_fit_strategy() {
+    let (mut heap, a, _, c, _) = fragmented_heap(Strategy::BestFit);
+    let layout = Layout::from_size_align(32, align_of::<usize>()).unwrap();
+    // the 32 byte hole fits exactly
+    assert_eq!(heap.allocate(layout).unwrap(), c);
+    // then the 64 byte one is the smallest
+    assert_eq!(heap.allocate(layout).unwrap(), a);
+    let large = Layout::from_size_align(heap.size() - 256, 1).unwrap();
+    assert!(heap.allocate(large).is_ok());
+    assert!(heap.allocate(layout).is_ok());
+}
+
+#[test]
+fn next_fit_strategy() {
+    let (mut heap, a, _, _, d) = fragmented_heap(Strategy::NextFit);
+    let layout = Layout::from_size_align(32, align_of::<usize>()).unwrap();
+    // the search continues behind the last allocation
+    let e = heap.allocate(layout).unwrap();
+    assert_eq!(e.as_ptr() as usize, d.as_ptr() as usize + 32);
+
+    // and wraps around once it reaches the end
+    let rest = heap.hole_stats().largest;
+    let large = Layout::from_size_align(rest, 1).unwrap();
+    assert!(heap.allocate(large).is_ok());
+    assert_eq!(heap.allocate(layout).unwrap(), a);
+}
+
+#[test]
+fn segregated_strategy() {
+    let mut heap = new_heap();
+    heap.set_strategy(Strategy::Segregated);
+    let small = Layout::from_size_align(24, align_of::<usize>()).unwrap();
+    let x = heap.allocate(small).unwrap();
+    let y = heap.allocate(small).unwrap();
+    unsafe {
+        heap.deallocate(x, small);
+    }
+    // freed blocks are kept aside instead of going back to the holes
+    let stats = heap.hole_stats();
+    assert_eq!(stats.cached, 24);
+    assert_eq!(stats.holes, 1);
+    assert_eq!(heap.used(), 24);
+
+    // and handed out again to allocations of the same size
+    let other = Layout::from_size_align(48, align_of::<usize>()).unwrap();
+    let z = heap.allocate(other).unwrap();
+    assert_ne!(z, x);
+    assert_eq!(heap.allocate(small).unwrap(), x);
+
+    // running out of memory merges them back
+    unsafe {
+        heap.deallocate(x, small);
+        heap.deallocate(y, small);
+        heap.deallocate(z, other);
+    }
+    assert_eq!(heap.hole_stats().cached, 24 + 24 + 48);
+    let all = Layout::from_size_align(heap.size(), 1).unwrap();
+    assert_eq!(heap.allocate(all).unwrap().as_ptr() as usize, heap.bottom());
+    assert_eq!(heap.hole_stats(), HoleStats::default());
+}
+
+#[test]
+fn change_strategy() {
+    let mut heap = new_heap();
+    heap.set_strategy(Strategy::Segregated);
+    let layout = Layout::from_size_align(16, align_of::<usize>()).unwrap();
+    let x = heap.allocate(layout).unwrap();
+    unsafe {
+        heap.deallocate(x, layout);
+    }
+    assert_eq!(heap.hole_stats().cached, 16);
+    heap.set_strategy(Strategy::BestFit);
+    let stats = heap.hole_stats();
+    assert_eq!(stats.cached, 0);
+    assert_eq!(stats.holes, 1);
+    assert_eq!(stats.free, heap.size());
+}
+
+#[test]
+fn reallocate_in_place() {
+    let mut heap = new_heap();
+    let layout = Layout::from_size_align(64, align_of::<usize>()).unwrap();
+    let x = heap.allocate(layout).unwrap();
+    unsafe {
+        *(x.as_ptr() as *mut usize) = 0xdeafdeadbeafbabe;
+
+        // grow into the hole behind it
+        assert_eq!(heap.reallocate(x, layout, 128).unwrap(), x);
+        assert_eq!(heap.used(), 128);
+        assert_eq!(
+            heap.holes.first_hole(),
+            Some((x.as_ptr() as usize + 128, heap.size() - 128))
+        );
+
+        // shrink, merging the end back into the hole
+        let layout = Layout::from_size_align(128, align_of::<usize>()).unwrap();
+        assert_eq!(heap.reallocate(x, layout, 40).unwrap(), x);
+        assert_eq!(heap.used(), 40);
+        assert_eq!(
+            heap.holes.first_hole(),
+            Some((x.as_ptr() as usize + 40, heap.size() - 40))
+        );
+        assert_eq!(*(x.as_ptr() as *const usize), 0xdeafdeadbeafbabe);
+    }
+}
+
+#[test]
+fn reallocate_moves() {
+    let mut heap = new_heap();
+    let layout = Layout::from_size_align(64, align_of::<usize>()).unwrap();
+    let x = heap.allocate(layout).unwrap();
+    let y = heap.allocate(layout).unwrap();
+    unsafe {
+        *(x.as_ptr() as *mut usize) = 0xdeafdeadbeafbabe;
+
+        // no room behind x
+        let z = heap.reallocate(x, layout, 128).unwrap();
+        assert_eq!(z.as_ptr() as usize, y.as_ptr() as usize + 64);
+        assert_eq!(*(z.as_ptr() as *const usize), 0xdeafdeadbeafbabe);
+        assert_eq!(heap.used(), 64 + 128);
+
+        // the freed end of y would be too small for a hole, and there's none behind it
+        let w = heap.reallocate(y, layout, 64 - size_of::<usize>()).unwrap();
+        assert_ne!(w, y);
+
+        // too big to fit anywhere
+        let big = Layout::from_size_align(128, align_of::<usize>()).unwrap();
+        assert!(heap.reallocate(z, big, heap.size()).is_err());
+        assert_eq!(*(z.as_ptr() as *const usize), 0xdeafdeadbeafbabe);
+    }
+}
+
+#[test]
+fn hole_stats() {
+    let (heap, _, _, _, _) = fragmented_heap(Strategy::FirstFit);
+    let stats = heap.hole_stats();
+    assert_eq!(stats.holes, 3);
+    assert_eq!(stats.largest, heap.size() - 64 - 3 * 32);
+    assert_eq!(stats.free, heap.free());
+    assert_eq!(stats.cached, 0);
+}
@@ -7,8 +7,9 @@
 use alloc::boxed::Box;
 use acpi_crate::{AcpiTables, AcpiHandler, PhysicalMapping};
 use core::ptr::NonNull;
-use alloc::sync::Arc;
 use crate::PHYS_MEM_OFFSET;
+use crate::memory::mmio::{ioremap, ioremap_region, iounmap, CacheMode};
+use x86_64::{PhysAddr, VirtAddr};
 use conquer_once::spin::OnceCell;
 use aml::AmlContext;
 use x86_64::instructions::port::Port;
@@ -18,39 +19,51 @@ use spin::Mutex;
 pub static ACPI_TABLES: OnceCell<AcpiTables<OsAcpiHandler>> = OnceCell::uninit();
 pub static AML_CONTEXT: OnceCell<Mutex<AmlContext>> = OnceCell::uninit();
 
+/// Reads a `T` from a SystemMemory operation region, which is usually device registers rather than RAM
+unsafe fn read_mmio<T: Copy>(address: usize) -> T {
+    let map = ioremap::<T>(PhysAddr::new(address as u64), CacheMode::Uncached).expect("failed to map AML memory");
+    map.as_ptr().read_volatile()
+}
+
+/// Writes a `T` to a SystemMemory operation region
+unsafe fn write_mmio<T: Copy>(address: usize, value: T) {
+    let map = ioremap::<T>(PhysAddr::new(address as u64), CacheMode::Uncached).expect("failed to map AML memory");
+    map.as_ptr().write_volatile(value);
+}
+
 #[derive(Debug)]
 pub struct AmlHandler;
 impl aml::Handler for AmlHandler {
     fn read_u8(&self, address: usize) -> u8 {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *const u8) }
+        unsafe { read_mmio(address) }
     }
 
     fn read_u16(&self, address: usize) -> u16 {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *const u16) }
+        unsafe { read_mmio(address) }
     }
 
     fn read_u32(&self, address: usize) -> u32 {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *const u32) }
+        unsafe { read_mmio(address) }
     }
 
     fn read_u64(&self, address: usize) -> u64 {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *const u64) }
+        unsafe { read_mmio(address) }
     }
 
     fn write_u8(&mut self, address: usize, value: u8) {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *mut u8) = value; }
+        unsafe { write_mmio(address, value) }
     }
 
     fn write_u16(&mut self, address: usize, value: u16) {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *mut u16) = value; }
+        unsafe { write_mmio(address, value) }
     }
 
     fn write_u32(&mut self, address: usize, value: u32) {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *mut u32) = value; }
+        unsafe { write_mmio(address, value) }
     }
 
     fn write_u64(&mut self, address: usize, value: u64) {
-        unsafe { *((address as u64 + PHYS_MEM_OFFSET) as *mut u64) = value; }
+        unsafe { write_mmio(address, value) }
     }
 
     fn read_io_u8(&self, port: u16) -> u8 {
@@ -158,35 +171,27 @@ impl aml::Handler for AmlHandler {
     }
 }
 
-#[derive(Debug)]
-pub struct OsAcpiHandlerInner {
-    offset: u64,
-}
-
 #[derive(Clone, Debug)]
-pub struct OsAcpiHandler(Arc<OsAcpiHandlerInner>);
-
-impl OsAcpiHandler {
-    pub fn new(offset: u64) -> Self {
-        Self(Arc::new(OsAcpiHandlerInner { offset }))
-    }
-}
+pub struct OsAcpiHandler;
 
 impl AcpiHandler for OsAcpiHandler {
     unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
-        // all physical memory is already mapped
+        // the tables are in RAM, so they're mapped write-back like the rest of it
+        let region = unsafe { ioremap_region(PhysAddr::new(physical_address as u64), size as u64, CacheMode::WriteBack) }
+            .expect("failed to map ACPI table");
+        let virt = region.leak().as_mut_ptr() as *mut T;
         unsafe {
             PhysicalMapping::new(
                 physical_address,
-                NonNull::new((physical_address + self.0.offset as usize) as *mut T).unwrap(),
+                NonNull::new(virt).unwrap(),
                 size,
                 size,
                 self.clone())
         }
     }
 
-    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {
-        // all physical memory is already mapped
+    fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
+        unsafe { iounmap(VirtAddr::from_ptr(region.virtual_start().as_ptr())) };
     }
 }
 
@@ -213,7 +218,7 @@ pub fn init() {
 
     {
         let mut step = crate::StartupStep::begin("Reading ACPI tables");
-        let acpi_handler = OsAcpiHandler::new(PHYS_MEM_OFFSET);
+        let acpi_handler = OsAcpiHandler;
         let acpi = unsafe { AcpiTables::from_rsdp(acpi_handler, rdsp_phys_addr as usize).unwrap() };
 
         //let mut aml_context = AmlContext::new();
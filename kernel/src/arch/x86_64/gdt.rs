@@ -4,14 +4,37 @@
 // See LICENSE.txt and CREDITS.txt for details
 ///////////////////////////////////////////////////////////////////////////////L
 
+use core::cell::UnsafeCell;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::Segment;
+use crate::memory::stack::{self, KernelStack};
 
-/// The index of the double fault handler entry in the IST
+/// The index of the double fault handler entry in the IST. Running off the end of a stack faults
+/// with the stack pointer in the guard page, where the CPU can't push the page fault's frame, so it
+/// raises a double fault instead and the handler needs a stack of its own to report it.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The index of the NMI handler entry in the IST. NMIs can't be masked, so they may arrive while
+/// the current stack is in any state.
+pub const NMI_IST_INDEX: u16 = 1;
+/// The index of the machine check handler entry in the IST
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// IST entries in use, and the names of their stacks
+const IST_STACKS: [(u16, &str); 3] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault stack"),
+    (NMI_IST_INDEX, "NMI stack"),
+    (MACHINE_CHECK_IST_INDEX, "machine check stack"),
+];
+/// Size of the stacks [init_stacks] allocates
+const IST_STACK_SIZE: u64 = 16 * 1024;
+/// Size of the static stacks used until then
+const EARLY_STACK_SIZE: usize = 4096;
+
+/// Stacks for the IST before there's a kernel address space to allocate them from
+static mut EARLY_STACKS: [[u8; EARLY_STACK_SIZE]; IST_STACKS.len()] = [[0; EARLY_STACK_SIZE]; IST_STACKS.len()];
 
 /// Segment selectors for CS and TSS
 struct Selectors {
@@ -19,17 +42,20 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+/// The TSS, which [init_stacks] changes after it's been loaded
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// only written by init_stacks during startup, the CPU is the only other reader
+unsafe impl Sync for Tss {}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE // stack end
-        };
-        tss
+        for (i, &(index, _)) in IST_STACKS.iter().enumerate() {
+            let stack_start = VirtAddr::from_ptr(unsafe { &EARLY_STACKS[i] });
+            tss.interrupt_stack_table[index as usize] = stack_start + EARLY_STACK_SIZE; // stack end
+        }
+        Tss(UnsafeCell::new(tss))
     };
 }
 
@@ -37,7 +63,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -52,3 +78,19 @@ pub fn init() {
     }
     step.ok();
 }
+
+/// Moves the IST onto stacks with guard pages, and registers the stack we're running on so
+/// overflowing it gets reported. Needs the kernel address space.
+pub fn init_stacks() {
+    stack::register_current("boot stack");
+    for &(index, name) in IST_STACKS.iter() {
+        let top = KernelStack::new(IST_STACK_SIZE, name).expect("failed to allocate an IST stack").leak();
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let tss = unsafe { &mut *TSS.0.get() };
+            // the TSS is packed, so go through a copy of the table
+            let mut table = tss.interrupt_stack_table;
+            table[index as usize] = top;
+            tss.interrupt_stack_table = table;
+        });
+    }
+}
@@ -12,8 +12,12 @@ use x2apic::lapic::{LocalApic, LocalApicBuilder};
 use x2apic::ioapic::{IoApic, IrqFlags};
 use acpi_crate::InterruptModel;
 use crate::acpi::ACPI_TABLES;
-use crate::{both_println, PHYS_MEM_OFFSET};
+use crate::both_println;
+use crate::memory::address_space::{handle_page_fault, Access};
+use crate::memory::mmio::{ioremap_region, CacheMode};
 use crate::util::halt_loop;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
 
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -30,6 +34,15 @@ pub static IO_APIC: spin::Mutex<Option<IoApic>> = spin::Mutex::new(None);
 
 static IDT: OnceCell<InterruptDescriptorTable> = OnceCell::uninit();
 
+/// First vector handed out by [allocate_vector], right after the remapped PIC lines
+pub const DEVICE_VECTOR_BASE: u8 = PIC_2_OFFSET + 8;
+/// Number of vectors available to [allocate_vector]
+pub const NUM_DEVICE_VECTORS: u8 = 16;
+
+/// Driver callbacks for hardware interrupts, indexed by `vector - PIC_1_OFFSET`
+static IRQ_HANDLERS: spin::Mutex<[Option<fn()>; 32]> = spin::Mutex::new([None; 32]);
+static NEXT_DEVICE_VECTOR: AtomicU8 = AtomicU8::new(DEVICE_VECTOR_BASE);
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -69,9 +82,12 @@ pub fn early_init_interrupts() {
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(crate::arch::gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.page_fault.set_handler_fn(page_fault_handler);
+            idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler)
+                .set_stack_index(crate::arch::gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(crate::arch::gdt::MACHINE_CHECK_IST_INDEX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
         idt.alignment_check.set_handler_fn(alignment_check_handler);
         idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
         idt.segment_not_present.set_handler_fn(segment_not_present_handler);
@@ -84,7 +100,6 @@ pub fn early_init_interrupts() {
         idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
         idt.invalid_tss.set_handler_fn(invalid_tss_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt.machine_check.set_handler_fn(machine_check_handler);
         idt.overflow.set_handler_fn(overflow_handler);
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
@@ -93,22 +108,32 @@ pub fn early_init_interrupts() {
         idt[InterruptIndex::Com2.as_usize()].set_handler_fn(com2_interrupt_handler);
         idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
         idt[InterruptIndex::LPT2.as_usize()].set_handler_fn(lpt2_interrupt_handler);
-        idt[InterruptIndex::FloppyDisk.as_usize()].set_handler_fn(floppy_interrupt_handler);
+        idt[InterruptIndex::FloppyDisk.as_usize()].set_handler_fn(floppy_irq_handler);
         idt[InterruptIndex::LPT1.as_usize()].set_handler_fn(lpt1_interrupt_handler);
         idt[InterruptIndex::CMOS.as_usize()].set_handler_fn(cmos_interrupt_handler);
-        idt[InterruptIndex::Peripheral1.as_usize()].set_handler_fn(disk_irq_handler);
-        idt[InterruptIndex::Peripheral2.as_usize()].set_handler_fn(disk_irq_handler);
-        idt[InterruptIndex::Peripheral3.as_usize()].set_handler_fn(disk_irq_handler);
+        idt[InterruptIndex::Peripheral1.as_usize()].set_handler_fn(peripheral1_irq_handler);
+        idt[InterruptIndex::Peripheral2.as_usize()].set_handler_fn(peripheral2_irq_handler);
+        idt[InterruptIndex::Peripheral3.as_usize()].set_handler_fn(peripheral3_irq_handler);
         idt[InterruptIndex::PS2Mouse.as_usize()].set_handler_fn(ps2_mouse_interrupt_handler);
         idt[InterruptIndex::Coprocessor.as_usize()].set_handler_fn(coprocessor_interrupt_handler);
-        idt[InterruptIndex::PrimaryATA.as_usize()].set_handler_fn(disk_irq_handler);
-        idt[InterruptIndex::SecondaryATA.as_usize()].set_handler_fn(disk_irq_handler);
+        idt[InterruptIndex::PrimaryATA.as_usize()].set_handler_fn(primary_ata_irq_handler);
+        idt[InterruptIndex::SecondaryATA.as_usize()].set_handler_fn(secondary_ata_irq_handler);
+        for (i, handler) in DEVICE_IRQ_HANDLERS.iter().enumerate() {
+            idt[DEVICE_VECTOR_BASE as usize + i].set_handler_fn(*handler);
+        }
         idt
     }).expect("early_init_interrupts should only be called once");
     IDT.get().unwrap().load();
     step.ok();
 }
 
+/// Map a page of APIC registers uncached for good, returning their virtual address
+fn map_apic_registers(phys: u64) -> u64 {
+    let registers = unsafe { ioremap_region(PhysAddr::new(phys), 0x1000, CacheMode::Uncached) }
+        .expect("failed to map APIC registers");
+    registers.leak().as_mut_ptr() as u64
+}
+
 pub fn late_init_interrupts() {
     crate::both_print!("Initializing interrupt controllers...");
     unsafe { PICS.lock().initialize() };
@@ -117,7 +142,7 @@ pub fn late_init_interrupts() {
     if let InterruptModel::Apic(a) = interrupt_model {
         unsafe {
             let ioapic_addr = a.io_apics[0].address;
-            let mut ioapic = x2apic::ioapic::IoApic::new(ioapic_addr as u64 + PHYS_MEM_OFFSET);
+            let mut ioapic = x2apic::ioapic::IoApic::new(map_apic_registers(ioapic_addr as u64));
             ioapic.init(32);
 
             let mut entry = x2apic::ioapic::RedirectionTableEntry::default();
@@ -133,7 +158,7 @@ pub fn late_init_interrupts() {
             .timer_vector(crate::arch::interrupts::InterruptIndex::Timer.as_usize())
             .error_vector(crate::arch::interrupts::InterruptIndex::Cascade.as_usize())
             .spurious_vector(0xFF)
-            .set_xapic_base(a.local_apic_address + PHYS_MEM_OFFSET)
+            .set_xapic_base(map_apic_registers(a.local_apic_address))
             .build()
             .unwrap_or_else(|err| panic!("{}", err));
         unsafe { lapic.enable(); }
@@ -148,20 +173,114 @@ pub fn late_init_interrupts() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// Reserve an unused interrupt vector for a device (e.g. for MSI).
+/// Returns `None` once all device vectors are used up.
+pub fn allocate_vector() -> Option<u8> {
+    let vector = NEXT_DEVICE_VECTOR.fetch_add(1, Ordering::Relaxed);
+    if vector < DEVICE_VECTOR_BASE + NUM_DEVICE_VECTORS {
+        Some(vector)
+    } else {
+        None
+    }
+}
+
+/// Set the function to call when the given hardware interrupt vector fires.
+/// The handler runs in interrupt context, EOI is taken care of by the caller.
+pub fn register_irq_handler(vector: u8, handler: fn()) {
+    assert!(vector >= PIC_1_OFFSET && vector < DEVICE_VECTOR_BASE + NUM_DEVICE_VECTORS,
+            "vector {} is not a hardware interrupt vector", vector);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[(vector - PIC_1_OFFSET) as usize] = Some(handler);
+    });
+}
+
+/// Route the given IO APIC input to `vector` and unmask it.
+/// Returns false if there's no IO APIC to program.
+pub fn enable_ioapic_irq(irq: u8, vector: u8, flags: IrqFlags) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        match IO_APIC.lock().as_mut() {
+            Some(ioapic) => unsafe {
+                let mut entry = x2apic::ioapic::RedirectionTableEntry::default();
+                entry.set_mode(x2apic::ioapic::IrqMode::Fixed);
+                entry.set_flags(flags);
+                entry.set_vector(vector);
+                entry.set_dest(0);
+                ioapic.set_table_entry(irq, entry);
+                ioapic.enable_irq(irq);
+                true
+            },
+            None => false
+        }
+    })
+}
+
+fn end_of_interrupt(vector: u8) {
+    match LOCAL_APIC.lock().as_mut() {
+        Some(apic) => unsafe { apic.end_of_interrupt() },
+        None => unsafe { PICS.lock().notify_end_of_interrupt(vector); }
+    }
+}
+
+/// Common path for device interrupts: run the registered driver handler, then EOI
+fn dispatch_irq(vector: u8) {
+    let handler = IRQ_HANDLERS.lock()[(vector - PIC_1_OFFSET) as usize];
+    match handler {
+        Some(handler) => handler(),
+        None => crate::serial_println!("Unhandled IRQ on vector {}", vector),
+    }
+    end_of_interrupt(vector);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     both_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, e: u64) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // running into a guard page leaves no stack for the page fault handler, which ends up here
+    let addr = Cr2::read();
+    if let Some(stack) = crate::memory::stack::overflowed_stack(addr) {
+        report_stack_overflow(stack, addr, &stack_frame);
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}\n{}", stack_frame, e);
 }
 
 extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, err: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
 
-    both_println!("EXCEPTION: PAGE FAULT");
-    both_println!("Accessed Address: {:?}", Cr2::read());
-    both_println!("Error Code: {:?}", err);
+    let addr = Cr2::read();
+    if let Some(stack) = crate::memory::stack::overflowed_stack(addr) {
+        // e.g. a big stack frame that skipped past the stack pointer, otherwise it's a double fault
+        report_stack_overflow(stack, addr, &frame);
+    }
+
+    let access = if err.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Access::Execute
+    } else if err.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Access::Write
+    } else {
+        Access::Read
+    };
+    let user = err.contains(PageFaultErrorCode::USER_MODE);
+    let present = err.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    let fault = match handle_page_fault(addr, access, user, present) {
+        Ok(()) => return,
+        Err(fault) => fault,
+    };
+    let mode = if user { " from user mode" } else { "" };
+    match fault.area {
+        Some(area) => panic!("EXCEPTION: PAGE FAULT\n{} of {:?}{}: {}\nRegion: {} at {:?}..{:?}, {:?}, {:?}\nError Code: {:?}\n{:#?}",
+                             access, addr, mode, fault.error, area.name, area.start, area.end(), area.protection, area.backing, err, frame),
+        None => panic!("EXCEPTION: PAGE FAULT\n{} of {:?}{}: {}\nRegion: none\nError Code: {:?}\n{:#?}",
+                       access, addr, mode, fault.error, err, frame),
+    }
+}
+
+fn report_stack_overflow(stack: crate::memory::stack::StackInfo, addr: VirtAddr, frame: &InterruptStackFrame) -> ! {
+    both_println!("EXCEPTION: STACK OVERFLOW");
+    both_println!("Overflowed the {} at {:?}..{:?}", stack.name, stack.bottom, stack.top);
+    both_println!("Accessed Address: {:?}", addr);
     both_println!("{:#?}", frame);
     halt_loop();
 }
@@ -262,14 +381,47 @@ extern "x86-interrupt" fn overflow_handler(frame: InterruptStackFrame) {
     halt_loop();
 }
 
-extern "x86-interrupt" fn disk_irq_handler(_frame: InterruptStackFrame) {
-    both_println!("disk irq");
-
-    match LOCAL_APIC.lock().as_mut() {
-        Some(apic) => unsafe { apic.end_of_interrupt() },
-        None => unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8()); }
-    }
-}
+macro_rules! irq_handlers {
+    ($($name:ident => $vector:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_frame: InterruptStackFrame) {
+                dispatch_irq($vector);
+            }
+        )*
+    };
+}
+
+irq_handlers! {
+    floppy_irq_handler => InterruptIndex::FloppyDisk.as_u8(),
+    peripheral1_irq_handler => InterruptIndex::Peripheral1.as_u8(),
+    peripheral2_irq_handler => InterruptIndex::Peripheral2.as_u8(),
+    peripheral3_irq_handler => InterruptIndex::Peripheral3.as_u8(),
+    primary_ata_irq_handler => InterruptIndex::PrimaryATA.as_u8(),
+    secondary_ata_irq_handler => InterruptIndex::SecondaryATA.as_u8(),
+    device_irq_handler_0 => DEVICE_VECTOR_BASE,
+    device_irq_handler_1 => DEVICE_VECTOR_BASE + 1,
+    device_irq_handler_2 => DEVICE_VECTOR_BASE + 2,
+    device_irq_handler_3 => DEVICE_VECTOR_BASE + 3,
+    device_irq_handler_4 => DEVICE_VECTOR_BASE + 4,
+    device_irq_handler_5 => DEVICE_VECTOR_BASE + 5,
+    device_irq_handler_6 => DEVICE_VECTOR_BASE + 6,
+    device_irq_handler_7 => DEVICE_VECTOR_BASE + 7,
+    device_irq_handler_8 => DEVICE_VECTOR_BASE + 8,
+    device_irq_handler_9 => DEVICE_VECTOR_BASE + 9,
+    device_irq_handler_10 => DEVICE_VECTOR_BASE + 10,
+    device_irq_handler_11 => DEVICE_VECTOR_BASE + 11,
+    device_irq_handler_12 => DEVICE_VECTOR_BASE + 12,
+    device_irq_handler_13 => DEVICE_VECTOR_BASE + 13,
+    device_irq_handler_14 => DEVICE_VECTOR_BASE + 14,
+    device_irq_handler_15 => DEVICE_VECTOR_BASE + 15,
+}
+
+static DEVICE_IRQ_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); NUM_DEVICE_VECTORS as usize] = [
+    device_irq_handler_0, device_irq_handler_1, device_irq_handler_2, device_irq_handler_3,
+    device_irq_handler_4, device_irq_handler_5, device_irq_handler_6, device_irq_handler_7,
+    device_irq_handler_8, device_irq_handler_9, device_irq_handler_10, device_irq_handler_11,
+    device_irq_handler_12, device_irq_handler_13, device_irq_handler_14, device_irq_handler_15,
+];
 
 extern "x86-interrupt" fn timer_interrupt_handler(_frame: InterruptStackFrame) {
     crate::time::pit_tick();
@@ -291,7 +443,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_frame: InterruptStackFrame
 
     match LOCAL_APIC.lock().as_mut() {
         Some(apic) => unsafe { apic.end_of_interrupt() },
-        None => unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8()); }
+        None => unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8()); }
     }
 }
 
@@ -320,11 +472,6 @@ extern "x86-interrupt" fn lpt2_interrupt_handler(_frame: InterruptStackFrame) {
     halt_loop();
 }
 
-extern "x86-interrupt" fn floppy_interrupt_handler(_frame: InterruptStackFrame) {
-    both_println!("Floppy disk interrupt");
-    halt_loop();
-}
-
 extern "x86-interrupt" fn coprocessor_interrupt_handler(_frame: InterruptStackFrame) {
     both_println!("Coprocessor interrupt");
     halt_loop();
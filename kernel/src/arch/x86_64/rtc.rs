@@ -28,6 +28,8 @@ pub enum RtcRegister {
     Year = 0x9,
     StatusA = 0xA,
     StatusB = 0xB,
+    /// Types of the first two floppy drives, drive 0 in the high nibble
+    FloppyTypes = 0x10,
 }
 
 
@@ -95,6 +97,12 @@ impl Rtc {
         }
     }
 
+    /// Drive types of floppy drives 0 and 1, as set up by the firmware (0 = none, 4 = 3.5" 1.44 MB)
+    pub fn floppy_types(&mut self) -> (u8, u8) {
+        let types = self.read(RtcRegister::FloppyTypes);
+        (types >> 4, types & 0xF)
+    }
+
     /// Get current time immediately (UNIX timestamp in seconds).
     ///
     /// # Safety
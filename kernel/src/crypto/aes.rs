@@ -0,0 +1,293 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::fmt::{Debug, Formatter};
+use super::{constant_time_eq, zeroize};
+
+pub const BLOCK_SIZE: usize = 16;
+pub const KEY_SIZE: usize = 32;
+const ROUNDS: usize = 14;
+
+const SBOX: [u8; 256] = make_sbox();
+const INV_SBOX: [u8; 256] = invert(&SBOX);
+const RCON: [u8; 8] = [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40];
+
+/// Multiply in GF(2^8) with the AES polynomial
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1B;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Build the S-box by walking the multiplicative group with generator 3, so no table has to be typed in
+const fn make_sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let mut p: u8 = 1;
+    let mut q: u8 = 1;
+    loop {
+        // p * 3
+        p = p ^ (p << 1) ^ if p & 0x80 != 0 { 0x1B } else { 0 };
+        // q / 3, so q is always the inverse of p
+        q ^= q << 1;
+        q ^= q << 2;
+        q ^= q << 4;
+        if q & 0x80 != 0 {
+            q ^= 0x09;
+        }
+        sbox[p as usize] = q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4) ^ 0x63;
+        if p == 1 {
+            break;
+        }
+    }
+    // 0 has no inverse
+    sbox[0] = 0x63;
+    sbox
+}
+
+const fn invert(sbox: &[u8; 256]) -> [u8; 256] {
+    let mut inverse = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        inverse[sbox[i] as usize] = i as u8;
+        i += 1;
+    }
+    inverse
+}
+
+/// AES with a 256-bit key
+pub struct Aes256 {
+    round_keys: [[u8; BLOCK_SIZE]; ROUNDS + 1],
+}
+
+impl Aes256 {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let mut words = [[0u8; 4]; 4 * (ROUNDS + 1)];
+        for (i, word) in key.chunks_exact(4).enumerate() {
+            words[i].copy_from_slice(word);
+        }
+        for i in 8..words.len() {
+            let mut temp = words[i - 1];
+            if i % 8 == 0 {
+                temp.rotate_left(1);
+                temp.iter_mut().for_each(|b| *b = SBOX[*b as usize]);
+                temp[0] ^= RCON[i / 8];
+            } else if i % 8 == 4 {
+                temp.iter_mut().for_each(|b| *b = SBOX[*b as usize]);
+            }
+            let previous = words[i - 8];
+            for (w, (p, t)) in words[i].iter_mut().zip(previous.iter().zip(temp.iter())) {
+                *w = p ^ t;
+            }
+        }
+
+        let mut round_keys = [[0u8; BLOCK_SIZE]; ROUNDS + 1];
+        for (i, word) in words.iter().enumerate() {
+            round_keys[i / 4][(i % 4) * 4..(i % 4) * 4 + 4].copy_from_slice(word);
+        }
+        words.iter_mut().for_each(|word| zeroize(word));
+        Self { round_keys }
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..ROUNDS {
+            sub_bytes(block, &SBOX);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, &self.round_keys[round]);
+        }
+        sub_bytes(block, &SBOX);
+        shift_rows(block);
+        add_round_key(block, &self.round_keys[ROUNDS]);
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[ROUNDS]);
+        for round in (1..ROUNDS).rev() {
+            inv_shift_rows(block);
+            sub_bytes(block, &INV_SBOX);
+            add_round_key(block, &self.round_keys[round]);
+            inv_mix_columns(block);
+        }
+        inv_shift_rows(block);
+        sub_bytes(block, &INV_SBOX);
+        add_round_key(block, &self.round_keys[0]);
+    }
+}
+
+impl Drop for Aes256 {
+    fn drop(&mut self) {
+        self.round_keys.iter_mut().for_each(|key| zeroize(key));
+    }
+}
+
+impl Debug for Aes256 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        // no key material in logs
+        write!(f, "Aes256")
+    }
+}
+
+fn add_round_key(block: &mut [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) {
+    for (b, k) in block.iter_mut().zip(key.iter()) {
+        *b ^= k;
+    }
+}
+
+fn sub_bytes(block: &mut [u8; BLOCK_SIZE], sbox: &[u8; 256]) {
+    for b in block.iter_mut() {
+        *b = sbox[*b as usize];
+    }
+}
+
+// the state is stored column by column, so row r of column c is block[c * 4 + r]
+
+fn shift_rows(block: &mut [u8; BLOCK_SIZE]) {
+    let old = *block;
+    for c in 0..4 {
+        for r in 1..4 {
+            block[c * 4 + r] = old[((c + r) % 4) * 4 + r];
+        }
+    }
+}
+
+fn inv_shift_rows(block: &mut [u8; BLOCK_SIZE]) {
+    let old = *block;
+    for c in 0..4 {
+        for r in 1..4 {
+            block[c * 4 + r] = old[((c + 4 - r) % 4) * 4 + r];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8; BLOCK_SIZE]) {
+    for column in block.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+        column[0] = gf_mul(a0, 2) ^ gf_mul(a1, 3) ^ a2 ^ a3;
+        column[1] = a0 ^ gf_mul(a1, 2) ^ gf_mul(a2, 3) ^ a3;
+        column[2] = a0 ^ a1 ^ gf_mul(a2, 2) ^ gf_mul(a3, 3);
+        column[3] = gf_mul(a0, 3) ^ a1 ^ a2 ^ gf_mul(a3, 2);
+    }
+}
+
+fn inv_mix_columns(block: &mut [u8; BLOCK_SIZE]) {
+    for column in block.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+        column[0] = gf_mul(a0, 14) ^ gf_mul(a1, 11) ^ gf_mul(a2, 13) ^ gf_mul(a3, 9);
+        column[1] = gf_mul(a0, 9) ^ gf_mul(a1, 14) ^ gf_mul(a2, 11) ^ gf_mul(a3, 13);
+        column[2] = gf_mul(a0, 13) ^ gf_mul(a1, 9) ^ gf_mul(a2, 14) ^ gf_mul(a3, 11);
+        column[3] = gf_mul(a0, 11) ^ gf_mul(a1, 13) ^ gf_mul(a2, 9) ^ gf_mul(a3, 14);
+    }
+}
+
+/// Initial value from RFC 3394, checked on unwrapping to tell a wrong key from a right one
+const WRAP_IV: [u8; 8] = [0xA6; 8];
+
+/// Encrypt `key` (a multiple of 8 bytes) with `kek` as in RFC 3394. The output is 8 bytes longer.
+pub fn wrap_key(kek: &Aes256, key: &[u8], out: &mut [u8]) {
+    assert!(key.len() % 8 == 0 && key.len() >= 16 && out.len() == key.len() + 8);
+    let n = key.len() / 8;
+    let mut a = WRAP_IV;
+    out[8..].copy_from_slice(key);
+    let mut block = [0u8; BLOCK_SIZE];
+    for j in 0..6 {
+        for i in 1..=n {
+            block[..8].copy_from_slice(&a);
+            block[8..].copy_from_slice(&out[i * 8..i * 8 + 8]);
+            kek.encrypt_block(&mut block);
+            let t = ((n * j + i) as u64).to_be_bytes();
+            for (a, (b, t)) in a.iter_mut().zip(block.iter().zip(t.iter())) {
+                *a = b ^ t;
+            }
+            out[i * 8..i * 8 + 8].copy_from_slice(&block[8..]);
+        }
+    }
+    out[..8].copy_from_slice(&a);
+    zeroize(&mut block);
+}
+
+/// Decrypt a key wrapped by [wrap_key]. Returns false, and leaves `out` zeroed, if `kek` isn't the key it was wrapped with.
+pub fn unwrap_key(kek: &Aes256, wrapped: &[u8], out: &mut [u8]) -> bool {
+    assert!(wrapped.len() % 8 == 0 && wrapped.len() >= 24 && out.len() == wrapped.len() - 8);
+    let n = out.len() / 8;
+    let mut a = [0u8; 8];
+    a.copy_from_slice(&wrapped[..8]);
+    out.copy_from_slice(&wrapped[8..]);
+    let mut block = [0u8; BLOCK_SIZE];
+    for j in (0..6).rev() {
+        for i in (1..=n).rev() {
+            let t = ((n * j + i) as u64).to_be_bytes();
+            for (b, (a, t)) in block.iter_mut().zip(a.iter().zip(t.iter())) {
+                *b = a ^ t;
+            }
+            block[8..].copy_from_slice(&out[(i - 1) * 8..i * 8]);
+            kek.decrypt_block(&mut block);
+            a.copy_from_slice(&block[..8]);
+            out[(i - 1) * 8..i * 8].copy_from_slice(&block[8..]);
+        }
+    }
+    zeroize(&mut block);
+    if constant_time_eq(&a, &WRAP_IV) {
+        true
+    } else {
+        zeroize(out);
+        false
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_aes256_fips197() {
+    // FIPS-197 appendix C.3
+    let mut key = [0u8; KEY_SIZE];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let aes = Aes256::new(&key);
+    let mut block = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF];
+    let plaintext = block;
+    aes.encrypt_block(&mut block);
+    assert_eq!(block, [0x8E, 0xA2, 0xB7, 0xCA, 0x51, 0x67, 0x45, 0xBF, 0xEA, 0xFC, 0x49, 0x90, 0x4B, 0x49, 0x60, 0x89]);
+    aes.decrypt_block(&mut block);
+    assert_eq!(block, plaintext);
+}
+
+#[test_case]
+fn test_aes256_key_wrap() {
+    // RFC 3394 section 4.6: 256 bits of key data with a 256-bit KEK
+    let mut kek = [0u8; KEY_SIZE];
+    for (i, b) in kek.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let key: [u8; 32] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF,
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+    ];
+    let kek = Aes256::new(&kek);
+    let mut wrapped = [0u8; 40];
+    wrap_key(&kek, &key, &mut wrapped);
+    assert_eq!(wrapped, [
+        0x28, 0xC9, 0xF4, 0x04, 0xC4, 0xB8, 0x10, 0xF4, 0xCB, 0xCC, 0xB3, 0x5C, 0xFB, 0x87, 0xF8, 0x26,
+        0x3F, 0x57, 0x86, 0xE2, 0xD8, 0x0E, 0xD3, 0x26, 0xCB, 0xC7, 0xF0, 0xE7, 0x1A, 0x99, 0xF4, 0x3B,
+        0xFB, 0x98, 0x8B, 0x9B, 0x7A, 0x02, 0xDD, 0x21,
+    ]);
+    let mut unwrapped = [0u8; 32];
+    assert!(unwrap_key(&kek, &wrapped, &mut unwrapped));
+    assert_eq!(unwrapped, key);
+    wrapped[20] ^= 1;
+    assert!(!unwrap_key(&kek, &wrapped, &mut unwrapped));
+}
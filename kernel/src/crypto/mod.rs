@@ -0,0 +1,30 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Software implementations of the primitives disk encryption needs. Nothing here relies on
+//! AES-NI or SHA extensions, so it works on every CPU we boot on, but it's also not constant
+//! time: the AES S-box lookups depend on the key and data.
+
+/// AES-256 block cipher and RFC 3394 key wrapping
+pub mod aes;
+/// SHA-256, HMAC-SHA256 and PBKDF2
+pub mod sha256;
+/// XTS mode for encrypting disk sectors
+pub mod xts;
+
+/// Overwrite key material so it doesn't linger in freed memory
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // volatile so the compiler can't drop the stores to memory that's about to be freed
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Compare two byte strings in time that only depends on their length
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,93 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use super::aes::{Aes256, BLOCK_SIZE, KEY_SIZE as AES_KEY_SIZE};
+
+/// XTS uses two AES keys, one for the data and one for the tweak
+pub const KEY_SIZE: usize = 2 * AES_KEY_SIZE;
+
+/// XTS-AES-256 (IEEE 1619) with the sector number as the tweak, like dm-crypt's `aes-xts-plain64`.
+/// Sectors have to be a multiple of 16 bytes, so ciphertext stealing isn't needed.
+#[derive(Debug)]
+pub struct XtsAes256 {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl XtsAes256 {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let mut data_key = [0u8; AES_KEY_SIZE];
+        let mut tweak_key = [0u8; AES_KEY_SIZE];
+        data_key.copy_from_slice(&key[..AES_KEY_SIZE]);
+        tweak_key.copy_from_slice(&key[AES_KEY_SIZE..]);
+        let xts = Self { data: Aes256::new(&data_key), tweak: Aes256::new(&tweak_key) };
+        super::zeroize(&mut data_key);
+        super::zeroize(&mut tweak_key);
+        xts
+    }
+
+    pub fn encrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, |aes, block| aes.encrypt_block(block));
+    }
+
+    pub fn decrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, |aes, block| aes.decrypt_block(block));
+    }
+
+    fn process(&self, sector: u64, data: &mut [u8], cipher: impl Fn(&Aes256, &mut [u8; BLOCK_SIZE])) {
+        assert_eq!(data.len() % BLOCK_SIZE, 0, "XTS: sector size isn't a multiple of the block size");
+        let mut tweak = [0u8; BLOCK_SIZE];
+        tweak[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+
+        let mut block = [0u8; BLOCK_SIZE];
+        for chunk in data.chunks_exact_mut(BLOCK_SIZE) {
+            for (b, (c, t)) in block.iter_mut().zip(chunk.iter().zip(tweak.iter())) {
+                *b = c ^ t;
+            }
+            cipher(&self.data, &mut block);
+            for (c, (b, t)) in chunk.iter_mut().zip(block.iter().zip(tweak.iter())) {
+                *c = b ^ t;
+            }
+            next_tweak(&mut tweak);
+        }
+        super::zeroize(&mut block);
+    }
+}
+
+/// Multiply the tweak by x in GF(2^128), little-endian as XTS wants it
+fn next_tweak(tweak: &mut [u8; BLOCK_SIZE]) {
+    let mut carry = 0;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_xts_aes256() {
+    // IEEE 1619 vector 10, data unit 0xFF
+    let key: [u8; KEY_SIZE] = [
+        0x27, 0x18, 0x28, 0x18, 0x28, 0x45, 0x90, 0x45, 0x23, 0x53, 0x60, 0x28, 0x74, 0x71, 0x35, 0x26,
+        0x62, 0x49, 0x77, 0x57, 0x24, 0x70, 0x93, 0x69, 0x99, 0x59, 0x57, 0x49, 0x66, 0x96, 0x76, 0x27,
+        0x31, 0x41, 0x59, 0x26, 0x53, 0x58, 0x97, 0x93, 0x23, 0x84, 0x62, 0x64, 0x33, 0x83, 0x27, 0x95,
+        0x02, 0x88, 0x41, 0x97, 0x16, 0x93, 0x99, 0x37, 0x51, 0x05, 0x82, 0x09, 0x74, 0x94, 0x45, 0x92,
+    ];
+    let xts = XtsAes256::new(&key);
+    let plaintext: alloc::vec::Vec<u8> = (0..512).map(|i| i as u8).collect();
+    let mut data = plaintext.clone();
+    xts.encrypt_sector(0xFF, &mut data);
+    assert_eq!(&data[..16], &[0x1c, 0x3b, 0x3a, 0x10, 0x2f, 0x77, 0x03, 0x86, 0xe4, 0x83, 0x6c, 0x99, 0xe3, 0x70, 0xcf, 0x9b]);
+    assert_eq!(&data[496..], &[0xc4, 0xf3, 0x6f, 0xfd, 0xa9, 0xfc, 0xea, 0x70, 0xb9, 0xc6, 0xe6, 0x93, 0xe1, 0x48, 0xc1, 0x51]);
+    xts.decrypt_sector(0xFF, &mut data);
+    assert_eq!(data, plaintext);
+}
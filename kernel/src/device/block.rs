@@ -9,15 +9,21 @@ use core::ops::Range;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 use crate::fs::partition::Partition;
+use crate::device::physical::SyncDisk;
 
 #[derive(Debug, Clone, Copy)]
 pub enum BlockDeviceError {
-
+    /// Requested range is outside of the underlying media
+    OutOfBounds,
+    /// The underlying disk reported an error
+    MediaError,
 }
 
 #[derive(Debug)]
 pub enum BlockDeviceMedia {
-    Partition(Partition)
+    Partition(Partition),
+    /// A whole disk without a partition table
+    Disk(SyncDisk),
 }
 
 pub const BLOCK_SIZE: usize = 4096;
@@ -30,28 +36,73 @@ pub struct BlockDevice {
 impl BlockDevice {
     pub fn new(media: BlockDeviceMedia) -> Self { Self { media } }
 
+    /// Number of requests the underlying disk can work on at once
+    pub fn queue_depth(&self) -> u32 {
+        match &self.media {
+            BlockDeviceMedia::Partition(part) => part.media().queue_depth(),
+            BlockDeviceMedia::Disk(disk) => disk.queue_depth(),
+        }
+    }
+
     pub fn open() -> Result<Box<Self>, BlockDeviceError> {
         unimplemented!();
     }
     pub fn close(&self) -> Result<(), BlockDeviceError> {
         unimplemented!();
     }
-    pub fn read(&self, block_num: u64) -> Result<Block, BlockDeviceError> {
+    pub async fn read(&self, block_num: u64) -> Result<Block, BlockDeviceError> {
         match &self.media {
             BlockDeviceMedia::Partition(part) => {
                 let mut buffer = [0u8; BLOCK_SIZE];
-                part.read_bytes((block_num * BLOCK_SIZE as u64)..((block_num + 1) * BLOCK_SIZE as u64), &mut buffer);
+                part.read_bytes((block_num * BLOCK_SIZE as u64)..((block_num + 1) * BLOCK_SIZE as u64), &mut buffer).await?;
                 Ok(buffer)
-            }
+            },
+            BlockDeviceMedia::Disk(disk) => {
+                let sector_size = disk.block_length().await
+                    .map_err(|_| BlockDeviceError::MediaError)? as u64;
+                let start = block_num * BLOCK_SIZE as u64;
+                if disk.size().map_or(false, |size| start + BLOCK_SIZE as u64 > size) {
+                    return Err(BlockDeviceError::OutOfBounds);
+                }
+                // sector sizes are all factors of the block size, so blocks always start on a sector
+                let mut buffer = [0u8; BLOCK_SIZE];
+                if let Err(e) = disk.read(start / sector_size, &mut buffer).await {
+                    crate::serial_println!("Disk read failed: {}", e);
+                    return Err(BlockDeviceError::MediaError);
+                }
+                Ok(buffer)
+            },
+        }
+    }
+    /// Tell the underlying disk the given blocks are no longer in use
+    pub async fn discard(&self, block_range: Range<u64>) -> Result<(), BlockDeviceError> {
+        match &self.media {
+            BlockDeviceMedia::Partition(part) => {
+                part.discard_bytes((block_range.start * BLOCK_SIZE as u64)..(block_range.end * BLOCK_SIZE as u64)).await
+            },
+            BlockDeviceMedia::Disk(disk) => {
+                let sector_size = disk.block_length().await
+                    .map_err(|_| BlockDeviceError::MediaError)? as u64;
+                if block_range.start >= block_range.end {
+                    return Err(BlockDeviceError::OutOfBounds);
+                }
+                let sectors_per_block = BLOCK_SIZE as u64 / sector_size;
+                if let Err(e) = disk.discard(block_range.start * sectors_per_block,
+                                             (block_range.end - block_range.start) * sectors_per_block).await {
+                    crate::serial_println!("Disk discard failed: {}", e);
+                    return Err(BlockDeviceError::MediaError);
+                }
+                Ok(())
+            },
         }
     }
-    pub fn read_range(&self, _block_range: Range<u64>) -> Result<Vec<Block>, BlockDeviceError> {
+    pub async fn read_range(&self, _block_range: Range<u64>) -> Result<Vec<Block>, BlockDeviceError> {
         unimplemented!();
     }
-    pub fn write(&self, _block_num: u64, _block: Block) -> Result<(), BlockDeviceError> {
+    pub async fn write(&self, _block_num: u64, _block: Block) -> Result<(), BlockDeviceError> {
         unimplemented!();
     }
-    pub fn write_range(&self, _block_range: Range<u64>, _block: Block) -> Result<(), BlockDeviceError> {
+    pub async fn write_range(&self, _block_range: Range<u64>, _block: Block) -> Result<(), BlockDeviceError> {
         unimplemented!();
     }
 }
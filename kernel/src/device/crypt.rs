@@ -0,0 +1,395 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Encrypted volumes: a partition (or whole disk) whose contents are encrypted with AES-XTS.
+//!
+//! The first 4 KiB of the volume hold a header with the key derivation parameters and the master
+//! key, wrapped with a key derived from the passphrase. Everything after the header is data,
+//! encrypted sector by sector with the master key. Unlocking a volume gives a [CryptDisk], which
+//! is registered with the disk service like any other disk and scanned for a filesystem.
+//!
+//! Changing the passphrase only means rewrapping the master key, and a wrong passphrase is caught
+//! by the integrity check built into the key wrap.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use byteorder::{ByteOrder, LittleEndian};
+use spin::Mutex;
+use crate::crypto::aes::{self, Aes256};
+use crate::crypto::sha256::pbkdf2_hmac_sha256;
+use crate::crypto::xts::{XtsAes256, KEY_SIZE};
+use crate::crypto::zeroize;
+use crate::device::block::{BlockDevice, BlockDeviceMedia};
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType, SyncDisk};
+use crate::fs::partition::Partition;
+use crate::util::{rdrand_u64, UUID};
+
+const MAGIC: &[u8; 8] = b"UOSCRYPT";
+const VERSION: u32 = 1;
+/// AES-256 in XTS mode, tweaked with the sector number from the start of the data
+const CIPHER_AES_XTS_PLAIN64: u32 = 1;
+const KDF_PBKDF2_SHA256: u32 = 1;
+/// PBKDF2 iterations for new volumes. Unlocking takes a moment on purpose.
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+/// Bytes at the start of the volume reserved for the header
+const HEADER_SIZE: usize = 4096;
+const SALT_SIZE: usize = 32;
+const WRAPPED_KEY_SIZE: usize = KEY_SIZE + 8;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+/// Volumes that are currently unlocked, so the same one doesn't end up registered twice
+static UNLOCKED: Mutex<Vec<UUID>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone)]
+struct Header {
+    uuid: UUID,
+    iterations: u32,
+    salt: [u8; SALT_SIZE],
+    wrapped_key: [u8; WRAPPED_KEY_SIZE],
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Result<Option<Self>, anyhow::Error> {
+        if &bytes[0..8] != MAGIC {
+            return Ok(None);
+        }
+        let version = LittleEndian::read_u32(&bytes[8..12]);
+        let cipher = LittleEndian::read_u32(&bytes[12..16]);
+        let kdf = LittleEndian::read_u32(&bytes[16..20]);
+        if version != VERSION || cipher != CIPHER_AES_XTS_PLAIN64 || kdf != KDF_PBKDF2_SHA256 {
+            return Err(anyhow::anyhow!("crypt: unsupported volume (version {}, cipher {}, KDF {})", version, cipher, kdf));
+        }
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&bytes[24..40]);
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(&bytes[40..40 + SALT_SIZE]);
+        let mut wrapped_key = [0u8; WRAPPED_KEY_SIZE];
+        wrapped_key.copy_from_slice(&bytes[72..72 + WRAPPED_KEY_SIZE]);
+        Ok(Some(Self { uuid: UUID(uuid), iterations: LittleEndian::read_u32(&bytes[20..24]), salt, wrapped_key }))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut bytes[8..12], VERSION);
+        LittleEndian::write_u32(&mut bytes[12..16], CIPHER_AES_XTS_PLAIN64);
+        LittleEndian::write_u32(&mut bytes[16..20], KDF_PBKDF2_SHA256);
+        LittleEndian::write_u32(&mut bytes[20..24], self.iterations);
+        bytes[24..40].copy_from_slice(&self.uuid.0);
+        bytes[40..40 + SALT_SIZE].copy_from_slice(&self.salt);
+        bytes[72..72 + WRAPPED_KEY_SIZE].copy_from_slice(&self.wrapped_key);
+        bytes
+    }
+
+    /// The key that wraps the master key
+    async fn key_encryption_key(&self, passphrase: &[u8]) -> Aes256 {
+        let mut kek = [0u8; aes::KEY_SIZE];
+        pbkdf2_hmac_sha256(passphrase, &self.salt, self.iterations, &mut kek).await;
+        let aes = Aes256::new(&kek);
+        zeroize(&mut kek);
+        aes
+    }
+}
+
+/// Fill `bytes` from RDRAND. Keys can't fall back to anything weaker, so this fails without it.
+fn random_bytes(bytes: &mut [u8]) -> Result<(), anyhow::Error> {
+    for chunk in bytes.chunks_mut(8) {
+        let value = rdrand_u64().ok_or_else(|| anyhow::anyhow!("crypt: no hardware random number generator"))?;
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+    Ok(())
+}
+
+/// The sectors an encrypted volume occupies
+#[derive(Debug, Clone)]
+pub struct CryptTarget {
+    disk: SyncDisk,
+    first_sector: u64,
+    sectors: u64,
+}
+
+impl CryptTarget {
+    pub async fn whole_disk(disk: SyncDisk) -> Result<Self, anyhow::Error> {
+        let sector_size = disk.block_length().await? as u64;
+        let size = disk.size().ok_or_else(|| anyhow::anyhow!("crypt: disk {} has no known size", disk.id()))?;
+        Ok(Self { disk, first_sector: 0, sectors: size / sector_size })
+    }
+
+    pub fn partition(partition: &Partition) -> Self {
+        let (first, last) = partition.sector_range();
+        Self { disk: partition.media().clone(), first_sector: first, sectors: last - first + 1 }
+    }
+
+    pub async fn from_media(media: &BlockDeviceMedia) -> Result<Self, anyhow::Error> {
+        match media {
+            BlockDeviceMedia::Partition(part) => Ok(Self::partition(part)),
+            BlockDeviceMedia::Disk(disk) => Self::whole_disk(disk.clone()).await,
+        }
+    }
+
+    /// Sectors taken up by the header
+    async fn header_sectors(&self) -> Result<u64, anyhow::Error> {
+        let sector_size = self.disk.block_length().await? as usize;
+        if sector_size == 0 || HEADER_SIZE % sector_size != 0 {
+            return Err(anyhow::anyhow!("crypt: unsupported sector size {}", sector_size));
+        }
+        Ok((HEADER_SIZE / sector_size) as u64)
+    }
+
+    async fn read_header(&self) -> Result<Option<Header>, anyhow::Error> {
+        if self.sectors <= self.header_sectors().await? {
+            return Ok(None);
+        }
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        self.disk.read(self.first_sector, &mut bytes).await?;
+        Header::parse(&bytes)
+    }
+}
+
+/// True if `device` starts with an encryption header
+pub async fn is_encrypted(device: &BlockDevice) -> bool {
+    match device.read(0).await {
+        Ok(block) => &block[0..8] == MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// True if `device` holds an encrypted volume that's currently unlocked
+pub async fn is_unlocked(device: &BlockDevice) -> bool {
+    match device.read(0).await {
+        Ok(block) => match Header::parse(&block) {
+            Ok(Some(header)) => UNLOCKED.lock().contains(&header.uuid),
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// Set up encryption on `target`, protected by `passphrase`. Whatever was on it is lost.
+pub async fn format(target: &CryptTarget, passphrase: &[u8]) -> Result<UUID, anyhow::Error> {
+    format_with_iterations(target, passphrase, DEFAULT_ITERATIONS).await
+}
+
+async fn format_with_iterations(target: &CryptTarget, passphrase: &[u8], iterations: u32) -> Result<UUID, anyhow::Error> {
+    if target.sectors <= target.header_sectors().await? {
+        return Err(anyhow::anyhow!("crypt: volume is too small"));
+    }
+    if let Ok(Some(old)) = target.read_header().await {
+        if UNLOCKED.lock().contains(&old.uuid) {
+            return Err(anyhow::anyhow!("crypt: volume {} is unlocked, refusing to erase it", old.uuid));
+        }
+    }
+    let mut master_key = [0u8; KEY_SIZE];
+    let mut salt = [0u8; SALT_SIZE];
+    random_bytes(&mut master_key)?;
+    random_bytes(&mut salt)?;
+    let mut header = Header { uuid: UUID::random(), iterations, salt, wrapped_key: [0; WRAPPED_KEY_SIZE] };
+    let kek = header.key_encryption_key(passphrase).await;
+    aes::wrap_key(&kek, &master_key, &mut header.wrapped_key);
+    zeroize(&mut master_key);
+
+    target.disk.write(target.first_sector, &header.to_bytes()).await?;
+    target.disk.flush().await?;
+    crate::both_println!("Encrypted volume {} created", header.uuid);
+    Ok(header.uuid)
+}
+
+/// Unlock the volume on `target` with `passphrase`
+pub async fn unlock(target: CryptTarget, passphrase: &[u8]) -> Result<CryptDisk, anyhow::Error> {
+    let header = target.read_header().await?
+        .ok_or_else(|| anyhow::anyhow!("crypt: not an encrypted volume"))?;
+    if UNLOCKED.lock().contains(&header.uuid) {
+        return Err(anyhow::anyhow!("crypt: volume {} is already unlocked", header.uuid));
+    }
+
+    let kek = header.key_encryption_key(passphrase).await;
+    let mut master_key = [0u8; KEY_SIZE];
+    if !aes::unwrap_key(&kek, &header.wrapped_key, &mut master_key) {
+        return Err(anyhow::anyhow!("crypt: wrong passphrase"));
+    }
+    let cipher = XtsAes256::new(&master_key);
+    zeroize(&mut master_key);
+
+    let header_sectors = target.header_sectors().await?;
+    let sector_size = target.disk.block_length().await?;
+    {
+        // it might have been unlocked while the key was being derived
+        let mut unlocked = UNLOCKED.lock();
+        if unlocked.contains(&header.uuid) {
+            return Err(anyhow::anyhow!("crypt: volume {} is already unlocked", header.uuid));
+        }
+        unlocked.push(header.uuid);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    crate::both_println!("Encrypted volume {} unlocked", header.uuid);
+    Ok(CryptDisk {
+        id,
+        uuid: header.uuid,
+        data_start: target.first_sector + header_sectors,
+        sectors: target.sectors - header_sectors,
+        sector_size,
+        disk: target.disk,
+        cipher,
+    })
+}
+
+/// The decrypted contents of an unlocked volume
+#[derive(Debug)]
+pub struct CryptDisk {
+    id: usize,
+    uuid: UUID,
+    disk: SyncDisk,
+    /// First sector of the data on the underlying disk
+    data_start: u64,
+    sectors: u64,
+    sector_size: u32,
+    cipher: XtsAes256,
+}
+
+impl CryptDisk {
+    pub fn uuid(&self) -> UUID { self.uuid }
+
+    fn check_range(&self, block: u64, len: usize) -> Result<(), anyhow::Error> {
+        let sectors = (len / self.sector_size as usize) as u64;
+        if len % self.sector_size as usize != 0 || block.checked_add(sectors).map_or(true, |end| end > self.sectors) {
+            return Err(anyhow::anyhow!("encrypted volume {}: bad request of {} bytes at {}", self.id, len, block));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CryptDisk {
+    fn drop(&mut self) {
+        UNLOCKED.lock().retain(|uuid| *uuid != self.uuid);
+    }
+}
+
+impl Disk for CryptDisk {
+    fn id(&self) -> usize { self.id }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::EncryptedVolume }
+    fn size(&self) -> Option<u64> { Some(self.sectors * self.sector_size as u64) }
+    fn queue_depth(&self) -> u32 { self.disk.queue_depth() }
+
+    fn info(&self) -> Option<DiskInfo> {
+        let mut info = self.disk.info()?.clone();
+        info.serial = self.uuid.as_string();
+        info.sectors = self.sectors;
+        // the disk must not learn which sectors are unused
+        info.trim = false;
+        info.smart = false;
+        info.smart_enabled = false;
+        Some(info)
+    }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_range(block, buffer.len())?;
+            let read = self.disk.read(self.data_start + block, buffer).await?;
+            for (i, sector) in buffer.chunks_exact_mut(self.sector_size as usize).enumerate() {
+                self.cipher.decrypt_sector(block + i as u64, sector);
+            }
+            Ok(read)
+        })
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_range(block, buffer.len())?;
+            let mut encrypted = buffer.to_vec();
+            for (i, sector) in encrypted.chunks_exact_mut(self.sector_size as usize).enumerate() {
+                self.cipher.encrypt_sector(block + i as u64, sector);
+            }
+            self.disk.write(self.data_start + block, &encrypted).await
+        })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        let sector_size = self.sector_size;
+        Box::pin(async move { Ok(sector_size) })
+    }
+
+    // discards aren't passed on: zeroed sectors on the disk would show which parts of the volume are unused
+
+    fn flush(&mut self) -> DiskFuture<'_, ()> {
+        Box::pin(self.disk.flush())
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_crypt_header_round_trip() {
+    let header = Header { uuid: UUID([3; 16]), iterations: 1000, salt: [5; SALT_SIZE], wrapped_key: [9; WRAPPED_KEY_SIZE] };
+    let parsed = Header::parse(&header.to_bytes()).unwrap().unwrap();
+    assert_eq!(parsed.uuid, header.uuid);
+    assert_eq!(parsed.iterations, 1000);
+    assert_eq!(parsed.salt, header.salt);
+    assert_eq!(parsed.wrapped_key[..], header.wrapped_key[..]);
+    assert!(Header::parse(&[0u8; HEADER_SIZE]).unwrap().is_none());
+}
+
+#[test_case]
+fn test_crypt_unlock_read_write() {
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    // a header written by hand, so the master key is known
+    let master_key = [0x42u8; KEY_SIZE];
+    let mut header = Header { uuid: UUID([0x77; 16]), iterations: 1, salt: [1; SALT_SIZE], wrapped_key: [0; WRAPPED_KEY_SIZE] };
+    aes::wrap_key(&header.key_encryption_key(b"hunter2").now_or_never().unwrap(), &master_key, &mut header.wrapped_key);
+    let disk = SyncDisk::new(Box::new(RamDisk::from_image(header.to_bytes(), 64 * 512).unwrap()));
+
+    let target = CryptTarget::whole_disk(disk.clone()).now_or_never().unwrap().unwrap();
+    assert!(unlock(target.clone(), b"hunter3").now_or_never().unwrap().is_err());
+    let mut crypt = unlock(target.clone(), b"hunter2").now_or_never().unwrap().unwrap();
+    assert_eq!(crypt.size(), Some((64 - 8) * 512));
+    // only one of it at a time
+    assert!(unlock(target, b"hunter2").now_or_never().unwrap().is_err());
+
+    let data = vec![0xABu8; 1024];
+    crypt.write(2, &data).now_or_never().unwrap().unwrap();
+    let mut raw = vec![0u8; 1024];
+    disk.read(8 + 2, &mut raw).now_or_never().unwrap().unwrap();
+    assert_ne!(raw, data);
+    let mut buffer = vec![0u8; 1024];
+    crypt.read(2, &mut buffer).now_or_never().unwrap().unwrap();
+    assert_eq!(buffer, data);
+}
+
+#[test_case]
+fn test_crypt_format_whole_disk() {
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    let disk = SyncDisk::new(Box::new(RamDisk::new(64 * 512).unwrap()));
+    let target = CryptTarget::whole_disk(disk.clone()).now_or_never().unwrap().unwrap();
+    // few enough iterations that the key derivation never yields
+    let uuid = format_with_iterations(&target, b"hunter2", 16).now_or_never().unwrap().unwrap();
+
+    // what the disk service sees when it rescans the disk
+    let device = BlockDevice::new(BlockDeviceMedia::Disk(disk.clone()));
+    assert!(is_encrypted(&device).now_or_never().unwrap());
+    assert!(!is_unlocked(&device).now_or_never().unwrap());
+
+    let target = CryptTarget::from_media(&device.media).now_or_never().unwrap().unwrap();
+    assert!(unlock(target.clone(), b"hunter3").now_or_never().unwrap().is_err());
+    let mut crypt = unlock(target, b"hunter2").now_or_never().unwrap().unwrap();
+    assert_eq!(crypt.uuid(), uuid);
+    assert!(is_unlocked(&device).now_or_never().unwrap());
+    assert_eq!(crypt.size(), Some((64 - 8) * 512));
+
+    let data = vec![0x5Au8; 512];
+    crypt.write(0, &data).now_or_never().unwrap().unwrap();
+    let mut buffer = vec![0u8; 512];
+    crypt.read(0, &mut buffer).now_or_never().unwrap().unwrap();
+    assert_eq!(buffer, data);
+    // the header survives writes to the volume
+    drop(crypt);
+    assert!(is_encrypted(&device).now_or_never().unwrap());
+    assert!(!is_unlocked(&device).now_or_never().unwrap());
+}
@@ -10,5 +10,9 @@ pub mod physical;
 pub mod virt;
 /// Block devices (read or write 4kiB blocks)
 pub mod block;
+/// Encrypted volumes (AES-XTS with a passphrase-protected key)
+pub mod crypt;
+/// Per-disk request queues (merging, ordering and barriers)
+pub mod scheduler;
 /// Serial devices (for printing output or receiving input from a physical terminal)
 pub mod serial;
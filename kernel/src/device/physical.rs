@@ -4,8 +4,18 @@
 // See LICENSE.txt and CREDITS.txt for details
 ///////////////////////////////////////////////////////////////////////////////L
 
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use futures_util::future::LocalBoxFuture;
+use crate::device::scheduler::{IoScheduler, QueueStats, RequestKind};
+use crate::driver::smart::SmartReport;
+use crate::sync::{AsyncMutex, AsyncMutexGuard};
+
+/// Future returned by the I/O methods on [Disk]
+pub type DiskFuture<'a, T> = LocalBoxFuture<'a, Result<T, anyhow::Error>>;
 
 pub trait Disk {
     /// Returns the ID for this disk
@@ -14,33 +24,183 @@ pub trait Disk {
     fn kind(&self) -> PhysicalDeviceType;
     /// Returns the size of the disk in bytes, or `None` if the size is unknown
     fn size(&self) -> Option<u64>;
-    /// Read data from the disk into the given buffer starting from block number `block`
-    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>, anyhow::Error>;
-    /// Write data to the disk from the given buffer starting at block number `block`
-    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>, anyhow::Error>;
+    /// Read data from the disk into the given buffer starting from block number `block`.
+    /// Resolves to the number of bytes read once the transfer is complete.
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize>;
+    /// Write data to the disk from the given buffer starting at block number `block`.
+    /// Resolves to the number of bytes written once the transfer is complete.
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize>;
     /// Return this disk's block length in bytes
-    fn block_length(&mut self) -> Result<u32, anyhow::Error>;
+    fn block_length(&mut self) -> DiskFuture<'_, u32>;
+    /// Number of commands the disk can work on at once. A single request larger than one command
+    /// is split up and kept in flight up to this depth, so bigger requests are better.
+    fn queue_depth(&self) -> u32 { 1 }
+    /// Information the device reported about itself, if the driver knows how to ask for it
+    fn info(&self) -> Option<DiskInfo> { None }
+    /// Tell the disk that `count` blocks starting at `block` no longer hold useful data,
+    /// so an SSD can erase them ahead of time. Disks that can't do this just ignore it.
+    fn discard(&mut self, _block: u64, _count: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+    /// Make sure everything written so far has reached stable storage.
+    /// Disks without a volatile write cache just ignore it.
+    fn flush(&mut self) -> DiskFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+    /// Read the disk's SMART health data
+    fn smart(&mut self) -> DiskFuture<'_, SmartReport> {
+        Box::pin(async { Err(anyhow::anyhow!("SMART is not supported by this disk")) })
+    }
+}
+
+/// Identification and capabilities of a disk, as reported by the device
+#[derive(Debug, Clone)]
+pub struct DiskInfo {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    /// Number of logical sectors
+    pub sectors: u64,
+    /// Size of the sectors that LBAs count in
+    pub logical_sector_size: u32,
+    /// Size of the sectors the media actually writes, writes smaller than this are read-modify-write
+    pub physical_sector_size: u32,
+    /// Supports 48-bit LBA addressing
+    pub lba48: bool,
+    /// Maximum NCQ queue depth, `None` if NCQ isn't supported
+    pub ncq_depth: Option<u32>,
+    /// Supports TRIM (discarding unused blocks)
+    pub trim: bool,
+    /// Has a volatile write cache
+    pub write_cache: bool,
+    pub write_cache_enabled: bool,
+    /// Supports SMART health reporting
+    pub smart: bool,
+    pub smart_enabled: bool,
+    /// Writes and discards are rejected
+    pub read_only: bool,
 }
 
+/// Shared handle to a [Disk]. Reads, writes, discards and flushes from every task go through the
+/// disk's [IoScheduler], and the disk itself is behind an async lock so waiting on it doesn't
+/// stall the executor.
 #[derive(Clone)]
 pub struct SyncDisk {
-    disk: Arc<Mutex<Arc<dyn Disk>>>
+    disk: Arc<AsyncMutex<Box<dyn Disk>>>,
+    scheduler: Arc<IoScheduler>,
+    id: usize,
+    kind: PhysicalDeviceType,
+    size: Option<u64>,
+    queue_depth: u32,
+    info: Option<Arc<DiskInfo>>,
 }
 impl SyncDisk {
-    pub fn new(disk: Arc<dyn Disk>) -> Self {
-        Self { disk: Arc::new(Mutex::new(disk)) }
+    pub fn new(disk: Box<dyn Disk>) -> Self {
+        let id = disk.id();
+        let kind = disk.kind();
+        let size = disk.size();
+        let queue_depth = disk.queue_depth();
+        let info = disk.info().map(Arc::new);
+        Self { disk: Arc::new(AsyncMutex::new(disk)), scheduler: Arc::new(IoScheduler::new()), id, kind, size, queue_depth, info }
+    }
+
+    /// See [Disk::info]
+    pub fn info(&self) -> Option<&DiskInfo> {
+        self.info.as_deref()
+    }
+
+    /// See [Disk::id]
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// See [Disk::kind]
+    pub fn kind(&self) -> PhysicalDeviceType {
+        self.kind
+    }
+
+    /// See [Disk::size]. This is the size when the disk was opened.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// See [Disk::queue_depth]
+    pub fn queue_depth(&self) -> u32 {
+        self.queue_depth
+    }
+
+    /// See [DiskInfo::read_only]
+    pub fn read_only(&self) -> bool {
+        self.info().map_or(false, |info| info.read_only)
+    }
+
+    /// Statistics about the disk's request queue
+    pub fn queue_stats(&self) -> QueueStats {
+        self.scheduler.stats()
+    }
+
+    /// Lock the disk for exclusive access. This bypasses the request queue.
+    pub async fn lock(&self) -> AsyncMutexGuard<'_, Box<dyn Disk>> {
+        self.disk.lock().await
+    }
+
+    pub async fn read(&self, block: u64, buffer: &mut [u8]) -> Result<usize, anyhow::Error> {
+        let data = self.scheduler.submit(&self.disk, RequestKind::Read, block, buffer.len(), 0, Vec::new()).await?;
+        buffer.copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    pub async fn write(&self, block: u64, buffer: &[u8]) -> Result<usize, anyhow::Error> {
+        if self.read_only() {
+            return Err(anyhow::anyhow!("disk {:?} {} is read only", self.kind, self.id));
+        }
+        self.scheduler.submit(&self.disk, RequestKind::Write, block, buffer.len(), 0, buffer.to_vec()).await?;
+        Ok(buffer.len())
+    }
+
+    pub async fn block_length(&self) -> Result<u32, anyhow::Error> {
+        self.disk.lock().await.block_length().await
+    }
+
+    pub async fn discard(&self, block: u64, count: u64) -> Result<(), anyhow::Error> {
+        if self.read_only() {
+            return Err(anyhow::anyhow!("disk {:?} {} is read only", self.kind, self.id));
+        }
+        self.scheduler.submit(&self.disk, RequestKind::Discard, block, 0, count, Vec::new()).await.map(|_| ())
+    }
+
+    /// Wait for every write submitted before this to complete, then flush the disk's write cache.
+    /// Writes submitted afterwards aren't started until the flush is done.
+    pub async fn flush(&self) -> Result<(), anyhow::Error> {
+        self.scheduler.submit(&self.disk, RequestKind::Flush, 0, 0, 0, Vec::new()).await.map(|_| ())
+    }
+
+    pub async fn smart(&self) -> Result<SmartReport, anyhow::Error> {
+        self.disk.lock().await.smart().await
+    }
+}
+impl Debug for SyncDisk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "SyncDisk {{ disk: {:?}, id: {}, kind: {:?}, queue_depth: {} }}",
+               self.disk, self.id, self.kind, self.queue_depth)
     }
 }
 unsafe impl Send for SyncDisk {}
 unsafe impl Sync for SyncDisk {}
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PhysicalDeviceType {
     FloppyDrive,
     IdeDrive,
+    IdeAtapiDrive,
     SataDrive,
     SatapiDrive,
     NVMeDrive,
+    VirtioDrive,
+    RamDisk,
+    LoopDevice,
+    RaidArray,
+    EncryptedVolume,
     Unknown
 }
 
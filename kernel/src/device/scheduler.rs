@@ -0,0 +1,456 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Per-disk request queue between [SyncDisk](super::physical::SyncDisk) and the driver.
+//!
+//! Every request goes into the disk's queue first. Whichever task holds the disk lock dispatches
+//! queued requests until its own one is done, so requests that pile up while the disk is busy get
+//! scheduled together without a separate task per disk. When picking the next command:
+//!
+//! - a flush is a barrier: it goes out after everything submitted before it, and nothing submitted
+//!   after it goes out before it completes
+//! - requests that overlap an earlier write (or a write overlapping an earlier read) wait for it
+//! - a request that has waited longer than [REQUEST_DEADLINE] goes next
+//! - a task that got [MAX_TASK_STREAK] commands in a row gives way to other tasks
+//! - otherwise the disk is swept in one direction from the last position (C-LOOK)
+//!
+//! Adjacent requests of the same kind are then merged into one command of up to [MAX_MERGE_BYTES].
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::device::physical::Disk;
+use crate::sync::AsyncMutex;
+use crate::time::Instant;
+
+/// Largest read or write command built by merging requests
+pub const MAX_MERGE_BYTES: usize = 128 * 1024;
+/// Requests that have waited this long are dispatched before anything else
+pub const REQUEST_DEADLINE: Duration = Duration::from_millis(500);
+/// Commands in a row one task can get while other tasks are waiting
+pub const MAX_TASK_STREAK: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Read,
+    Write,
+    Discard,
+    Flush,
+}
+
+/// Statistics about a disk's request queue
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueStats {
+    /// Requests submitted to the queue
+    pub submitted: u64,
+    /// Requests that were merged into another request's command
+    pub merged: u64,
+    /// Commands sent to the disk
+    pub dispatched: u64,
+    /// Requests waiting to be dispatched right now
+    pub queued: usize,
+    /// Most requests that were waiting at once
+    pub max_queued: usize,
+    /// Requests that have completed, successfully or not
+    pub completed: u64,
+    /// Time from submission to completion, summed over all completed requests
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+impl QueueStats {
+    pub fn average_latency(&self) -> Duration {
+        if self.completed == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total_latency / self.completed as u32
+        }
+    }
+}
+
+type RequestResult = Result<Vec<u8>, anyhow::Error>;
+
+/// Where the dispatcher leaves the result of a request for the task that submitted it
+#[derive(Debug, Default)]
+struct Completion {
+    result: Mutex<Option<RequestResult>>,
+    /// The submitter went away, don't bother dispatching it
+    cancelled: AtomicBool,
+}
+impl Completion {
+    fn set(&self, result: RequestResult) {
+        let mut lock = self.result.lock();
+        if lock.is_none() {
+            *lock = Some(result);
+        }
+    }
+}
+
+/// Marks a request as cancelled if the future waiting for it is dropped
+struct CancelOnDrop<'a>(&'a Completion);
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    seq: u64,
+    kind: RequestKind,
+    block: u64,
+    /// Length of reads and writes in bytes
+    bytes: usize,
+    /// Length of discards in blocks
+    discard_blocks: u64,
+    /// Data to write
+    data: Vec<u8>,
+    task: Option<u64>,
+    /// Number of flushes submitted before this request
+    epoch: u64,
+    submitted: Instant,
+    completion: Arc<Completion>,
+}
+impl Request {
+    /// Number of blocks touched, 0 for flushes
+    fn blocks(&self, block_size: usize) -> u64 {
+        match self.kind {
+            RequestKind::Discard => self.discard_blocks,
+            _ => ((self.bytes + block_size - 1) / block_size) as u64,
+        }
+    }
+
+    fn overlaps(&self, other: &Request, block_size: usize) -> bool {
+        self.block < other.block + other.blocks(block_size) && other.block < self.block + self.blocks(block_size)
+    }
+
+    fn mergeable(&self, block_size: usize) -> bool {
+        match self.kind {
+            RequestKind::Read | RequestKind::Write => self.bytes % block_size == 0,
+            RequestKind::Discard => true,
+            RequestKind::Flush => false,
+        }
+    }
+}
+impl Drop for Request {
+    fn drop(&mut self) {
+        // the request was dropped without being completed, e.g. because the task dispatching it went away
+        let mut result = self.completion.result.lock();
+        if result.is_none() {
+            *result = Some(Err(anyhow::anyhow!("request was dropped before it completed")));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    pending: Vec<Request>,
+    next_seq: u64,
+    /// Flushes submitted so far
+    epoch: u64,
+    /// Block after the end of the last command
+    position: u64,
+    last_task: Option<u64>,
+    streak: u32,
+    stats: QueueStats,
+}
+impl QueueState {
+    /// Take the next batch of adjacent requests to send to the disk as one command, sorted by block
+    fn select(&mut self, now: Instant, block_size: usize) -> Option<Vec<Request>> {
+        self.pending.retain(|r| !r.completion.cancelled.load(Ordering::Relaxed));
+        self.stats.queued = self.pending.len();
+        let epoch = self.pending.iter().map(|r| r.epoch).min()?;
+
+        let ready: Vec<usize> = (0..self.pending.len())
+            .filter(|&i| self.pending[i].epoch == epoch && self.pending[i].kind != RequestKind::Flush)
+            .filter(|&i| !self.blocked(&self.pending[i], block_size))
+            .collect();
+        if ready.is_empty() {
+            // only the barrier that ends this epoch is left
+            let flush = self.pending.iter().position(|r| r.epoch == epoch)?;
+            self.stats.dispatched += 1;
+            return Some(vec![self.pending.remove(flush)]);
+        }
+
+        let others: Vec<usize> = ready.iter().copied()
+            .filter(|&i| self.pending[i].task != self.last_task)
+            .collect();
+        let candidates = if self.streak >= MAX_TASK_STREAK && !others.is_empty() { others } else { ready };
+
+        let oldest = *candidates.iter().min_by_key(|&&i| self.pending[i].seq).unwrap();
+        let first = if self.pending[oldest].submitted.until(now) >= REQUEST_DEADLINE {
+            oldest
+        } else {
+            let ahead = candidates.iter().copied()
+                .filter(|&i| self.pending[i].block >= self.position)
+                .min_by_key(|&i| (self.pending[i].block, self.pending[i].seq));
+            ahead.unwrap_or_else(|| *candidates.iter().min_by_key(|&&i| (self.pending[i].block, self.pending[i].seq)).unwrap())
+        };
+
+        // grow the batch forwards and backwards with requests that start or end right next to it
+        let kind = self.pending[first].kind;
+        let mut batch = vec![first];
+        let mut start = self.pending[first].block;
+        let mut end = start + self.pending[first].blocks(block_size);
+        let mut bytes = self.pending[first].bytes;
+        if self.pending[first].mergeable(block_size) {
+            loop {
+                let next = (0..self.pending.len()).find(|&i| {
+                    let r = &self.pending[i];
+                    r.epoch == epoch && r.kind == kind && r.mergeable(block_size) && !batch.contains(&i)
+                        && (r.block == end || r.block + r.blocks(block_size) == start)
+                        && (kind == RequestKind::Discard || bytes + r.bytes <= MAX_MERGE_BYTES)
+                        && !self.blocked(r, block_size)
+                });
+                match next {
+                    Some(i) => {
+                        let r = &self.pending[i];
+                        if r.block == end { end += r.blocks(block_size); } else { start = r.block; }
+                        bytes += r.bytes;
+                        batch.push(i);
+                    },
+                    None => break,
+                }
+            }
+        }
+
+        let task = self.pending[first].task;
+        if task == self.last_task {
+            self.streak += 1;
+        } else {
+            self.last_task = task;
+            self.streak = 1;
+        }
+        self.position = end;
+        self.stats.dispatched += 1;
+        self.stats.merged += batch.len() as u64 - 1;
+
+        // remove from the back so the other indices stay valid
+        batch.sort_unstable_by(|a, b| b.cmp(a));
+        let mut requests: Vec<Request> = batch.into_iter().map(|i| self.pending.remove(i)).collect();
+        requests.sort_by_key(|r| r.block);
+        Some(requests)
+    }
+
+    /// Whether an earlier request overlaps `request` and they can't be reordered
+    fn blocked(&self, request: &Request, block_size: usize) -> bool {
+        self.pending.iter().any(|r| r.seq < request.seq
+            && (r.kind != RequestKind::Read || request.kind != RequestKind::Read)
+            && r.overlaps(request, block_size))
+    }
+}
+
+/// Request queue for one disk
+#[derive(Debug, Default)]
+pub struct IoScheduler {
+    state: Mutex<QueueState>,
+}
+impl IoScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.state.lock().stats
+    }
+
+    fn enqueue(&self, kind: RequestKind, block: u64, bytes: usize, discard_blocks: u64, data: Vec<u8>) -> Arc<Completion> {
+        let completion = Arc::new(Completion::default());
+        let mut state = self.state.lock();
+        let epoch = state.epoch;
+        if kind == RequestKind::Flush {
+            state.epoch += 1;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.pending.push(Request {
+            seq, kind, block, bytes, discard_blocks, data,
+            task: crate::task::current_task_id(),
+            epoch,
+            submitted: Instant::now(),
+            completion: completion.clone(),
+        });
+        state.stats.submitted += 1;
+        state.stats.queued = state.pending.len();
+        state.stats.max_queued = core::cmp::max(state.stats.max_queued, state.pending.len());
+        completion
+    }
+
+    /// Queue a request and wait for it to complete, dispatching queued requests whenever we get hold of the disk.
+    /// Resolves to the data read for reads and an empty buffer for everything else.
+    pub async fn submit(&self, disk: &AsyncMutex<Box<dyn Disk>>, kind: RequestKind, block: u64,
+                        bytes: usize, discard_blocks: u64, data: Vec<u8>) -> RequestResult {
+        let completion = self.enqueue(kind, block, bytes, discard_blocks, data);
+        let _cancel = CancelOnDrop(&completion);
+        loop {
+            if let Some(result) = completion.result.lock().take() {
+                return result;
+            }
+            let mut disk = disk.lock().await;
+            // whoever had the disk before might have dispatched our request already
+            if completion.result.lock().is_some() {
+                continue;
+            }
+            let block_size = disk.block_length().await? as usize;
+            while completion.result.lock().is_none() {
+                let batch = self.state.lock().select(Instant::now(), block_size);
+                match batch {
+                    Some(batch) => self.dispatch(&mut **disk, batch, block_size).await,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Send one command for a batch of adjacent requests and complete them
+    async fn dispatch(&self, disk: &mut dyn Disk, batch: Vec<Request>, block_size: usize) {
+        match Self::execute(disk, &batch, block_size).await {
+            Ok(results) => {
+                for (request, data) in batch.into_iter().zip(results) {
+                    self.complete(request, Ok(data));
+                }
+            },
+            Err(_) if batch.len() > 1 => {
+                // try them one by one, so only the request that really failed gets the error
+                for request in batch {
+                    let result = Self::execute(disk, core::slice::from_ref(&request), block_size).await
+                        .map(|mut results| results.pop().unwrap_or_default());
+                    self.complete(request, result);
+                }
+            },
+            Err(err) => {
+                for request in batch {
+                    self.complete(request, Err(anyhow::anyhow!("{}", err)));
+                }
+            }
+        }
+    }
+
+    /// Issue the command for a batch, returning the data for each request
+    async fn execute(disk: &mut dyn Disk, batch: &[Request], block_size: usize) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        let first = &batch[0];
+        match first.kind {
+            RequestKind::Read => {
+                let mut buffer = vec![0u8; batch.iter().map(|r| r.bytes).sum()];
+                disk.read(first.block, &mut buffer).await?;
+                if batch.len() == 1 {
+                    return Ok(vec![buffer]);
+                }
+                // cut the pieces off the end, the first request keeps the buffer itself
+                let mut results: Vec<Vec<u8>> = batch[1..].iter().rev()
+                    .map(|r| buffer.split_off(buffer.len() - r.bytes))
+                    .collect();
+                results.push(buffer);
+                results.reverse();
+                Ok(results)
+            },
+            RequestKind::Write => {
+                if batch.len() == 1 {
+                    disk.write(first.block, &first.data).await?;
+                } else {
+                    let mut buffer = Vec::with_capacity(batch.iter().map(|r| r.data.len()).sum());
+                    for request in batch {
+                        buffer.extend_from_slice(&request.data);
+                    }
+                    disk.write(first.block, &buffer).await?;
+                }
+                Ok(batch.iter().map(|_| Vec::new()).collect())
+            },
+            RequestKind::Discard => {
+                let blocks = batch.iter().map(|r| r.blocks(block_size)).sum();
+                disk.discard(first.block, blocks).await?;
+                Ok(batch.iter().map(|_| Vec::new()).collect())
+            },
+            RequestKind::Flush => {
+                disk.flush().await?;
+                Ok(vec![Vec::new()])
+            },
+        }
+    }
+
+    fn complete(&self, request: Request, result: RequestResult) {
+        let latency = request.submitted.until(Instant::now());
+        {
+            let mut state = self.state.lock();
+            state.stats.completed += 1;
+            state.stats.total_latency += latency;
+            state.stats.max_latency = core::cmp::max(state.stats.max_latency, latency);
+        }
+        request.completion.set(result);
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+fn queue_with(requests: &[(RequestKind, u64, usize)]) -> QueueState {
+    let scheduler = IoScheduler::new();
+    for (kind, block, bytes) in requests.iter().copied() {
+        scheduler.enqueue(kind, block, bytes, 0, vec![0; if kind == RequestKind::Write { bytes } else { 0 }]);
+    }
+    let mut state = scheduler.state.lock();
+    core::mem::take(&mut *state)
+}
+
+#[test_case]
+fn test_scheduler_merges_adjacent() {
+    let mut queue = queue_with(&[(RequestKind::Read, 8, 1024), (RequestKind::Read, 4, 2048), (RequestKind::Read, 20, 512)]);
+    let batch = queue.select(Instant::new(0), 512).unwrap();
+    assert_eq!(batch.iter().map(|r| r.block).collect::<Vec<_>>(), vec![4, 8]);
+    assert_eq!(queue.stats.merged, 1);
+    let batch = queue.select(Instant::new(0), 512).unwrap();
+    assert_eq!(batch[0].block, 20);
+    assert!(queue.select(Instant::new(0), 512).is_none());
+}
+
+#[test_case]
+fn test_scheduler_flush_is_barrier() {
+    let mut queue = queue_with(&[(RequestKind::Write, 10, 512), (RequestKind::Flush, 0, 0), (RequestKind::Write, 11, 512)]);
+    let kinds: Vec<(RequestKind, u64)> = core::iter::from_fn(|| queue.select(Instant::new(0), 512))
+        .map(|batch| (batch[0].kind, batch[0].block))
+        .collect();
+    assert_eq!(kinds, vec![(RequestKind::Write, 10), (RequestKind::Flush, 0), (RequestKind::Write, 11)]);
+}
+
+#[test_case]
+fn test_scheduler_keeps_overlapping_writes_in_order() {
+    // the second write would come first in elevator order, but it overlaps the first one
+    let mut queue = queue_with(&[(RequestKind::Write, 4, 2048), (RequestKind::Write, 5, 512)]);
+    queue.position = 5;
+    assert_eq!(queue.select(Instant::new(0), 512).unwrap()[0].block, 4);
+    assert_eq!(queue.select(Instant::new(0), 512).unwrap()[0].block, 5);
+}
+
+#[test_case]
+fn test_scheduler_merged_data() {
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+    let image: Vec<u8> = (0..16 * 512).map(|i| (i / 512) as u8).collect();
+    let mut disk = RamDisk::from_image(image, 16 * 512).unwrap();
+
+    let mut queue = queue_with(&[(RequestKind::Read, 4, 1024), (RequestKind::Read, 6, 512), (RequestKind::Read, 7, 1536)]);
+    let batch = queue.select(Instant::new(0), 512).unwrap();
+    assert_eq!(batch.len(), 3);
+    let results = IoScheduler::execute(&mut disk, &batch, 512).now_or_never().unwrap().unwrap();
+    assert_eq!(results.iter().map(|data| data.len()).collect::<Vec<_>>(), vec![1024, 512, 1536]);
+    assert!(results[0][..512].iter().all(|&b| b == 4) && results[0][512..].iter().all(|&b| b == 5));
+    assert!(results[1].iter().all(|&b| b == 6));
+    assert!(results[2][1024..].iter().all(|&b| b == 9));
+    drop(batch);
+
+    let mut queue = queue_with(&[(RequestKind::Write, 2, 512), (RequestKind::Write, 3, 1024)]);
+    let mut batch = queue.select(Instant::new(0), 512).unwrap();
+    assert_eq!(batch.len(), 2);
+    batch[0].data.fill(0xA0);
+    batch[1].data.fill(0xB0);
+    IoScheduler::execute(&mut disk, &batch, 512).now_or_never().unwrap().unwrap();
+    let mut buffer = vec![0u8; 1536];
+    disk.read(2, &mut buffer).now_or_never().unwrap().unwrap();
+    assert!(buffer[..512].iter().all(|&b| b == 0xA0) && buffer[512..].iter().all(|&b| b == 0xB0));
+}
@@ -6,120 +6,116 @@
 
 use core::ptr;
 use super::hba::HbaPort;
-use crate::device::physical::{Disk, PhysicalDeviceType};
+use super::constants::{AHCI_MAX_RETRIES, ATA_DSM_MAX_RANGE_SECTORS, MAX_COMMAND_BYTES};
+use crate::driver::smart::{SmartReport, SMART_READ_DATA, SMART_READ_THRESHOLDS};
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
 use alloc::boxed::Box;
-
-enum BufferKind<'a> {
-    Read(&'a mut [u8]),
-    Write(&'a [u8]),
-}
-
-#[derive(Debug)]
-struct Request {
-    address: usize,
-    total_sectors: usize,
-    sector: usize,
-    running_opt: Option<(u32, usize)>,
-}
+use alloc::collections::VecDeque;
+use alloc::vec;
 
 #[derive(Debug)]
 pub struct AtaDisk {
     id: usize,
     port: &'static mut HbaPort,
     size: Option<u64>,
-    request_opt: Option<Request>,
-    buf: Box<[u8; 256 * 512]>
+    /// Bytes per logical sector, the unit LBAs count in
+    sector_size: usize,
+    /// Number of commands kept in flight at once. Anything above 1 means NCQ is in use.
+    queue_depth: u32,
+    info: Option<DiskInfo>,
 }
 
 impl AtaDisk {
-    pub fn new(id: usize, port: &'static mut HbaPort) -> Result<Self, anyhow::Error> {
-        let buf = Box::new([0u8; 256 * 512]);
-        port.init(id as u8);
-        let size = unsafe { port.identify() };
-        Ok(AtaDisk { id, port, size, request_opt: None, buf })
-    }
-
-    fn request(&mut self, block: u64, mut buffer_kind: BufferKind<'_>) -> Result<Option<usize>, anyhow::Error> {
-        let (write, address, total_sectors) = match buffer_kind {
-            BufferKind::Read(ref buffer) => (false, buffer.as_ptr() as usize, buffer.len()/512),
-            BufferKind::Write(buffer) => (true, buffer.as_ptr() as usize, buffer.len()/512),
+    pub async fn new(id: usize, port: &'static mut HbaPort) -> Result<Self, anyhow::Error> {
+        port.init(id as u8)?;
+        let identify = unsafe { port.identify().await };
+        let info = identify.as_ref().map(|id| id.info());
+        let sector_size = identify.as_ref().map_or(512, |id| id.logical_sector_size() as usize);
+        let size = identify.as_ref().map(|id| id.sectors() * sector_size as u64);
+        let queue_depth = match (super::supports_ncq(), identify.as_ref().and_then(|id| id.ncq_depth())) {
+            (true, Some(depth)) => core::cmp::min(depth, super::command_slots()),
+            _ => 1,
         };
+        crate::serial_println!("   + Queue depth: {}", queue_depth);
+        Ok(AtaDisk { id, port, size, sector_size, queue_depth, info })
+    }
 
-        //TODO: Go back to interrupt magic
-        let use_interrupts = false;
+    /// Transfer `len` bytes between `buffer` and the disk, starting at `block`.
+    ///
+    /// The transfer is split into commands of at most [MAX_COMMAND_BYTES], and up to `queue_depth`
+    /// of them are kept in flight at once. The PRDTs point straight at `buffer` unless the HBA can't reach it.
+    /// Commands that fail are retried up to [AHCI_MAX_RETRIES] times while the device is still attached.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be word aligned and valid for `len` bytes until the returned future completes.
+    async unsafe fn request(&mut self, block: u64, buffer: *const u8, len: usize, write: bool) -> Result<usize, anyhow::Error> {
+        if !self.port.device_present() {
+            return Err(anyhow::anyhow!("AHCI port {}: device not present", self.id));
+        }
+        let total_sectors = len / self.sector_size;
+        let sectors_per_command = MAX_COMMAND_BYTES / self.sector_size;
+        let queued = self.queue_depth > 1;
+
+        // (slot, mapping of the chunk, first sector, sector count, attempts so far)
+        let mut in_flight = VecDeque::new();
+        // (first sector, sector count, attempts so far) of failed commands that should be issued again
+        let mut retries = VecDeque::new();
+        let mut result = Ok(());
+        let mut sector = 0;
         loop {
-            let mut request = match self.request_opt.take() {
-                Some(request) => if address == request.address && total_sectors == request.total_sectors {
-                    // Keep servicing current request
-                    request
-                } else {
-                    // Have to wait for another request to finish
-                    self.request_opt = Some(request);
-                    return Ok(None);
+            // keep the queue as full as possible, retries first
+            let next = match retries.front() {
+                Some(&chunk) => Some(chunk),
+                None if sector < total_sectors => {
+                    Some((sector, core::cmp::min(sectors_per_command, total_sectors - sector), 0))
                 },
-                None => {
-                    // Create new request
-                    Request {
-                        address,
-                        total_sectors,
-                        sector: 0,
-                        running_opt: None,
-                    }
-                }
+                None => None,
             };
-
-            // Finish a previously running request
-            if let Some(running) = request.running_opt.take() {
-                if self.port.ata_running(running.0) {
-                    // Continue waiting for request
-                    request.running_opt = Some(running);
-                    self.request_opt = Some(request);
-                    if use_interrupts {
-                        return Ok(None);
-                    } else {
-                        //::std::thread::yield_now();
-                        continue;
+            if let Some((start, sectors, attempts)) = next {
+                if result.is_ok() && in_flight.len() < self.queue_depth as usize {
+                    let chunk = unsafe { buffer.add(start * self.sector_size) };
+                    match unsafe { self.port.ata_dma(block + start as u64, sectors, self.sector_size, write, queued, chunk) } {
+                        Some((slot, mapping)) => {
+                            in_flight.push_back((slot, mapping, start, sectors, attempts));
+                            if attempts == 0 {
+                                sector += sectors;
+                            } else {
+                                retries.pop_front();
+                            }
+                            continue;
+                        },
+                        None if in_flight.is_empty() => {
+                            result = Err(anyhow::anyhow!("AHCI DMA start failed: no free command slot or the buffer can't be mapped"));
+                        },
+                        // wait for a slot to free up
+                        None => {}
                     }
                 }
-
-                self.port.ata_stop(running.0)?;
-
-                if let BufferKind::Read(ref mut buffer) = buffer_kind {
-                    unsafe { ptr::copy(self.buf.as_ptr(), buffer.as_mut_ptr().add(request.sector * 512), running.1 * 512); }
-                }
-
-                request.sector += running.1;
             }
 
-            if request.sector < request.total_sectors {
-                // Start a new request
-                let sectors = if request.total_sectors - request.sector >= 255 {
-                    255
-                } else {
-                    request.total_sectors - request.sector
-                };
-
-                if let BufferKind::Write(buffer) = buffer_kind {
-                    unsafe { ptr::copy(buffer.as_ptr().add(request.sector * 512), self.buf.as_mut_ptr(), sectors * 512); }
-                }
-
-                if let Some(slot) = self.port.ata_dma(block + request.sector as u64, sectors, write, &mut self.buf) {
-                    request.running_opt = Some((slot, sectors));
-                }
-
-                self.request_opt = Some(request);
-
-                if use_interrupts {
-                    return Ok(None);
-                } else {
-                    //::std::thread::yield_now();
-                    continue;
-                }
-            } else {
-                // Done
-                return Ok(Some(request.sector * 512));
+            // parks this task until the HBA is done with the oldest command
+            match in_flight.pop_front() {
+                Some((slot, mapping, start, sectors, attempts)) => {
+                    let completion = self.port.ata_complete(slot).await;
+                    // copies the data out of the bounce buffer, if there is one
+                    drop(mapping);
+                    if let Err(err) = completion {
+                        if attempts < AHCI_MAX_RETRIES && result.is_ok() && self.port.device_present() {
+                            crate::serial_println!("AHCI port {}: retrying LBA {} ({}/{})",
+                                                   self.id, block + start as u64, attempts + 1, AHCI_MAX_RETRIES);
+                            retries.push_back((start, sectors, attempts + 1));
+                        } else if result.is_ok() {
+                            // keep draining the rest, but report the first error
+                            result = Err(err);
+                        }
+                    }
+                },
+                None => break,
             }
         }
+
+        result.map(|_| sector * self.sector_size)
     }
 }
 
@@ -127,14 +123,86 @@ impl Disk for AtaDisk {
     fn id(&self) -> usize { self.id }
     fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::SataDrive }
     fn size(&self) -> Option<u64> { self.size }
+    fn queue_depth(&self) -> u32 { self.queue_depth }
+    fn info(&self) -> Option<DiskInfo> { self.info.clone() }
+
+    fn discard(&mut self, block: u64, count: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            if !self.info.as_ref().map_or(false, |info| info.trim) {
+                return Ok(());
+            }
+            let end = block + count;
+            let mut next = block;
+            while next < end {
+                // one 512 byte block of range entries per command, unused entries stay zero
+                let mut ranges = vec![0u64; 64];
+                for entry in ranges.iter_mut() {
+                    if next >= end {
+                        break;
+                    }
+                    let sectors = core::cmp::min(end - next, ATA_DSM_MAX_RANGE_SECTORS);
+                    *entry = next | sectors << 48;
+                    next += sectors;
+                }
+                self.port.dsm_trim(&ranges).await?;
+            }
+            Ok(())
+        })
+    }
 
-    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>, anyhow::Error> {
-        self.request(block, BufferKind::Read(buffer))
+    fn flush(&mut self) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            match &self.info {
+                Some(info) if info.write_cache_enabled => self.port.flush_cache(info.lba48).await,
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn smart(&mut self) -> DiskFuture<'_, SmartReport> {
+        Box::pin(async move {
+            if !self.info.as_ref().map_or(false, |info| info.smart_enabled) {
+                return Err(anyhow::anyhow!("SMART is not enabled on disk {}", self.id));
+            }
+            let healthy = self.port.smart_return_status().await?;
+            let mut data = Box::new([0u16; 256]);
+            let mut thresholds = Box::new([0u16; 256]);
+            self.port.smart_read(SMART_READ_DATA, &mut data).await?;
+            self.port.smart_read(SMART_READ_THRESHOLDS, &mut thresholds).await?;
+
+            let as_bytes = |words: &[u16; 256]| unsafe { core::slice::from_raw_parts(words.as_ptr() as *const u8, 512) };
+            Ok(SmartReport::parse(healthy, as_bytes(&data), as_bytes(&thresholds)))
+        })
     }
 
-    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>, anyhow::Error> {
-        self.request(block, BufferKind::Write(buffer))
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            if buffer.as_ptr() as usize & 1 == 0 {
+                unsafe { self.request(block, buffer.as_mut_ptr(), buffer.len(), false).await }
+            } else {
+                // PRDT entries have to be word aligned, bounce odd buffers through an aligned one
+                let mut bounce = vec![0u16; (buffer.len() + 1) / 2];
+                let read = unsafe { self.request(block, bounce.as_mut_ptr() as *const u8, buffer.len(), false).await? };
+                unsafe { ptr::copy_nonoverlapping(bounce.as_ptr() as *const u8, buffer.as_mut_ptr(), read); }
+                Ok(read)
+            }
+        })
     }
 
-    fn block_length(&mut self) -> Result<u32, anyhow::Error> { Ok(512) }
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            if buffer.as_ptr() as usize & 1 == 0 {
+                unsafe { self.request(block, buffer.as_ptr(), buffer.len(), true).await }
+            } else {
+                let mut bounce = vec![0u16; (buffer.len() + 1) / 2];
+                unsafe { ptr::copy_nonoverlapping(buffer.as_ptr(), bounce.as_mut_ptr() as *mut u8, buffer.len()); }
+                unsafe { self.request(block, bounce.as_ptr() as *const u8, buffer.len(), true).await }
+            }
+        })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        let sector_size = self.sector_size as u32;
+        Box::pin(async move { Ok(sector_size) })
+    }
 }
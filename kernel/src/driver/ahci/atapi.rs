@@ -9,7 +9,8 @@
 use core::ptr;
 use byteorder::{ByteOrder, BigEndian};
 use super::hba::HbaPort;
-use crate::device::physical::{Disk, PhysicalDeviceType};
+use super::constants::MAX_COMMAND_BYTES;
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
 use alloc::boxed::Box;
 
 const SCSI_READ_CAPACITY: u8 = 0x25;
@@ -20,33 +21,44 @@ pub struct AtapiDisk {
     id: usize,
     port: &'static mut HbaPort,
     size: Option<u64>,
+    info: Option<DiskInfo>,
     // Just using the same buffer size as DiskATA
     // Although the sector size is different (and varies)
     buf: Box<[u8; 256 * 512]>
 }
 
 impl AtapiDisk {
-    pub fn new(id: usize, port: &'static mut HbaPort) -> Result<Self, anyhow::Error> {
+    pub async fn new(id: usize, port: &'static mut HbaPort) -> Result<Self, anyhow::Error> {
         let buf = Box::new([0u8; 256 * 512]);
 
-        port.init(id as u8);
+        port.init(id as u8)?;
 
-        let size = unsafe { port.identify_packet() };
+        let identify = unsafe { port.identify_packet().await };
+        let size = identify.as_ref().map(|id| id.sectors() * 512);
+        let info = identify.map(|id| DiskInfo {
+            // optical media always uses 2K sectors, words 106/117 don't apply to packet devices
+            logical_sector_size: 2048,
+            physical_sector_size: 2048,
+            // writing isn't implemented
+            read_only: true,
+            ..id.info()
+        });
 
         Ok(AtapiDisk {
             id,
             port,
             size,
+            info,
             buf,
         })
     }
 
-    fn read_capacity(&mut self) -> Result<(u32, u32), anyhow::Error> {
+    async fn read_capacity(&mut self) -> Result<(u32, u32), anyhow::Error> {
         // TODO: only query when needed (disk changed)
 
         let mut cmd = [0; 16];
         cmd[0] = SCSI_READ_CAPACITY;
-        self.port.atapi_dma(&cmd, 8, &mut self.buf)?;
+        self.port.atapi_dma(&cmd, 8, &mut self.buf).await?;
 
         // Instead of a count, contains number of last LBA, so add 1
         let blk_count = BigEndian::read_u32(&self.buf[0..4]) + 1;
@@ -59,6 +71,7 @@ impl AtapiDisk {
 impl Disk for AtapiDisk {
     fn id(&self) -> usize { self.id }
     fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::SatapiDrive }
+    fn info(&self) -> Option<DiskInfo> { self.info.clone() }
 
     fn size(&self) -> Option<u64> {
         self.size
@@ -69,48 +82,50 @@ impl Disk for AtapiDisk {
         // }
     }
 
-    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>, anyhow::Error> {
-        // TODO: Handle audio CDs, which use special READ CD command
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            // TODO: Handle audio CDs, which use special READ CD command
 
-        let blk_len = self.block_length()?;
-        let sectors = buffer.len() as u32 / blk_len;
+            let blk_len = self.read_capacity().await?.1;
+            let sectors = buffer.len() as u32 / blk_len;
 
-        fn read10_cmd(block: u32, count: u16) -> [u8; 16] {
-            let mut cmd = [0; 16];
-            cmd[0] = SCSI_READ10;
-            BigEndian::write_u32(&mut cmd[2..6], block);
-            BigEndian::write_u16(&mut cmd[7..9], count);
-            cmd
-        }
+            fn read10_cmd(block: u32, count: u16) -> [u8; 16] {
+                let mut cmd = [0; 16];
+                cmd[0] = SCSI_READ10;
+                BigEndian::write_u32(&mut cmd[2..6], block);
+                BigEndian::write_u16(&mut cmd[7..9], count);
+                cmd
+            }
 
-        let mut sector = 0;
-        let buf_len = (256 * 512) / blk_len;
-        let buf_size = buf_len * blk_len;
-        while sectors - sector >= buf_len {
-            let cmd = read10_cmd(block as u32 + sector, buf_len as u16);
-            self.port.atapi_dma(&cmd, buf_size, &mut self.buf)?;
+            let mut sector = 0;
+            let buf_len = MAX_COMMAND_BYTES as u32 / blk_len;
+            let buf_size = buf_len * blk_len;
+            while sectors - sector >= buf_len {
+                let cmd = read10_cmd(block as u32 + sector, buf_len as u16);
+                self.port.atapi_dma(&cmd, buf_size, &mut self.buf).await?;
 
-            unsafe { ptr::copy(self.buf.as_ptr(), buffer.as_mut_ptr().offset(sector as isize * blk_len as isize), buf_size as usize); }
+                unsafe { ptr::copy(self.buf.as_ptr(), buffer.as_mut_ptr().offset(sector as isize * blk_len as isize), buf_size as usize); }
 
-            sector += blk_len;
-        }
-        if sector < sectors {
-            let cmd = read10_cmd(block as u32 + sector, (sectors - sector) as u16);
-            self.port.atapi_dma(&cmd, buf_size, &mut self.buf)?;
+                sector += buf_len;
+            }
+            if sector < sectors {
+                let cmd = read10_cmd(block as u32 + sector, (sectors - sector) as u16);
+                self.port.atapi_dma(&cmd, buf_size, &mut self.buf).await?;
 
-            unsafe { ptr::copy(self.buf.as_ptr(), buffer.as_mut_ptr().offset(sector as isize * blk_len as isize), ((sectors - sector) * blk_len) as usize); }
+                unsafe { ptr::copy(self.buf.as_ptr(), buffer.as_mut_ptr().offset(sector as isize * blk_len as isize), ((sectors - sector) * blk_len) as usize); }
 
-            sector += sectors - sector;
-        }
+                sector += sectors - sector;
+            }
 
-        Ok(Some((sector * blk_len) as usize))
+            Ok((sector * blk_len) as usize)
+        })
     }
 
-    fn write(&mut self, _block: u64, _buffer: &[u8]) -> Result<Option<usize>, anyhow::Error> {
+    fn write<'a>(&'a mut self, _block: u64, _buffer: &'a [u8]) -> DiskFuture<'a, usize> {
         todo!() // TODO: Implement writing to ATAPI disks
     }
 
-    fn block_length(&mut self) -> Result<u32, anyhow::Error> {
-        Ok(self.read_capacity()?.1)
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        Box::pin(async move { Ok(self.read_capacity().await?.1) })
     }
 }
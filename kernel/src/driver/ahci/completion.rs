@@ -0,0 +1,223 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use futures_util::task::AtomicWaker;
+use super::constants::{HbaPortInterrupt, HBA_SERR_DIAG_EXCHANGED, HBA_SERR_DIAG_PHY_READY_CHANGE};
+use super::hba::HbaPort;
+
+/// Set once the AHCI interrupt has been routed somewhere.
+/// Until then, command futures fall back to polling the port registers.
+pub static IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const PORT_COMPLETION_INIT: PortCompletion = PortCompletion::new();
+/// Completion state for every port on the HBA, indexed by port number
+pub static PORT_COMPLETIONS: [PortCompletion; 32] = [PORT_COMPLETION_INIT; 32];
+
+/// Error information for a command that failed
+#[derive(Debug, Clone, Copy)]
+pub struct AhciCommandError {
+    pub port: usize,
+    pub slot: u32,
+    /// Port interrupt status (PxIS) when the error was reported
+    pub interrupt_status: HbaPortInterrupt,
+    /// Port task file data (PxTFD) when the error was reported. Bits 0-7 are the ATA status register,
+    /// bits 8-15 are the ATA error register
+    pub task_file: u32,
+    /// Port SATA error register (PxSERR) when the error was reported
+    pub sata_error: u32,
+}
+
+impl AhciCommandError {
+    /// ATA status register from the device
+    pub fn ata_status(&self) -> u8 { self.task_file as u8 }
+    /// ATA error register from the device
+    pub fn ata_error(&self) -> u8 { (self.task_file >> 8) as u8 }
+
+    fn description(&self) -> &'static str {
+        let is = self.interrupt_status;
+        if is.contains(HbaPortInterrupt::HostBusFatalError) { "host bus fatal error" }
+        else if is.contains(HbaPortInterrupt::HostBusDataError) { "host bus data error" }
+        else if is.contains(HbaPortInterrupt::InterfaceFatalError) { "interface fatal error" }
+        else if is.contains(HbaPortInterrupt::InterfaceNonFatalError) { "interface non-fatal error" }
+        else if is.contains(HbaPortInterrupt::Overflow) { "data overflow" }
+        else if is.contains(HbaPortInterrupt::TaskFileError) { "device reported an error" }
+        else { "command aborted" }
+    }
+}
+
+impl Display for AhciCommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AHCI port {} slot {}: {} (IS {:#X} STS {:#X} ERR {:#X} SERR {:#X})",
+               self.port, self.slot, self.description(), self.interrupt_status.bits(),
+               self.ata_status(), self.ata_error(), self.sata_error)
+    }
+}
+
+/// Tracks which command slots on a port are in flight and wakes whoever is waiting on them.
+///
+/// This is shared between the IRQ handler and the futures returned by [HbaPort::ata_wait],
+/// so everything in here is lock-free.
+#[derive(Debug)]
+pub struct PortCompletion {
+    /// Slots that are reserved by a caller, from [PortCompletion::allocate] until the result is taken
+    allocated: AtomicU32,
+    /// Slots that have been issued and haven't completed yet
+    outstanding: AtomicU32,
+    /// Slots that completed with an error, cleared once the result is taken
+    failed: AtomicU32,
+    /// PxIS at the time of the last error
+    error_status: AtomicU32,
+    /// PxTFD at the time of the last error
+    error_task_file: AtomicU32,
+    /// PxSERR at the time of the last error
+    error_sata_error: AtomicU32,
+    /// Set when an error stopped the port, cleared by whoever recovers it
+    needs_recovery: AtomicBool,
+    wakers: [AtomicWaker; 32],
+}
+
+impl PortCompletion {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const WAKER_INIT: AtomicWaker = AtomicWaker::new();
+
+    pub const fn new() -> Self {
+        Self {
+            allocated: AtomicU32::new(0),
+            outstanding: AtomicU32::new(0),
+            failed: AtomicU32::new(0),
+            error_status: AtomicU32::new(0),
+            error_task_file: AtomicU32::new(0),
+            error_sata_error: AtomicU32::new(0),
+            needs_recovery: AtomicBool::new(false),
+            wakers: [Self::WAKER_INIT; 32],
+        }
+    }
+
+    /// Reserve a free command slot. `busy` is a mask of slots the hardware is still using (PxCI | PxSACT),
+    /// `num_slots` the number of slots the HBA supports.
+    pub fn allocate(&self, busy: u32, num_slots: u32) -> Option<u32> {
+        loop {
+            let allocated = self.allocated.load(Ordering::SeqCst);
+            let slot = (0..num_slots).find(|i| (allocated | busy) & (1 << i) == 0)?;
+            if self.allocated.compare_exchange(allocated, allocated | (1 << slot),
+                                               Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return Some(slot);
+            }
+        }
+    }
+
+    /// Give a slot back without issuing it
+    pub fn release(&self, slot: u32) {
+        self.allocated.fetch_and(!(1 << slot), Ordering::SeqCst);
+    }
+
+    /// Fail every outstanding command. Used when the port is reset and the HBA forgets about them.
+    pub fn abort_all(&self) {
+        let outstanding = self.outstanding.swap(0, Ordering::SeqCst);
+        self.failed.fetch_or(outstanding, Ordering::SeqCst);
+        for slot in 0..32 {
+            if outstanding & (1 << slot) != 0 {
+                self.wakers[slot].wake();
+            }
+        }
+    }
+
+    /// Returns true (once) if the port has to be recovered after an error
+    pub fn take_needs_recovery(&self) -> bool {
+        self.needs_recovery.swap(false, Ordering::SeqCst)
+    }
+
+    /// Mark a slot as in flight. Must be called *before* the slot is set in PxCI,
+    /// otherwise an interrupt for another slot could see it as already completed.
+    pub fn issue(&self, slot: u32) {
+        self.failed.fetch_and(!(1 << slot), Ordering::SeqCst);
+        self.outstanding.fetch_or(1 << slot, Ordering::SeqCst);
+    }
+
+    /// Returns true if the command in `slot` hasn't completed yet
+    pub fn is_outstanding(&self, slot: u32) -> bool {
+        self.outstanding.load(Ordering::SeqCst) & (1 << slot) != 0
+    }
+
+    /// Slots that have been issued and haven't completed yet
+    pub fn outstanding(&self) -> u32 {
+        self.outstanding.load(Ordering::SeqCst)
+    }
+
+    pub fn register_waker(&self, slot: u32, waker: &core::task::Waker) {
+        self.wakers[slot as usize].register(waker);
+    }
+
+    /// Takes the result for a completed slot, clearing its error state and freeing the slot
+    pub fn take_result(&self, port: usize, slot: u32) -> Result<(), AhciCommandError> {
+        let failed = self.failed.fetch_and(!(1 << slot), Ordering::SeqCst) & (1 << slot) != 0;
+        self.release(slot);
+        if failed {
+            Err(AhciCommandError {
+                port,
+                slot,
+                interrupt_status: HbaPortInterrupt::from_bits_truncate(self.error_status.load(Ordering::SeqCst)),
+                task_file: self.error_task_file.load(Ordering::SeqCst),
+                sata_error: self.error_sata_error.load(Ordering::SeqCst),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read and acknowledge the port's interrupt status, then complete any slots the HBA is done with.
+    /// Called from the IRQ handler, or directly by waiting futures when interrupts aren't available.
+    pub fn service(&self, port: &mut HbaPort) -> HbaPortInterrupt {
+        let raw = port.interrupt_status.read();
+        // write-1-to-clear
+        port.interrupt_status.write(raw);
+        let status = HbaPortInterrupt::from_bits_truncate(raw);
+
+        let outstanding = self.outstanding.load(Ordering::SeqCst);
+        let running = port.command_issue.read() | port.sata_active.read();
+        let mut done = outstanding & !running;
+
+        if status.intersects(HbaPortInterrupt::ERRORS) {
+            self.error_status.store(raw, Ordering::SeqCst);
+            self.error_task_file.store(port.task_file_data.read(), Ordering::SeqCst);
+            self.error_sata_error.store(port.sata_error.read(), Ordering::SeqCst);
+            // the HBA stops processing the command list on an error, so anything that
+            // was still running isn't going to finish
+            let failed = outstanding & running;
+            self.failed.fetch_or(failed, Ordering::SeqCst);
+            self.needs_recovery.store(true, Ordering::SeqCst);
+            done |= failed;
+        }
+
+        if status.intersects(HbaPortInterrupt::HOTPLUG) {
+            // PCS and PRCS stay set until the matching diagnostic bits are cleared
+            port.sata_error.write(HBA_SERR_DIAG_EXCHANGED | HBA_SERR_DIAG_PHY_READY_CHANGE);
+            if !port.device_present() {
+                // the device is gone, nothing that's in flight is going to finish
+                let failed = self.outstanding.load(Ordering::SeqCst);
+                self.error_status.store(raw, Ordering::SeqCst);
+                self.error_task_file.store(port.task_file_data.read(), Ordering::SeqCst);
+                self.error_sata_error.store(port.sata_error.read(), Ordering::SeqCst);
+                self.failed.fetch_or(failed, Ordering::SeqCst);
+                done |= failed;
+            }
+            super::hotplug::notify(port.number());
+        }
+
+        if done != 0 {
+            self.outstanding.fetch_and(!done, Ordering::SeqCst);
+            for slot in 0..32 {
+                if done & (1 << slot) != 0 {
+                    self.wakers[slot].wake();
+                }
+            }
+        }
+        status
+    }
+}
@@ -18,17 +18,33 @@ pub const PRDT_LIST_TOTAL_SIZE: u64 = PRDT_SIZE * NUM_PRDTS_PER_COMMAND;
 // Command tables must be 128-byte aligned, but 4224 is already a multiple of 128
 pub const COMMAND_TABLE_SIZE: u64 = PRDT_LIST_TOTAL_SIZE + PRDT_OFFSET_IN_TABLE; // 4224 bytes
 pub const COMMAND_TABLE_LIST_OFFSET: u64 = COMMAND_LIST_TOTAL_SIZE + RECEIVED_FIS_SIZE + PRDT_LIST_TOTAL_SIZE;
-pub const PORT_MEMORY_SIZE: u64 = COMMAND_TABLE_LIST_OFFSET + COMMAND_TABLE_SIZE * 32;
-/// Total size of AHCI memory region in bytes.
-pub const AHCI_MEMORY_SIZE: u64 = PORT_MEMORY_SIZE * 32;
+// Each port's command list must be 1K-byte aligned, so round the per-port size up
+pub const PORT_MEMORY_SIZE: u64 = (COMMAND_TABLE_LIST_OFFSET + COMMAND_TABLE_SIZE * 32 + 1023) & !1023;
+/// Largest transfer for a single command. Limited by the number of PRDT entries:
+/// a buffer this size can touch at most 31 pages no matter how it's aligned.
+pub const MAX_COMMAND_BYTES: usize = (NUM_PRDTS_PER_COMMAND as usize - 2) * 4096;
 
 // Miscellaneous ATA constants /////////////////////////////////////////////////////////////////////
 
 pub const ATA_DEV_BUSY: u8 = 0x80;
 pub const ATA_DEV_DRQ: u8 = 0x08;
 pub const HBA_SSTS_PRESENT: u32 = 0x3;
-pub const HBA_PXIS_TASK_FILE_ERR: u32 = 0x40000000;
-pub const HBA_PORT_IS_ERR: u32 = 1 << 30 | 1 << 29 | 1 << 28 | 1 << 27;
+/// Mask for the device detection field in PxSSTS and PxSCTL
+pub const HBA_DET_MASK: u32 = 0xF;
+/// PxSCTL.DET value that performs interface initialization (COMRESET) while set
+pub const HBA_SCTL_DET_INIT: u32 = 0x1;
+/// PxSERR.DIAG.N, PhyRdy changed
+pub const HBA_SERR_DIAG_PHY_READY_CHANGE: u32 = 1 << 16;
+/// PxSERR.DIAG.X, device presence changed
+pub const HBA_SERR_DIAG_EXCHANGED: u32 = 1 << 26;
+/// Feature register value that selects TRIM for a DATA SET MANAGEMENT command
+pub const ATA_DSM_TRIM: u8 = 0x01;
+/// Maximum number of sectors in a single DATA SET MANAGEMENT range entry
+pub const ATA_DSM_MAX_RANGE_SECTORS: u64 = 0xFFFF;
+/// Offset of the D2H Register FIS in the received FIS area
+pub const RECEIVED_FIS_D2H_OFFSET: u64 = 0x40;
+/// How many times a failed command is retried before the error is passed to the caller
+pub const AHCI_MAX_RETRIES: u32 = 3;
 
 // ATA commands ////////////////////////////////////////////////////////////////////////////////////
 
@@ -40,7 +56,13 @@ pub enum AtaCommand {
     ReadDmaExt = 0x25,
     WriteDma = 0xCA,
     WriteDmaExt = 0x35,
+    ReadFpdmaQueued = 0x60,
+    WriteFpdmaQueued = 0x61,
+    DataSetManagement = 0x06,
+    FlushCache = 0xE7,
+    FlushCacheExt = 0xEA,
     AtapiCmdPacket = 0xA0,
+    Smart = 0xB0,
     AtapiIdentifyPacket = 0xA1,
     Identify = 0xEC,
 }
@@ -56,6 +78,22 @@ bitflags::bitflags! {
     }
 }
 
+bitflags::bitflags! {
+    /// Bitmasks for the Host Capabilities register (CAP)
+    pub struct AhciCapabilities: u32 {
+        /// Supports 64-bit addressing
+        const Addressing64Bit      = 1 << 31;
+        /// Supports Native Command Queuing
+        const NativeCommandQueuing = 1 << 30;
+        /// Supports staggered spin-up
+        const StaggeredSpinUp      = 1 << 27;
+    }
+}
+impl AhciCapabilities {
+    /// Number of command slots per port (CAP.NCS is zero-based)
+    pub fn command_slots(raw: u32) -> u32 { ((raw >> 8) & 0x1F) + 1 }
+}
+
 bitflags::bitflags! {
     /// Bitmasks for the per-port Command and Status register (PxCMD)
     pub struct HbaPortCmdBit: u32 {
@@ -71,6 +109,59 @@ impl HbaPortCmdBit {
     pub fn as_u32(self) -> u32 { self.bits }
 }
 
+bitflags::bitflags! {
+    /// Bits in the per-port Interrupt Status (PxIS) and Interrupt Enable (PxIE) registers
+    pub struct HbaPortInterrupt: u32 {
+        /// A D2H Register FIS has been received with the 'I' bit set
+        const DeviceToHostRegisterFis = 1 << 0;
+        /// A PIO Setup FIS has been received with the 'I' bit set
+        const PioSetupFis             = 1 << 1;
+        /// A DMA Setup FIS has been received with the 'I' bit set
+        const DmaSetupFis             = 1 << 2;
+        /// A Set Device Bits FIS has been received with the 'I' bit set (NCQ completion)
+        const SetDeviceBitsFis        = 1 << 3;
+        /// An unknown FIS was received
+        const UnknownFis              = 1 << 4;
+        /// A PRD with the 'I' bit set has transferred all of its data
+        const DescriptorProcessed     = 1 << 5;
+        /// PxSERR.DIAG.X changed (device presence changed)
+        const PortConnectChange       = 1 << 6;
+        /// Mechanical presence switch changed
+        const DeviceMechanicalPresence = 1 << 7;
+        /// PhyRdy changed (PxSERR.DIAG.N)
+        const PhyReadyChange          = 1 << 22;
+        /// Incorrect port multiplier status
+        const IncorrectPortMultiplier = 1 << 23;
+        /// The HBA received more bytes than the PRDs could hold
+        const Overflow                = 1 << 24;
+        /// Non-fatal interface error
+        const InterfaceNonFatalError  = 1 << 26;
+        /// Fatal interface error
+        const InterfaceFatalError     = 1 << 27;
+        /// Host bus data error (e.g. ECC error on a DMA transfer)
+        const HostBusDataError        = 1 << 28;
+        /// Host bus fatal error (e.g. target abort on a DMA transfer)
+        const HostBusFatalError       = 1 << 29;
+        /// The device set the ERR bit in its status register
+        const TaskFileError           = 1 << 30;
+        /// Cold presence changed
+        const ColdPortDetect          = 1 << 31;
+
+        /// Interrupts that mean the last command(s) failed
+        const ERRORS = Self::Overflow.bits | Self::InterfaceNonFatalError.bits
+            | Self::InterfaceFatalError.bits | Self::HostBusDataError.bits
+            | Self::HostBusFatalError.bits | Self::TaskFileError.bits;
+        /// Interrupts that signal that a device was attached or removed
+        const HOTPLUG = Self::PortConnectChange.bits | Self::PhyReadyChange.bits;
+        /// Errors that need a COMRESET to recover from, rather than just restarting the port
+        const FATAL_ERRORS = Self::InterfaceFatalError.bits | Self::HostBusDataError.bits
+            | Self::HostBusFatalError.bits;
+        /// Interrupts that signal that a command finished
+        const COMPLETIONS = Self::DeviceToHostRegisterFis.bits | Self::PioSetupFis.bits
+            | Self::DmaSetupFis.bits | Self::SetDeviceBitsFis.bits | Self::DescriptorProcessed.bits;
+    }
+}
+
 // HBA port types //////////////////////////////////////////////////////////////////////////////////
 
 const HBA_SIGNATURE_SATA:   u32 = 0x00000101;
@@ -5,15 +5,27 @@
 ///////////////////////////////////////////////////////////////////////////////L
 
 use core::mem::size_of;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
-use super::fis::{FisType, FisRegH2D};
+use super::fis::{FisType, FisRegH2D, FisRegD2H};
+use crate::driver::smart::*;
 use super::constants::*;
+use super::completion::{AhciCommandError, PortCompletion, PORT_COMPLETIONS, IRQ_ENABLED};
 use crate::PHYS_MEM_OFFSET;
-use crate::memory::AHCI_MEM_REGION;
 use volatile::Volatile;
-use alloc::string::String;
 use x86_64::VirtAddr;
+use crate::driver::identify::IdentifyData;
+use crate::memory::dma::{DmaDirection, DmaMapping};
 use alloc::boxed::Box;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use crate::task::sleep::sleep;
+use crate::time::Instant;
+
+/// How long to wait for a device to come back after a COMRESET
+const COMRESET_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[repr(C)]
 #[derive(Debug)]
@@ -65,8 +77,92 @@ impl HbaPort {
         }
     }
 
+    /// Index of this port in the HBA's port array
+    pub fn number(&self) -> usize {
+        let hba_base = super::HBA_ADDR.load(Ordering::Relaxed);
+        debug_assert!(hba_base != 0, "HBA not initialized");
+        (self as *const _ as usize - hba_base as usize - 0x100) / size_of::<HbaPort>()
+    }
+
+    /// Completion tracking for the commands issued on this port
+    pub fn completion(&self) -> &'static PortCompletion {
+        &PORT_COMPLETIONS[self.number()]
+    }
+
+    /// Returns true if a device is attached and the PHY is communicating with it
+    pub fn device_present(&self) -> bool {
+        self.sata_status.read() & HBA_DET_MASK == HBA_SSTS_PRESENT
+    }
+
+    /// Recover the port after a command error so it can accept new commands.
+    ///
+    /// Stops the command engine (which clears PxCI), fails any other commands that were in flight
+    /// and clears the error registers. If the error was fatal or the device is still busy,
+    /// a COMRESET is done to get it talking again. Then the port is restarted.
+    pub async fn recover(&mut self, error: HbaPortInterrupt) -> Result<(), anyhow::Error> {
+        self.stop();
+        // stopping the port clears PxCI and PxSACT, so nothing that was in flight is going to complete
+        self.completion().abort_all();
+        let serr = self.sata_error.read();
+        self.sata_error.write(serr);
+        let is = self.interrupt_status.read();
+        self.interrupt_status.write(is);
+
+        let busy = self.task_file_data.read() & (ATA_DEV_BUSY | ATA_DEV_DRQ) as u32 != 0;
+        if busy || error.intersects(HbaPortInterrupt::FATAL_ERRORS) {
+            self.comreset().await?;
+        }
+        self.start();
+        Ok(())
+    }
+
+    /// Reset the link to the device (COMRESET) and wait for it to come back.
+    ///
+    /// Anything that was in flight on the port is failed. The command engine is left stopped.
+    pub async fn comreset(&mut self) -> Result<(), anyhow::Error> {
+        crate::serial_println!("AHCI port {}: COMRESET", self.number());
+        self.stop();
+        self.completion().abort_all();
+
+        let sctl = self.sata_control.read() & !HBA_DET_MASK;
+        self.sata_control.write(sctl | HBA_SCTL_DET_INIT);
+        // DET has to be held for at least 1ms so the COMRESET actually gets sent
+        sleep(Duration::from_millis(2)).await;
+        self.sata_control.write(sctl);
+
+        if !self.wait_for(|port| port.device_present(), COMRESET_TIMEOUT).await {
+            return Err(anyhow::anyhow!("AHCI port {}: no device after COMRESET", self.number()));
+        }
+        // the link coming up sets a bunch of diagnostic bits
+        self.sata_error.write(u32::MAX);
+        if !self.wait_for(|port| port.task_file_data.read() & (ATA_DEV_BUSY | ATA_DEV_DRQ) as u32 == 0,
+                          COMRESET_TIMEOUT).await {
+            return Err(anyhow::anyhow!("AHCI port {}: device still busy after COMRESET", self.number()));
+        }
+        self.interrupt_status.write(u32::MAX);
+        Ok(())
+    }
+
+    /// Poll `condition` every millisecond until it's true or `timeout` passes.
+    /// Returns the final value of the condition.
+    async fn wait_for<F: Fn(&Self) -> bool>(&self, condition: F, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !condition(self) {
+            if Instant::now().until(deadline) == Duration::ZERO {
+                return condition(self);
+            }
+            sleep(Duration::from_millis(1)).await;
+        }
+        true
+    }
+
     /// Start the command engine on this port
     pub fn start(&mut self) {
+        if self.command_and_status.read() & HbaPortCmdBit::Start.as_u32() != 0 {
+            // already running
+            return;
+        }
+
         while self.command_and_status.read() & HbaPortCmdBit::CmdListRunning.as_u32() != 0 {
             // TODO: async wait
         }
@@ -88,33 +184,35 @@ impl HbaPort {
 
     }
 
-    /// Finds an unused command slot or returns `None` if all ports are busy
+    /// Reserves an unused command slot or returns `None` if all slots are busy
     pub fn slot(&self) -> Option<u32> {
-        let slots = self.sata_active.read() | self.command_issue.read();
-        for i in 0..32 {
-            if slots & 1 << i == 0 {
-                return Some(i);
-            }
-        }
-        None
+        let busy = self.sata_active.read() | self.command_issue.read();
+        self.completion().allocate(busy, super::command_slots())
+    }
+
+    /// Enable just the device attach/removal interrupts, for ports that don't have a device yet
+    pub fn enable_hotplug_interrupts(&mut self) {
+        self.sata_error.write(u32::MAX);
+        self.interrupt_status.write(u32::MAX);
+        self.interrupt_enable.write(HbaPortInterrupt::HOTPLUG.bits());
     }
 
     /// Initialize this port.
     ///
     /// This involves setting the command list and FIS addresses for the port,
     /// as well as assigning command table pointers for each command header.
-    pub fn init(&mut self, num: u8) {
+    pub fn init(&mut self, num: u8) -> Result<(), anyhow::Error> {
         self.stop();
 
-        let all_ports_working_mem_base = AHCI_MEM_REGION.lock().unwrap().range.start_addr();
-        let working_mem_base = all_ports_working_mem_base + PORT_MEMORY_SIZE * num as u64;
-
-        let cmd_table_addr = AHCI_MEM_REGION.lock().unwrap().range.start_addr() + COMMAND_LIST_TOTAL_SIZE + RECEIVED_FIS_SIZE + (COMMAND_TABLE_SIZE * num as u64);
+        let working_mem_base = super::port_memory(num)
+            .ok_or_else(|| anyhow::anyhow!("AHCI port {}: couldn't allocate memory for the command list", num))?
+            .as_u64();
 
+        // every slot gets its own command table so commands can be in flight at the same time
         for i in 0..32 {
             let cmd_hdr_addr = VirtAddr::new(working_mem_base + COMMAND_HEADER_SIZE * i + PHYS_MEM_OFFSET);
             let cmdheader = unsafe { &mut *(cmd_hdr_addr.as_u64() as *mut HbaCommandHeader) };
-            cmdheader.cmd_table_base_addr.write(cmd_table_addr);
+            cmdheader.cmd_table_base_addr.write(working_mem_base + COMMAND_TABLE_LIST_OFFSET + COMMAND_TABLE_SIZE * i);
             cmdheader.prdt_length.write(0);
         }
 
@@ -125,7 +223,7 @@ impl HbaPort {
         self.fis_base_addr[1].write((fis_base >> 32) as u32);
         let is = self.interrupt_status.read();
         self.interrupt_status.write(is);
-        self.interrupt_enable.write(0 /*TODO: Enable interrupts: 0b10111*/);
+        self.interrupt_enable.write((HbaPortInterrupt::COMPLETIONS | HbaPortInterrupt::ERRORS | HbaPortInterrupt::HOTPLUG).bits());
         let serr = self.sata_error.read();
         self.sata_error.write(serr);
 
@@ -140,126 +238,183 @@ impl HbaPort {
         self.command_and_status.write(self.command_and_status.read() | 1 << 2 | 1 << 1);
 
         crate::serial_println!("   - AHCI init port {} - CMD: {:b}", num, self.command_and_status.read());
+        Ok(())
     }
 
     /// Send an ATA identify command to the disk
-    pub unsafe fn identify(&mut self) -> Option<u64> {
-        unsafe { self.identify_inner(AtaCommand::Identify.as_u8()) }
+    pub async unsafe fn identify(&mut self) -> Option<IdentifyData> {
+        unsafe { self.identify_inner(AtaCommand::Identify.as_u8()).await }
     }
 
     /// Send an ATAPI packet identify command to the disk
-    pub unsafe fn identify_packet(&mut self) -> Option<u64> {
-        unsafe { self.identify_inner(AtaCommand::AtapiIdentifyPacket.as_u8()) }
+    pub async unsafe fn identify_packet(&mut self) -> Option<IdentifyData> {
+        unsafe { self.identify_inner(AtaCommand::AtapiIdentifyPacket.as_u8()).await }
     }
 
     // Shared between identify() and identify_packet()
-    unsafe fn identify_inner(&mut self, cmd: u8) -> Option<u64> {
-        let dest = Box::new([0u16; 256]);
-
-        let slot = self.ata_start(|cmdheader, cmdfis, prdt_entry, _acmd| {
-            cmdheader.prdt_length.write(1);
+    async unsafe fn identify_inner(&mut self, cmd: u8) -> Option<IdentifyData> {
+        let mut dest = Box::new(IdentifyData([0u16; 256]));
+        let mapping = unsafe { map_buffer(dest.0.as_mut_ptr() as *const u8, 512, DmaDirection::FromDevice) }?;
 
-            prdt_entry.data_base_addr.write(dest.as_ref() as *const _ as u64 - PHYS_MEM_OFFSET);
-            prdt_entry.data_byte_count.write(512 | 1);
+        let slot = self.ata_start(|cmdheader, cmdfis, prdt, _acmd| {
+            let entries = fill_prdt(prdt, &mapping)?;
+            cmdheader.prdt_length.write(entries);
 
             cmdfis.pm.write(1 << 7);
             cmdfis.command.write(cmd);
             cmdfis.device.write(0);
             cmdfis.countl.write(1);
             cmdfis.counth.write(0);
+            Some(())
         })?;
 
-        if self.ata_stop(slot).is_ok() {
-            let mut serial = String::new();
-            for word in 10..20 {
-                let d = dest[word];
-                let a = ((d >> 8) as u8) as char;
-                if a != '\0' {
-                    serial.push(a);
-                }
-                let b = (d as u8) as char;
-                if b != '\0' {
-                    serial.push(b);
-                }
-            }
+        let result = self.ata_complete(slot).await;
+        drop(mapping);
+        if result.is_ok() {
+            let lba_bits = if dest.supports_lba48() { 48 } else { 28 };
+            crate::serial_println!("   + Serial: '{}' Firmware: '{}' Model: '{}' LBA: {}-bit Capacity: {} MB",
+                  dest.serial(), dest.firmware(), dest.model(), lba_bits,
+                  dest.sectors() * dest.logical_sector_size() as u64 / (1024 * 1024));
 
-            let mut firmware = String::new();
-            for word in 23..27 {
-                let d = dest[word];
-                let a = ((d >> 8) as u8) as char;
-                if a != '\0' {
-                    firmware.push(a);
-                }
-                let b = (d as u8) as char;
-                if b != '\0' {
-                    firmware.push(b);
-                }
-            }
+            Some(*dest)
+        } else {
+            None
+        }
+    }
 
-            let mut model = String::new();
-            for word in 27..47 {
-                let d = dest[word];
-                let a = ((d >> 8) as u8) as char;
-                if a != '\0' {
-                    model.push(a);
-                }
-                let b = (d as u8) as char;
-                if b != '\0' {
-                    model.push(b);
-                }
-            }
+    /// The last D2H Register FIS the device sent, which holds the result registers of the last command
+    fn received_d2h(&self) -> &FisRegD2H {
+        let fis_base = self.fis_base_addr[0].read() as u64 | (self.fis_base_addr[1].read() as u64) << 32;
+        unsafe { &*((fis_base + RECEIVED_FIS_D2H_OFFSET + PHYS_MEM_OFFSET) as *const FisRegD2H) }
+    }
 
-            let mut sectors = (dest[100] as u64) |
-                              ((dest[101] as u64) << 16) |
-                              ((dest[102] as u64) << 32) |
-                              ((dest[103] as u64) << 48);
+    /// Read one of the 512 byte SMART structures (`SMART_READ_DATA` or `SMART_READ_THRESHOLDS`)
+    pub async fn smart_read(&mut self, feature: u8, buf: &mut [u16; 256]) -> Result<(), anyhow::Error> {
+        let mapping = unsafe { map_buffer(buf.as_mut_ptr() as *const u8, 512, DmaDirection::FromDevice) }
+            .ok_or_else(|| anyhow::anyhow!("AHCI port {}: couldn't map the SMART buffer", self.number()))?;
+        let slot = self.ata_start(|cmdheader, cmdfis, prdt, _acmd| {
+            let entries = fill_prdt(prdt, &mapping)?;
+            cmdheader.prdt_length.write(entries);
 
-            let lba_bits = if sectors == 0 {
-                sectors = (dest[60] as u64) | ((dest[61] as u64) << 16);
-                28
-            } else {
-                48
-            };
+            cmdfis.pm.write(1 << 7);
+            cmdfis.command.write(AtaCommand::Smart.as_u8());
+            cmdfis.featurel.write(feature);
+            cmdfis.lba1.write(SMART_LBA_MID);
+            cmdfis.lba2.write(SMART_LBA_HIGH);
+            cmdfis.countl.write(1);
+            Some(())
+        }).ok_or_else(|| anyhow::anyhow!("AHCI port {}: no free command slot", self.number()))?;
+        let result = self.ata_complete(slot).await;
+        drop(mapping);
+        result
+    }
 
-            crate::serial_println!("   + Serial: '{}' Firmware: '{}' Model: '{}' LBA: {}-bit Capacity: {} MB",
-                  serial.trim(), firmware.trim(), model.trim(), lba_bits, sectors / 2048);
+    /// Ask the device whether any SMART threshold has been exceeded. Resolves to true if it's healthy.
+    pub async fn smart_return_status(&mut self) -> Result<bool, anyhow::Error> {
+        let slot = self.ata_start(|cmdheader, cmdfis, _prdt, _acmd| {
+            cmdheader.prdt_length.write(0);
 
-            Some(sectors * 512)
-        } else {
-            None
+            cmdfis.pm.write(1 << 7);
+            cmdfis.command.write(AtaCommand::Smart.as_u8());
+            cmdfis.featurel.write(SMART_RETURN_STATUS);
+            cmdfis.lba1.write(SMART_LBA_MID);
+            cmdfis.lba2.write(SMART_LBA_HIGH);
+            Some(())
+        }).ok_or_else(|| anyhow::anyhow!("AHCI port {}: no free command slot", self.number()))?;
+        self.ata_complete(slot).await?;
+
+        // the answer comes back in the LBA registers
+        let d2h = self.received_d2h();
+        match (d2h.lba1.read(), d2h.lba2.read()) {
+            (SMART_LBA_MID, SMART_LBA_HIGH) => Ok(true),
+            (SMART_FAILING_LBA_MID, SMART_FAILING_LBA_HIGH) => Ok(false),
+            (mid, high) => Err(anyhow::anyhow!("AHCI port {}: unexpected SMART status {:#X}/{:#X}",
+                                               self.number(), mid, high)),
         }
     }
 
+    /// Write the device's volatile cache out to the media
+    pub async fn flush_cache(&mut self, lba48: bool) -> Result<(), anyhow::Error> {
+        let slot = self.ata_start(|cmdheader, cmdfis, _prdt, _acmd| {
+            cmdheader.prdt_length.write(0);
+
+            cmdfis.pm.write(1 << 7);
+            cmdfis.command.write(if lba48 { AtaCommand::FlushCacheExt } else { AtaCommand::FlushCache }.as_u8());
+            Some(())
+        }).ok_or_else(|| anyhow::anyhow!("AHCI port {}: no free command slot", self.number()))?;
+        self.ata_complete(slot).await
+    }
+
+    /// Send a DATA SET MANAGEMENT command with the TRIM bit set.
+    ///
+    /// `ranges` holds the encoded range entries (LBA in bits 0-47, sector count in bits 48-63),
+    /// padded with zero entries to a multiple of 64 (one 512 byte block).
+    pub async fn dsm_trim(&mut self, ranges: &[u64]) -> Result<(), anyhow::Error> {
+        assert!(!ranges.is_empty() && ranges.len() % 64 == 0 && ranges.len() * 8 <= MAX_COMMAND_BYTES);
+        let blocks = ranges.len() / 64;
+        let mapping = unsafe { map_buffer(ranges.as_ptr() as *const u8, blocks * 512, DmaDirection::ToDevice) }
+            .ok_or_else(|| anyhow::anyhow!("AHCI port {}: couldn't map the TRIM ranges", self.number()))?;
+        let slot = self.ata_start(|cmdheader, cmdfis, prdt, _acmd| {
+            let entries = fill_prdt(prdt, &mapping)?;
+            // the range list goes from host to device
+            let cfl = cmdheader.command_fis_length.read();
+            cmdheader.command_fis_length.write(cfl | 1 << 6);
+            cmdheader.prdt_length.write(entries);
+
+            cmdfis.pm.write(1 << 7);
+            cmdfis.command.write(AtaCommand::DataSetManagement.as_u8());
+            cmdfis.featurel.write(ATA_DSM_TRIM);
+            cmdfis.device.write(1 << 6);
+            cmdfis.countl.write(blocks as u8);
+            cmdfis.counth.write((blocks >> 8) as u8);
+            Some(())
+        }).ok_or_else(|| anyhow::anyhow!("AHCI port {}: no free command slot", self.number()))?;
+        let result = self.ata_complete(slot).await;
+        drop(mapping);
+        result
+    }
+
     /// Begin an ATA DMA transaction
     ///
     /// # Arguments
     ///
     /// * `block` - the starting LBA for the transaction
     /// * `sectors` -  the number of sectors to transfer
+    /// * `sector_size` - bytes per logical sector of the device
     /// * `write` - true -> writing to the device, false -> reading from the device
-    /// * `buf` - A reference to the host-side data buffer for sending/receiving data
-    pub fn ata_dma(&mut self, block: u64, sectors: usize, write: bool, buf: &mut Box<[u8; 256 * 512]>) -> Option<u32> {
-        crate::serial_println!("AHCI DMA - BLOCK: {:X} SECTORS: {} WRITE: {}", block, sectors, write);
+    /// * `queued` - use FPDMA QUEUED (NCQ) instead of DMA EXT. The device must support NCQ.
+    /// * `buf` - host-side data buffer, at least `sectors * sector_size` bytes long. The PRDT points straight at
+    ///   it, or at a bounce buffer if the HBA can't reach it.
+    ///
+    /// Returns the slot and the mapping of `buf`, which has to be kept until the command completes.
+    /// Returns `None` if there's no free command slot or the buffer can't be described by the PRDT
+    /// (not word aligned, or larger than [MAX_COMMAND_BYTES]).
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid (and, for reads, not be accessed) until the mapping is dropped.
+    pub unsafe fn ata_dma(&mut self, block: u64, sectors: usize, sector_size: usize, write: bool, queued: bool, buf: *const u8) -> Option<(u32, DmaMapping)> {
+        crate::serial_println!("AHCI DMA - BLOCK: {:X} SECTORS: {} WRITE: {} NCQ: {}", block, sectors, write, queued);
 
-        assert!(sectors > 0 && sectors < 256);
+        assert!(sectors > 0 && sectors * sector_size <= MAX_COMMAND_BYTES);
 
-        self.ata_start(|cmdheader, cmdfis, prdt_entry, _acmd| {
+        let direction = if write { DmaDirection::ToDevice } else { DmaDirection::FromDevice };
+        let mapping = unsafe { map_buffer(buf, sectors * sector_size, direction) }?;
+        let slot = self.ata_start(|cmdheader, cmdfis, prdt, _acmd| {
+            let entries = fill_prdt(prdt, &mapping)?;
             if write {
                 let cfl = cmdheader.command_fis_length.read();
                 cmdheader.command_fis_length.write(cfl | 1 << 7 | 1 << 6)
             }
-
-            cmdheader.prdt_length.write(1);
-
-            prdt_entry.data_base_addr.write(buf as *const _ as u64 - PHYS_MEM_OFFSET);
-            prdt_entry.data_byte_count.write(((sectors * 512) as u32) | 1);
+            cmdheader.prdt_length.write(entries);
 
             cmdfis.pm.write(1 << 7);
-            if write {
-                cmdfis.command.write(AtaCommand::WriteDmaExt.as_u8());
-            } else {
-                cmdfis.command.write(AtaCommand::ReadDmaExt.as_u8());
-            }
+            cmdfis.command.write(match (queued, write) {
+                (true, true) => AtaCommand::WriteFpdmaQueued,
+                (true, false) => AtaCommand::ReadFpdmaQueued,
+                (false, true) => AtaCommand::WriteDmaExt,
+                (false, false) => AtaCommand::ReadDmaExt,
+            }.as_u8());
 
             cmdfis.lba0.write(block as u8);
             cmdfis.lba1.write((block >> 8) as u8);
@@ -271,21 +426,31 @@ impl HbaPort {
             cmdfis.lba4.write((block >> 32) as u8);
             cmdfis.lba5.write((block >> 40) as u8);
 
-            cmdfis.countl.write(sectors as u8);
-            cmdfis.counth.write((sectors >> 8) as u8);
-        })
+            if queued {
+                // FPDMA QUEUED puts the sector count in the feature register and the tag in the count register.
+                // The tag is filled in by ata_start once the slot is known.
+                cmdfis.featurel.write(sectors as u8);
+                cmdfis.featureh.write((sectors >> 8) as u8);
+            } else {
+                cmdfis.countl.write(sectors as u8);
+                cmdfis.counth.write((sectors >> 8) as u8);
+            }
+            Some(())
+        })?;
+        Some((slot, mapping))
     }
 
-    /// Send ATAPI packet
-    pub fn atapi_dma(&mut self, cmd: &[u8; 16], size: u32, buf: &mut Box<[u8; 256 * 512]>) -> Result<(), anyhow::Error> {
-        let slot = self.ata_start(|cmdheader, cmdfis, prdt_entry, acmd| {
+    /// Send ATAPI packet and wait for it to complete
+    pub async fn atapi_dma(&mut self, cmd: &[u8; 16], size: u32, buf: &mut Box<[u8; 256 * 512]>) -> Result<(), anyhow::Error> {
+        assert!(size as usize <= MAX_COMMAND_BYTES);
+        let mapping = unsafe { map_buffer(buf.as_mut_ptr(), size as usize, DmaDirection::FromDevice) }
+            .ok_or_else(|| anyhow::anyhow!("ATAPI DMA: couldn't map the buffer"))?;
+        let slot = self.ata_start(|cmdheader, cmdfis, prdt, acmd| {
+            let entries = fill_prdt(prdt, &mapping)?;
             let cfl = cmdheader.command_fis_length.read();
             cmdheader.command_fis_length.write(cfl | 1 << 5);
 
-            cmdheader.prdt_length.write(1);
-
-            prdt_entry.data_base_addr.write(buf as *mut _ as u64 - PHYS_MEM_OFFSET);
-            prdt_entry.data_byte_count.write(size - 1);
+            cmdheader.prdt_length.write(entries);
 
             cmdfis.pm.write(1 << 7);
             cmdfis.command.write(AtaCommand::AtapiCmdPacket.as_u8());
@@ -296,71 +461,131 @@ impl HbaPort {
             cmdfis.featureh.write(0);
 
             unsafe { core::ptr::write_volatile(acmd.as_mut_ptr() as *mut [u8; 16], *cmd) };
+            Some(())
         }).ok_or_else(|| anyhow::anyhow!("ATAPI DMA start failed"))?;
-        self.ata_stop(slot)
+        let result = self.ata_complete(slot).await;
+        drop(mapping);
+        result
     }
 
+    /// Fill out the command header, command FIS and PRDT for a free slot via `callback`,
+    /// then issue the command. Returns the slot number, or `None` if all slots are busy or
+    /// the callback failed to set up the command.
+    ///
+    /// Commands using one of the FPDMA QUEUED opcodes are issued as native queued commands,
+    /// with the slot number as their tag.
     pub fn ata_start<F>(&mut self, callback: F) -> Option<u32>
-        where F: FnOnce(&mut HbaCommandHeader, &mut FisRegH2D, &mut HbaPrdtEntry, &mut [Volatile<u8>; 16])
+        where F: FnOnce(&mut HbaCommandHeader, &mut FisRegH2D, &mut [HbaPrdtEntry], &mut [Volatile<u8>; 16]) -> Option<()>
     {
+        let slot = self.slot()?;
+        let queued = {
+            let cmd_list_base = self.cmd_list_base_addr[0].read() as u64 | (self.cmd_list_base_addr[1].read() as u64) << 32;
+            let header_addr = cmd_list_base + (COMMAND_HEADER_SIZE * slot as u64) + PHYS_MEM_OFFSET;
+            let cmdheader = unsafe { &mut *(header_addr as *mut HbaCommandHeader) };
+            cmdheader.command_fis_length.write((size_of::<FisRegH2D>() / size_of::<u32>()) as u8);
+            cmdheader.prd_bytes_transferred.write(0);
+
+            let cmd_tbl_addr = cmdheader.cmd_table_base_addr.read() + PHYS_MEM_OFFSET;
+            let cmdtbl = unsafe { &mut *(cmd_tbl_addr as *mut HbaCommandTable) };
+            unsafe { core::ptr::write_bytes(cmdtbl as *mut HbaCommandTable as *mut u8, 0, COMMAND_TABLE_SIZE as usize); }
+
+            let cmdfis = unsafe { &mut *(cmdtbl.command_fis.as_mut_ptr() as *mut FisRegH2D) };
+            cmdfis.fis_type.write(FisType::RegH2D as u8);
+
+            let acmd = unsafe { &mut *(cmdtbl.atapi_command.as_mut_ptr() as *mut [Volatile<u8>; 16]) };
+
+            if callback(cmdheader, cmdfis, &mut cmdtbl.prdt_entries, acmd).is_none() {
+                self.completion().release(slot);
+                return None;
+            }
 
-        //TODO: Should probably remove
-        self.interrupt_status.write(u32::MAX);
-
-        if let Some(slot) = self.slot() {
-            {
-                let header_addr = AHCI_MEM_REGION.lock().unwrap().range.start_addr() + (COMMAND_HEADER_SIZE * slot as u64) + PHYS_MEM_OFFSET;
-                let cmdheader = unsafe { &mut *(header_addr as *mut HbaCommandHeader) };
-                cmdheader.command_fis_length.write((size_of::<FisRegH2D>() / size_of::<u32>()) as u8);
-
-                let cmd_tbl_addr = AHCI_MEM_REGION.lock().unwrap().range.start_addr() + COMMAND_LIST_TOTAL_SIZE + RECEIVED_FIS_SIZE + (COMMAND_TABLE_SIZE * slot as u64) + PHYS_MEM_OFFSET;
-                let cmdtbl = unsafe { &mut *(cmd_tbl_addr as *mut HbaCommandTable) };
-                unsafe { core::ptr::write_bytes(cmdtbl as *mut HbaCommandTable as *mut u8, 0, COMMAND_TABLE_SIZE as usize); }
-
-                let cmdfis = unsafe { &mut *(cmdtbl.command_fis.as_mut_ptr() as *mut FisRegH2D) };
-                cmdfis.fis_type.write(FisType::RegH2D as u8);
-
-                let prdt_addr = cmd_tbl_addr + PRDT_OFFSET_IN_TABLE;
-                let prdt = unsafe { &mut *(prdt_addr as *mut HbaPrdtEntry) };
-
-                let acmd = unsafe { &mut *(cmdtbl.atapi_command.as_mut_ptr() as *mut [Volatile<u8>; 16]) };
-
-                callback(cmdheader, cmdfis, prdt, acmd)
+            let command = cmdfis.command.read();
+            let queued = command == AtaCommand::ReadFpdmaQueued.as_u8() || command == AtaCommand::WriteFpdmaQueued.as_u8();
+            if queued {
+                cmdfis.countl.write((slot as u8) << 3);
             }
+            queued
+        };
 
+        if !queued {
+            // a non-queued command can't be issued while queued ones are still running
             while self.task_file_data.read() & (ATA_DEV_BUSY | ATA_DEV_DRQ) as u32 != 0 {
                 //unsafe { asm!("pause"); }
             }
+        }
 
-            self.command_issue.write(self.command_issue.read() | 1 << slot);
+        self.start();
 
-            //TODO: Should probably remove
-            self.start();
+        // don't let the IRQ handler see the slot in PxCI before it's marked as outstanding
+        let completion = self.completion();
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            completion.issue(slot);
+            if queued {
+                self.sata_active.write(1 << slot);
+            }
+            self.command_issue.write(1 << slot);
+        });
 
-            Some(slot)
-        } else {
-            None
-        }
+        Some(slot)
     }
 
+    /// Returns true if the command in `slot` hasn't completed yet
     pub fn ata_running(&self, slot: u32) -> bool {
-        (self.command_issue.read() & (1 << slot) != 0 || self.task_file_data.read() & 0x80 != 0) && self.interrupt_status.read() & HBA_PORT_IS_ERR == 0
+        self.completion().is_outstanding(slot)
+    }
+
+    /// Returns a future that resolves once the command in `slot` has completed or failed
+    pub fn ata_wait(&mut self, slot: u32) -> AtaCommandFuture<'_> {
+        AtaCommandFuture { port: self, slot }
     }
 
-    pub fn ata_stop(&mut self, slot: u32) -> Result<(), anyhow::Error> {
-        while self.ata_running(slot) {
-            // TODO: async yield
+    /// Wait for the command in `slot` to finish. If it failed, the port is recovered
+    /// so it can keep processing commands, and the decoded error is returned.
+    pub async fn ata_complete(&mut self, slot: u32) -> Result<(), anyhow::Error> {
+        match self.ata_wait(slot).await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                crate::serial_println!("{}", err);
+                // an error fails every command on the port, only the first one to notice has to clean up
+                if self.completion().take_needs_recovery() {
+                    if let Err(reset_err) = self.recover(err.interrupt_status).await {
+                        crate::serial_println!("{}", reset_err);
+                    }
+                }
+                Err(anyhow::anyhow!("{}", err))
+            }
         }
+    }
+}
 
-        self.stop();
+/// Future that resolves once a command issued with [HbaPort::ata_start] has been processed
+#[derive(Debug)]
+pub struct AtaCommandFuture<'a> {
+    port: &'a mut HbaPort,
+    slot: u32,
+}
+
+impl Future for AtaCommandFuture<'_> {
+    type Output = Result<(), AhciCommandError>;
 
-        if self.interrupt_status.read() & HBA_PORT_IS_ERR != 0 {
-            panic!("ERROR IS {:X} IE {:X} CMD {:X} TFD {:X}\nSSTS {:X} SCTL {:X} SERR {:X} SACT {:X}\nCI {:X} SNTF {:X} FBS {:X}",
-                   self.interrupt_status.read(), self.interrupt_enable.read(), self.command_and_status.read(), self.task_file_data.read(),
-                   self.sata_status.read(), self.sata_control.read(), self.sata_error.read(), self.sata_active.read(),
-                   self.command_issue.read(), self.sata_notif.read(), self.fis_switch_ctrl.read());
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let completion = self.port.completion();
+        completion.register_waker(self.slot, cx.waker());
+
+        let irq_enabled = IRQ_ENABLED.load(Ordering::Relaxed);
+        if !irq_enabled {
+            // nobody is going to wake us, check the registers ourselves
+            x86_64::instructions::interrupts::without_interrupts(|| completion.service(&mut self.port));
+        }
+
+        if completion.is_outstanding(self.slot) {
+            if !irq_enabled {
+                // requeue the task so the rest of the executor gets a turn before we check again
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
         } else {
-            Ok(())
+            Poll::Ready(completion.take_result(self.port.number(), self.slot))
         }
     }
 }
@@ -408,6 +633,42 @@ impl HbaMemory {
     }
 }
 
+/// Largest number of bytes a single PRDT entry can describe
+const PRDT_ENTRY_MAX_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Lend `len` bytes at `buf` to the HBA, through a bounce buffer if it can't reach them
+///
+/// # Safety
+///
+/// Same as [DmaMapping::new].
+unsafe fn map_buffer(buf: *const u8, len: usize, direction: DmaDirection) -> Option<DmaMapping> {
+    if buf as usize & 1 != 0 || len & 1 != 0 || len == 0 {
+        return None;
+    }
+    unsafe { DmaMapping::new(buf, len, direction, super::dma_limit()) }
+}
+
+/// Point the PRDT at the segments of `mapping`, splitting the ones that are too big for one entry.
+/// Returns the number of entries used, or `None` if a segment isn't word aligned or more entries
+/// are needed than are available.
+fn fill_prdt(prdt: &mut [HbaPrdtEntry], mapping: &DmaMapping) -> Option<u16> {
+    let mut count = 0;
+    for segment in mapping.segments() {
+        let (mut phys, mut left) = (segment.phys.as_u64(), segment.len as u64);
+        if phys & 1 != 0 || left & 1 != 0 {
+            return None;
+        }
+        while left > 0 {
+            let len = core::cmp::min(left, PRDT_ENTRY_MAX_BYTES);
+            prdt.get_mut(count)?.set(phys, len as u32);
+            count += 1;
+            phys += len;
+            left -= len;
+        }
+    }
+    Some(count as u16)
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct HbaPrdtEntry {
@@ -419,6 +680,16 @@ pub struct HbaPrdtEntry {
     data_byte_count: Volatile<u32>,
 }
 
+impl HbaPrdtEntry {
+    /// Point this entry at `len` bytes of physical memory starting at `addr`
+    pub fn set(&mut self, addr: u64, len: u32) {
+        debug_assert!(addr & 1 == 0 && len & 1 == 0 && len > 0);
+        self.data_base_addr.write(addr);
+        // byte count is zero-based
+        self.data_byte_count.write(len - 1);
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct HbaCommandTable {
@@ -0,0 +1,113 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
+use crate::device::physical::PhysicalDeviceType;
+use crate::service::DISK_SERVICE;
+use crate::task::sleep::sleep;
+use super::completion::{IRQ_ENABLED, PORT_COMPLETIONS};
+
+/// Ports that reported a connect or PhyRdy change since the hotplug task last looked
+static PENDING_PORTS: AtomicU32 = AtomicU32::new(0);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// How long to let the link settle after a change before looking at the port
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+/// How often to check the ports when the HBA has no interrupt
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Called when a port reports a connect or PhyRdy change. Safe to call from the IRQ handler.
+pub fn notify(port: usize) {
+    PENDING_PORTS.fetch_or(1 << port, Ordering::SeqCst);
+    WAKER.wake();
+}
+
+/// Resolves to the mask of ports with pending changes
+#[derive(Debug)]
+struct HotplugEvents;
+
+impl Future for HotplugEvents {
+    type Output = u32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+        let pending = PENDING_PORTS.swap(0, Ordering::SeqCst);
+        if pending != 0 {
+            return Poll::Ready(pending);
+        }
+        WAKER.register(cx.waker());
+        // check again in case a change came in before the waker was registered
+        match PENDING_PORTS.swap(0, Ordering::SeqCst) {
+            0 => Poll::Pending,
+            pending => Poll::Ready(pending),
+        }
+    }
+}
+
+/// Watches for disks being attached to or removed from the HBA and keeps the disk service up to date.
+pub async fn hotplug_task() {
+    loop {
+        let pending = if IRQ_ENABLED.load(Ordering::SeqCst) {
+            HotplugEvents.await
+        } else {
+            // without an interrupt nobody else is going to look at PxIS
+            sleep(POLL_INTERVAL).await;
+            let implemented = super::implemented_ports();
+            for i in (0..32).filter(|i| implemented & (1 << i) != 0) {
+                if let Some(port) = super::port(i) {
+                    PORT_COMPLETIONS[i].service(port);
+                }
+            }
+            PENDING_PORTS.swap(0, Ordering::SeqCst)
+        };
+        if pending == 0 {
+            continue;
+        }
+
+        // a plug event usually comes as a burst of changes, wait for the link to settle
+        sleep(SETTLE_TIME).await;
+        for i in (0..32).filter(|i| pending & (1 << i) != 0) {
+            handle_change(i).await;
+        }
+    }
+}
+
+/// Look at a port that reported a change and add or remove its disk
+async fn handle_change(i: usize) {
+    let port = match super::port(i) {
+        Some(port) => port,
+        None => return,
+    };
+    let registered = match DISK_SERVICE.lock().as_ref() {
+        Some(service) => service.find(PhysicalDeviceType::SataDrive, i)
+            .or_else(|| service.find(PhysicalDeviceType::SatapiDrive, i)),
+        // the initial scan hasn't finished, it'll pick the port up
+        None => return,
+    };
+
+    match (port.device_present(), registered) {
+        (true, None) => {
+            if let Some(disk) = super::open_port(i, port).await {
+                if let Some(id) = crate::service::add_disk(disk).await {
+                    crate::both_println!("AHCI port {}: disk {} attached", i, id);
+                }
+            }
+        },
+        (false, Some(id)) => {
+            port.stop();
+            if let Some(service) = DISK_SERVICE.lock().as_mut() {
+                service.unregister(id);
+            }
+            port.enable_hotplug_interrupts();
+            crate::both_println!("AHCI port {}: disk {} removed", i, id);
+        },
+        _ => {}
+    }
+}
@@ -6,17 +6,23 @@
 
 // TODO: check for redundancy with DiskService
 
-use alloc::sync::Arc;
+use alloc::boxed::Box;
 use self::ata::AtaDisk;
 use self::atapi::AtapiDisk;
-use self::hba::HbaMemory;
-use self::constants::HbaPortType;
+use self::hba::{HbaMemory, HbaPort};
+use self::constants::{AhciCapabilities, HbaPortType};
 use x86_64::PhysAddr;
 use alloc::vec::Vec;
-use crate::{AHCI_MEM_REGION, PCI_DEVICES, PHYS_MEM_OFFSET};
 use spin::Mutex;
-use tinypci::PciClass;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x2apic::ioapic::IrqFlags;
 use crate::device::physical::SyncDisk;
+use crate::driver::pci::PciAddress;
+use crate::arch::interrupts;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::frame::AddressLimit;
+use crate::memory::mmio::{ioremap, CacheMode};
+use self::constants::PORT_MEMORY_SIZE;
 
 /// Types related to the AHCI HBA (Host Bus Adapter)
 pub mod hba;
@@ -28,72 +34,179 @@ pub mod constants;
 pub mod ata;
 /// `Disk` implementation for (S)ATAPI disks
 pub mod atapi;
+/// Interrupt-driven command completion tracking
+pub mod completion;
+/// Device attach/removal handling
+pub mod hotplug;
 
 static HBA: Mutex<Option<&'static mut HbaMemory>> = Mutex::new(None);
+/// Virtual address of the HBA registers, for use in the IRQ handler where we can't take the lock
+static HBA_ADDR: AtomicU64 = AtomicU64::new(0);
+/// Number of command slots per port supported by the HBA
+static COMMAND_SLOTS: AtomicU32 = AtomicU32::new(1);
+/// Whether the HBA supports Native Command Queuing
+static SUPPORTS_NCQ: AtomicBool = AtomicBool::new(false);
+/// Whether the HBA can reach memory above 4 GiB
+static ADDRESSING_64BIT: AtomicBool = AtomicBool::new(false);
+const NO_PORT_MEMORY: Option<DmaBuffer> = None;
+/// Command list, received FIS and command tables of each port, allocated when the port is first set up
+static PORT_MEMORY: Mutex<[Option<DmaBuffer>; 32]> = Mutex::new([NO_PORT_MEMORY; 32]);
+
+/// PCI class and subclass for an AHCI controller
+const PCI_CLASS_MASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_SATA: u8 = 0x06;
 
 /// Initialize the HBA and scan for disks
 pub fn init() {
     crate::both_println!("Initializing AHCI controller...");
-    let ahci_mem_region = AHCI_MEM_REGION.lock()
-        .expect("called ahci_init without AHCI_MEM_REGION initialized")
-        .range;
-    let ahci_mem_range = ahci_mem_region.start_addr()..ahci_mem_region.end_addr();
-    for addr in ahci_mem_range {
-        // zero out all AHCI memory
-        unsafe { *((addr + PHYS_MEM_OFFSET) as *mut u8) = 0; }
-    }
-    crate::both_println!("AHCI memory initialized at {:x}..{:x}", ahci_mem_region.start_addr(), ahci_mem_region.end_addr());
-
-    let pci_lock = PCI_DEVICES.lock();
-    let ahci_controller_info = pci_lock.iter()
-        .find(|x| { x.class() == PciClass::MassStorage })
-        .expect("No AHCI controller found.");
+    let pci_addr = match PciAddress::find(PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_SATA) {
+        Some(addr) => addr,
+        None => {
+            crate::both_println!("No AHCI controller found.");
+            return;
+        }
+    };
+    pci_addr.enable_bus_master();
 
-    let hba_mem_base = PhysAddr::new((ahci_controller_info.bars[5] & 0xFFFFFFF0) as u64);
+    let hba_mem_base = PhysAddr::new(pci_addr.bar(5) as u64);
 
-    let hba_mem = unsafe { &mut *((hba_mem_base.as_u64() + PHYS_MEM_OFFSET) as *mut HbaMemory) };
+    let hba_mem = match unsafe { ioremap::<HbaMemory>(hba_mem_base, CacheMode::Uncached) } {
+        Ok(registers) => registers.leak(),
+        Err(e) => {
+            crate::both_println!("AHCI: failed to map HBA registers: {}", e);
+            return;
+        }
+    };
+    HBA_ADDR.store(hba_mem as *mut HbaMemory as u64, Ordering::SeqCst);
     hba_mem.init();
+    let caps = hba_mem.capabilities.read();
+    COMMAND_SLOTS.store(AhciCapabilities::command_slots(caps), Ordering::SeqCst);
+    SUPPORTS_NCQ.store(AhciCapabilities::from_bits_truncate(caps).contains(AhciCapabilities::NativeCommandQueuing), Ordering::SeqCst);
+    ADDRESSING_64BIT.store(AhciCapabilities::from_bits_truncate(caps).contains(AhciCapabilities::Addressing64Bit), Ordering::SeqCst);
     *HBA.lock() = Some(hba_mem);
     crate::both_println!("HBA initialized.");
+
+    init_interrupts(pci_addr);
+}
+
+/// Number of command slots per port supported by the HBA
+pub fn command_slots() -> u32 {
+    COMMAND_SLOTS.load(Ordering::Relaxed)
+}
+
+/// Whether the HBA supports Native Command Queuing
+pub fn supports_ncq() -> bool {
+    SUPPORTS_NCQ.load(Ordering::Relaxed)
+}
+
+/// Memory the HBA can reach with DMA
+pub fn dma_limit() -> AddressLimit {
+    if ADDRESSING_64BIT.load(Ordering::Relaxed) { AddressLimit::Any } else { AddressLimit::Below4GiB }
+}
+
+/// Physical address of port `num`'s working memory, which is allocated the first time it's asked for
+/// and kept for when the port is set up again after a hotplug.
+pub fn port_memory(num: u8) -> Option<PhysAddr> {
+    let mut memory = PORT_MEMORY.lock();
+    let slot = &mut memory[num as usize];
+    if slot.is_none() {
+        // the command list needs 1K alignment, everything after it is laid out to keep theirs
+        *slot = Some(DmaBuffer::new(PORT_MEMORY_SIZE as usize, 1024, dma_limit())?);
+    }
+    slot.as_ref().map(|buffer| buffer.phys())
+}
+
+/// Route the HBA's interrupt to [handle_interrupt], preferring MSI over the legacy interrupt line
+fn init_interrupts(pci_addr: PciAddress) {
+    let vector = match interrupts::allocate_vector() {
+        Some(v) => v,
+        None => {
+            crate::both_println!("AHCI: no free interrupt vectors, falling back to polling");
+            return;
+        }
+    };
+    interrupts::register_irq_handler(vector, handle_interrupt);
+
+    if pci_addr.enable_msi(vector) {
+        crate::serial_println!("AHCI: using MSI on vector {}", vector);
+    } else {
+        // PCI interrupts are level triggered, active low and shared
+        let line = pci_addr.interrupt_line();
+        let flags = IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE;
+        if line == 0xFF || !interrupts::enable_ioapic_irq(line, vector, flags) {
+            crate::both_println!("AHCI: no usable interrupt line, falling back to polling");
+            return;
+        }
+        crate::serial_println!("AHCI: using IRQ {} on vector {}", line, vector);
+    }
+    completion::IRQ_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// AHCI interrupt handler. Acknowledges the interrupt on every port that raised one
+/// and wakes the tasks waiting on the commands that finished.
+fn handle_interrupt() {
+    let addr = HBA_ADDR.load(Ordering::SeqCst);
+    if addr == 0 {
+        return;
+    }
+    let hba_mem = unsafe { &mut *(addr as *mut HbaMemory) };
+
+    let pending = hba_mem.interrupt_status.read();
+    for i in 0..hba_mem.ports.len() {
+        if pending & (1 << i) != 0 {
+            completion::PORT_COMPLETIONS[i].service(&mut hba_mem.ports[i]);
+        }
+    }
+    // port interrupts have to be cleared before the global ones, or they'll just fire again
+    hba_mem.interrupt_status.write(pending);
+}
+
+/// Get a port by number, or `None` if the HBA doesn't implement it
+pub(crate) fn port(i: usize) -> Option<&'static mut HbaPort> {
+    let mut lock = HBA.lock();
+    let hba_mem = lock.as_mut()?;
+    if i >= hba_mem.ports.len() || hba_mem.ports_impl.read() & (1 << i) == 0 {
+        return None;
+    }
+    Some(unsafe { &mut *hba_mem.ports.as_mut_ptr().add(i) })
+}
+
+/// Bitmask of the ports implemented by the HBA
+pub(crate) fn implemented_ports() -> u32 {
+    HBA.lock().as_ref().map(|hba_mem| hba_mem.ports_impl.read()).unwrap_or(0)
+}
+
+/// Set up the device attached to a port, if there is one
+pub(crate) async fn open_port(i: usize, port: &'static mut HbaPort) -> Option<SyncDisk> {
+    let port_type = port.probe();
+    crate::serial_println!("disk-{}: {:?}", i, port_type);
+
+    let disk = match port_type {
+        HbaPortType::SATA => AtaDisk::new(i, port).await.map(|disk| SyncDisk::new(Box::new(disk))),
+        HbaPortType::SATAPI => AtapiDisk::new(i, port).await.map(|disk| SyncDisk::new(Box::new(disk))),
+        _ => {
+            // nothing attached (yet), but still listen for something being plugged in
+            port.enable_hotplug_interrupts();
+            return None;
+        }
+    };
+    match disk {
+        Ok(disk) => Some(disk),
+        Err(err) => {
+            crate::serial_println!("{}: {}", i, err);
+            None
+        }
+    }
 }
 
 pub async fn scan_disks() -> Vec<SyncDisk> {
     crate::both_println!("Scanning for disks...");
-    let disks: Vec<SyncDisk> =  {
-        let mut lock = HBA.lock();
-        let hba_mem = lock.as_mut().unwrap();
-        let pi = hba_mem.ports_impl.read();
-        (0..hba_mem.ports.len())
-            .filter(|&i| pi & 1 << i as i32 == 1 << i as i32)
-            .filter_map(|i| {
-                let port = unsafe { &mut *hba_mem.ports.as_mut_ptr().add(i) };
-                let port_type = port.probe();
-                crate::serial_println!("disk-{}: {:?}", i, port_type);
-
-                let disk: Option<SyncDisk> = match port_type {
-                    HbaPortType::SATA => {
-                        match AtaDisk::new(i, port) {
-                            Ok(disk) => Some(SyncDisk::new(Arc::new(disk))),
-                            Err(err) => {
-                                crate::serial_println!("{}: {}", i, err);
-                                None
-                            }
-                        }
-                    }
-                    HbaPortType::SATAPI => {
-                        match AtapiDisk::new(i, port) {
-                            Ok(disk) => Some(SyncDisk::new(Arc::new(disk))),
-                            Err(err) => {
-                                crate::serial_println!("{}: {}", i, err);
-                                None
-                            }
-                        }
-                    }
-                    _ => None,
-                };
-                disk
-            })
-            .collect()
-    };
+    let implemented = implemented_ports();
+    let mut disks = Vec::new();
+    for i in (0..32).filter(|i| implemented & (1 << i) != 0) {
+        if let Some(disk) = open_port(i, port(i).unwrap()).await {
+            disks.push(disk);
+        }
+    }
     disks
 }
@@ -0,0 +1,358 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use alloc::vec::Vec;
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+use super::Geometry;
+use crate::driver::isa_dma::{self, DmaDirection};
+use crate::task::sleep::sleep;
+use crate::time::Instant;
+
+/// I/O base of the primary floppy controller
+pub const FDC_BASE: u16 = 0x3F0;
+/// ISA DMA channel the floppy controller is wired to
+const FDC_DMA_CHANNEL: u8 = 2;
+
+// Registers, offsets from the I/O base
+const REG_DOR: u16 = 2;
+const REG_MSR: u16 = 4;
+const REG_FIFO: u16 = 5;
+const REG_DIR: u16 = 7;
+const REG_CCR: u16 = 7;
+
+// Digital output register bits
+/// Clear to hold the controller in reset
+const DOR_NOT_RESET: u8 = 1 << 2;
+const DOR_IRQ_DMA: u8 = 1 << 3;
+/// Motor enable for drive 0, the other drives follow
+const DOR_MOTOR0: u8 = 1 << 4;
+
+// Main status register bits
+const MSR_RQM: u8 = 1 << 7;
+/// Set when the controller has data for us
+const MSR_DIO: u8 = 1 << 6;
+const MSR_BUSY: u8 = 1 << 4;
+/// Drive 0-3 is seeking
+const MSR_SEEKING: u8 = 0x0F;
+
+/// Digital input register: the disk was changed since the last seek
+const DIR_DISK_CHANGE: u8 = 1 << 7;
+
+// Commands
+const CMD_SPECIFY: u8 = 0x03;
+const CMD_WRITE_DATA: u8 = 0x05;
+const CMD_READ_DATA: u8 = 0x06;
+const CMD_RECALIBRATE: u8 = 0x07;
+const CMD_SENSE_INTERRUPT: u8 = 0x08;
+const CMD_SEEK: u8 = 0x0F;
+const CMD_VERSION: u8 = 0x10;
+/// Multi-track: continue on head 1 after the last sector on head 0
+const CMD_FLAG_MT: u8 = 0x80;
+const CMD_FLAG_MFM: u8 = 0x40;
+
+/// Returned by VERSION on an 82077AA (or compatible)
+pub const VERSION_82077AA: u8 = 0x90;
+
+// Status register 0 bits
+const ST0_INTERRUPT_CODE: u8 = 0xC0;
+/// Returned by SENSE INTERRUPT when there's no interrupt to report
+const ST0_INVALID_COMMAND: u8 = 0x80;
+const ST0_SEEK_END: u8 = 1 << 5;
+
+/// Sector size code for 512 byte sectors
+const SECTOR_SIZE_CODE: u8 = 2;
+/// Step rate 3ms, head unload 240ms
+const SPECIFY_SRT_HUT: u8 = 0xDF;
+/// Head load 2ms, DMA mode
+const SPECIFY_HLT_ND: u8 = 0x02;
+
+/// Time for the motor to reach full speed
+const MOTOR_SPINUP: Duration = Duration::from_millis(300);
+/// How long a command can take before we give up on the controller
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long to wait for the controller to accept or return a command byte
+const FIFO_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Set once IRQ 6 is routed somewhere. Until then, waiting futures poll the main status register.
+pub static IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set by the IRQ handler, cleared before every command that raises an interrupt
+static IRQ_PENDING: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Called from the IRQ 6 handler
+pub fn service_irq() {
+    IRQ_PENDING.store(true, Ordering::SeqCst);
+    WAKER.wake();
+}
+
+/// An 82077AA compatible floppy controller with up to two drives
+#[derive(Debug)]
+pub struct FloppyController {
+    base: u16,
+    /// Current DOR value, minus the reset and IRQ bits
+    dor: u8,
+    /// Cylinder each drive's head is over, `None` if it has to be recalibrated first
+    cylinder: [Option<u8>; 2],
+    /// When each drive was last used, for turning the motors off
+    last_used: [Option<Instant>; 2],
+    /// Physical and virtual address of the ISA DMA buffer
+    dma_phys: u64,
+    dma_virt: u64,
+}
+
+impl FloppyController {
+    pub fn new(base: u16, dma_phys: u64, dma_virt: u64) -> Self {
+        Self { base, dor: 0, cylinder: [None; 2], last_used: [None; 2], dma_phys, dma_virt }
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + reg).read() }
+    }
+
+    fn write_reg(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + reg).write(value) }
+    }
+
+    fn write_dor(&self) {
+        self.write_reg(REG_DOR, self.dor | DOR_NOT_RESET | DOR_IRQ_DMA);
+    }
+
+    /// The DMA buffer, which limits how much a single transfer can move
+    pub fn dma_buffer(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.dma_virt as *mut u8, isa_dma::ISA_DMA_BUFFER_SIZE as usize) }
+    }
+
+    /// Spin until the main status register says the FIFO is ready in the given direction
+    fn wait_fifo(&self, to_cpu: bool) -> Result<(), anyhow::Error> {
+        let deadline = Instant::now() + FIFO_TIMEOUT;
+        loop {
+            let msr = self.read_reg(REG_MSR);
+            if msr & MSR_RQM != 0 && (msr & MSR_DIO != 0) == to_cpu {
+                return Ok(());
+            }
+            if Instant::now().until(deadline) == Duration::ZERO {
+                return Err(anyhow::anyhow!("floppy: FIFO timed out (MSR {:#X})", msr));
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Send a command and its parameters
+    fn command(&self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+        for &byte in bytes {
+            self.wait_fifo(false)?;
+            self.write_reg(REG_FIFO, byte);
+        }
+        Ok(())
+    }
+
+    /// Read result bytes until the controller leaves the result phase or `max` bytes were read
+    fn results(&self, max: usize) -> Result<Vec<u8>, anyhow::Error> {
+        let mut results = Vec::with_capacity(max);
+        while results.len() < max {
+            self.wait_fifo(true)?;
+            results.push(self.read_reg(REG_FIFO));
+            if self.read_reg(REG_MSR) & MSR_BUSY == 0 {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Wait for the interrupt that ends a command. Without interrupts, `ready` is checked against
+    /// the main status register instead.
+    async fn wait_irq(&self, ready: fn(u8) -> bool) -> Result<(), anyhow::Error> {
+        let wait = IrqWait { msr: self.base + REG_MSR, ready };
+        let timeout = sleep(COMMAND_TIMEOUT);
+        pin_mut!(wait, timeout);
+        match select(wait, timeout).await {
+            Either::Left(_) => Ok(()),
+            Either::Right(_) => Err(anyhow::anyhow!("floppy: command timed out")),
+        }
+    }
+
+    /// Returns (ST0, present cylinder) for the last seek, recalibrate or reset
+    fn sense_interrupt(&self) -> Result<(u8, u8), anyhow::Error> {
+        self.command(&[CMD_SENSE_INTERRUPT])?;
+        let results = self.results(2)?;
+        match results.as_slice() {
+            [st0, cylinder] => Ok((*st0, *cylinder)),
+            [st0] if *st0 == ST0_INVALID_COMMAND => Ok((*st0, 0)),
+            _ => Err(anyhow::anyhow!("floppy: bad SENSE INTERRUPT result {:?}", results)),
+        }
+    }
+
+    pub fn version(&self) -> Result<u8, anyhow::Error> {
+        self.command(&[CMD_VERSION])?;
+        Ok(self.results(1)?[0])
+    }
+
+    /// Reset the controller and set it up for 1.44 MB disks
+    pub async fn reset(&mut self) -> Result<(), anyhow::Error> {
+        IRQ_PENDING.store(false, Ordering::SeqCst);
+        self.write_reg(REG_DOR, 0);
+        // the reset bit has to stay low for a few microseconds
+        for _ in 0..1000 {
+            core::hint::spin_loop();
+        }
+        self.write_dor();
+        self.wait_irq(|msr| msr & MSR_RQM != 0).await?;
+        // drive polling mode reports a "ready changed" interrupt for each of the four drives
+        for _ in 0..4 {
+            self.sense_interrupt()?;
+        }
+
+        // 500 kbit/s, the data rate for 1.44 MB disks
+        self.write_reg(REG_CCR, 0);
+        self.command(&[CMD_SPECIFY, SPECIFY_SRT_HUT, SPECIFY_HLT_ND])?;
+        self.cylinder = [None; 2];
+        Ok(())
+    }
+
+    /// Turn on the motor of `drive` and select it, waiting for it to spin up if it was off
+    pub async fn motor_on(&mut self, drive: u8) {
+        self.mark_used(drive);
+        let motor = DOR_MOTOR0 << drive;
+        let was_on = self.dor & motor != 0;
+        self.dor = (self.dor & !0x3) | motor | drive;
+        self.write_dor();
+        if !was_on {
+            sleep(MOTOR_SPINUP).await;
+        }
+    }
+
+    pub fn mark_used(&mut self, drive: u8) {
+        self.last_used[drive as usize] = Some(Instant::now());
+    }
+
+    /// Turn off the motors of drives that haven't been used for `idle` or longer
+    pub fn motor_idle_check(&mut self, idle: Duration) {
+        for drive in 0..2u8 {
+            if let Some(last_used) = self.last_used[drive as usize] {
+                if (last_used + idle).until(Instant::now()) > Duration::ZERO {
+                    self.dor &= !(DOR_MOTOR0 << drive);
+                    self.last_used[drive as usize] = None;
+                    self.write_dor();
+                }
+            }
+        }
+    }
+
+    /// Move the head of `drive` back to cylinder 0
+    pub async fn recalibrate(&mut self, drive: u8) -> Result<(), anyhow::Error> {
+        self.cylinder[drive as usize] = None;
+        // a recalibrate steps at most 79 times, drives with more cylinders might need two
+        for _ in 0..2 {
+            IRQ_PENDING.store(false, Ordering::SeqCst);
+            self.command(&[CMD_RECALIBRATE, drive])?;
+            self.wait_irq(|msr| msr & MSR_SEEKING == 0 && msr & MSR_RQM != 0).await?;
+            let (st0, cylinder) = self.sense_interrupt()?;
+            if st0 & ST0_SEEK_END != 0 && st0 & ST0_INTERRUPT_CODE == 0 && cylinder == 0 {
+                self.cylinder[drive as usize] = Some(0);
+                return Ok(());
+            }
+        }
+        Err(anyhow::anyhow!("floppy {}: recalibrate failed", drive))
+    }
+
+    /// Move the head of `drive` to `cylinder`, recalibrating first if its position isn't known
+    pub async fn seek(&mut self, drive: u8, cylinder: u8, head: u8) -> Result<(), anyhow::Error> {
+        if self.read_reg(REG_DIR) & DIR_DISK_CHANGE != 0 {
+            // a new disk, don't trust the old position
+            self.cylinder[drive as usize] = None;
+        }
+        if self.cylinder[drive as usize].is_none() {
+            self.recalibrate(drive).await?;
+        }
+        if self.cylinder[drive as usize] == Some(cylinder) {
+            return Ok(());
+        }
+        self.cylinder[drive as usize] = None;
+        IRQ_PENDING.store(false, Ordering::SeqCst);
+        self.command(&[CMD_SEEK, head << 2 | drive, cylinder])?;
+        self.wait_irq(|msr| msr & MSR_SEEKING == 0 && msr & MSR_RQM != 0).await?;
+        let (st0, present) = self.sense_interrupt()?;
+        if st0 & ST0_SEEK_END == 0 || st0 & ST0_INTERRUPT_CODE != 0 || present != cylinder {
+            return Err(anyhow::anyhow!("floppy {}: seek to cylinder {} failed (ST0 {:#X}, at {})", drive, cylinder, st0, present));
+        }
+        self.cylinder[drive as usize] = Some(cylinder);
+        Ok(())
+    }
+
+    /// Transfer `sectors` sectors starting at `lba` between the disk and the start of the DMA buffer.
+    /// The transfer can't go past the end of the cylinder. The head has to be on the right cylinder already.
+    pub async fn transfer(&mut self, drive: u8, geometry: &Geometry, lba: u64, sectors: usize, write: bool) -> Result<(), anyhow::Error> {
+        let (cylinder, head, sector) = geometry.chs(lba);
+        let len = sectors * geometry.sector_size();
+        let direction = if write { DmaDirection::FromMemory } else { DmaDirection::ToMemory };
+        isa_dma::setup(FDC_DMA_CHANNEL, self.dma_phys, len, direction)?;
+
+        let opcode = if write { CMD_WRITE_DATA } else { CMD_READ_DATA } | CMD_FLAG_MT | CMD_FLAG_MFM;
+        IRQ_PENDING.store(false, Ordering::SeqCst);
+        self.command(&[
+            opcode, head << 2 | drive, cylinder, head, sector, SECTOR_SIZE_CODE,
+            // last sector on a track, the DMA terminal count ends the transfer before that if needed
+            geometry.sectors_per_track, geometry.gap_length, 0xFF,
+        ])?;
+        let waited = self.wait_irq(|msr| msr & (MSR_RQM | MSR_DIO | MSR_BUSY) == MSR_RQM | MSR_DIO | MSR_BUSY).await;
+        if let Err(err) = waited {
+            isa_dma::mask(FDC_DMA_CHANNEL);
+            // the controller is stuck in the execution phase, only a reset gets it out
+            self.reset().await?;
+            return Err(err);
+        }
+
+        let results = self.results(7)?;
+        isa_dma::mask(FDC_DMA_CHANNEL);
+        match results.as_slice() {
+            [st0, st1, st2, ..] if st0 & ST0_INTERRUPT_CODE != 0 => {
+                self.cylinder[drive as usize] = None;
+                Err(anyhow::anyhow!("floppy {}: {} at C/H/S {}/{}/{} failed (ST0 {:#X} ST1 {:#X} ST2 {:#X})",
+                                    drive, if write { "write" } else { "read" }, cylinder, head, sector, st0, st1, st2))
+            },
+            [_, _, _, _, _, _, _] => Ok(()),
+            _ => Err(anyhow::anyhow!("floppy {}: short result {:?}", drive, results)),
+        }
+    }
+}
+
+/// Resolves once the floppy interrupt fires, or once `ready` returns true for the main status register
+/// when interrupts aren't available
+#[derive(Debug)]
+struct IrqWait {
+    msr: u16,
+    ready: fn(u8) -> bool,
+}
+
+impl Future for IrqWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if IRQ_PENDING.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        WAKER.register(cx.waker());
+        if IRQ_PENDING.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        if !IRQ_ENABLED.load(Ordering::SeqCst) {
+            let msr = unsafe { Port::<u8>::new(self.msr).read() };
+            if (self.ready)(msr) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,136 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use super::controller::FloppyController;
+use super::Geometry;
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
+use crate::sync::AsyncMutex;
+
+/// How many times a failed transfer is retried, with a recalibrate in between
+const MAX_RETRIES: usize = 3;
+
+/// A floppy drive on the floppy controller
+#[derive(Debug)]
+pub struct FloppyDisk {
+    /// Drive number, 0 or 1
+    drive: u8,
+    controller: Arc<AsyncMutex<FloppyController>>,
+    geometry: Geometry,
+    info: DiskInfo,
+}
+
+impl FloppyDisk {
+    pub fn new(drive: u8, controller: Arc<AsyncMutex<FloppyController>>, geometry: Geometry) -> Self {
+        let sectors = geometry.total_sectors();
+        crate::both_println!("   + Floppy {}: {} KB, {} cylinders, {} heads, {} sectors per track", drive,
+                             sectors * geometry.sector_size() as u64 / 1024, geometry.cylinders, geometry.heads,
+                             geometry.sectors_per_track);
+        Self {
+            drive,
+            controller,
+            geometry,
+            info: DiskInfo {
+                model: String::from("3.5\" 1.44 MB floppy drive"),
+                serial: String::new(),
+                firmware: String::new(),
+                sectors,
+                logical_sector_size: geometry.sector_size() as u32,
+                physical_sector_size: geometry.sector_size() as u32,
+                lba48: false,
+                ncq_depth: None,
+                trim: false,
+                write_cache: false,
+                write_cache_enabled: false,
+                smart: false,
+                smart_enabled: false,
+                read_only: false,
+            },
+        }
+    }
+
+    /// Transfer whole sectors between `buffer` and the disk, at most a cylinder at a time,
+    /// bouncing everything through the ISA DMA buffer
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be valid for `len` bytes (and writable if `write` is false) until the returned future completes.
+    async unsafe fn request(&mut self, block: u64, buffer: *const u8, len: usize, write: bool) -> Result<usize, anyhow::Error> {
+        let sector_size = self.geometry.sector_size();
+        let total_sectors = len / sector_size;
+        if block + total_sectors as u64 > self.geometry.total_sectors() {
+            return Err(anyhow::anyhow!("floppy {}: access past the end", self.drive));
+        }
+
+        let mut controller = self.controller.lock().await;
+        controller.motor_on(self.drive).await;
+        let max_sectors = controller.dma_buffer().len() / sector_size;
+        let mut done = 0;
+        while done < total_sectors {
+            let lba = block + done as u64;
+            let sectors = [total_sectors - done, self.geometry.sectors_left_in_cylinder(lba), max_sectors]
+                .iter().copied().min().unwrap();
+            let bytes = sectors * sector_size;
+            let chunk = unsafe { buffer.add(done * sector_size) };
+            if write {
+                unsafe { core::ptr::copy_nonoverlapping(chunk, controller.dma_buffer().as_mut_ptr(), bytes); }
+            }
+
+            let (cylinder, head, _) = self.geometry.chs(lba);
+            let mut attempt = 0;
+            loop {
+                let result = match controller.seek(self.drive, cylinder, head).await {
+                    Ok(()) => controller.transfer(self.drive, &self.geometry, lba, sectors, write).await,
+                    Err(err) => Err(err),
+                };
+                match result {
+                    Ok(()) => break,
+                    Err(err) if attempt + 1 < MAX_RETRIES => {
+                        crate::serial_println!("{}, retrying", err);
+                        attempt += 1;
+                        // a failed recalibrate shows up again on the next seek
+                        let _ = controller.recalibrate(self.drive).await;
+                    },
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if !write {
+                unsafe { core::ptr::copy_nonoverlapping(controller.dma_buffer().as_ptr(), chunk as *mut u8, bytes); }
+            }
+            done += sectors;
+        }
+        // the motor idle timeout starts now, not when the request started
+        controller.mark_used(self.drive);
+        Ok(done * sector_size)
+    }
+}
+
+impl Disk for FloppyDisk {
+    fn id(&self) -> usize { self.drive as usize }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::FloppyDrive }
+    fn size(&self) -> Option<u64> { Some(self.geometry.total_sectors() * self.geometry.sector_size() as u64) }
+    fn info(&self) -> Option<DiskInfo> { Some(self.info.clone()) }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            unsafe { self.request(block, buffer.as_mut_ptr(), buffer.len(), false).await }
+        })
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            unsafe { self.request(block, buffer.as_ptr(), buffer.len(), true).await }
+        })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        let sector_size = self.geometry.sector_size() as u32;
+        Box::pin(async move { Ok(sector_size) })
+    }
+}
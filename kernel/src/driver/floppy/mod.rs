@@ -0,0 +1,163 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use spin::Mutex;
+use x2apic::ioapic::IrqFlags;
+use self::controller::{FloppyController, FDC_BASE, VERSION_82077AA};
+use self::disk::FloppyDisk;
+use crate::arch::interrupts::{self, InterruptIndex};
+use crate::arch::rtc::Rtc;
+use crate::device::physical::SyncDisk;
+use crate::memory::ISA_DMA_REGION;
+use crate::sync::AsyncMutex;
+use crate::task::sleep::sleep;
+use crate::PHYS_MEM_OFFSET;
+
+/// Register access, seeking and DMA transfers
+pub mod controller;
+/// `Disk` implementation for floppy drives
+pub mod disk;
+
+/// ISA IRQ of the floppy controller
+const FLOPPY_IRQ: u8 = 6;
+/// CMOS drive type of a 3.5" 1.44 MB drive
+const CMOS_TYPE_1440K: u8 = 4;
+/// Motors are turned off after being idle this long
+const MOTOR_IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+static CONTROLLER: Mutex<Option<Arc<AsyncMutex<FloppyController>>>> = Mutex::new(None);
+
+/// Physical layout of a floppy disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub cylinders: u8,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+    /// GAP3 length for read and write commands
+    pub gap_length: u8,
+}
+
+/// 3.5" high density disk
+pub const GEOMETRY_1440K: Geometry = Geometry { cylinders: 80, heads: 2, sectors_per_track: 18, gap_length: 0x1B };
+
+impl Geometry {
+    pub fn sector_size(&self) -> usize { 512 }
+
+    pub fn total_sectors(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors_per_track as u64
+    }
+
+    /// Convert a logical block address to (cylinder, head, sector). Sectors start at 1.
+    pub fn chs(&self, lba: u64) -> (u8, u8, u8) {
+        let track = lba / self.sectors_per_track as u64;
+        let cylinder = track / self.heads as u64;
+        let head = track % self.heads as u64;
+        let sector = lba % self.sectors_per_track as u64 + 1;
+        (cylinder as u8, head as u8, sector as u8)
+    }
+
+    /// Sectors from `lba` up to the end of its cylinder, the most a multi-track command can transfer
+    pub fn sectors_left_in_cylinder(&self, lba: u64) -> usize {
+        let per_cylinder = self.heads as u64 * self.sectors_per_track as u64;
+        (per_cylinder - lba % per_cylinder) as usize
+    }
+}
+
+/// Set up the floppy controller if the firmware reports any drives. Drives are probed later by [scan_disks].
+pub fn init() {
+    let (drive0, drive1) = Rtc::new().floppy_types();
+    if drive0 == 0 && drive1 == 0 {
+        crate::both_println!("No floppy drives found.");
+        return;
+    }
+    let dma_phys = match *ISA_DMA_REGION.lock() {
+        Some(region) => region.range.start_addr(),
+        None => {
+            crate::both_println!("Floppy: no ISA DMA buffer, can't use the controller");
+            return;
+        }
+    };
+    crate::both_println!("Initializing floppy controller...");
+    let controller = FloppyController::new(FDC_BASE, dma_phys, dma_phys + PHYS_MEM_OFFSET);
+    *CONTROLLER.lock() = Some(Arc::new(AsyncMutex::new(controller)));
+
+    interrupts::register_irq_handler(InterruptIndex::FloppyDisk.as_u8(), handle_interrupt);
+    if interrupts::enable_ioapic_irq(FLOPPY_IRQ, InterruptIndex::FloppyDisk.as_u8(), IrqFlags::empty()) {
+        controller::IRQ_ENABLED.store(true, Ordering::SeqCst);
+    } else {
+        crate::both_println!("Floppy: no usable interrupt, falling back to polling");
+    }
+    crate::both_println!("Floppy controller initialized.");
+}
+
+fn handle_interrupt() {
+    controller::service_irq();
+}
+
+/// Reset the controller and open every drive the firmware knows about
+pub async fn scan_disks() -> Vec<SyncDisk> {
+    let controller = match CONTROLLER.lock().clone() {
+        Some(controller) => controller,
+        None => return Vec::new(),
+    };
+    {
+        let mut lock = controller.lock().await;
+        if let Err(err) = lock.reset().await {
+            crate::both_println!("{}", err);
+            return Vec::new();
+        }
+        match lock.version() {
+            Ok(VERSION_82077AA) => {},
+            Ok(version) => crate::serial_println!("Floppy: controller version {:#X} isn't an 82077AA, trying anyway", version),
+            Err(err) => crate::serial_println!("{}", err),
+        }
+    }
+
+    let (drive0, drive1) = Rtc::new().floppy_types();
+    let mut disks = Vec::new();
+    for (drive, drive_type) in [drive0, drive1].iter().enumerate() {
+        match *drive_type {
+            0 => {},
+            CMOS_TYPE_1440K => disks.push(SyncDisk::new(Box::new(FloppyDisk::new(drive as u8, controller.clone(), GEOMETRY_1440K)))),
+            other => crate::both_println!("Floppy {}: unsupported drive type {}", drive, other),
+        }
+    }
+    disks
+}
+
+/// Turns off drive motors that have been idle for a while
+pub async fn motor_task() {
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        let controller = CONTROLLER.lock().clone();
+        if let Some(controller) = controller {
+            // a request in progress keeps its motor running anyway
+            if let Some(mut lock) = controller.try_lock() {
+                lock.motor_idle_check(MOTOR_IDLE_TIMEOUT);
+            }
+        }
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_chs_1440k() {
+    let geometry = GEOMETRY_1440K;
+    assert_eq!(geometry.total_sectors(), 2880);
+    assert_eq!(geometry.chs(0), (0, 0, 1));
+    assert_eq!(geometry.chs(17), (0, 0, 18));
+    assert_eq!(geometry.chs(18), (0, 1, 1));
+    assert_eq!(geometry.chs(36), (1, 0, 1));
+    assert_eq!(geometry.chs(2879), (79, 1, 18));
+    assert_eq!(geometry.sectors_left_in_cylinder(0), 36);
+    assert_eq!(geometry.sectors_left_in_cylinder(20), 16);
+}
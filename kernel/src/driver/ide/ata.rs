@@ -0,0 +1,95 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use super::channel::{IdeChannel, MAX_SECTORS_PER_COMMAND};
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
+use crate::driver::identify::IdentifyData;
+use crate::sync::AsyncMutex;
+
+/// An ATA hard disk on an IDE channel
+#[derive(Debug)]
+pub struct IdeAtaDisk {
+    id: usize,
+    channel: Arc<AsyncMutex<IdeChannel>>,
+    /// 0 = master, 1 = slave
+    drive: u8,
+    lba48: bool,
+    /// Use bus master DMA when the buffer allows it, otherwise always PIO
+    dma: bool,
+    size: u64,
+    info: DiskInfo,
+}
+
+impl IdeAtaDisk {
+    pub fn new(id: usize, channel: Arc<AsyncMutex<IdeChannel>>, drive: u8, has_dma: bool, identify: &IdentifyData) -> Self {
+        let info = identify.info();
+        let dma = has_dma && identify.supports_dma();
+        crate::serial_println!("   + IDE {}: {} ({} MB, {})", id, info.model, identify.sectors() / 2048,
+                               if dma { "DMA" } else { "PIO" });
+        Self {
+            id,
+            channel,
+            drive,
+            lba48: identify.supports_lba48(),
+            dma,
+            size: identify.sectors() * 512,
+            info,
+        }
+    }
+
+    /// Transfer whole sectors between `buffer` and the disk, one command at a time
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be valid for `len` bytes (and writable if `write` is false) until the returned future completes.
+    async unsafe fn request(&mut self, block: u64, buffer: *const u8, len: usize, write: bool) -> Result<usize, anyhow::Error> {
+        let total_sectors = len / 512;
+        let mut channel = self.channel.lock().await;
+        let mut sector = 0;
+        while sector < total_sectors {
+            let sectors = core::cmp::min(MAX_SECTORS_PER_COMMAND, total_sectors - sector);
+            let lba = block + sector as u64;
+            let chunk = unsafe { buffer.add(sector * 512) };
+            let bytes = sectors * 512;
+
+            let done = self.dma && unsafe { channel.dma(self.drive, lba, self.lba48, chunk, bytes, write).await? };
+            if !done {
+                if write {
+                    let data = unsafe { core::slice::from_raw_parts(chunk, bytes) };
+                    channel.pio_write(self.drive, lba, self.lba48, data).await?;
+                } else {
+                    let data = unsafe { core::slice::from_raw_parts_mut(chunk as *mut u8, bytes) };
+                    channel.pio_read(self.drive, lba, self.lba48, data).await?;
+                }
+            }
+            sector += sectors;
+        }
+        Ok(sector * 512)
+    }
+}
+
+impl Disk for IdeAtaDisk {
+    fn id(&self) -> usize { self.id }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::IdeDrive }
+    fn size(&self) -> Option<u64> { Some(self.size) }
+    fn info(&self) -> Option<DiskInfo> { Some(self.info.clone()) }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            unsafe { self.request(block, buffer.as_mut_ptr(), buffer.len(), false).await }
+        })
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            unsafe { self.request(block, buffer.as_ptr(), buffer.len(), true).await }
+        })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> { Box::pin(async { Ok(512) }) }
+}
@@ -0,0 +1,95 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use byteorder::{ByteOrder, BigEndian};
+use super::channel::IdeChannel;
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
+use crate::driver::identify::IdentifyData;
+use crate::sync::AsyncMutex;
+
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ10: u8 = 0x28;
+
+/// Most sectors read with a single packet, keeps each transfer under the 64K PIO byte count
+const MAX_SECTORS_PER_PACKET: u32 = 16;
+
+/// An ATAPI (optical) drive on an IDE channel. Read only, transfers are always PIO.
+#[derive(Debug)]
+pub struct IdeAtapiDisk {
+    id: usize,
+    channel: Arc<AsyncMutex<IdeChannel>>,
+    drive: u8,
+    info: DiskInfo,
+}
+
+impl IdeAtapiDisk {
+    pub fn new(id: usize, channel: Arc<AsyncMutex<IdeChannel>>, drive: u8, identify: &IdentifyData) -> Self {
+        let info = DiskInfo {
+            logical_sector_size: 2048,
+            physical_sector_size: 2048,
+            read_only: true,
+            ..identify.info()
+        };
+        crate::serial_println!("   + IDE {}: {} (ATAPI)", id, info.model);
+        Self { id, channel, drive, info }
+    }
+
+    async fn read_capacity(&mut self) -> Result<(u32, u32), anyhow::Error> {
+        let mut packet = [0; 12];
+        packet[0] = SCSI_READ_CAPACITY;
+        let mut response = [0u8; 8];
+        let received = self.channel.lock().await.packet(self.drive, &packet, &mut response).await?;
+        if received < 8 {
+            return Err(anyhow::anyhow!("IDE {}: short READ CAPACITY response", self.id));
+        }
+
+        // Instead of a count, contains number of last LBA, so add 1
+        let blk_count = BigEndian::read_u32(&response[0..4]) + 1;
+        let blk_size = BigEndian::read_u32(&response[4..8]);
+        Ok((blk_count, blk_size))
+    }
+}
+
+impl Disk for IdeAtapiDisk {
+    fn id(&self) -> usize { self.id }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::IdeAtapiDrive }
+    // removable media, the size is only known once something is inserted
+    fn size(&self) -> Option<u64> { None }
+    fn info(&self) -> Option<DiskInfo> { Some(self.info.clone()) }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            let blk_len = self.read_capacity().await?.1;
+            let sectors = buffer.len() as u32 / blk_len;
+
+            let mut channel = self.channel.lock().await;
+            let mut sector = 0;
+            while sector < sectors {
+                let count = core::cmp::min(MAX_SECTORS_PER_PACKET, sectors - sector);
+                let mut packet = [0; 12];
+                packet[0] = SCSI_READ10;
+                BigEndian::write_u32(&mut packet[2..6], block as u32 + sector);
+                BigEndian::write_u16(&mut packet[7..9], count as u16);
+
+                let start = (sector * blk_len) as usize;
+                let end = start + (count * blk_len) as usize;
+                channel.packet(self.drive, &packet, &mut buffer[start..end]).await?;
+                sector += count;
+            }
+            Ok((sector * blk_len) as usize)
+        })
+    }
+
+    fn write<'a>(&'a mut self, _block: u64, _buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move { Err(anyhow::anyhow!("IDE {}: writing to ATAPI drives is not supported", self.id)) })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        Box::pin(async move { Ok(self.read_capacity().await?.1) })
+    }
+}
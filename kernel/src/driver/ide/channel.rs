@@ -0,0 +1,474 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+use crate::driver::identify::IdentifyData;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::frame::AddressLimit;
+use crate::memory::translate_addr;
+use crate::task::sleep::sleep;
+
+// Task file registers, offsets from the channel's I/O base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_FEATURES: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA0: u16 = 3;
+const REG_LBA1: u16 = 4;
+const REG_LBA2: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+// Bus master registers, offsets from the channel's bus master base
+const BM_COMMAND: u16 = 0;
+const BM_STATUS: u16 = 2;
+const BM_PRDT: u16 = 4;
+
+const BM_COMMAND_START: u8 = 1 << 0;
+/// Set for transfers from the device to memory
+const BM_COMMAND_READ: u8 = 1 << 3;
+const BM_STATUS_ERROR: u8 = 1 << 1;
+const BM_STATUS_INTERRUPT: u8 = 1 << 2;
+
+pub const ATA_SR_BSY: u8 = 0x80;
+pub const ATA_SR_DF: u8 = 0x20;
+pub const ATA_SR_DRQ: u8 = 0x08;
+pub const ATA_SR_ERR: u8 = 0x01;
+
+pub const ATA_CMD_READ_PIO: u8 = 0x20;
+pub const ATA_CMD_READ_PIO_EXT: u8 = 0x24;
+pub const ATA_CMD_READ_DMA: u8 = 0xC8;
+pub const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_CMD_WRITE_PIO: u8 = 0x30;
+pub const ATA_CMD_WRITE_PIO_EXT: u8 = 0x34;
+pub const ATA_CMD_WRITE_DMA: u8 = 0xCA;
+pub const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_CMD_CACHE_FLUSH: u8 = 0xE7;
+pub const ATA_CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+pub const ATA_CMD_PACKET: u8 = 0xA0;
+pub const ATA_CMD_IDENTIFY_PACKET: u8 = 0xA1;
+pub const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+/// Largest transfer done with a single command, in sectors. Keeps the PRDT small and fits LBA28 commands.
+pub const MAX_SECTORS_PER_COMMAND: usize = 128;
+/// Enough PRD entries for [MAX_SECTORS_PER_COMMAND] sectors split at every page boundary
+const PRDT_ENTRIES: usize = MAX_SECTORS_PER_COMMAND * 512 / 4096 + 1;
+
+/// How long a command can take before we give up on the device
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set once the channel interrupts are routed somewhere. Until then, waiting futures poll the status register.
+pub static IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const WAKER_INIT: AtomicWaker = AtomicWaker::new();
+/// Woken by the IRQ handler for each channel
+static CHANNEL_WAKERS: [AtomicWaker; 2] = [WAKER_INIT; 2];
+/// Ports the IRQ handler needs to acknowledge interrupts, since it can't take the channel locks
+static STATUS_PORTS: [AtomicU16; 2] = [AtomicU16::new(0), AtomicU16::new(0)];
+static BUS_MASTER_PORTS: [AtomicU16; 2] = [AtomicU16::new(0), AtomicU16::new(0)];
+
+/// Kind of device attached to a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdeDeviceType {
+    Ata,
+    Atapi,
+}
+
+/// Physical Region Descriptor, one contiguous piece of a bus master DMA transfer
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct PrdEntry {
+    address: u32,
+    /// Byte count, 0 means 64K
+    count: u16,
+    /// Bit 15 marks the last entry
+    flags: u16,
+}
+
+const PRD_END_OF_TABLE: u16 = 1 << 15;
+
+/// The PRDT can't cross a 64K boundary, it gets a page of its own which takes care of that.
+/// The bus master only takes a 32 bit address for it.
+#[repr(C)]
+#[derive(Debug)]
+struct PrdTable([PrdEntry; PRDT_ENTRIES]);
+
+/// One IDE channel (primary or secondary) with up to two drives
+#[derive(Debug)]
+pub struct IdeChannel {
+    index: usize,
+    base: u16,
+    ctrl: u16,
+    bus_master: Option<u16>,
+    /// Holds a [PrdTable]
+    prdt: DmaBuffer,
+}
+
+impl IdeChannel {
+    pub fn new(index: usize, base: u16, ctrl: u16, bus_master: Option<u16>) -> Self {
+        STATUS_PORTS[index].store(base + REG_STATUS, Ordering::SeqCst);
+        BUS_MASTER_PORTS[index].store(bus_master.unwrap_or(0), Ordering::SeqCst);
+        let prdt = DmaBuffer::new(core::mem::size_of::<PrdTable>(), 4096, AddressLimit::Below4GiB)
+            .expect("IDE: out of memory for the PRDT");
+        let channel = Self { index, base, ctrl, bus_master, prdt };
+        // clear nIEN so the drives raise interrupts
+        channel.write_ctrl(0);
+        channel
+    }
+
+    pub fn index(&self) -> usize { self.index }
+
+    fn prdt(&mut self) -> &mut PrdTable {
+        // zeroed entries are valid, and `&mut self` keeps it from being changed while a transfer is running
+        unsafe { &mut *(self.prdt.as_mut_ptr() as *mut PrdTable) }
+    }
+
+    /// Whether the controller can do bus master DMA on this channel
+    pub fn has_dma(&self) -> bool { self.bus_master.is_some() }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + reg).read() }
+    }
+
+    fn write_reg(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + reg).write(value) }
+    }
+
+    fn write_ctrl(&self, value: u8) {
+        unsafe { Port::<u8>::new(self.ctrl).write(value) }
+    }
+
+    /// Read the alternate status register, which doesn't acknowledge the interrupt
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.ctrl).read() }
+    }
+
+    fn read_bm(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.bus_master.unwrap() + reg).read() }
+    }
+
+    fn write_bm(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.bus_master.unwrap() + reg).write(value) }
+    }
+
+    /// The status register isn't valid for 400ns after selecting a drive or sending a command.
+    /// Each read of the alternate status register takes about 100ns.
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    /// Select `drive` (0 = master, 1 = slave) in LBA mode, with the top 4 bits of an LBA28 address
+    fn select(&self, drive: u8, lba_high: u8) {
+        self.write_reg(REG_DRIVE, 0xA0 | 1 << 6 | drive << 4 | (lba_high & 0xF));
+        self.delay_400ns();
+    }
+
+    /// Returns true if nothing is attached to this channel at all (the bus floats high)
+    pub fn is_floating(&self) -> bool {
+        self.read_reg(REG_STATUS) == 0xFF
+    }
+
+    /// Wait until the drive isn't busy. Resolves to the status register.
+    async fn wait(&self) -> Result<u8, anyhow::Error> {
+        let wait = IdeWait { channel: self };
+        let timeout = sleep(COMMAND_TIMEOUT);
+        pin_mut!(wait, timeout);
+        match select(wait, timeout).await {
+            Either::Left((status, _)) => Ok(status),
+            Either::Right(_) => Err(anyhow::anyhow!("IDE channel {}: command timed out", self.index)),
+        }
+    }
+
+    /// Wait until the drive is ready to transfer data
+    async fn wait_drq(&self) -> Result<(), anyhow::Error> {
+        let status = self.wait().await?;
+        self.check_status(status)?;
+        if status & ATA_SR_DRQ == 0 {
+            return Err(anyhow::anyhow!("IDE channel {}: drive isn't requesting data (status {:#X})", self.index, status));
+        }
+        Ok(())
+    }
+
+    fn check_status(&self, status: u8) -> Result<(), anyhow::Error> {
+        if status & ATA_SR_ERR != 0 {
+            Err(anyhow::anyhow!("IDE channel {}: device error (status {:#X} error {:#X})",
+                                self.index, status, self.read_reg(REG_ERROR)))
+        } else if status & ATA_SR_DF != 0 {
+            Err(anyhow::anyhow!("IDE channel {}: device fault (status {:#X})", self.index, status))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send IDENTIFY (and IDENTIFY PACKET if the device turns out to be ATAPI) to `drive`.
+    /// Resolves to `None` if nothing is attached.
+    pub async fn identify(&mut self, drive: u8) -> Result<Option<(IdeDeviceType, IdentifyData)>, anyhow::Error> {
+        self.select(drive, 0);
+        self.write_reg(REG_SECTOR_COUNT, 0);
+        self.write_reg(REG_LBA0, 0);
+        self.write_reg(REG_LBA1, 0);
+        self.write_reg(REG_LBA2, 0);
+        self.write_reg(REG_COMMAND, ATA_CMD_IDENTIFY);
+        self.delay_400ns();
+        if self.read_reg(REG_STATUS) == 0 {
+            return Ok(None);
+        }
+
+        let status = self.wait().await?;
+        // ATAPI devices abort IDENTIFY and leave their signature in the LBA registers
+        let kind = match (self.read_reg(REG_LBA1), self.read_reg(REG_LBA2)) {
+            (0x00, 0x00) => IdeDeviceType::Ata,
+            (0x14, 0xEB) | (0x69, 0x96) => IdeDeviceType::Atapi,
+            _ => return Ok(None),
+        };
+        if kind == IdeDeviceType::Atapi {
+            self.write_reg(REG_COMMAND, ATA_CMD_IDENTIFY_PACKET);
+            self.delay_400ns();
+        } else {
+            self.check_status(status)?;
+        }
+        self.wait_drq().await?;
+
+        let mut data = IdentifyData([0u16; 256]);
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for word in data.0.iter_mut() {
+            *word = unsafe { port.read() };
+        }
+        Ok(Some((kind, data)))
+    }
+
+    /// Load the address and sector count registers for a read or write
+    fn set_lba(&self, drive: u8, lba: u64, sectors: usize, lba48: bool) {
+        if lba48 {
+            self.select(drive, 0);
+            // high bytes first, the registers are two-deep FIFOs
+            self.write_reg(REG_SECTOR_COUNT, (sectors >> 8) as u8);
+            self.write_reg(REG_LBA0, (lba >> 24) as u8);
+            self.write_reg(REG_LBA1, (lba >> 32) as u8);
+            self.write_reg(REG_LBA2, (lba >> 40) as u8);
+        } else {
+            self.select(drive, (lba >> 24) as u8);
+        }
+        // 0 means 256 (or 65536) sectors
+        self.write_reg(REG_SECTOR_COUNT, sectors as u8);
+        self.write_reg(REG_LBA0, lba as u8);
+        self.write_reg(REG_LBA1, (lba >> 8) as u8);
+        self.write_reg(REG_LBA2, (lba >> 16) as u8);
+    }
+
+    /// Read `buffer.len() / 512` sectors starting at `lba` using PIO
+    pub async fn pio_read(&mut self, drive: u8, lba: u64, lba48: bool, buffer: &mut [u8]) -> Result<(), anyhow::Error> {
+        let sectors = buffer.len() / 512;
+        assert!(sectors > 0 && sectors <= MAX_SECTORS_PER_COMMAND);
+        self.set_lba(drive, lba, sectors, lba48);
+        self.write_reg(REG_COMMAND, if lba48 { ATA_CMD_READ_PIO_EXT } else { ATA_CMD_READ_PIO });
+        self.delay_400ns();
+
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for sector in buffer.chunks_exact_mut(512) {
+            self.wait_drq().await?;
+            for bytes in sector.chunks_exact_mut(2) {
+                let word: u16 = unsafe { port.read() };
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `buffer.len() / 512` sectors starting at `lba` using PIO, then flush the drive's cache
+    pub async fn pio_write(&mut self, drive: u8, lba: u64, lba48: bool, buffer: &[u8]) -> Result<(), anyhow::Error> {
+        let sectors = buffer.len() / 512;
+        assert!(sectors > 0 && sectors <= MAX_SECTORS_PER_COMMAND);
+        self.set_lba(drive, lba, sectors, lba48);
+        self.write_reg(REG_COMMAND, if lba48 { ATA_CMD_WRITE_PIO_EXT } else { ATA_CMD_WRITE_PIO });
+        self.delay_400ns();
+
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for sector in buffer.chunks_exact(512) {
+            self.wait_drq().await?;
+            for bytes in sector.chunks_exact(2) {
+                unsafe { port.write(u16::from_le_bytes([bytes[0], bytes[1]])); }
+            }
+        }
+        let status = self.wait().await?;
+        self.check_status(status)?;
+
+        self.write_reg(REG_COMMAND, if lba48 { ATA_CMD_CACHE_FLUSH_EXT } else { ATA_CMD_CACHE_FLUSH });
+        self.delay_400ns();
+        let status = self.wait().await?;
+        self.check_status(status)
+    }
+
+    /// Point the PRDT at `len` bytes at `buf`. Returns false if the buffer can't be used for
+    /// bus master DMA (misaligned, or not below 4GiB), in which case the caller should use PIO.
+    fn fill_prdt(&mut self, buf: *const u8, len: usize) -> bool {
+        if buf as usize & 1 != 0 || len & 1 != 0 {
+            return false;
+        }
+        let mut offset = 0;
+        let mut entries = 0;
+        while offset < len {
+            let virt = buf as u64 + offset as u64;
+            // split at every page boundary, that also keeps entries from crossing 64K
+            let chunk = core::cmp::min(len - offset, (4096 - (virt & 0xFFF)) as usize);
+            let phys = match translate_addr(VirtAddr::new(virt)) {
+                Some(phys) if phys.as_u64() + chunk as u64 <= u32::MAX as u64 => phys.as_u64(),
+                _ => return false,
+            };
+            if entries == PRDT_ENTRIES {
+                return false;
+            }
+            self.prdt().0[entries] = PrdEntry { address: phys as u32, count: chunk as u16, flags: 0 };
+            entries += 1;
+            offset += chunk;
+        }
+        if entries == 0 {
+            return false;
+        }
+        self.prdt().0[entries - 1].flags = PRD_END_OF_TABLE;
+        true
+    }
+
+    /// Transfer `len` bytes at `lba` using bus master DMA. Resolves to `Ok(false)` without
+    /// touching the drive if the buffer can't be used for DMA.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for `len` bytes (and writable if `write` is false) until the returned future completes.
+    pub async unsafe fn dma(&mut self, drive: u8, lba: u64, lba48: bool, buf: *const u8, len: usize, write: bool) -> Result<bool, anyhow::Error> {
+        let sectors = len / 512;
+        assert!(sectors > 0 && sectors <= MAX_SECTORS_PER_COMMAND);
+        if !self.has_dma() || !self.fill_prdt(buf, len) {
+            return Ok(false);
+        }
+        // allocated below 4GiB
+        let prdt_phys = self.prdt.phys().as_u64() as u32;
+
+        self.write_bm(BM_COMMAND, 0);
+        unsafe { Port::<u32>::new(self.bus_master.unwrap() + BM_PRDT).write(prdt_phys); }
+        self.write_bm(BM_COMMAND, if write { 0 } else { BM_COMMAND_READ });
+        // write-1-to-clear
+        self.write_bm(BM_STATUS, BM_STATUS_ERROR | BM_STATUS_INTERRUPT);
+
+        self.set_lba(drive, lba, sectors, lba48);
+        self.write_reg(REG_COMMAND, match (write, lba48) {
+            (true, true) => ATA_CMD_WRITE_DMA_EXT,
+            (true, false) => ATA_CMD_WRITE_DMA,
+            (false, true) => ATA_CMD_READ_DMA_EXT,
+            (false, false) => ATA_CMD_READ_DMA,
+        });
+        self.delay_400ns();
+        self.write_bm(BM_COMMAND, (if write { 0 } else { BM_COMMAND_READ }) | BM_COMMAND_START);
+
+        let result = self.wait().await;
+        self.write_bm(BM_COMMAND, 0);
+        let bm_status = self.read_bm(BM_STATUS);
+        self.write_bm(BM_STATUS, BM_STATUS_ERROR | BM_STATUS_INTERRUPT);
+
+        self.check_status(result?)?;
+        if bm_status & BM_STATUS_ERROR != 0 {
+            return Err(anyhow::anyhow!("IDE channel {}: bus master DMA error", self.index));
+        }
+        Ok(true)
+    }
+
+    /// Send an ATAPI packet and read the response into `buffer` using PIO.
+    /// Resolves to the number of bytes the device sent.
+    pub async fn packet(&mut self, drive: u8, packet: &[u8; 12], buffer: &mut [u8]) -> Result<usize, anyhow::Error> {
+        self.select(drive, 0);
+        // PIO, and the largest byte count per DRQ block we're willing to take
+        let max_bytes = core::cmp::min(buffer.len(), 0xFFFE) as u16 & !1;
+        self.write_reg(REG_FEATURES, 0);
+        self.write_reg(REG_LBA1, max_bytes as u8);
+        self.write_reg(REG_LBA2, (max_bytes >> 8) as u8);
+        self.write_reg(REG_COMMAND, ATA_CMD_PACKET);
+        self.delay_400ns();
+        self.wait_drq().await?;
+
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for bytes in packet.chunks_exact(2) {
+            unsafe { port.write(u16::from_le_bytes([bytes[0], bytes[1]])); }
+        }
+
+        let mut received = 0;
+        loop {
+            let status = self.wait().await?;
+            self.check_status(status)?;
+            if status & ATA_SR_DRQ == 0 {
+                break;
+            }
+            let count = self.read_reg(REG_LBA1) as usize | (self.read_reg(REG_LBA2) as usize) << 8;
+            for _ in 0..(count + 1) / 2 {
+                let word: u16 = unsafe { port.read() };
+                for byte in word.to_le_bytes() {
+                    // drain anything that doesn't fit so the device doesn't get stuck
+                    if received < buffer.len() {
+                        buffer[received] = byte;
+                    }
+                    received += 1;
+                }
+            }
+        }
+        Ok(core::cmp::min(received, buffer.len()))
+    }
+}
+
+/// Acknowledge an interrupt on a channel and wake whoever is waiting on it. Called from the IRQ handler.
+pub fn service_irq(index: usize) {
+    let bus_master = BUS_MASTER_PORTS[index].load(Ordering::SeqCst);
+    if bus_master != 0 {
+        let status = unsafe { Port::<u8>::new(bus_master + BM_STATUS).read() };
+        if status & BM_STATUS_INTERRUPT == 0 {
+            // shared line, not ours
+            return;
+        }
+        unsafe { Port::<u8>::new(bus_master + BM_STATUS).write(BM_STATUS_INTERRUPT); }
+    }
+    // reading the status register deasserts the drive's interrupt
+    let status_port = STATUS_PORTS[index].load(Ordering::SeqCst);
+    if status_port != 0 {
+        unsafe { Port::<u8>::new(status_port).read(); }
+    }
+    CHANNEL_WAKERS[index].wake();
+}
+
+/// Resolves once the drive on a channel stops being busy
+#[derive(Debug)]
+struct IdeWait<'a> {
+    channel: &'a IdeChannel,
+}
+
+impl Future for IdeWait<'_> {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u8> {
+        let channel = self.channel;
+        if channel.alt_status() & ATA_SR_BSY == 0 {
+            return Poll::Ready(channel.read_reg(REG_STATUS));
+        }
+        CHANNEL_WAKERS[channel.index].register(cx.waker());
+        if channel.alt_status() & ATA_SR_BSY == 0 {
+            return Poll::Ready(channel.read_reg(REG_STATUS));
+        }
+        if !IRQ_ENABLED.load(Ordering::SeqCst) {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
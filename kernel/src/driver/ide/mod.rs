@@ -0,0 +1,158 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x2apic::ioapic::IrqFlags;
+use self::ata::IdeAtaDisk;
+use self::atapi::IdeAtapiDisk;
+use self::channel::{IdeChannel, IdeDeviceType};
+use crate::arch::interrupts::{self, InterruptIndex};
+use crate::device::physical::SyncDisk;
+use crate::driver::pci::{PciAddress, PciCommand};
+use crate::sync::AsyncMutex;
+
+/// Register access, PIO and bus master DMA for a single channel
+pub mod channel;
+/// `Disk` implementation for ATA disks
+pub mod ata;
+/// `Disk` implementation for ATAPI disks
+pub mod atapi;
+
+/// PCI class and subclass for an IDE controller
+const PCI_CLASS_MASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_IDE: u8 = 0x01;
+
+/// Programming interface bits: channel is in PCI native mode, and the controller supports bus mastering
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+const PROG_IF_BUS_MASTER: u8 = 1 << 7;
+
+/// I/O ports and IRQs used by channels in compatibility mode
+const LEGACY_PORTS: [(u16, u16, u8); 2] = [(0x1F0, 0x3F6, 14), (0x170, 0x376, 15)];
+
+static CHANNELS: Mutex<Vec<Arc<AsyncMutex<IdeChannel>>>> = Mutex::new(Vec::new());
+
+/// Find the IDE controller and set up its channels. Drives are probed later by [scan_disks].
+pub fn init() {
+    let pci_addr = match PciAddress::find(PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_IDE) {
+        Some(addr) => addr,
+        None => {
+            crate::both_println!("No IDE controller found.");
+            return;
+        }
+    };
+    crate::both_println!("Initializing IDE controller...");
+    let (_, _, prog_if) = pci_addr.class_code();
+
+    let bus_master_base = if prog_if & PROG_IF_BUS_MASTER != 0 {
+        pci_addr.set_command(pci_addr.command() | PciCommand::IoSpace | PciCommand::BusMaster);
+        match pci_addr.bar(4) {
+            0 => None,
+            bar => Some(bar as u16),
+        }
+    } else {
+        pci_addr.set_command(pci_addr.command() | PciCommand::IoSpace);
+        None
+    };
+
+    let mut channels = Vec::new();
+    let mut native = false;
+    for (index, native_bit) in [PROG_IF_PRIMARY_NATIVE, PROG_IF_SECONDARY_NATIVE].iter().enumerate() {
+        let (base, ctrl) = if prog_if & native_bit != 0 {
+            native = true;
+            let base = pci_addr.bar(index as u8 * 2) as u16;
+            // the control block BAR points at a 4 byte block, the register is at offset 2
+            let ctrl = pci_addr.bar(index as u8 * 2 + 1) as u16 + 2;
+            (base, ctrl)
+        } else {
+            let (base, ctrl, _) = LEGACY_PORTS[index];
+            (base, ctrl)
+        };
+        let bus_master = bus_master_base.map(|bm| bm + index as u16 * 8);
+        let channel = IdeChannel::new(index, base, ctrl, bus_master);
+        if channel.is_floating() {
+            crate::serial_println!("IDE channel {}: nothing attached", index);
+            continue;
+        }
+        crate::serial_println!("IDE channel {}: I/O {:#X} control {:#X} bus master {:?}", index, base, ctrl, bus_master);
+        channels.push(Arc::new(AsyncMutex::new(channel)));
+    }
+    *CHANNELS.lock() = channels;
+
+    init_interrupts(pci_addr, native);
+    crate::both_println!("IDE controller initialized.");
+}
+
+/// Route the channel interrupts to [handle_interrupt]. Compatibility mode channels use ISA IRQs 14 and 15,
+/// native mode channels share the controller's PCI interrupt.
+fn init_interrupts(pci_addr: PciAddress, native: bool) {
+    let enabled = if native {
+        let line = pci_addr.interrupt_line();
+        match interrupts::allocate_vector() {
+            Some(vector) if line != 0xFF => {
+                interrupts::register_irq_handler(vector, handle_interrupt);
+                interrupts::enable_ioapic_irq(line, vector, IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE)
+            },
+            _ => false,
+        }
+    } else {
+        interrupts::register_irq_handler(InterruptIndex::PrimaryATA.as_u8(), handle_primary_interrupt);
+        interrupts::register_irq_handler(InterruptIndex::SecondaryATA.as_u8(), handle_secondary_interrupt);
+        let (_, _, primary_irq) = LEGACY_PORTS[0];
+        let (_, _, secondary_irq) = LEGACY_PORTS[1];
+        interrupts::enable_ioapic_irq(primary_irq, InterruptIndex::PrimaryATA.as_u8(), IrqFlags::empty())
+            && interrupts::enable_ioapic_irq(secondary_irq, InterruptIndex::SecondaryATA.as_u8(), IrqFlags::empty())
+    };
+    if enabled {
+        channel::IRQ_ENABLED.store(true, Ordering::SeqCst);
+    } else {
+        crate::both_println!("IDE: no usable interrupt, falling back to polling");
+    }
+}
+
+fn handle_primary_interrupt() {
+    channel::service_irq(0);
+}
+
+fn handle_secondary_interrupt() {
+    channel::service_irq(1);
+}
+
+/// Both channels share the interrupt in native mode
+fn handle_interrupt() {
+    channel::service_irq(0);
+    channel::service_irq(1);
+}
+
+/// Probe both drives on every channel and open the ones that respond
+pub async fn scan_disks() -> Vec<SyncDisk> {
+    let channels: Vec<_> = CHANNELS.lock().clone();
+    let mut disks = Vec::new();
+    for channel in channels {
+        for drive in 0..2 {
+            let (index, has_dma, identified) = {
+                let mut lock = channel.lock().await;
+                (lock.index(), lock.has_dma(), lock.identify(drive).await)
+            };
+            let id = index * 2 + drive as usize;
+            match identified {
+                Ok(Some((IdeDeviceType::Ata, identify))) => {
+                    disks.push(SyncDisk::new(Box::new(IdeAtaDisk::new(id, channel.clone(), drive, has_dma, &identify))));
+                },
+                Ok(Some((IdeDeviceType::Atapi, identify))) => {
+                    disks.push(SyncDisk::new(Box::new(IdeAtapiDisk::new(id, channel.clone(), drive, &identify))));
+                },
+                Ok(None) => {},
+                Err(err) => crate::serial_println!("IDE {}: {}", id, err),
+            }
+        }
+    }
+    disks
+}
@@ -0,0 +1,139 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::string::String;
+use crate::device::physical::DiskInfo;
+
+/// Raw response to an ATA IDENTIFY DEVICE / IDENTIFY PACKET DEVICE command
+#[derive(Clone)]
+pub struct IdentifyData(pub [u16; 256]);
+
+impl IdentifyData {
+    /// Read a string field. ATA strings are stored as big-endian byte pairs in each word.
+    fn string(&self, words: core::ops::Range<usize>) -> String {
+        let mut result = String::new();
+        for word in words {
+            let d = self.0[word];
+            for c in [(d >> 8) as u8, d as u8] {
+                if c != 0 {
+                    result.push(c as char);
+                }
+            }
+        }
+        String::from(result.trim())
+    }
+
+    pub fn serial(&self) -> String { self.string(10..20) }
+    pub fn firmware(&self) -> String { self.string(23..27) }
+    pub fn model(&self) -> String { self.string(27..47) }
+
+    /// True if the device supports DMA transfers
+    pub fn supports_dma(&self) -> bool {
+        self.0[49] & (1 << 8) != 0
+    }
+
+    /// True if the device supports 48-bit LBA addressing
+    pub fn supports_lba48(&self) -> bool {
+        self.0[83] & (1 << 10) != 0
+    }
+
+    /// Number of user addressable sectors
+    pub fn sectors(&self) -> u64 {
+        let lba48 = (self.0[100] as u64) |
+                    ((self.0[101] as u64) << 16) |
+                    ((self.0[102] as u64) << 32) |
+                    ((self.0[103] as u64) << 48);
+        if lba48 != 0 {
+            lba48
+        } else {
+            (self.0[60] as u64) | ((self.0[61] as u64) << 16)
+        }
+    }
+
+    /// Size of a logical sector (the unit LBAs count in) in bytes
+    pub fn logical_sector_size(&self) -> u32 {
+        let word = self.0[106];
+        // word 106 is only valid if bit 14 is set and bit 15 is clear
+        if word & 0xC000 == 0x4000 && word & (1 << 12) != 0 {
+            // words 117-118 hold the size in words
+            ((self.0[117] as u32) | ((self.0[118] as u32) << 16)) * 2
+        } else {
+            512
+        }
+    }
+
+    /// Size of a physical sector (the unit the media actually writes) in bytes
+    pub fn physical_sector_size(&self) -> u32 {
+        let word = self.0[106];
+        if word & 0xC000 == 0x4000 && word & (1 << 13) != 0 {
+            // 2^N logical sectors per physical sector
+            self.logical_sector_size() << (word & 0xF)
+        } else {
+            self.logical_sector_size()
+        }
+    }
+
+    /// True if the device supports the TRIM function of DATA SET MANAGEMENT
+    pub fn supports_trim(&self) -> bool {
+        self.0[169] & 1 != 0
+    }
+
+    /// True if the device has a volatile write cache
+    pub fn supports_write_cache(&self) -> bool {
+        self.0[82] & (1 << 5) != 0
+    }
+
+    /// True if the volatile write cache is turned on
+    pub fn write_cache_enabled(&self) -> bool {
+        self.0[85] & (1 << 5) != 0
+    }
+
+    /// True if the device supports the SMART feature set
+    pub fn supports_smart(&self) -> bool {
+        self.0[82] & 1 != 0
+    }
+
+    /// True if SMART is turned on
+    pub fn smart_enabled(&self) -> bool {
+        self.0[85] & 1 != 0
+    }
+
+    /// Maximum NCQ queue depth, or `None` if the device doesn't support NCQ
+    pub fn ncq_depth(&self) -> Option<u32> {
+        if self.0[76] != 0xFFFF && self.0[76] & (1 << 8) != 0 {
+            Some((self.0[75] & 0x1F) as u32 + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Decode everything we care about into a [DiskInfo]
+    pub fn info(&self) -> DiskInfo {
+        DiskInfo {
+            model: self.model(),
+            serial: self.serial(),
+            firmware: self.firmware(),
+            sectors: self.sectors(),
+            logical_sector_size: self.logical_sector_size(),
+            physical_sector_size: self.physical_sector_size(),
+            lba48: self.supports_lba48(),
+            ncq_depth: self.ncq_depth(),
+            trim: self.supports_trim(),
+            write_cache: self.supports_write_cache(),
+            write_cache_enabled: self.write_cache_enabled(),
+            smart: self.supports_smart(),
+            smart_enabled: self.smart_enabled(),
+            read_only: false,
+        }
+    }
+}
+
+impl core::fmt::Debug for IdentifyData {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "IdentifyData {{ model: '{}', serial: '{}', firmware: '{}', sectors: {} }}",
+               self.model(), self.serial(), self.firmware(), self.sectors())
+    }
+}
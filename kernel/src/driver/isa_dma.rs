@@ -0,0 +1,78 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Programming the 8237 ISA DMA controller, used by the floppy driver.
+//!
+//! Only the 8-bit channels (0-3) are supported. The controller can only reach the first 16 MiB
+//! of physical memory and a transfer can't cross a 64 KiB boundary, so drivers use the buffer
+//! reserved in [ISA_DMA_REGION](crate::memory::ISA_DMA_REGION) instead of heap memory.
+
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Size of the buffer reserved for ISA DMA, also its alignment
+pub const ISA_DMA_BUFFER_SIZE: u64 = 0x10000;
+/// The controller only has 24 address bits
+pub const ISA_DMA_LIMIT: u64 = 0x100_0000;
+
+// 8-bit controller registers
+const REG_SINGLE_MASK: u16 = 0x0A;
+const REG_MODE: u16 = 0x0B;
+const REG_CLEAR_FLIP_FLOP: u16 = 0x0C;
+
+/// Address, count and page registers for channels 0-3
+const CHANNEL_PORTS: [(u16, u16, u16); 4] = [(0x00, 0x01, 0x87), (0x02, 0x03, 0x83), (0x04, 0x05, 0x81), (0x06, 0x07, 0x82)];
+
+const MASK_SET: u8 = 1 << 2;
+const MODE_SINGLE: u8 = 0x40;
+
+/// Direction of a transfer, from the point of view of memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// Device writes to memory
+    ToMemory = 0x04,
+    /// Device reads from memory
+    FromMemory = 0x08,
+}
+
+/// Set up `channel` for a single transfer of `len` bytes at physical address `phys`, and unmask it.
+/// The transfer starts when the device asks for it.
+pub fn setup(channel: u8, phys: u64, len: usize, direction: DmaDirection) -> Result<(), anyhow::Error> {
+    if channel > 3 {
+        return Err(anyhow::anyhow!("ISA DMA: channel {} isn't an 8-bit channel", channel));
+    }
+    if len == 0 || len as u64 > ISA_DMA_BUFFER_SIZE || phys + len as u64 > ISA_DMA_LIMIT
+        || phys / ISA_DMA_BUFFER_SIZE != (phys + len as u64 - 1) / ISA_DMA_BUFFER_SIZE {
+        return Err(anyhow::anyhow!("ISA DMA: can't transfer {} bytes at {:#X}", len, phys));
+    }
+
+    let (addr_port, count_port, page_port) = CHANNEL_PORTS[channel as usize];
+    // the count register holds the length minus one
+    let count = (len - 1) as u16;
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(REG_SINGLE_MASK).write(MASK_SET | channel);
+
+        Port::<u8>::new(REG_CLEAR_FLIP_FLOP).write(0xFF);
+        let mut addr = Port::<u8>::new(addr_port);
+        addr.write(phys as u8);
+        addr.write((phys >> 8) as u8);
+        Port::<u8>::new(page_port).write((phys >> 16) as u8);
+
+        Port::<u8>::new(REG_CLEAR_FLIP_FLOP).write(0xFF);
+        let mut count_reg = Port::<u8>::new(count_port);
+        count_reg.write(count as u8);
+        count_reg.write((count >> 8) as u8);
+
+        Port::<u8>::new(REG_MODE).write(MODE_SINGLE | direction as u8 | channel);
+        Port::<u8>::new(REG_SINGLE_MASK).write(channel);
+    });
+    Ok(())
+}
+
+/// Mask `channel` so it stops responding to the device
+pub fn mask(channel: u8) {
+    unsafe { Port::<u8>::new(REG_SINGLE_MASK).write(MASK_SET | (channel & 3)); }
+}
@@ -0,0 +1,109 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
+use crate::fs::Filesystem;
+use crate::fs::vfs::GLOBAL_VFS;
+use crate::path::Path;
+
+/// Block size of loop devices
+pub const SECTOR_SIZE: usize = 512;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A disk backed by a file on a mounted filesystem. Read only, since none of our filesystems can write files yet.
+#[derive(Debug)]
+pub struct LoopDevice {
+    id: usize,
+    filesystem: Arc<dyn Filesystem>,
+    path: Path,
+    /// Size of the file when it was opened, rounded down to whole sectors
+    size: u64,
+}
+
+impl LoopDevice {
+    /// Open the file at `path` on whichever filesystem is mounted there
+    pub async fn open(path: Path) -> Result<Self, anyhow::Error> {
+        // don't hold the VFS lock while reading from the filesystem
+        let filesystem = unsafe {
+            match GLOBAL_VFS.lock().as_ref() {
+                Some(vfs) => vfs.fs_for_path(&path)
+                    .map_err(|e| anyhow::anyhow!("loop: can't open {}: {:?}", path, e))?
+                    .clone(),
+                None => return Err(anyhow::anyhow!("loop: no filesystems are mounted")),
+            }
+        };
+        Self::with_filesystem(filesystem, path).await
+    }
+
+    /// Open the file at `path` on `filesystem`, which doesn't have to be mounted
+    pub async fn with_filesystem(filesystem: Arc<dyn Filesystem>, path: Path) -> Result<Self, anyhow::Error> {
+        let size = filesystem.file_size(&path).await
+            .map_err(|e| anyhow::anyhow!("loop: can't open {}: {:?}", path, e))?;
+        let size = size / SECTOR_SIZE as u64 * SECTOR_SIZE as u64;
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        crate::both_println!("   + Loop device {}: {} ({} KB)", id, path, size / 1024);
+        Ok(Self { id, filesystem, path, size })
+    }
+
+    /// Byte offset of a request, if it's inside the file
+    fn offset(&self, block: u64, len: usize) -> Result<u64, anyhow::Error> {
+        let offset = block.checked_mul(SECTOR_SIZE as u64);
+        match offset.and_then(|offset| offset.checked_add(len as u64)) {
+            Some(end) if end <= self.size => Ok(end - len as u64),
+            _ => Err(anyhow::anyhow!("loop device {}: access past the end", self.id)),
+        }
+    }
+}
+
+impl Disk for LoopDevice {
+    fn id(&self) -> usize { self.id }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::LoopDevice }
+    fn size(&self) -> Option<u64> { Some(self.size) }
+
+    fn info(&self) -> Option<DiskInfo> {
+        Some(DiskInfo {
+            model: alloc::format!("Loop device ({})", self.path),
+            serial: String::new(),
+            firmware: String::new(),
+            sectors: self.size / SECTOR_SIZE as u64,
+            logical_sector_size: SECTOR_SIZE as u32,
+            physical_sector_size: SECTOR_SIZE as u32,
+            lba48: false,
+            ncq_depth: None,
+            trim: false,
+            write_cache: false,
+            write_cache_enabled: false,
+            smart: false,
+            smart_enabled: false,
+            read_only: true,
+        })
+    }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            let offset = self.offset(block, buffer.len())?;
+            let read = self.filesystem.read_file(&self.path, offset, buffer).await
+                .map_err(|e| anyhow::anyhow!("loop device {}: read failed: {:?}", self.id, e))?;
+            // the file shrank after it was opened
+            buffer[read..].fill(0);
+            Ok(buffer.len())
+        })
+    }
+
+    fn write<'a>(&'a mut self, _block: u64, _buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        // SyncDisk turns writes away before they get here, see `info`
+        Box::pin(async move { Err(anyhow::anyhow!("loop device {}: read only", self.id)) })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        Box::pin(async { Ok(SECTOR_SIZE as u32) })
+    }
+}
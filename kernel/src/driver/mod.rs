@@ -12,6 +12,17 @@
 use alloc::vec::Vec;
 
 pub mod ahci;
+pub mod floppy;
+pub mod ide;
+pub mod isa_dma;
+pub mod loop_device;
+pub mod nvme;
+pub mod raid1;
+pub mod ramdisk;
+pub mod virtio;
+pub mod pci;
+pub mod identify;
+pub mod smart;
 
 #[derive(Clone)]
 enum Handle {
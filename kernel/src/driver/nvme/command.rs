@@ -0,0 +1,95 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::fmt::{Display, Formatter};
+
+// Admin command opcodes
+pub const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+pub const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+pub const ADMIN_IDENTIFY: u8 = 0x06;
+
+// I/O command opcodes
+pub const IO_FLUSH: u8 = 0x00;
+pub const IO_WRITE: u8 = 0x01;
+pub const IO_READ: u8 = 0x02;
+
+// IDENTIFY CNS values
+pub const IDENTIFY_NAMESPACE: u32 = 0x00;
+pub const IDENTIFY_CONTROLLER: u32 = 0x01;
+pub const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+/// Submission queue entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SubmissionEntry {
+    /// Opcode in bits 0-7, command ID in bits 16-31
+    pub cdw0: u32,
+    pub nsid: u32,
+    pub rsvd: u64,
+    pub metadata: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+impl SubmissionEntry {
+    pub fn new(opcode: u8) -> Self {
+        Self { cdw0: opcode as u32, ..Default::default() }
+    }
+
+    pub fn opcode(&self) -> u8 { self.cdw0 as u8 }
+
+    pub fn set_command_id(&mut self, id: u16) {
+        self.cdw0 = (self.cdw0 & 0xFFFF) | (id as u32) << 16;
+    }
+}
+
+/// Completion queue entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompletionEntry {
+    /// Command specific result
+    pub result: u32,
+    pub rsvd: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub command_id: u16,
+    /// Phase tag in bit 0, status field in bits 1-15
+    pub status: u16,
+}
+
+impl CompletionEntry {
+    pub fn phase(&self) -> bool { self.status & 1 != 0 }
+    /// Status code type and status code, zero on success
+    pub fn status_field(&self) -> u16 { self.status >> 1 }
+}
+
+/// A command that completed with a non-zero status
+#[derive(Debug, Clone, Copy)]
+pub struct NvmeCommandError {
+    pub queue: u16,
+    pub opcode: u8,
+    /// Status field from the completion entry, without the phase tag
+    pub status: u16,
+}
+
+impl NvmeCommandError {
+    /// Status code type (0 = generic, 1 = command specific, 2 = media/data integrity)
+    pub fn status_code_type(&self) -> u8 { ((self.status >> 8) & 0x7) as u8 }
+    pub fn status_code(&self) -> u8 { self.status as u8 }
+}
+
+impl Display for NvmeCommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "NVMe queue {} opcode {:#X} failed: status code type {} status code {:#X}",
+               self.queue, self.opcode, self.status_code_type(), self.status_code())
+    }
+}
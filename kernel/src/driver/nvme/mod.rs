@@ -0,0 +1,346 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use byteorder::{ByteOrder, LittleEndian};
+use conquer_once::spin::OnceCell;
+use x2apic::ioapic::IrqFlags;
+use x86_64::PhysAddr;
+use self::command::*;
+use self::namespace::NvmeNamespace;
+use self::queue::QueuePair;
+use crate::arch::interrupts;
+use crate::device::physical::SyncDisk;
+use crate::driver::pci::PciAddress;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::mmio::{ioremap, ioremap_region, CacheMode, IoMap};
+use crate::time::Instant;
+
+/// Submission and completion queue entries
+pub mod command;
+/// Submission/completion queue pairs and command completion
+pub mod queue;
+/// `Disk` implementation for NVMe namespaces
+pub mod namespace;
+
+/// PCI class, subclass and programming interface for an NVMe controller
+const PCI_CLASS_MASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_NVM: u8 = 0x08;
+const PCI_PROG_IF_NVME: u8 = 0x02;
+
+// Controller registers
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELL_BASE: u64 = 0x1000;
+
+const CC_ENABLE: u32 = 1 << 0;
+/// I/O submission queue entry size, 2^6 = 64 bytes
+const CC_IOSQES: u32 = 6 << 16;
+/// I/O completion queue entry size, 2^4 = 16 bytes
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+const ADMIN_QUEUE_SIZE: u16 = 64;
+const IO_QUEUE_SIZE: u16 = 64;
+/// Most controllers we'll drive at once
+const MAX_CONTROLLERS: usize = 4;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CONTROLLER_INIT: OnceCell<NvmeController> = OnceCell::uninit();
+static CONTROLLERS: [OnceCell<NvmeController>; MAX_CONTROLLERS] = [CONTROLLER_INIT; MAX_CONTROLLERS];
+
+/// A zeroed page for the controller to DMA to or from, for queues and PRP lists
+pub fn dma_page() -> DmaBuffer {
+    DmaBuffer::page().expect("NVMe: out of memory for DMA pages")
+}
+
+/// Identification data for a controller
+#[derive(Debug, Clone)]
+pub struct ControllerInfo {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    /// Number of namespaces
+    pub namespaces: u32,
+    /// Largest transfer the controller accepts, `None` if unlimited
+    pub max_transfer: Option<usize>,
+    /// Has a volatile write cache
+    pub write_cache: bool,
+}
+
+/// One NVMe controller
+#[derive(Debug)]
+pub struct NvmeController {
+    index: usize,
+    /// The controller registers and the doorbells of the admin and I/O queues, mapped uncached
+    regs: IoMap<[u8]>,
+    /// Doorbell stride in bytes
+    stride: u64,
+    admin: QueuePair,
+    io: OnceCell<QueuePair>,
+    info: OnceCell<ControllerInfo>,
+}
+
+impl NvmeController {
+    fn reg(&self, reg: usize) -> u64 {
+        self.regs.virt_addr().as_u64() + reg as u64
+    }
+
+    fn read_u32(&self, reg: usize) -> u32 {
+        unsafe { (self.reg(reg) as *const u32).read_volatile() }
+    }
+
+    fn write_u32(&self, reg: usize, value: u32) {
+        unsafe { (self.reg(reg) as *mut u32).write_volatile(value) }
+    }
+
+    fn read_u64(&self, reg: usize) -> u64 {
+        unsafe { (self.reg(reg) as *const u64).read_volatile() }
+    }
+
+    fn write_u64(&self, reg: usize, value: u64) {
+        unsafe { (self.reg(reg) as *mut u64).write_volatile(value) }
+    }
+
+    /// Reset the controller and bring it back up with a fresh admin queue
+    fn new(index: usize, pci_addr: PciAddress) -> Result<Self, anyhow::Error> {
+        pci_addr.enable_bus_master();
+        let bar = PhysAddr::new(pci_addr.bar_address(0));
+
+        let cap = unsafe { ioremap::<u64>(bar, CacheMode::Uncached) }
+            .map_err(|e| anyhow::anyhow!("NVMe {}: failed to map registers: {}", index, e))?;
+        let cap = unsafe { cap.as_ptr().read_volatile() };
+        let max_queue_entries = ((cap & 0xFFFF) + 1) as u16;
+        let stride = 4u64 << ((cap >> 32) & 0xF);
+        // CAP.TO is in 500ms units
+        let timeout = Duration::from_millis(((cap >> 24) & 0xFF).max(1) * 500);
+
+        // submission and completion doorbells for the admin queue and the one I/O queue
+        let regs = unsafe { ioremap_region(bar, DOORBELL_BASE + 4 * stride, CacheMode::Uncached) }
+            .map_err(|e| anyhow::anyhow!("NVMe {}: failed to map registers: {}", index, e))?;
+        let doorbells = regs.virt_addr().as_u64() + DOORBELL_BASE;
+
+        let admin_size = core::cmp::min(ADMIN_QUEUE_SIZE, max_queue_entries);
+        let controller = Self {
+            index,
+            regs,
+            stride,
+            admin: QueuePair::new(0, admin_size, admin_size, doorbells, stride, false),
+            io: OnceCell::uninit(),
+            info: OnceCell::uninit(),
+        };
+        let version = controller.read_u32(REG_VS);
+        crate::serial_println!("NVMe {}: version {}.{}, max queue entries {}", index,
+                               version >> 16, (version >> 8) & 0xFF, max_queue_entries);
+
+        // the admin queue can only be set up while the controller is disabled
+        controller.write_u32(REG_CC, controller.read_u32(REG_CC) & !CC_ENABLE);
+        controller.wait_status(false, timeout)?;
+
+        controller.write_u32(REG_AQA, ((admin_size as u32 - 1) << 16) | (admin_size as u32 - 1));
+        controller.write_u64(REG_ASQ, controller.admin.sq_phys());
+        controller.write_u64(REG_ACQ, controller.admin.cq_phys());
+        // NVM command set, 4K pages, round robin arbitration
+        controller.write_u32(REG_CC, CC_ENABLE | CC_IOSQES | CC_IOCQES);
+        controller.wait_status(true, timeout)?;
+        Ok(controller)
+    }
+
+    /// Spin until CSTS.RDY matches `ready`
+    fn wait_status(&self, ready: bool, timeout: Duration) -> Result<(), anyhow::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let csts = self.read_u32(REG_CSTS);
+            if csts & CSTS_FATAL != 0 {
+                return Err(anyhow::anyhow!("NVMe {}: controller fatal status", self.index));
+            }
+            if (csts & CSTS_READY != 0) == ready {
+                return Ok(());
+            }
+            if Instant::now().until(deadline) == Duration::ZERO {
+                return Err(anyhow::anyhow!("NVMe {}: timed out waiting for CSTS.RDY = {}", self.index, ready));
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn info(&self) -> Option<&ControllerInfo> {
+        self.info.get()
+    }
+
+    /// The I/O queue, once [scan_disks] has created it
+    pub fn io_queue(&self) -> Option<&QueuePair> {
+        self.io.get()
+    }
+
+    /// Run an IDENTIFY command, returning the 4K data structure
+    async fn identify(&self, cns: u32, nsid: u32) -> Result<DmaBuffer, anyhow::Error> {
+        let page = DmaBuffer::page().ok_or_else(|| anyhow::anyhow!("NVMe: out of memory for identify data"))?;
+        let mut cmd = SubmissionEntry::new(ADMIN_IDENTIFY);
+        cmd.nsid = nsid;
+        cmd.cdw10 = cns;
+        unsafe { self.admin.execute(cmd, page.as_mut_ptr(), 4096).await? };
+        Ok(page)
+    }
+
+    /// Read the controller identification and set up the I/O queue pair
+    async fn setup(&self) -> Result<(), anyhow::Error> {
+        let page = self.identify(IDENTIFY_CONTROLLER, 0).await?;
+        let data = unsafe { core::slice::from_raw_parts(page.as_mut_ptr(), 4096) };
+        let string = |range: core::ops::Range<usize>| String::from(core::str::from_utf8(&data[range]).unwrap_or("").trim());
+        // MDTS is a power of two in units of the minimum page size (4K)
+        let mdts = data[77];
+        let info = ControllerInfo {
+            serial: string(4..24),
+            model: string(24..64),
+            firmware: string(64..72),
+            namespaces: LittleEndian::read_u32(&data[516..520]),
+            max_transfer: if mdts == 0 { None } else { Some(4096usize << mdts) },
+            write_cache: data[525] & 1 != 0,
+        };
+        crate::both_println!("NVMe {}: {} (serial {}, firmware {}), {} namespace(s)",
+                             self.index, info.model, info.serial, info.firmware, info.namespaces);
+        let _ = self.info.try_init_once(|| info);
+
+        let max_queue_entries = ((self.read_u64(REG_CAP) & 0xFFFF) + 1) as u16;
+        let size = core::cmp::min(IO_QUEUE_SIZE, max_queue_entries);
+        let io = QueuePair::new(1, size, size, self.reg(DOORBELL_BASE as usize), self.stride, true);
+        // the I/O queue shares the admin queue's interrupt
+        if self.admin.irq_enabled() {
+            io.enable_irq();
+        }
+
+        let mut create_cq = SubmissionEntry::new(ADMIN_CREATE_IO_CQ);
+        create_cq.prp1 = io.cq_phys();
+        create_cq.cdw10 = ((io.cq_size() as u32 - 1) << 16) | io.id() as u32;
+        // interrupt vector 0, interrupts enabled, physically contiguous
+        create_cq.cdw11 = 0b11;
+        unsafe { self.admin.execute(create_cq, core::ptr::null(), 0).await? };
+
+        let mut create_sq = SubmissionEntry::new(ADMIN_CREATE_IO_SQ);
+        create_sq.prp1 = io.sq_phys();
+        create_sq.cdw10 = ((io.sq_size() as u32 - 1) << 16) | io.id() as u32;
+        // completions go to the CQ with the same ID, physically contiguous
+        create_sq.cdw11 = (io.id() as u32) << 16 | 1;
+        unsafe { self.admin.execute(create_sq, core::ptr::null(), 0).await? };
+
+        let _ = self.io.try_init_once(|| io);
+        Ok(())
+    }
+
+    /// IDs of the active namespaces
+    async fn active_namespaces(&self) -> Result<Vec<u32>, anyhow::Error> {
+        let page = self.identify(IDENTIFY_ACTIVE_NAMESPACES, 0).await?;
+        let data = unsafe { core::slice::from_raw_parts(page.as_mut_ptr(), 4096) };
+        Ok(data.chunks_exact(4).map(LittleEndian::read_u32).take_while(|&nsid| nsid != 0).collect())
+    }
+
+    /// Service every queue on the controller
+    fn service(&self) {
+        self.admin.service();
+        if let Some(io) = self.io.get() {
+            io.service();
+        }
+    }
+}
+
+/// Find and reset every NVMe controller. Namespaces are probed later by [scan_disks].
+pub fn init() {
+    let controllers: Vec<PciAddress> = PciAddress::find_all(PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_NVM)
+        .into_iter()
+        .filter(|addr| addr.class_code().2 == PCI_PROG_IF_NVME)
+        .collect();
+    if controllers.is_empty() {
+        crate::both_println!("No NVMe controller found.");
+        return;
+    }
+
+    for (index, pci_addr) in controllers.into_iter().take(MAX_CONTROLLERS).enumerate() {
+        crate::both_println!("Initializing NVMe controller {}...", index);
+        match NvmeController::new(index, pci_addr) {
+            Ok(controller) => {
+                if init_interrupts(pci_addr) {
+                    controller.admin.enable_irq();
+                }
+                let _ = CONTROLLERS[index].try_init_once(|| controller);
+            },
+            Err(err) => crate::both_println!("{}", err),
+        }
+    }
+}
+
+/// Route a controller's interrupt to [handle_interrupt], preferring MSI over the legacy interrupt line.
+/// All queues use interrupt vector 0. Returns false if the controller has to be polled instead.
+fn init_interrupts(pci_addr: PciAddress) -> bool {
+    let vector = match interrupts::allocate_vector() {
+        Some(v) => v,
+        None => {
+            crate::both_println!("NVMe: no free interrupt vectors, falling back to polling");
+            return false;
+        }
+    };
+    interrupts::register_irq_handler(vector, handle_interrupt);
+
+    if pci_addr.enable_msi(vector) {
+        crate::serial_println!("NVMe: using MSI on vector {}", vector);
+    } else {
+        let line = pci_addr.interrupt_line();
+        let flags = IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE;
+        if line == 0xFF || !interrupts::enable_ioapic_irq(line, vector, flags) {
+            crate::both_println!("NVMe: no usable interrupt line, falling back to polling");
+            return false;
+        }
+        crate::serial_println!("NVMe: using IRQ {} on vector {}", line, vector);
+    }
+    true
+}
+
+/// NVMe interrupt handler. Controllers can share vectors, so just check every queue.
+fn handle_interrupt() {
+    for controller in CONTROLLERS.iter().filter_map(|c| c.get()) {
+        controller.service();
+    }
+}
+
+/// Create the I/O queues on every controller and open each active namespace
+pub async fn scan_disks() -> Vec<SyncDisk> {
+    let mut disks = Vec::new();
+    for controller in CONTROLLERS.iter().filter_map(|c| c.get()) {
+        if let Err(err) = controller.setup().await {
+            crate::both_println!("{}", err);
+            continue;
+        }
+        let namespaces = match controller.active_namespaces().await {
+            Ok(namespaces) => namespaces,
+            Err(err) => {
+                crate::both_println!("{}", err);
+                continue;
+            }
+        };
+        for nsid in namespaces {
+            match controller.identify(IDENTIFY_NAMESPACE, nsid).await {
+                Ok(page) => {
+                    let data = unsafe { core::slice::from_raw_parts(page.as_mut_ptr(), 4096) };
+                    match NvmeNamespace::new(controller, nsid, data) {
+                        Some(ns) => disks.push(SyncDisk::new(Box::new(ns))),
+                        None => crate::serial_println!("NVMe {}: namespace {} isn't usable", controller.index, nsid),
+                    }
+                },
+                Err(err) => crate::both_println!("{}", err),
+            }
+        }
+    }
+    disks
+}
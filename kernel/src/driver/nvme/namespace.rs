@@ -0,0 +1,185 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use byteorder::{ByteOrder, LittleEndian};
+use super::command::{SubmissionEntry, IO_FLUSH, IO_READ, IO_WRITE};
+use super::queue::QUEUE_SLOTS;
+use super::NvmeController;
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
+
+/// Largest transfer we do with a single command, even if the controller allows more
+const MAX_COMMAND_BYTES: usize = 128 * 1024;
+
+/// A namespace on an NVMe controller
+#[derive(Debug)]
+pub struct NvmeNamespace {
+    id: usize,
+    controller: &'static NvmeController,
+    nsid: u32,
+    block_size: u32,
+    blocks: u64,
+    info: DiskInfo,
+}
+
+impl NvmeNamespace {
+    /// Open a namespace from its IDENTIFY NAMESPACE data. Returns `None` if the namespace
+    /// is empty or uses a format we can't handle.
+    pub fn new(controller: &'static NvmeController, nsid: u32, identify: &[u8]) -> Option<Self> {
+        let blocks = LittleEndian::read_u64(&identify[0..8]);
+        let format = (identify[26] & 0xF) as usize;
+        let lba_format = LittleEndian::read_u32(&identify[128 + format * 4..132 + format * 4]);
+        let metadata_size = lba_format & 0xFFFF;
+        let block_size = 1u32 << ((lba_format >> 16) & 0xFF);
+        if blocks == 0 || metadata_size != 0 || block_size < 512 {
+            return None;
+        }
+
+        let controller_info = controller.info()?;
+        let info = DiskInfo {
+            model: controller_info.model.clone(),
+            serial: controller_info.serial.clone(),
+            firmware: controller_info.firmware.clone(),
+            sectors: blocks,
+            logical_sector_size: block_size,
+            physical_sector_size: block_size,
+            lba48: false,
+            ncq_depth: None,
+            trim: false,
+            write_cache: controller_info.write_cache,
+            write_cache_enabled: controller_info.write_cache,
+            smart: false,
+            smart_enabled: false,
+            read_only: false,
+        };
+        crate::both_println!("   + NVMe {} namespace {}: {} MB, {} byte blocks", controller.index, nsid,
+                             blocks * block_size as u64 / (1024 * 1024), block_size);
+        Some(Self {
+            // namespace IDs start at 1, so this never collides across controllers
+            id: controller.index << 16 | nsid as usize,
+            controller,
+            nsid,
+            block_size,
+            blocks,
+            info,
+        })
+    }
+
+    /// Transfer `len` bytes between `buffer` and the namespace, starting at `block`.
+    /// Commands are pipelined up to the queue depth, like the AHCI driver does with NCQ.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be dword aligned and valid for `len` bytes until the returned future completes.
+    async unsafe fn request(&mut self, block: u64, buffer: *const u8, len: usize, write: bool) -> Result<usize, anyhow::Error> {
+        let queue = self.controller.io_queue()
+            .ok_or_else(|| anyhow::anyhow!("NVMe {}: no I/O queue", self.controller.index))?;
+        let block_size = self.block_size as usize;
+        let total_blocks = len / block_size;
+        let max_bytes = [MAX_COMMAND_BYTES, queue.max_transfer(), self.controller.info().and_then(|i| i.max_transfer).unwrap_or(usize::MAX)]
+            .iter().copied().min().unwrap();
+        let blocks_per_command = max_bytes / block_size;
+        if block + total_blocks as u64 > self.blocks {
+            return Err(anyhow::anyhow!("NVMe namespace {}: access past the end", self.nsid));
+        }
+
+        let mut in_flight = VecDeque::new();
+        let mut result = Ok(());
+        let mut done = 0;
+        loop {
+            if result.is_ok() && done < total_blocks && in_flight.len() < QUEUE_SLOTS {
+                let count = core::cmp::min(blocks_per_command, total_blocks - done);
+                let lba = block + done as u64;
+                let mut cmd = SubmissionEntry::new(if write { IO_WRITE } else { IO_READ });
+                cmd.nsid = self.nsid;
+                cmd.cdw10 = lba as u32;
+                cmd.cdw11 = (lba >> 32) as u32;
+                // number of blocks, zero based
+                cmd.cdw12 = count as u32 - 1;
+                let chunk = unsafe { buffer.add(done * block_size) };
+                match unsafe { queue.submit(cmd, chunk, count * block_size) } {
+                    Some(id) => {
+                        in_flight.push_back(id);
+                        done += count;
+                        continue;
+                    },
+                    None if in_flight.is_empty() => {
+                        result = Err(anyhow::anyhow!("NVMe namespace {}: couldn't submit command", self.nsid));
+                    },
+                    // wait for a slot to free up
+                    None => {}
+                }
+            }
+
+            match in_flight.pop_front() {
+                Some(id) => {
+                    if let Err(err) = queue.wait(id).await {
+                        crate::serial_println!("{}", err);
+                        if result.is_ok() {
+                            result = Err(anyhow::anyhow!("{}", err));
+                        }
+                    }
+                },
+                None => break,
+            }
+        }
+        result.map(|_| done * block_size)
+    }
+}
+
+impl Disk for NvmeNamespace {
+    fn id(&self) -> usize { self.id }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::NVMeDrive }
+    fn size(&self) -> Option<u64> { Some(self.blocks * self.block_size as u64) }
+    fn queue_depth(&self) -> u32 { QUEUE_SLOTS as u32 }
+    fn info(&self) -> Option<DiskInfo> { Some(self.info.clone()) }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            if buffer.as_ptr() as usize & 3 == 0 {
+                unsafe { self.request(block, buffer.as_mut_ptr(), buffer.len(), false).await }
+            } else {
+                // PRP entries have to be dword aligned, bounce other buffers through an aligned one
+                let mut bounce = vec![0u32; (buffer.len() + 3) / 4];
+                let read = unsafe { self.request(block, bounce.as_mut_ptr() as *const u8, buffer.len(), false).await? };
+                unsafe { core::ptr::copy_nonoverlapping(bounce.as_ptr() as *const u8, buffer.as_mut_ptr(), read); }
+                Ok(read)
+            }
+        })
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            if buffer.as_ptr() as usize & 3 == 0 {
+                unsafe { self.request(block, buffer.as_ptr(), buffer.len(), true).await }
+            } else {
+                let mut bounce = vec![0u32; (buffer.len() + 3) / 4];
+                unsafe { core::ptr::copy_nonoverlapping(buffer.as_ptr(), bounce.as_mut_ptr() as *mut u8, buffer.len()); }
+                unsafe { self.request(block, bounce.as_ptr() as *const u8, buffer.len(), true).await }
+            }
+        })
+    }
+
+    fn flush(&mut self) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            if !self.info.write_cache_enabled {
+                return Ok(());
+            }
+            let queue = self.controller.io_queue()
+                .ok_or_else(|| anyhow::anyhow!("NVMe {}: no I/O queue", self.controller.index))?;
+            let mut cmd = SubmissionEntry::new(IO_FLUSH);
+            cmd.nsid = self.nsid;
+            unsafe { queue.execute(cmd, core::ptr::null(), 0).await }
+        })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        let block_size = self.block_size;
+        Box::pin(async move { Ok(block_size) })
+    }
+}
@@ -0,0 +1,279 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use alloc::vec::Vec;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+use super::command::{CompletionEntry, NvmeCommandError, SubmissionEntry};
+use super::dma_page;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::translate_addr;
+
+/// Most commands in flight on one queue at once. Command IDs are slot numbers.
+pub const QUEUE_SLOTS: usize = 32;
+
+/// Mutable queue state, shared between submitters and the IRQ handler
+#[derive(Debug)]
+struct QueueState {
+    sq_tail: u16,
+    cq_head: u16,
+    /// Expected phase tag of the next new completion entry
+    phase: bool,
+    /// Slots that are in use by a caller
+    allocated: u32,
+    /// Slots whose command has completed but whose result hasn't been taken
+    done: u32,
+    /// Completion status of each slot
+    status: [u16; QUEUE_SLOTS],
+    /// Opcode of the command in each slot, for error messages
+    opcodes: [u8; QUEUE_SLOTS],
+}
+
+/// A submission queue and the completion queue it posts to
+#[derive(Debug)]
+pub struct QueuePair {
+    id: u16,
+    sq: DmaBuffer,
+    sq_size: u16,
+    cq: DmaBuffer,
+    cq_size: u16,
+    sq_doorbell: *mut u32,
+    cq_doorbell: *mut u32,
+    /// One page per slot for PRP lists, empty if the queue only handles single page transfers
+    prp_lists: Vec<DmaBuffer>,
+    state: spin::Mutex<QueueState>,
+    wakers: [AtomicWaker; QUEUE_SLOTS],
+    /// Set once the controller interrupt is routed somewhere. Until then, waiting futures poll the completion queue.
+    irq_enabled: AtomicBool,
+}
+
+// the raw pointers are MMIO doorbells, all access goes through `state`
+unsafe impl Send for QueuePair {}
+unsafe impl Sync for QueuePair {}
+
+impl QueuePair {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const WAKER_INIT: AtomicWaker = AtomicWaker::new();
+
+    /// Allocate memory for a queue pair. `doorbell_base` is the virtual address of the controller's
+    /// doorbell registers and `stride` the doorbell stride in bytes.
+    pub fn new(id: u16, sq_size: u16, cq_size: u16, doorbell_base: u64, stride: u64, prp_lists: bool) -> Self {
+        // each queue has to fit in a single page, which keeps it physically contiguous
+        assert!(sq_size as usize * core::mem::size_of::<SubmissionEntry>() <= 4096);
+        assert!(cq_size as usize * core::mem::size_of::<CompletionEntry>() <= 4096);
+        assert!(QUEUE_SLOTS < sq_size as usize && QUEUE_SLOTS < cq_size as usize);
+        Self {
+            id,
+            sq: dma_page(),
+            sq_size,
+            cq: dma_page(),
+            cq_size,
+            sq_doorbell: (doorbell_base + (2 * id as u64) * stride) as *mut u32,
+            cq_doorbell: (doorbell_base + (2 * id as u64 + 1) * stride) as *mut u32,
+            prp_lists: if prp_lists { (0..QUEUE_SLOTS).map(|_| dma_page()).collect() } else { Vec::new() },
+            state: spin::Mutex::new(QueueState {
+                sq_tail: 0,
+                cq_head: 0,
+                phase: true,
+                allocated: 0,
+                done: 0,
+                status: [0; QUEUE_SLOTS],
+                opcodes: [0; QUEUE_SLOTS],
+            }),
+            wakers: [Self::WAKER_INIT; QUEUE_SLOTS],
+            irq_enabled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn id(&self) -> u16 { self.id }
+    pub fn sq_size(&self) -> u16 { self.sq_size }
+    pub fn cq_size(&self) -> u16 { self.cq_size }
+    pub fn sq_phys(&self) -> u64 { self.sq.phys().as_u64() }
+    pub fn cq_phys(&self) -> u64 { self.cq.phys().as_u64() }
+    pub fn irq_enabled(&self) -> bool { self.irq_enabled.load(Ordering::SeqCst) }
+
+    /// Leave the completion queue to the IRQ handler instead of polling it from waiting futures
+    pub fn enable_irq(&self) { self.irq_enabled.store(true, Ordering::SeqCst); }
+
+    /// Largest transfer a single command on this queue can describe
+    pub fn max_transfer(&self) -> usize {
+        if self.prp_lists.is_empty() { 4096 } else { 512 * 4096 }
+    }
+
+    /// Fill in the PRP entries for a transfer of `len` bytes at `buf`, using `list` for the PRP list if it's needed.
+    /// Returns false if the buffer can't be described.
+    fn fill_prps(entry: &mut SubmissionEntry, buf: *const u8, len: usize, list: Option<&DmaBuffer>) -> bool {
+        if len == 0 {
+            return true;
+        }
+        if buf as usize & 3 != 0 {
+            return false;
+        }
+        let phys = |addr: u64| translate_addr(VirtAddr::new(addr)).map(|p| p.as_u64());
+        let start = buf as u64;
+        entry.prp1 = match phys(start) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        // every page after the first one needs its own entry
+        let first_page_end = (start & !0xFFF) + 4096;
+        let end = start + len as u64;
+        let pages: Vec<u64> = (0..).map(|i| first_page_end + i * 4096).take_while(|&page| page < end).collect();
+        match pages.len() {
+            0 => {},
+            1 => match phys(pages[0]) {
+                Some(p) => entry.prp2 = p,
+                None => return false,
+            },
+            n => {
+                let list = match list {
+                    Some(list) if n <= 512 => list,
+                    _ => return false,
+                };
+                let entries = list.as_mut_ptr() as *mut u64;
+                for (i, page) in pages.iter().enumerate() {
+                    match phys(*page) {
+                        Some(p) => unsafe { entries.add(i).write_volatile(p) },
+                        None => return false,
+                    }
+                }
+                entry.prp2 = list.phys().as_u64();
+            }
+        }
+        true
+    }
+
+    /// Put a command on the submission queue and ring the doorbell. The command ID is filled in.
+    /// Returns the command ID, or `None` if every slot is busy or the buffer can't be used for DMA.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for `len` bytes until the command completes.
+    pub unsafe fn submit(&self, mut entry: SubmissionEntry, buf: *const u8, len: usize) -> Option<u16> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let slot = (0..QUEUE_SLOTS).find(|i| state.allocated & (1 << i) == 0)?;
+            if !Self::fill_prps(&mut entry, buf, len, self.prp_lists.get(slot)) {
+                return None;
+            }
+            entry.set_command_id(slot as u16);
+            state.allocated |= 1 << slot;
+            state.done &= !(1 << slot);
+            state.opcodes[slot] = entry.opcode();
+
+            let tail = state.sq_tail;
+            unsafe {
+                (self.sq.as_mut_ptr() as *mut SubmissionEntry).add(tail as usize).write_volatile(entry);
+            }
+            state.sq_tail = (tail + 1) % self.sq_size;
+            unsafe { self.sq_doorbell.write_volatile(state.sq_tail as u32); }
+            Some(slot as u16)
+        })
+    }
+
+    /// Returns a future that resolves once the command with the given ID completes
+    pub fn wait(&self, command_id: u16) -> CommandFuture<'_> {
+        CommandFuture { queue: self, slot: command_id as usize }
+    }
+
+    /// Submit a command and wait for it to complete
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for `len` bytes until the returned future completes.
+    pub async unsafe fn execute(&self, entry: SubmissionEntry, buf: *const u8, len: usize) -> Result<(), anyhow::Error> {
+        let id = unsafe { self.submit(entry, buf, len) }
+            .ok_or_else(|| anyhow::anyhow!("NVMe queue {}: couldn't submit command {:#X}", self.id, entry.opcode()))?;
+        self.wait(id).await.map_err(|err| anyhow::anyhow!("{}", err))
+    }
+
+    /// Consume new entries from the completion queue and wake whoever is waiting on them.
+    /// Called from the IRQ handler, or by waiting futures when interrupts aren't available.
+    pub fn service(&self) {
+        let completed = without_interrupts(|| {
+            let mut state = self.state.lock();
+            let mut completed = 0u32;
+            loop {
+                let entry = unsafe {
+                    (self.cq.as_mut_ptr() as *const CompletionEntry).add(state.cq_head as usize).read_volatile()
+                };
+                if entry.phase() != state.phase {
+                    break;
+                }
+                let slot = entry.command_id as usize;
+                if slot < QUEUE_SLOTS {
+                    state.status[slot] = entry.status_field();
+                    completed |= 1 << slot;
+                }
+                state.cq_head += 1;
+                if state.cq_head == self.cq_size {
+                    state.cq_head = 0;
+                    state.phase = !state.phase;
+                }
+            }
+            if completed != 0 {
+                state.done |= completed;
+                unsafe { self.cq_doorbell.write_volatile(state.cq_head as u32); }
+            }
+            completed
+        });
+        for slot in 0..QUEUE_SLOTS {
+            if completed & (1 << slot) != 0 {
+                self.wakers[slot].wake();
+            }
+        }
+    }
+
+    /// Take the result for a completed slot and free it. Returns `None` if it hasn't completed.
+    fn take_result(&self, slot: usize) -> Option<Result<(), NvmeCommandError>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.done & (1 << slot) == 0 {
+                return None;
+            }
+            state.done &= !(1 << slot);
+            state.allocated &= !(1 << slot);
+            Some(match state.status[slot] {
+                0 => Ok(()),
+                status => Err(NvmeCommandError { queue: self.id, opcode: state.opcodes[slot], status }),
+            })
+        })
+    }
+}
+
+/// Future returned by [QueuePair::wait]
+#[derive(Debug)]
+pub struct CommandFuture<'a> {
+    queue: &'a QueuePair,
+    slot: usize,
+}
+
+impl Future for CommandFuture<'_> {
+    type Output = Result<(), NvmeCommandError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(result) = self.queue.take_result(self.slot) {
+            return Poll::Ready(result);
+        }
+        self.queue.wakers[self.slot].register(cx.waker());
+
+        if !self.queue.irq_enabled() {
+            // nobody else is going to look at the completion queue
+            self.queue.service();
+            cx.waker().wake_by_ref();
+        }
+        match self.queue.take_result(self.slot) {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
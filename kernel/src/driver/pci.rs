@@ -0,0 +1,221 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Helpers for poking at PCI configuration space that tinypci doesn't expose.
+
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+use crate::PCI_DEVICES;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// Config space offsets
+const REG_COMMAND: u8 = 0x04;
+const REG_STATUS: u8 = 0x06;
+const REG_CLASS: u8 = 0x08;
+const REG_CAPABILITIES: u8 = 0x34;
+const REG_INTERRUPT_LINE: u8 = 0x3C;
+
+const STATUS_HAS_CAPABILITIES: u16 = 1 << 4;
+
+/// Capability IDs found in the capability list
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_MSIX: u8 = 0x11;
+pub const CAP_VENDOR: u8 = 0x09;
+
+bitflags::bitflags! {
+    /// Bits in the PCI command register
+    pub struct PciCommand: u16 {
+        const IoSpace          = 1 << 0;
+        const MemorySpace      = 1 << 1;
+        const BusMaster        = 1 << 2;
+        const InterruptDisable = 1 << 10;
+    }
+}
+
+/// Location of a single function on the PCI bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self { bus, device, function }
+    }
+
+    /// Finds the first function with the given class and subclass on any device found during the PCI scan
+    pub fn find(class: u8, subclass: u8) -> Option<Self> {
+        Self::find_all(class, subclass).into_iter().next()
+    }
+
+    /// Finds every function with the given class and subclass on any device found during the PCI scan
+    pub fn find_all(class: u8, subclass: u8) -> Vec<Self> {
+        let devices: Vec<(u8, u8)> = PCI_DEVICES.lock().iter().map(|d| (d.bus, d.device)).collect();
+        let mut result = Vec::new();
+        for (bus, device) in devices {
+            for function in 0..8 {
+                let addr = Self::new(bus, device, function);
+                if addr.vendor_id() == 0xFFFF {
+                    continue;
+                }
+                let (c, s, _) = addr.class_code();
+                if c == class && s == subclass {
+                    result.push(addr);
+                }
+            }
+        }
+        result
+    }
+
+    /// Finds every function made by the given vendor on any device found during the PCI scan
+    pub fn find_by_vendor(vendor: u16) -> Vec<Self> {
+        let devices: Vec<(u8, u8)> = PCI_DEVICES.lock().iter().map(|d| (d.bus, d.device)).collect();
+        let mut result = Vec::new();
+        for (bus, device) in devices {
+            for function in 0..8 {
+                let addr = Self::new(bus, device, function);
+                if addr.vendor_id() == vendor {
+                    result.push(addr);
+                }
+            }
+        }
+        result
+    }
+
+    fn config_address(&self, offset: u8) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset as u32 & 0xFC)
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset);
+        self.write_u32(offset, (old & !(0xFFFF << shift)) | (value as u32) << shift);
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn vendor_id(&self) -> u16 { self.read_u16(0x00) }
+    pub fn device_id(&self) -> u16 { self.read_u16(0x02) }
+
+    /// Returns (class, subclass, programming interface)
+    pub fn class_code(&self) -> (u8, u8, u8) {
+        let reg = self.read_u32(REG_CLASS);
+        ((reg >> 24) as u8, (reg >> 16) as u8, (reg >> 8) as u8)
+    }
+
+    /// Reads the given base address register, masking off the flag bits
+    pub fn bar(&self, index: u8) -> u32 {
+        let raw = self.read_u32(0x10 + index * 4);
+        if raw & 1 == 1 { raw & !0x3 } else { raw & !0xF }
+    }
+
+    /// Reads a memory BAR as a full physical address, including the upper half of 64-bit BARs
+    pub fn bar_address(&self, index: u8) -> u64 {
+        let raw = self.read_u32(0x10 + index * 4);
+        let low = self.bar(index) as u64;
+        // memory BAR with type 0b10 is 64 bits wide and takes up the next BAR too
+        if raw & 1 == 0 && (raw >> 1) & 0x3 == 0x2 {
+            low | (self.read_u32(0x10 + (index + 1) * 4) as u64) << 32
+        } else {
+            low
+        }
+    }
+
+    /// Legacy (PIC) interrupt line assigned by the firmware
+    pub fn interrupt_line(&self) -> u8 { self.read_u8(REG_INTERRUPT_LINE) }
+
+    pub fn command(&self) -> PciCommand {
+        PciCommand::from_bits_truncate(self.read_u16(REG_COMMAND))
+    }
+
+    pub fn set_command(&self, command: PciCommand) {
+        // write zeros to the upper half, the status bits are write-1-to-clear
+        self.write_u32(REG_COMMAND, command.bits() as u32);
+    }
+
+    /// Enable memory-mapped registers and DMA for this function
+    pub fn enable_bus_master(&self) {
+        self.set_command(self.command() | PciCommand::MemorySpace | PciCommand::BusMaster);
+    }
+
+    /// Returns the config space offset of the first capability with the given ID
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.find_capabilities(id).first().copied()
+    }
+
+    /// Returns the config space offsets of every capability with the given ID, in list order
+    pub fn find_capabilities(&self, id: u8) -> Vec<u8> {
+        let mut result = Vec::new();
+        if self.read_u16(REG_STATUS) & STATUS_HAS_CAPABILITIES == 0 {
+            return result;
+        }
+        let mut ptr = self.read_u8(REG_CAPABILITIES) & 0xFC;
+        // the list is at most 48 entries long, guard against broken loops
+        for _ in 0..48 {
+            if ptr == 0 {
+                break;
+            }
+            if self.read_u8(ptr) == id {
+                result.push(ptr);
+            }
+            ptr = self.read_u8(ptr + 1) & 0xFC;
+        }
+        result
+    }
+
+    /// Route this function's interrupts to `vector` on the boot CPU using MSI.
+    /// Returns false if the function doesn't support MSI.
+    pub fn enable_msi(&self, vector: u8) -> bool {
+        let cap = match self.find_capability(CAP_MSI) {
+            Some(c) => c,
+            None => return false,
+        };
+        let control = self.read_u16(cap + 2);
+        let is_64bit = control & (1 << 7) != 0;
+
+        // fixed delivery, edge triggered, destination APIC 0
+        self.write_u32(cap + 4, 0xFEE0_0000);
+        if is_64bit {
+            self.write_u32(cap + 8, 0);
+            self.write_u16(cap + 12, vector as u16);
+        } else {
+            self.write_u16(cap + 8, vector as u16);
+        }
+        // only one message, enable
+        self.write_u16(cap + 2, (control & !(0b111 << 4)) | 1);
+        // MSI replaces INTx
+        self.set_command(self.command() | PciCommand::InterruptDisable);
+        true
+    }
+}
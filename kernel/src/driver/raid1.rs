@@ -0,0 +1,953 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Software RAID-1: a virtual disk that mirrors every write to two or more member disks.
+//!
+//! Each member starts with a superblock sector describing the array, followed by the write-intent
+//! bitmap and then the mirrored data. Before a write goes out, the bits for the chunks it touches
+//! are set in the bitmap on every member, and they're cleared again once the members have been
+//! flushed. After an unclean shutdown only the chunks still marked in the bitmap need to be copied
+//! from one member to the others.
+//!
+//! A member that fails a request is dropped from the array and the rest carry on. A member that
+//! missed writes (it failed, or was missing when the array was assembled) is copied in full by
+//! [raid_task] before it's used for reads again.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use byteorder::{ByteOrder, LittleEndian};
+use futures_util::future::join_all;
+use spin::Mutex;
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType, SyncDisk};
+use crate::sync::AsyncMutex;
+use crate::task::sleep::sleep;
+use crate::time::Instant;
+use crate::util::UUID;
+
+const MAGIC: &[u8; 8] = b"UOSRAID1";
+const VERSION: u32 = 1;
+/// Members have to use 512 byte sectors, and so does the array
+pub const SECTOR_SIZE: usize = 512;
+/// Sectors covered by one bit of the write-intent bitmap (1 MiB)
+const CHUNK_SECTORS: u64 = 2048;
+/// Data starts on a 4 KiB boundary
+const DATA_ALIGN: u64 = 8;
+/// Bitmap bits are cleared once the array hasn't been written for this long
+const BITMAP_CLEAR_DELAY: Duration = Duration::from_secs(5);
+/// Sectors copied at once while resyncing
+const RESYNC_SECTORS: u64 = 128;
+/// Most members an array can have, limited by the out-of-sync mask in the superblock
+pub const MAX_MEMBERS: usize = 32;
+
+static ARRAYS: Mutex<Vec<Arc<Array>>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Array metadata at the start of every member
+#[derive(Debug, Clone)]
+struct Superblock {
+    uuid: UUID,
+    /// Position of this member in the array
+    slot: u32,
+    /// Number of slots in the array
+    slots: u32,
+    /// Bumped every time a member leaves or rejoins, so stale members can be told apart
+    events: u64,
+    data_offset: u64,
+    /// Size of the array in sectors
+    sectors: u64,
+    bitmap_sectors: u64,
+    /// Slots that were failed or still resyncing when this superblock was written
+    out_of_sync: u32,
+}
+impl Superblock {
+    /// Read the superblock of a member with `disk_sectors` sectors. Anything that doesn't describe
+    /// an array that could have been created on that disk is rejected.
+    fn parse(sector: &[u8], disk_sectors: u64) -> Option<Self> {
+        if &sector[0..8] != MAGIC || LittleEndian::read_u32(&sector[8..12]) != VERSION
+            || LittleEndian::read_u64(&sector[20..28]) != CHUNK_SECTORS {
+            return None;
+        }
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&sector[28..44]);
+        let sb = Self {
+            uuid: UUID(uuid),
+            slot: LittleEndian::read_u32(&sector[12..16]),
+            slots: LittleEndian::read_u32(&sector[16..20]),
+            events: LittleEndian::read_u64(&sector[44..52]),
+            data_offset: LittleEndian::read_u64(&sector[52..60]),
+            sectors: LittleEndian::read_u64(&sector[60..68]),
+            bitmap_sectors: LittleEndian::read_u64(&sector[68..76]),
+            out_of_sync: LittleEndian::read_u32(&sector[76..80]),
+        };
+        if sb.slots < 2 || sb.slots as usize > MAX_MEMBERS || sb.slot >= sb.slots || sb.sectors == 0 {
+            return None;
+        }
+        // the array was created on members of exactly data_offset + sectors sectors
+        let member_sectors = sb.data_offset.checked_add(sb.sectors).filter(|&end| end <= disk_sectors)?;
+        if layout(member_sectors) != (sb.bitmap_sectors, sb.data_offset) {
+            return None;
+        }
+        Some(sb)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        sector[0..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut sector[8..12], VERSION);
+        LittleEndian::write_u32(&mut sector[12..16], self.slot);
+        LittleEndian::write_u32(&mut sector[16..20], self.slots);
+        LittleEndian::write_u64(&mut sector[20..28], CHUNK_SECTORS);
+        sector[28..44].copy_from_slice(&self.uuid.0);
+        LittleEndian::write_u64(&mut sector[44..52], self.events);
+        LittleEndian::write_u64(&mut sector[52..60], self.data_offset);
+        LittleEndian::write_u64(&mut sector[60..68], self.sectors);
+        LittleEndian::write_u64(&mut sector[68..76], self.bitmap_sectors);
+        LittleEndian::write_u32(&mut sector[76..80], self.out_of_sync);
+        sector
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberState {
+    InSync,
+    /// Being copied from an in-sync member. Gets writes, but only reads below the copy position.
+    Resyncing,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Member {
+    slot: u32,
+    disk: SyncDisk,
+    state: MemberState,
+    /// Sectors from the start of the data that are known to match the in-sync members
+    synced_to: u64,
+    /// Sector after the last read, for picking the closest member
+    head: u64,
+}
+
+#[derive(Debug)]
+struct ArrayState {
+    members: Vec<Member>,
+    events: u64,
+    /// Chunks that have been written since the members were last flushed
+    dirty: Vec<u64>,
+    /// Chunks that might differ between members after an unclean shutdown
+    unsynced: Vec<u64>,
+    last_write: Instant,
+}
+impl ArrayState {
+    /// The member resyncs copy from and reads of unsynced chunks go to
+    fn primary(&self) -> Option<usize> {
+        self.members.iter().position(|m| m.state == MemberState::InSync)
+    }
+
+    fn working(&self) -> impl Iterator<Item = (usize, &Member)> + '_ {
+        self.members.iter().enumerate().filter(|(_, m)| m.state != MemberState::Failed)
+    }
+
+    fn out_of_sync_mask(&self, slots: u32) -> u32 {
+        let mut mask = (0..slots).fold(0, |mask, slot| mask | 1 << slot);
+        for member in self.members.iter().filter(|m| m.state == MemberState::InSync) {
+            mask &= !(1 << member.slot);
+        }
+        mask
+    }
+
+    /// The bitmap as it should be on disk
+    fn bitmap_bytes(&self, bitmap_sectors: u64) -> Vec<u8> {
+        let mut bytes = vec![0u8; bitmap_sectors as usize * SECTOR_SIZE];
+        for (i, word) in self.dirty.iter().zip(self.unsynced.iter()).map(|(d, u)| d | u).enumerate() {
+            LittleEndian::write_u64(&mut bytes[i * 8..i * 8 + 8], word);
+        }
+        bytes
+    }
+}
+
+/// Sectors taken by the write-intent bitmap and the sector the data starts at, for members of
+/// `member_sectors` sectors. One bit per chunk of the member, a bit more than needed since the
+/// data doesn't start at 0.
+fn layout(member_sectors: u64) -> (u64, u64) {
+    let bitmap_sectors = ((member_sectors + CHUNK_SECTORS - 1) / CHUNK_SECTORS + 4095) / 4096;
+    let data_offset = (1 + bitmap_sectors + DATA_ALIGN - 1) / DATA_ALIGN * DATA_ALIGN;
+    (bitmap_sectors, data_offset)
+}
+
+fn chunk_bit(bitmap: &[u64], chunk: u64) -> bool {
+    bitmap[(chunk / 64) as usize] & 1 << (chunk % 64) != 0
+}
+
+/// A mirrored array and its members
+#[derive(Debug)]
+pub struct Array {
+    id: usize,
+    uuid: UUID,
+    slots: u32,
+    /// Size of the array in sectors
+    sectors: u64,
+    data_offset: u64,
+    bitmap_sectors: u64,
+    /// Never held across an await
+    state: Mutex<ArrayState>,
+    /// Held while writing, so a resync never copies a chunk that's halfway through being written
+    write_lock: AsyncMutex<()>,
+}
+
+impl Array {
+    /// Create a new array on `disks`, overwriting whatever was on them.
+    /// The first disk is copied to the others in the background.
+    ///
+    /// The disks have to be distinct and not part of another array. Whether they hold anything
+    /// that's in use is up to the caller, see [crate::service::check_disk_unused].
+    pub async fn create(disks: Vec<SyncDisk>) -> Result<Arc<Self>, anyhow::Error> {
+        if disks.len() < 2 || disks.len() > MAX_MEMBERS {
+            return Err(anyhow::anyhow!("RAID-1: needs between 2 and {} disks", MAX_MEMBERS));
+        }
+        let mut member_sectors = u64::MAX;
+        for (i, disk) in disks.iter().enumerate() {
+            if disks[..i].iter().any(|other| other.kind() == disk.kind() && other.id() == disk.id()) {
+                return Err(anyhow::anyhow!("RAID-1: disk {} is given more than once", disk.id()));
+            }
+            if is_member(disk) {
+                return Err(anyhow::anyhow!("RAID-1: disk {} is already part of an array", disk.id()));
+            }
+            if disk.block_length().await? as usize != SECTOR_SIZE {
+                return Err(anyhow::anyhow!("RAID-1: members need {} byte sectors", SECTOR_SIZE));
+            }
+            let size = disk.size().ok_or_else(|| anyhow::anyhow!("RAID-1: disk {} has no known size", disk.id()))?;
+            member_sectors = core::cmp::min(member_sectors, size / SECTOR_SIZE as u64);
+        }
+
+        let (bitmap_sectors, data_offset) = layout(member_sectors);
+        if member_sectors <= data_offset {
+            return Err(anyhow::anyhow!("RAID-1: disks are too small"));
+        }
+
+        let members: Vec<Member> = disks.into_iter().enumerate().map(|(slot, disk)| Member {
+            slot: slot as u32,
+            disk,
+            state: if slot == 0 { MemberState::InSync } else { MemberState::Resyncing },
+            synced_to: 0,
+            head: 0,
+        }).collect();
+        let slots = members.len() as u32;
+        let array = Self::new(UUID::random(), slots, member_sectors - data_offset, data_offset, bitmap_sectors, 1, members);
+        array.write_bitmap().await;
+        array.write_superblocks().await?;
+        crate::both_println!("RAID-1 {}: created array {} with {} members, {} MB", array.id, array.uuid,
+                             array.slots, array.sectors * SECTOR_SIZE as u64 / (1024 * 1024));
+        Ok(Self::publish(array))
+    }
+
+    /// Put an existing array back together from the members that are present
+    async fn assemble(disks: Vec<(Superblock, SyncDisk)>) -> Result<Arc<Self>, anyhow::Error> {
+        let newest = disks.iter().map(|(sb, _)| sb).max_by_key(|sb| sb.events)
+            .ok_or_else(|| anyhow::anyhow!("RAID-1: no members"))?
+            .clone();
+
+        let mut members: Vec<Member> = Vec::new();
+        let mut stale = false;
+        for (sb, disk) in disks {
+            if members.iter().any(|m| m.slot == sb.slot) || sb.slot >= newest.slots {
+                crate::both_println!("RAID-1 {}: ignoring disk {} with duplicate slot {}", newest.uuid, disk.id(), sb.slot);
+                continue;
+            }
+            // it missed writes if it was already out when the newest superblock was written, or was gone since
+            let in_sync = sb.events == newest.events && newest.out_of_sync & 1 << sb.slot == 0;
+            stale |= !in_sync;
+            members.push(Member {
+                slot: sb.slot,
+                disk,
+                state: if in_sync { MemberState::InSync } else { MemberState::Resyncing },
+                synced_to: 0,
+                head: 0,
+            });
+        }
+        members.sort_by_key(|m| m.slot);
+        if !members.iter().any(|m| m.state == MemberState::InSync) {
+            return Err(anyhow::anyhow!("RAID-1 {}: no in-sync members, can't assemble", newest.uuid));
+        }
+
+        // whatever was dirty when it went down has to be resynced
+        let primary = members.iter().find(|m| m.state == MemberState::InSync).unwrap();
+        let mut bitmap = vec![0u8; newest.bitmap_sectors as usize * SECTOR_SIZE];
+        primary.disk.read(1, &mut bitmap).await?;
+
+        let array = Self::new(newest.uuid, newest.slots, newest.sectors, newest.data_offset, newest.bitmap_sectors, newest.events, members);
+        let missing = array.slots - array.state.lock().members.len() as u32;
+        {
+            let mut state = array.state.lock();
+            if bitmap.len() < state.unsynced.len() * 8 {
+                return Err(anyhow::anyhow!("RAID-1 {}: bitmap is too small for the array", newest.uuid));
+            }
+            for (i, word) in state.unsynced.iter_mut().enumerate() {
+                *word = LittleEndian::read_u64(&bitmap[i * 8..i * 8 + 8]);
+            }
+            let unsynced: u32 = state.unsynced.iter().map(|w| w.count_ones()).sum();
+            crate::both_println!("RAID-1 {}: assembled array {} with {} of {} members, {} chunks to resync",
+                                 array.id, array.uuid, state.members.len(), array.slots, unsynced);
+        }
+        if missing > 0 {
+            crate::both_println!("RAID-1 {}: {} members missing, array is degraded", array.id, missing);
+        }
+        if stale {
+            array.state.lock().events += 1;
+            array.write_superblocks().await?;
+        }
+        Ok(Self::publish(array))
+    }
+
+    fn new(uuid: UUID, slots: u32, sectors: u64, data_offset: u64, bitmap_sectors: u64, events: u64, members: Vec<Member>) -> Self {
+        let chunks = (sectors + CHUNK_SECTORS - 1) / CHUNK_SECTORS;
+        let words = ((chunks + 63) / 64) as usize;
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            uuid,
+            slots,
+            sectors,
+            data_offset,
+            bitmap_sectors,
+            state: Mutex::new(ArrayState {
+                members,
+                events,
+                dirty: vec![0; words],
+                unsynced: vec![0; words],
+                last_write: Instant::now(),
+            }),
+            write_lock: AsyncMutex::new(()),
+        }
+    }
+
+    /// Make the array known to [raid_task]
+    fn publish(array: Self) -> Arc<Self> {
+        let array = Arc::new(array);
+        ARRAYS.lock().push(array.clone());
+        array
+    }
+
+    pub fn id(&self) -> usize { self.id }
+    pub fn uuid(&self) -> UUID { self.uuid }
+
+    /// Disk IDs and states of the members that are present
+    pub fn members(&self) -> Vec<(usize, MemberState)> {
+        self.state.lock().members.iter().map(|m| (m.disk.id(), m.state)).collect()
+    }
+
+    /// Write every working member's superblock, dropping members that fail
+    async fn write_superblocks(&self) -> Result<(), anyhow::Error> {
+        loop {
+            let (targets, superblocks): (Vec<(usize, SyncDisk)>, Vec<Vec<u8>>) = {
+                let state = self.state.lock();
+                let out_of_sync = state.out_of_sync_mask(self.slots);
+                state.working().map(|(i, m)| {
+                    let sb = Superblock {
+                        uuid: self.uuid, slot: m.slot, slots: self.slots, events: state.events,
+                        data_offset: self.data_offset, sectors: self.sectors,
+                        bitmap_sectors: self.bitmap_sectors, out_of_sync,
+                    };
+                    ((i, m.disk.clone()), sb.to_bytes())
+                }).unzip()
+            };
+            if targets.is_empty() {
+                return Err(anyhow::anyhow!("RAID-1 {}: no working members left", self.id));
+            }
+            let results = join_all(targets.iter().zip(superblocks.iter())
+                .map(|((_, disk), sb)| disk.write(0, sb))).await;
+
+            let mut failed = false;
+            for ((i, _), result) in targets.iter().zip(results) {
+                if let Err(err) = result {
+                    // failing a member changes the superblock, so go round again
+                    self.mark_failed(*i, &err);
+                    failed = true;
+                }
+            }
+            if !failed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write the bitmap to every working member. Members that fail are dropped.
+    async fn write_bitmap(&self) {
+        let (targets, bitmap) = {
+            let state = self.state.lock();
+            let targets: Vec<(usize, SyncDisk)> = state.working().map(|(i, m)| (i, m.disk.clone())).collect();
+            (targets, state.bitmap_bytes(self.bitmap_sectors))
+        };
+        let results = join_all(targets.iter().map(|(_, disk)| disk.write(1, &bitmap))).await;
+        self.handle_failures(&targets, results).await;
+    }
+
+    fn mark_failed(&self, index: usize, err: &anyhow::Error) {
+        let mut state = self.state.lock();
+        if state.members[index].state != MemberState::Failed {
+            state.members[index].state = MemberState::Failed;
+            state.events += 1;
+            crate::both_println!("RAID-1 {}: member disk {} failed ({}), array is degraded",
+                                 self.id, state.members[index].disk.id(), err);
+        }
+    }
+
+    /// Drop the members whose request failed and record it on the others.
+    /// Returns how many requests succeeded.
+    async fn handle_failures<T>(&self, targets: &[(usize, SyncDisk)], results: Vec<Result<T, anyhow::Error>>) -> usize {
+        let mut succeeded = 0;
+        let mut failed = false;
+        for ((i, _), result) in targets.iter().zip(results) {
+            match result {
+                Ok(_) => succeeded += 1,
+                Err(err) => {
+                    self.mark_failed(*i, &err);
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            if let Err(err) = self.write_superblocks().await {
+                crate::both_println!("{}", err);
+            }
+        }
+        succeeded
+    }
+
+    fn check_range(&self, block: u64, len: usize) -> Result<u64, anyhow::Error> {
+        let sectors = (len / SECTOR_SIZE) as u64;
+        if len % SECTOR_SIZE != 0 || block.checked_add(sectors).map_or(true, |end| end > self.sectors) {
+            return Err(anyhow::anyhow!("RAID-1 {}: bad request of {} bytes at {}", self.id, len, block));
+        }
+        Ok(block + sectors)
+    }
+
+    /// Pick the member to read `block..end` from: the one that's closest to it,
+    /// or the primary if the range might not be in sync yet
+    fn pick_reader(&self, block: u64, end: u64) -> Option<(usize, SyncDisk)> {
+        let state = self.state.lock();
+        let primary = state.primary()?;
+        let unsynced = (block / CHUNK_SECTORS..=(end - 1) / CHUNK_SECTORS).any(|c| chunk_bit(&state.unsynced, c));
+        let index = if unsynced {
+            primary
+        } else {
+            state.working()
+                .filter(|(_, m)| m.state == MemberState::InSync || m.synced_to >= end)
+                .min_by_key(|(_, m)| if m.head > block { m.head - block } else { block - m.head })
+                .map(|(i, _)| i)?
+        };
+        Some((index, state.members[index].disk.clone()))
+    }
+
+    async fn read(&self, block: u64, buffer: &mut [u8]) -> Result<usize, anyhow::Error> {
+        let end = self.check_range(block, buffer.len())?;
+        if block == end {
+            return Ok(0);
+        }
+        loop {
+            let (index, disk) = self.pick_reader(block, end)
+                .ok_or_else(|| anyhow::anyhow!("RAID-1 {}: no working members left", self.id))?;
+            match disk.read(self.data_offset + block, buffer).await {
+                Ok(read) => {
+                    self.state.lock().members[index].head = end;
+                    return Ok(read);
+                },
+                // try the next member
+                Err(err) => { self.handle_failures(&[(index, disk)], vec![Err::<(), _>(err)]).await; },
+            }
+        }
+    }
+
+    async fn write(&self, block: u64, buffer: &[u8]) -> Result<usize, anyhow::Error> {
+        let end = self.check_range(block, buffer.len())?;
+        if block == end {
+            return Ok(0);
+        }
+        let _lock = self.write_lock.lock().await;
+
+        // the bitmap has to be on disk before the data, or a crash could leave the members different without a trace
+        let bitmap_changed = {
+            let mut state = self.state.lock();
+            state.last_write = Instant::now();
+            let mut changed = false;
+            for chunk in block / CHUNK_SECTORS..=(end - 1) / CHUNK_SECTORS {
+                if !chunk_bit(&state.dirty, chunk) {
+                    state.dirty[(chunk / 64) as usize] |= 1 << (chunk % 64);
+                    changed = true;
+                }
+            }
+            changed
+        };
+        if bitmap_changed {
+            self.write_bitmap().await;
+            // written isn't enough, it could still be sitting in a write cache when the data reaches the media
+            let targets: Vec<(usize, SyncDisk)> = self.state.lock().working().map(|(i, m)| (i, m.disk.clone())).collect();
+            let results = join_all(targets.iter().map(|(_, disk)| disk.flush())).await;
+            self.handle_failures(&targets, results).await;
+        }
+
+        let targets: Vec<(usize, SyncDisk)> = self.state.lock().working().map(|(i, m)| (i, m.disk.clone())).collect();
+        let results = join_all(targets.iter().map(|(_, disk)| disk.write(self.data_offset + block, buffer))).await;
+        if self.handle_failures(&targets, results).await == 0 {
+            return Err(anyhow::anyhow!("RAID-1 {}: write failed on every member", self.id));
+        }
+        Ok(buffer.len())
+    }
+
+    async fn discard(&self, block: u64, count: u64) -> Result<(), anyhow::Error> {
+        let end = block.checked_add(count).filter(|end| *end <= self.sectors)
+            .ok_or_else(|| anyhow::anyhow!("RAID-1 {}: discard past the end", self.id))?;
+        let _lock = self.write_lock.lock().await;
+        let targets: Vec<(usize, SyncDisk)> = self.state.lock().working().map(|(i, m)| (i, m.disk.clone())).collect();
+        let results = join_all(targets.iter().map(|(_, disk)| disk.discard(self.data_offset + block, end - block))).await;
+        self.handle_failures(&targets, results).await;
+        Ok(())
+    }
+
+    /// Flush every member, then clear the bitmap since everything written so far is on all of them
+    async fn flush(&self) -> Result<(), anyhow::Error> {
+        let _lock = self.write_lock.lock().await;
+        let targets: Vec<(usize, SyncDisk)> = self.state.lock().working().map(|(i, m)| (i, m.disk.clone())).collect();
+        let results = join_all(targets.iter().map(|(_, disk)| disk.flush())).await;
+        if self.handle_failures(&targets, results).await == 0 {
+            return Err(anyhow::anyhow!("RAID-1 {}: flush failed on every member", self.id));
+        }
+        let was_dirty = {
+            let mut state = self.state.lock();
+            let was_dirty = state.dirty.iter().any(|w| *w != 0);
+            state.dirty.iter_mut().for_each(|w| *w = 0);
+            was_dirty
+        };
+        if was_dirty {
+            self.write_bitmap().await;
+        }
+        Ok(())
+    }
+
+    /// Copy the next piece of whatever needs resyncing. Returns false if there's nothing left.
+    async fn resync_step(&self) -> bool {
+        let _lock = self.write_lock.lock().await;
+        // (primary, members to copy to, first sector, sectors, unsynced chunk being worked on)
+        let plan = {
+            let state = self.state.lock();
+            let primary = match state.primary() {
+                Some(primary) => primary,
+                None => return false,
+            };
+            let chunk = (0..(self.sectors + CHUNK_SECTORS - 1) / CHUNK_SECTORS).find(|c| chunk_bit(&state.unsynced, *c));
+            if let Some(chunk) = chunk {
+                let targets: Vec<usize> = state.working().map(|(i, _)| i).filter(|i| *i != primary).collect();
+                let start = chunk * CHUNK_SECTORS;
+                Some((primary, targets, start, core::cmp::min(CHUNK_SECTORS, self.sectors - start), Some(chunk)))
+            } else if let Some((i, m)) = state.working().find(|(_, m)| m.state == MemberState::Resyncing) {
+                Some((primary, vec![i], m.synced_to, core::cmp::min(RESYNC_SECTORS, self.sectors - m.synced_to), None))
+            } else {
+                None
+            }
+        };
+        let (primary, targets, start, sectors, chunk) = match plan {
+            Some(plan) => plan,
+            None => return false,
+        };
+
+        let (source, destinations): (SyncDisk, Vec<(usize, SyncDisk)>) = {
+            let state = self.state.lock();
+            (state.members[primary].disk.clone(), targets.iter().map(|i| (*i, state.members[*i].disk.clone())).collect())
+        };
+        let mut buffer = vec![0u8; RESYNC_SECTORS as usize * SECTOR_SIZE];
+        let mut done = 0;
+        while done < sectors {
+            let count = core::cmp::min(RESYNC_SECTORS, sectors - done);
+            let piece = &mut buffer[..count as usize * SECTOR_SIZE];
+            let sector = self.data_offset + start + done;
+            if let Err(err) = source.read(sector, piece).await {
+                // try again with the next in-sync member
+                self.handle_failures(&[(primary, source)], vec![Err::<(), _>(err)]).await;
+                return true;
+            }
+            let piece = &*piece;
+            let results = join_all(destinations.iter().map(|(_, disk)| disk.write(sector, piece))).await;
+            self.handle_failures(&destinations, results).await;
+            done += count;
+        }
+
+        match chunk {
+            Some(chunk) => {
+                self.state.lock().unsynced[(chunk / 64) as usize] &= !(1 << (chunk % 64));
+                self.write_bitmap().await;
+            },
+            None => {
+                let finished = {
+                    let mut state = self.state.lock();
+                    let member = &mut state.members[targets[0]];
+                    if member.state != MemberState::Resyncing {
+                        false
+                    } else {
+                        member.synced_to = start + sectors;
+                        if member.synced_to >= self.sectors {
+                            member.state = MemberState::InSync;
+                            crate::both_println!("RAID-1 {}: member disk {} is in sync", self.id, member.disk.id());
+                            state.events += 1;
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                };
+                if finished {
+                    if let Err(err) = self.write_superblocks().await {
+                        crate::both_println!("{}", err);
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Clear the bitmap if nothing has been written for a while
+    async fn clear_idle_bitmap(&self) {
+        let idle = {
+            let state = self.state.lock();
+            state.dirty.iter().any(|w| *w != 0) && state.last_write.until(Instant::now()) >= BITMAP_CLEAR_DELAY
+        };
+        if idle {
+            if let Err(err) = self.flush().await {
+                crate::serial_println!("{}", err);
+            }
+        }
+    }
+}
+
+/// The array as a [Disk], for registering with the disk service
+#[derive(Debug)]
+pub struct Raid1Disk {
+    array: Arc<Array>,
+    info: DiskInfo,
+}
+
+impl Raid1Disk {
+    pub fn new(array: Arc<Array>) -> Self {
+        let info = DiskInfo {
+            model: format!("RAID-1 array ({} members)", array.slots),
+            serial: array.uuid.as_string(),
+            firmware: String::new(),
+            sectors: array.sectors,
+            logical_sector_size: SECTOR_SIZE as u32,
+            physical_sector_size: SECTOR_SIZE as u32,
+            lba48: false,
+            ncq_depth: None,
+            trim: false,
+            write_cache: true,
+            write_cache_enabled: true,
+            smart: false,
+            smart_enabled: false,
+            read_only: false,
+        };
+        Self { array, info }
+    }
+
+    pub fn array(&self) -> &Arc<Array> { &self.array }
+}
+
+impl Disk for Raid1Disk {
+    fn id(&self) -> usize { self.array.id }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::RaidArray }
+    fn size(&self) -> Option<u64> { Some(self.array.sectors * SECTOR_SIZE as u64) }
+    fn info(&self) -> Option<DiskInfo> { Some(self.info.clone()) }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(self.array.read(block, buffer))
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(self.array.write(block, buffer))
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        Box::pin(async { Ok(SECTOR_SIZE as u32) })
+    }
+
+    fn discard(&mut self, block: u64, count: u64) -> DiskFuture<'_, ()> {
+        Box::pin(self.array.discard(block, count))
+    }
+
+    fn flush(&mut self) -> DiskFuture<'_, ()> {
+        Box::pin(self.array.flush())
+    }
+}
+
+/// Look for RAID superblocks on `disks` and assemble every array found.
+/// Resolves to the assembled arrays along with the disk service IDs of their members.
+pub async fn scan_disks(disks: &[(u32, SyncDisk)]) -> Vec<(SyncDisk, Vec<u32>)> {
+    let mut found: Vec<(u32, Superblock, SyncDisk)> = Vec::new();
+    for (id, disk) in disks {
+        if disk.block_length().await.map_or(true, |len| len as usize != SECTOR_SIZE) {
+            continue;
+        }
+        let disk_sectors = match disk.size() {
+            Some(size) => size / SECTOR_SIZE as u64,
+            None => continue,
+        };
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        if disk.read(0, &mut sector).await.is_err() {
+            continue;
+        }
+        if let Some(sb) = Superblock::parse(&sector, disk_sectors) {
+            found.push((*id, sb, disk.clone()));
+        }
+    }
+
+    let mut arrays = Vec::new();
+    while let Some((_, first, _)) = found.first() {
+        let uuid = first.uuid;
+        let (members, rest): (Vec<_>, Vec<_>) = found.into_iter().partition(|(_, sb, _)| sb.uuid == uuid);
+        found = rest;
+        let ids = members.iter().map(|(id, _, _)| *id).collect();
+        match Array::assemble(members.into_iter().map(|(_, sb, disk)| (sb, disk)).collect()).await {
+            Ok(array) => arrays.push((SyncDisk::new(Box::new(Raid1Disk::new(array))), ids)),
+            Err(err) => crate::both_println!("{}", err),
+        }
+    }
+    arrays
+}
+
+/// True if `disk` is a member of an assembled array
+pub fn is_member(disk: &SyncDisk) -> bool {
+    ARRAYS.lock().iter().any(|array| array.state.lock().members.iter()
+        .any(|m| m.state != MemberState::Failed && m.disk.kind() == disk.kind() && m.disk.id() == disk.id()))
+}
+
+/// Resyncs arrays that need it and clears the write-intent bitmaps of idle arrays
+pub async fn raid_task() {
+    loop {
+        let arrays: Vec<Arc<Array>> = ARRAYS.lock().clone();
+        let mut busy = false;
+        for array in arrays {
+            // a bit at a time, so the array stays usable
+            for _ in 0..32 {
+                if !array.resync_step().await {
+                    break;
+                }
+                busy = true;
+            }
+            array.clear_idle_bitmap().await;
+        }
+        sleep(if busy { Duration::from_millis(10) } else { Duration::from_secs(1) }).await;
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_raid1_superblock_round_trip() {
+    let sb = Superblock {
+        uuid: UUID([7; 16]), slot: 1, slots: 2, events: 42, data_offset: 8,
+        sectors: 1 << 20, bitmap_sectors: 1, out_of_sync: 0b10,
+    };
+    let parsed = Superblock::parse(&sb.to_bytes(), (1 << 20) + 8).unwrap();
+    assert_eq!(parsed.uuid, sb.uuid);
+    assert_eq!((parsed.slot, parsed.slots, parsed.events), (1, 2, 42));
+    assert_eq!((parsed.data_offset, parsed.sectors, parsed.bitmap_sectors, parsed.out_of_sync), (8, 1 << 20, 1, 0b10));
+    assert!(Superblock::parse(&[0u8; SECTOR_SIZE], (1 << 20) + 8).is_none());
+    // the disk has to be big enough for it
+    assert!(Superblock::parse(&sb.to_bytes(), 1 << 20).is_none());
+}
+
+#[test_case]
+fn test_raid1_scan_rejects_corrupt_superblocks() {
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+    let good = Superblock {
+        uuid: UUID([9; 16]), slot: 0, slots: 2, events: 1, data_offset: 8,
+        sectors: 64 * 2048 - 8, bitmap_sectors: 1, out_of_sync: 0,
+    };
+    let corrupt = [
+        Superblock { slots: 40, slot: 33, ..good.clone() },
+        Superblock { slot: 2, ..good.clone() },
+        Superblock { bitmap_sectors: 1 << 40, ..good.clone() },
+        Superblock { sectors: u64::MAX, ..good.clone() },
+        Superblock { sectors: 65 * 2048, ..good.clone() },
+        Superblock { data_offset: 16, sectors: 64 * 2048 - 16, ..good.clone() },
+    ];
+    for sb in corrupt.iter() {
+        let disk = SyncDisk::new(Box::new(RamDisk::from_image(sb.to_bytes(), 64 * 1024 * 1024).unwrap()));
+        assert!(scan_disks(&[(0, disk)]).now_or_never().unwrap().is_empty());
+    }
+    assert!(ARRAYS.lock().iter().all(|array| array.uuid != good.uuid));
+}
+
+/// A RAM disk that fails every request while `fail` is set
+#[cfg(test)]
+#[derive(Debug)]
+struct FlakyDisk {
+    disk: crate::driver::ramdisk::RamDisk,
+    fail: Arc<core::sync::atomic::AtomicBool>,
+}
+
+#[cfg(test)]
+impl FlakyDisk {
+    fn check(&self) -> Result<(), anyhow::Error> {
+        match self.fail.load(Ordering::SeqCst) {
+            true => Err(anyhow::anyhow!("flaky disk {} failed", self.disk.id())),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Disk for FlakyDisk {
+    fn id(&self) -> usize { self.disk.id() }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::RamDisk }
+    fn size(&self) -> Option<u64> { self.disk.size() }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        match self.check() {
+            Ok(()) => self.disk.read(block, buffer),
+            Err(err) => Box::pin(async move { Err(err) }),
+        }
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        match self.check() {
+            Ok(()) => self.disk.write(block, buffer),
+            Err(err) => Box::pin(async move { Err(err) }),
+        }
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        self.disk.block_length()
+    }
+}
+
+/// A new array on `disks`, with every member copied in
+#[cfg(test)]
+fn in_sync_array(disks: Vec<SyncDisk>) -> Arc<Array> {
+    use futures_util::FutureExt;
+    let array = Array::create(disks).now_or_never().unwrap().unwrap();
+    while array.resync_step().now_or_never().unwrap() {}
+    assert!(array.members().iter().all(|(_, state)| *state == MemberState::InSync));
+    array
+}
+
+#[test_case]
+fn test_raid1_degraded_read() {
+    use core::sync::atomic::AtomicBool;
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    let fail = Arc::new(AtomicBool::new(false));
+    let flaky = SyncDisk::new(Box::new(FlakyDisk { disk: RamDisk::new(256 * SECTOR_SIZE).unwrap(), fail: fail.clone() }));
+    let other = SyncDisk::new(Box::new(RamDisk::new(256 * SECTOR_SIZE).unwrap()));
+    assert!(Array::create(vec![other.clone(), other.clone()]).now_or_never().unwrap().is_err());
+    let array = in_sync_array(vec![flaky, other.clone()]);
+    let spare = SyncDisk::new(Box::new(RamDisk::new(256 * SECTOR_SIZE).unwrap()));
+    assert!(Array::create(vec![spare, other.clone()]).now_or_never().unwrap().is_err());
+
+    let data: Vec<u8> = (0..4 * SECTOR_SIZE).map(|i| i as u8).collect();
+    array.write(10, &data).now_or_never().unwrap().unwrap();
+    fail.store(true, Ordering::SeqCst);
+    // the failing member is the closest one, the read moves on to the other
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(array.read(10, &mut buffer).now_or_never().unwrap().unwrap(), data.len());
+    assert_eq!(buffer, data);
+    let states: Vec<MemberState> = array.members().iter().map(|(_, state)| *state).collect();
+    assert_eq!(states, vec![MemberState::Failed, MemberState::InSync]);
+
+    // writes carry on with the member that's left
+    let zeroes = vec![0u8; SECTOR_SIZE];
+    array.write(11, &zeroes).now_or_never().unwrap().unwrap();
+    array.read(10, &mut buffer).now_or_never().unwrap().unwrap();
+    assert_eq!(&buffer[..SECTOR_SIZE], &data[..SECTOR_SIZE]);
+    assert_eq!(&buffer[SECTOR_SIZE..2 * SECTOR_SIZE], &zeroes[..]);
+    other.read(array.data_offset + 11, &mut buffer[..SECTOR_SIZE]).now_or_never().unwrap().unwrap();
+    assert_eq!(&buffer[..SECTOR_SIZE], &zeroes[..]);
+}
+
+#[test_case]
+fn test_raid1_bitmap_resync() {
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    let disks: Vec<SyncDisk> = (0..2).map(|_| SyncDisk::new(Box::new(RamDisk::new(4096 * SECTOR_SIZE).unwrap()))).collect();
+    let array = in_sync_array(disks.clone());
+    let bitmap = |disk: &SyncDisk| {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        disk.read(1, &mut sector).now_or_never().unwrap().unwrap();
+        LittleEndian::read_u64(&sector)
+    };
+
+    // the chunk is marked on every member before the data goes out
+    let block = CHUNK_SECTORS + 3;
+    let data = vec![0x5Au8; SECTOR_SIZE];
+    array.write(block, &data).now_or_never().unwrap().unwrap();
+    assert!(disks.iter().all(|disk| bitmap(disk) == 0b10));
+
+    // crash before the second member got the data, and put the array back together
+    disks[1].write(array.data_offset + block, &[0u8; SECTOR_SIZE]).now_or_never().unwrap().unwrap();
+    let found: Vec<(Superblock, SyncDisk)> = disks.iter().map(|disk| {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        disk.read(0, &mut sector).now_or_never().unwrap().unwrap();
+        (Superblock::parse(&sector).unwrap(), disk.clone())
+    }).collect();
+    let assembled = Array::assemble(found).now_or_never().unwrap().unwrap();
+    assert_eq!(assembled.state.lock().unsynced[0], 0b10);
+
+    // the unsynced chunk is read from the primary even if the other member is closer
+    assembled.state.lock().members[1].head = block;
+    let mut buffer = vec![0u8; SECTOR_SIZE];
+    assembled.read(block, &mut buffer).now_or_never().unwrap().unwrap();
+    assert_eq!(buffer, data);
+
+    // resyncing copies just that chunk and clears its bit
+    assert!(assembled.resync_step().now_or_never().unwrap());
+    assert!(!assembled.resync_step().now_or_never().unwrap());
+    assert!(assembled.state.lock().unsynced.iter().all(|word| *word == 0));
+    disks[1].read(assembled.data_offset + block, &mut buffer).now_or_never().unwrap().unwrap();
+    assert_eq!(buffer, data);
+    assert!(disks.iter().all(|disk| bitmap(disk) == 0));
+
+    // flushing clears the bits of finished writes
+    assembled.write(5, &data).now_or_never().unwrap().unwrap();
+    assert!(disks.iter().all(|disk| bitmap(disk) == 0b01));
+    assembled.flush().now_or_never().unwrap().unwrap();
+    assert!(disks.iter().all(|disk| bitmap(disk) == 0));
+}
+
+#[test_case]
+fn test_raid1_read_balancing() {
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    let disks: Vec<SyncDisk> = (0..2).map(|_| SyncDisk::new(Box::new(RamDisk::new(256 * SECTOR_SIZE).unwrap()))).collect();
+    let array = in_sync_array(disks);
+    let heads = || array.state.lock().members.iter().map(|m| m.head).collect::<Vec<u64>>();
+    let mut buffer = vec![0u8; 8 * SECTOR_SIZE];
+    let mut read = |block: u64| { array.read(block, &mut buffer).now_or_never().unwrap().unwrap(); };
+
+    // ties go to the first member, after that reads go to whichever member is closer
+    read(100);
+    assert_eq!(heads(), vec![108, 0]);
+    read(0);
+    assert_eq!(heads(), vec![108, 8]);
+    read(108);
+    assert_eq!(heads(), vec![116, 8]);
+    read(8);
+    assert_eq!(heads(), vec![116, 16]);
+
+    // a member that's still being copied only gets reads of what it already has
+    {
+        let mut state = array.state.lock();
+        state.members[1].state = MemberState::Resyncing;
+        state.members[1].synced_to = 50;
+    }
+    read(16);
+    assert_eq!(heads(), vec![116, 24]);
+    read(60);
+    assert_eq!(heads(), vec![68, 24]);
+}
@@ -0,0 +1,112 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::device::physical::{Disk, DiskFuture, PhysicalDeviceType};
+
+/// Block size of RAM disks
+pub const SECTOR_SIZE: usize = 512;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A disk that only exists in memory, for tests and for booting from an image loaded into memory
+pub struct RamDisk {
+    id: usize,
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// Create a zero-filled RAM disk of `size` bytes, rounded up to whole sectors
+    pub fn new(size: usize) -> Result<Self, anyhow::Error> {
+        Self::from_image(Vec::new(), size)
+    }
+
+    /// Create a RAM disk holding `image`, padded with zeroes up to `size` bytes or the end of its last sector
+    pub fn from_image(mut image: Vec<u8>, size: usize) -> Result<Self, anyhow::Error> {
+        let size = core::cmp::max(size, image.len());
+        let size = (size + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+        // a RAM disk that doesn't fit shouldn't take the kernel down with it
+        image.try_reserve_exact(size - image.len())
+            .map_err(|_| anyhow::anyhow!("RAM disk: not enough memory for {} bytes", size))?;
+        image.resize(size, 0);
+        Ok(Self { id: NEXT_ID.fetch_add(1, Ordering::SeqCst), data: image })
+    }
+
+    /// Byte range covered by a request, if it's inside the disk
+    fn range(&self, block: u64, len: usize) -> Result<Range<usize>, anyhow::Error> {
+        let start = (block as usize).checked_mul(SECTOR_SIZE);
+        match start.and_then(|start| Some(start..start.checked_add(len)?)) {
+            Some(range) if range.end <= self.data.len() => Ok(range),
+            _ => Err(anyhow::anyhow!("RAM disk {}: access past the end", self.id)),
+        }
+    }
+}
+
+impl Debug for RamDisk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "RamDisk {{ id: {}, size: {} }}", self.id, self.data.len())
+    }
+}
+
+impl Disk for RamDisk {
+    fn id(&self) -> usize { self.id }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::RamDisk }
+    fn size(&self) -> Option<u64> { Some(self.data.len() as u64) }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            let range = self.range(block, buffer.len())?;
+            buffer.copy_from_slice(&self.data[range]);
+            Ok(buffer.len())
+        })
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move {
+            let range = self.range(block, buffer.len())?;
+            self.data[range].copy_from_slice(buffer);
+            Ok(buffer.len())
+        })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        Box::pin(async { Ok(SECTOR_SIZE as u32) })
+    }
+
+    fn discard(&mut self, block: u64, count: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            let range = self.range(block, (count as usize).saturating_mul(SECTOR_SIZE))?;
+            self.data[range].fill(0);
+            Ok(())
+        })
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_ramdisk_read_write() {
+    use futures_util::FutureExt;
+    let mut disk = RamDisk::new(4 * SECTOR_SIZE - 100).unwrap();
+    assert_eq!(disk.size(), Some(4 * SECTOR_SIZE as u64));
+
+    let data: Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| i as u8).collect();
+    assert_eq!(disk.write(1, &data).now_or_never().unwrap().unwrap(), data.len());
+    let mut buffer = alloc::vec![0u8; 2 * SECTOR_SIZE];
+    disk.read(1, &mut buffer).now_or_never().unwrap().unwrap();
+    assert_eq!(buffer, data);
+
+    disk.discard(2, 1).now_or_never().unwrap().unwrap();
+    disk.read(1, &mut buffer).now_or_never().unwrap().unwrap();
+    assert_eq!(&buffer[..SECTOR_SIZE], &data[..SECTOR_SIZE]);
+    assert!(buffer[SECTOR_SIZE..].iter().all(|b| *b == 0));
+
+    assert!(disk.read(3, &mut buffer).now_or_never().unwrap().is_err());
+}
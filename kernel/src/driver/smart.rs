@@ -0,0 +1,125 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Parsing for ATA SMART (Self-Monitoring, Analysis and Reporting Technology) data.
+
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// SMART subcommands, written to the feature register of an [AtaCommand::Smart](crate::driver::ahci::constants::AtaCommand::Smart)
+pub const SMART_READ_DATA: u8 = 0xD0;
+pub const SMART_READ_THRESHOLDS: u8 = 0xD1;
+pub const SMART_RETURN_STATUS: u8 = 0xDA;
+
+/// Every SMART command has to carry this in LBA mid/high, or the device rejects it
+pub const SMART_LBA_MID: u8 = 0x4F;
+pub const SMART_LBA_HIGH: u8 = 0xC2;
+/// LBA mid/high returned by SMART RETURN STATUS when a threshold has been exceeded
+pub const SMART_FAILING_LBA_MID: u8 = 0xF4;
+pub const SMART_FAILING_LBA_HIGH: u8 = 0x2C;
+
+/// Number of attribute entries in the data and threshold structures
+const NUM_ATTRIBUTES: usize = 30;
+/// Size of an attribute entry in the data structure
+const ATTRIBUTE_SIZE: usize = 12;
+
+/// A single SMART attribute with its threshold
+#[derive(Debug, Clone, Copy)]
+pub struct SmartAttribute {
+    pub id: u8,
+    pub flags: u16,
+    /// Normalized current value, higher is better
+    pub current: u8,
+    /// Worst normalized value seen so far
+    pub worst: u8,
+    /// Vendor-specific raw value, usually a counter
+    pub raw: u64,
+    /// The attribute is failing once `current` drops to or below this. Zero means never.
+    pub threshold: u8,
+}
+
+impl SmartAttribute {
+    /// Pre-failure attributes predict the drive dying, the rest just age
+    pub fn is_prefailure(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    /// True if the value has dropped to the threshold
+    pub fn is_failing(&self) -> bool {
+        self.threshold != 0 && self.current <= self.threshold
+    }
+
+    /// Common name for the attribute, if it's one of the well-known ones
+    pub fn name(&self) -> &'static str {
+        match self.id {
+            1 => "Raw_Read_Error_Rate",
+            3 => "Spin_Up_Time",
+            4 => "Start_Stop_Count",
+            5 => "Reallocated_Sector_Ct",
+            7 => "Seek_Error_Rate",
+            9 => "Power_On_Hours",
+            10 => "Spin_Retry_Count",
+            12 => "Power_Cycle_Count",
+            177 => "Wear_Leveling_Count",
+            187 => "Reported_Uncorrect",
+            190 | 194 => "Temperature",
+            196 => "Reallocated_Event_Count",
+            197 => "Current_Pending_Sector",
+            198 => "Offline_Uncorrectable",
+            199 => "UDMA_CRC_Error_Count",
+            231 => "SSD_Life_Left",
+            _ => "Unknown_Attribute",
+        }
+    }
+}
+
+/// Result of a SMART health check
+#[derive(Debug, Clone)]
+pub struct SmartReport {
+    /// False if SMART RETURN STATUS says a threshold has been exceeded
+    pub healthy: bool,
+    pub attributes: Vec<SmartAttribute>,
+}
+
+impl SmartReport {
+    /// Build a report from the 512 byte responses to SMART READ DATA and SMART READ THRESHOLDS
+    pub fn parse(healthy: bool, data: &[u8], thresholds: &[u8]) -> Self {
+        let mut attributes = Vec::new();
+        for i in 0..NUM_ATTRIBUTES {
+            // both tables start at offset 2, after the revision number
+            let entry = &data[2 + i * ATTRIBUTE_SIZE..2 + (i + 1) * ATTRIBUTE_SIZE];
+            let id = entry[0];
+            if id == 0 {
+                continue;
+            }
+            // thresholds are usually in the same order, but match by ID to be safe
+            let threshold = (0..NUM_ATTRIBUTES)
+                .map(|j| &thresholds[2 + j * ATTRIBUTE_SIZE..2 + (j + 1) * ATTRIBUTE_SIZE])
+                .find(|t| t[0] == id)
+                .map(|t| t[1])
+                .unwrap_or(0);
+            attributes.push(SmartAttribute {
+                id,
+                flags: LittleEndian::read_u16(&entry[1..3]),
+                current: entry[3],
+                worst: entry[4],
+                raw: LittleEndian::read_u48(&entry[5..11]),
+                threshold,
+            });
+        }
+        Self { healthy, attributes }
+    }
+
+    /// Attributes that are at or below their threshold
+    pub fn failing(&self) -> impl Iterator<Item = &SmartAttribute> {
+        self.attributes.iter().filter(|a| a.is_failing())
+    }
+
+    /// Drive temperature in degrees celsius, if the drive reports it
+    pub fn temperature(&self) -> Option<u8> {
+        self.attributes.iter().find(|a| a.id == 194 || a.id == 190).map(|a| a.raw as u8)
+    }
+}
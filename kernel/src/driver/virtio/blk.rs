@@ -0,0 +1,277 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+use super::queue::Segment;
+use super::VirtioDevice;
+use crate::device::physical::{Disk, DiskFuture, DiskInfo, PhysicalDeviceType};
+use crate::memory::dma::{DmaDirection, DmaMapping};
+use crate::memory::frame::AddressLimit;
+use crate::memory::translate_addr;
+
+// Feature bits
+pub const FEATURE_RO: u64 = 1 << 5;
+pub const FEATURE_FLUSH: u64 = 1 << 9;
+
+// Request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const REQUEST_GET_ID: u32 = 8;
+
+// Request status values written by the device
+const STATUS_OK: u8 = 0;
+const STATUS_IOERR: u8 = 1;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Config space offset of the capacity, in 512 byte sectors
+const CONFIG_CAPACITY: u16 = 0;
+
+/// virtio-blk always addresses the disk in 512 byte sectors
+const SECTOR_SIZE: usize = 512;
+/// Largest transfer we do with a single request
+const MAX_REQUEST_BYTES: usize = 128 * 1024;
+/// Length of the serial number returned by GET_ID
+const ID_BYTES: usize = 20;
+
+/// Request header followed by the status byte. Aligned so it never crosses a page,
+/// which lets both parts be described with a single physical address each.
+#[repr(C, align(32))]
+#[derive(Debug)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+    status: u8,
+}
+
+/// Size of the part of [RequestHeader] the device reads
+const HEADER_BYTES: u32 = 16;
+
+impl RequestHeader {
+    fn new(request_type: u32, sector: u64) -> Box<Self> {
+        // anything the device doesn't overwrite counts as a failure
+        Box::new(Self { request_type, reserved: 0, sector, status: 0xFF })
+    }
+
+    fn phys(&self) -> u64 {
+        translate_addr(VirtAddr::new(self as *const Self as u64)).expect("heap page isn't mapped").as_u64()
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { core::ptr::read_volatile(&self.status) }
+    }
+}
+
+/// Lend a buffer to the device, returning the mapping along with the segments describing it.
+/// Returns `None` if part of it isn't mapped.
+///
+/// # Safety
+///
+/// `buffer` must be valid for `len` bytes until the mapping is dropped.
+unsafe fn map_buffer(buffer: *const u8, len: usize, device_writable: bool) -> Option<(DmaMapping, Vec<Segment>)> {
+    let direction = if device_writable { DmaDirection::FromDevice } else { DmaDirection::ToDevice };
+    let mapping = unsafe { DmaMapping::new(buffer, len, direction, AddressLimit::Any) }?;
+    let segments = mapping.segments().iter()
+        .map(|segment| Segment { phys: segment.phys.as_u64(), len: segment.len as u32, device_writable })
+        .collect();
+    Some((mapping, segments))
+}
+
+/// A virtio block device
+#[derive(Debug)]
+pub struct VirtioBlk {
+    device: &'static VirtioDevice,
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+    info: DiskInfo,
+}
+
+impl VirtioBlk {
+    /// Read the device config and identification
+    pub async fn new(device: &'static VirtioDevice) -> Self {
+        let sectors = device.transport().config_u64(CONFIG_CAPACITY);
+        let read_only = device.features() & FEATURE_RO != 0;
+        let flush = device.features() & FEATURE_FLUSH != 0;
+
+        let mut blk = Self {
+            device,
+            sectors,
+            read_only,
+            flush,
+            info: DiskInfo {
+                model: String::from("virtio-blk"),
+                serial: String::new(),
+                firmware: String::new(),
+                sectors,
+                logical_sector_size: SECTOR_SIZE as u32,
+                physical_sector_size: SECTOR_SIZE as u32,
+                lba48: false,
+                ncq_depth: None,
+                trim: false,
+                write_cache: flush,
+                write_cache_enabled: flush,
+                smart: false,
+                smart_enabled: false,
+                read_only,
+            },
+        };
+        // GET_ID is optional, older devices just fail it
+        match blk.get_id().await {
+            Ok(serial) => blk.info.serial = serial,
+            Err(err) => crate::serial_println!("{}", err),
+        }
+        crate::both_println!("   + virtio-blk {}: {} MB{}", device.index(), sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+                             if read_only { ", read only" } else { "" });
+        blk
+    }
+
+    /// Submit one request and wait for it. `data` is the buffer following the header, if any.
+    async fn simple_request(&self, request_type: u32, data: Option<(*const u8, usize, bool)>) -> Result<(), anyhow::Error> {
+        let header = RequestHeader::new(request_type, 0);
+        let mut segments = alloc::vec![Segment { phys: header.phys(), len: HEADER_BYTES, device_writable: false }];
+        // the mapping has to stay around until the request completes
+        let mapped = match data {
+            Some((buffer, len, device_writable)) => Some(unsafe { map_buffer(buffer, len, device_writable) }
+                .ok_or_else(|| anyhow::anyhow!("virtio-blk {}: buffer isn't mapped", self.device.index()))?),
+            None => None,
+        };
+        if let Some((_, data)) = &mapped {
+            segments.extend_from_slice(data);
+        }
+        segments.push(Segment { phys: header.phys() + HEADER_BYTES as u64, len: 1, device_writable: true });
+
+        let queue = self.device.queue(0);
+        let head = unsafe { queue.add(&segments) }
+            .ok_or_else(|| anyhow::anyhow!("virtio-blk {}: queue is full", self.device.index()))?;
+        self.device.transport().notify(queue);
+        queue.wait(head).await;
+        self.check_status(request_type, header.status())
+    }
+
+    fn check_status(&self, request_type: u32, status: u8) -> Result<(), anyhow::Error> {
+        match status {
+            STATUS_OK => Ok(()),
+            STATUS_IOERR => Err(anyhow::anyhow!("virtio-blk {}: I/O error on request type {}", self.device.index(), request_type)),
+            STATUS_UNSUPPORTED => Err(anyhow::anyhow!("virtio-blk {}: request type {} is unsupported", self.device.index(), request_type)),
+            status => Err(anyhow::anyhow!("virtio-blk {}: request type {} failed with status {}", self.device.index(), request_type, status)),
+        }
+    }
+
+    /// Ask the device for its serial number
+    async fn get_id(&self) -> Result<String, anyhow::Error> {
+        let mut id = [0u8; ID_BYTES];
+        self.simple_request(REQUEST_GET_ID, Some((id.as_mut_ptr(), ID_BYTES, true))).await?;
+        let len = id.iter().position(|&b| b == 0).unwrap_or(ID_BYTES);
+        Ok(String::from(core::str::from_utf8(&id[..len]).unwrap_or("").trim()))
+    }
+
+    /// Transfer `len` bytes between `buffer` and the disk, starting at `sector`.
+    /// Requests are pipelined up to what fits in the queue, like the NVMe driver does.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be valid for `len` bytes until the returned future completes.
+    async unsafe fn request(&self, sector: u64, buffer: *const u8, len: usize, write: bool) -> Result<usize, anyhow::Error> {
+        if write && self.read_only {
+            return Err(anyhow::anyhow!("virtio-blk {}: device is read only", self.device.index()));
+        }
+        let total_sectors = len / SECTOR_SIZE;
+        if sector + total_sectors as u64 > self.sectors {
+            return Err(anyhow::anyhow!("virtio-blk {}: access past the end", self.device.index()));
+        }
+        let queue = self.device.queue(0);
+        // a request needs the header, the status byte, and one descriptor per page it touches
+        let max_bytes = core::cmp::min(MAX_REQUEST_BYTES, (queue.size() as usize).saturating_sub(3) * 4096);
+        let sectors_per_request = max_bytes / SECTOR_SIZE;
+        if sectors_per_request == 0 {
+            return Err(anyhow::anyhow!("virtio-blk {}: queue is too small", self.device.index()));
+        }
+        let request_type = if write { REQUEST_OUT } else { REQUEST_IN };
+
+        let mut in_flight: VecDeque<(u16, Box<RequestHeader>, DmaMapping)> = VecDeque::new();
+        let mut result = Ok(());
+        let mut done = 0;
+        loop {
+            if result.is_ok() && done < total_sectors {
+                let count = core::cmp::min(sectors_per_request, total_sectors - done);
+                let header = RequestHeader::new(request_type, sector + done as u64);
+                let chunk = unsafe { buffer.add(done * SECTOR_SIZE) };
+                match unsafe { map_buffer(chunk, count * SECTOR_SIZE, !write) } {
+                    Some((mapping, data)) => {
+                        let mut segments = Vec::with_capacity(data.len() + 2);
+                        segments.push(Segment { phys: header.phys(), len: HEADER_BYTES, device_writable: false });
+                        segments.extend(data);
+                        segments.push(Segment { phys: header.phys() + HEADER_BYTES as u64, len: 1, device_writable: true });
+                        match unsafe { queue.add(&segments) } {
+                            Some(head) => {
+                                self.device.transport().notify(queue);
+                                in_flight.push_back((head, header, mapping));
+                                done += count;
+                                continue;
+                            },
+                            None if in_flight.is_empty() => {
+                                result = Err(anyhow::anyhow!("virtio-blk {}: couldn't submit request", self.device.index()));
+                            },
+                            // wait for descriptors to free up
+                            None => {}
+                        }
+                    },
+                    None => result = Err(anyhow::anyhow!("virtio-blk {}: buffer isn't mapped", self.device.index())),
+                }
+            }
+
+            match in_flight.pop_front() {
+                Some((head, header, mapping)) => {
+                    queue.wait(head).await;
+                    drop(mapping);
+                    if let Err(err) = self.check_status(request_type, header.status()) {
+                        crate::serial_println!("{}", err);
+                        if result.is_ok() {
+                            result = Err(err);
+                        }
+                    }
+                },
+                None => break,
+            }
+        }
+        result.map(|_| done * SECTOR_SIZE)
+    }
+}
+
+impl Disk for VirtioBlk {
+    fn id(&self) -> usize { self.device.index() }
+    fn kind(&self) -> PhysicalDeviceType { PhysicalDeviceType::VirtioDrive }
+    fn size(&self) -> Option<u64> { Some(self.sectors * SECTOR_SIZE as u64) }
+    fn queue_depth(&self) -> u32 { self.device.queue(0).size() as u32 }
+    fn info(&self) -> Option<DiskInfo> { Some(self.info.clone()) }
+
+    fn read<'a>(&'a mut self, block: u64, buffer: &'a mut [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move { unsafe { self.request(block, buffer.as_mut_ptr(), buffer.len(), false).await } })
+    }
+
+    fn write<'a>(&'a mut self, block: u64, buffer: &'a [u8]) -> DiskFuture<'a, usize> {
+        Box::pin(async move { unsafe { self.request(block, buffer.as_ptr(), buffer.len(), true).await } })
+    }
+
+    fn block_length(&mut self) -> DiskFuture<'_, u32> {
+        Box::pin(async move { Ok(SECTOR_SIZE as u32) })
+    }
+
+    fn flush(&mut self) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            if self.flush {
+                self.simple_request(REQUEST_FLUSH, None).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,165 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x2apic::ioapic::IrqFlags;
+use self::blk::VirtioBlk;
+use self::queue::Virtqueue;
+use self::transport::Transport;
+use crate::arch::interrupts;
+use crate::device::physical::SyncDisk;
+use crate::driver::pci::PciAddress;
+
+/// Legacy and modern PCI register access
+pub mod transport;
+/// Split virtqueues and request completion
+pub mod queue;
+/// `Disk` implementation for virtio block devices
+pub mod blk;
+
+const PCI_VENDOR_VIRTIO: u16 = 0x1AF4;
+/// Device ID of a transitional block device. Modern devices use 0x1040 + the virtio device type.
+const PCI_DEVICE_BLOCK_TRANSITIONAL: u16 = 0x1001;
+const PCI_DEVICE_BLOCK_MODERN: u16 = 0x1042;
+
+/// Most devices we'll drive at once
+const MAX_DEVICES: usize = 8;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const DEVICE_INIT: OnceCell<VirtioDevice> = OnceCell::uninit();
+static DEVICES: [OnceCell<VirtioDevice>; MAX_DEVICES] = [DEVICE_INIT; MAX_DEVICES];
+
+/// An initialized virtio device with its queues set up
+#[derive(Debug)]
+pub struct VirtioDevice {
+    index: usize,
+    transport: Transport,
+    features: u64,
+    queues: Vec<Virtqueue>,
+}
+
+impl VirtioDevice {
+    /// Reset the device, negotiate features and set up `queue_count` queues
+    fn new(index: usize, pci_addr: PciAddress, wanted_features: u64, queue_count: u16) -> Result<Self, anyhow::Error> {
+        let transport = Transport::probe(pci_addr)
+            .ok_or_else(|| anyhow::anyhow!("virtio {}: no usable transport", index))?;
+        let features = transport.negotiate(wanted_features)?;
+
+        let mut queues = Vec::new();
+        for i in 0..queue_count {
+            let max_size = transport.max_queue_size(i);
+            if max_size == 0 {
+                transport.set_status(transport::STATUS_FAILED);
+                return Err(anyhow::anyhow!("virtio {}: queue {} doesn't exist", index, i));
+            }
+            let size = if transport.can_resize_queues() { core::cmp::min(max_size, queue::MAX_QUEUE_SIZE) } else { max_size };
+            let mut queue = match Virtqueue::new(i, size) {
+                Ok(queue) => queue,
+                Err(err) => {
+                    transport.set_status(transport::STATUS_FAILED);
+                    return Err(err);
+                }
+            };
+            transport.setup_queue(&mut queue);
+            queues.push(queue);
+        }
+        transport.driver_ok();
+
+        crate::serial_println!("virtio {}: {} transport, features {:#X}, queue size {}", index,
+                               if transport.is_modern() { "modern" } else { "legacy" }, features,
+                               queues.first().map(|q| q.size()).unwrap_or(0));
+        Ok(Self { index, transport, features, queues })
+    }
+
+    pub fn index(&self) -> usize { self.index }
+    pub fn transport(&self) -> &Transport { &self.transport }
+    pub fn features(&self) -> u64 { self.features }
+    pub fn queue(&self, index: usize) -> &Virtqueue { &self.queues[index] }
+
+    /// Stop polling the queues, the device's interrupt reaches [handle_interrupt]
+    fn enable_irq(&self) {
+        for queue in self.queues.iter() {
+            queue.enable_irq();
+        }
+    }
+
+    /// Service every queue if the device raised a queue interrupt
+    fn service(&self) {
+        // reading the ISR also deasserts the interrupt line
+        if self.transport.read_isr() & transport::ISR_QUEUE != 0 {
+            for queue in self.queues.iter() {
+                queue.service();
+            }
+        }
+    }
+}
+
+/// Find and set up every virtio block device. Disks are opened later by [scan_disks].
+pub fn init() {
+    let devices: Vec<PciAddress> = PciAddress::find_by_vendor(PCI_VENDOR_VIRTIO)
+        .into_iter()
+        .filter(|addr| matches!(addr.device_id(), PCI_DEVICE_BLOCK_TRANSITIONAL | PCI_DEVICE_BLOCK_MODERN))
+        .collect();
+    if devices.is_empty() {
+        crate::both_println!("No virtio block devices found.");
+        return;
+    }
+
+    for (index, pci_addr) in devices.iter().take(MAX_DEVICES).enumerate() {
+        crate::both_println!("Initializing virtio block device {}...", index);
+        match VirtioDevice::new(index, *pci_addr, blk::FEATURE_RO | blk::FEATURE_FLUSH, 1) {
+            Ok(device) => { let _ = DEVICES[index].try_init_once(|| device); },
+            Err(err) => crate::both_println!("{}", err),
+        }
+    }
+    init_interrupts(&devices);
+}
+
+/// Route the devices' legacy interrupt lines to [handle_interrupt]. Devices without a usable line keep polling.
+/// Modern devices only offer MSI-X on top of that, which we don't support yet.
+fn init_interrupts(devices: &[PciAddress]) {
+    let vector = match interrupts::allocate_vector() {
+        Some(v) => v,
+        None => {
+            crate::both_println!("virtio: no free interrupt vectors, falling back to polling");
+            return;
+        }
+    };
+    interrupts::register_irq_handler(vector, handle_interrupt);
+
+    let flags = IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE;
+    for (index, pci_addr) in devices.iter().take(MAX_DEVICES).enumerate() {
+        let device = match DEVICES[index].get() {
+            Some(device) => device,
+            None => continue,
+        };
+        // devices sharing a line just program the same entry again
+        let line = pci_addr.interrupt_line();
+        if line != 0xFF && interrupts::enable_ioapic_irq(line, vector, flags) {
+            device.enable_irq();
+        } else {
+            crate::both_println!("virtio {}: no usable interrupt line, falling back to polling", index);
+        }
+    }
+}
+
+/// virtio interrupt handler. Devices can share lines, so check every one of them.
+fn handle_interrupt() {
+    for device in DEVICES.iter().filter_map(|d| d.get()) {
+        device.service();
+    }
+}
+
+/// Open a disk for every initialized block device
+pub async fn scan_disks() -> Vec<SyncDisk> {
+    let mut disks = Vec::new();
+    for device in DEVICES.iter().filter_map(|d| d.get()) {
+        disks.push(SyncDisk::new(Box::new(VirtioBlk::new(device).await)));
+    }
+    disks
+}
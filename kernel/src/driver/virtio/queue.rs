@@ -0,0 +1,269 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use alloc::vec::Vec;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::frame::AddressLimit;
+
+/// Largest queue we'll set up, if the transport lets us pick
+pub const MAX_QUEUE_SIZE: u16 = 128;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Legacy devices need the used ring on its own page
+const QUEUE_ALIGN: usize = 4096;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// One part of a buffer chain: physical address, length, and whether the device writes to it
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub phys: u64,
+    pub len: u32,
+    pub device_writable: bool,
+}
+
+/// Mutable queue state, shared between submitters and the IRQ handler
+#[derive(Debug)]
+struct QueueState {
+    /// Head of the free descriptor list, chained through `next`
+    free_head: u16,
+    num_free: u16,
+    /// Next index we'll write in the available ring
+    avail_idx: u16,
+    /// Next used ring index we haven't looked at
+    last_used: u16,
+    /// Bytes written by the device for each completed chain, indexed by head descriptor
+    done: Vec<Option<u32>>,
+}
+
+/// A split virtqueue: descriptor table, available ring and used ring in one physically contiguous block
+#[derive(Debug)]
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    notify_offset: u16,
+    mem: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    state: spin::Mutex<QueueState>,
+    /// Wakers for each chain in flight, indexed by head descriptor
+    wakers: Vec<AtomicWaker>,
+    /// Set once the device interrupt is routed somewhere. Until then, waiting futures poll the used ring.
+    irq_enabled: AtomicBool,
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl Virtqueue {
+    /// Allocate a queue with `size` entries, laid out the way legacy devices expect
+    pub fn new(index: u16, size: u16) -> Result<Self, anyhow::Error> {
+        if size == 0 || !size.is_power_of_two() {
+            return Err(anyhow::anyhow!("virtqueue {}: invalid size {}", index, size));
+        }
+        let n = size as usize;
+        let avail_offset = 16 * n;
+        let used_offset = align_up(avail_offset + 6 + 2 * n, QUEUE_ALIGN);
+        let total = used_offset + align_up(6 + 8 * n, QUEUE_ALIGN);
+
+        // the device sees the whole queue as one physical block
+        let mem = DmaBuffer::new(total, QUEUE_ALIGN, AddressLimit::Any)
+            .ok_or_else(|| anyhow::anyhow!("virtqueue {}: couldn't get {} physically contiguous bytes", index, total))?;
+
+        let queue = Self {
+            index,
+            size,
+            notify_offset: 0,
+            mem,
+            avail_offset,
+            used_offset,
+            state: spin::Mutex::new(QueueState {
+                free_head: 0,
+                num_free: size,
+                avail_idx: 0,
+                last_used: 0,
+                done: alloc::vec![None; n],
+            }),
+            wakers: (0..n).map(|_| AtomicWaker::new()).collect(),
+            irq_enabled: AtomicBool::new(false),
+        };
+        for i in 0..size {
+            unsafe { queue.desc(i).write_volatile(Descriptor { next: (i + 1) % size, ..Default::default() }); }
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 { self.index }
+    pub fn size(&self) -> u16 { self.size }
+    pub fn desc_phys(&self) -> u64 { self.mem.phys().as_u64() }
+    pub fn avail_phys(&self) -> u64 { self.desc_phys() + self.avail_offset as u64 }
+    pub fn used_phys(&self) -> u64 { self.desc_phys() + self.used_offset as u64 }
+    pub fn notify_offset(&self) -> u16 { self.notify_offset }
+    pub fn set_notify_offset(&mut self, offset: u16) { self.notify_offset = offset; }
+
+    /// Leave the used ring to the IRQ handler instead of polling it from waiting futures
+    pub fn enable_irq(&self) { self.irq_enabled.store(true, Ordering::SeqCst); }
+
+    fn desc(&self, i: u16) -> *mut Descriptor {
+        unsafe { (self.mem.as_mut_ptr() as *mut Descriptor).add(i as usize) }
+    }
+
+    /// Pointer to a u16 field of the available ring: 0 = flags, 1 = idx, 2.. = ring
+    fn avail(&self, field: usize) -> *mut u16 {
+        unsafe { (self.mem.as_mut_ptr().add(self.avail_offset) as *mut u16).add(field) }
+    }
+
+    /// Pointer to the used ring header (flags, idx)
+    fn used_idx(&self) -> *const u16 {
+        unsafe { (self.mem.as_mut_ptr().add(self.used_offset) as *const u16).add(1) }
+    }
+
+    /// Pointer to a used ring element (id, len)
+    fn used_elem(&self, i: u16) -> *const [u32; 2] {
+        unsafe { (self.mem.as_mut_ptr().add(self.used_offset + 4) as *const [u32; 2]).add(i as usize) }
+    }
+
+    /// Put a chain of buffers on the available ring. The caller has to notify the device afterwards.
+    /// Returns the head descriptor, or `None` if there aren't enough free descriptors.
+    ///
+    /// # Safety
+    ///
+    /// Every segment must stay valid until the chain completes.
+    pub unsafe fn add(&self, segments: &[Segment]) -> Option<u16> {
+        if segments.is_empty() {
+            return None;
+        }
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if (state.num_free as usize) < segments.len() {
+                return None;
+            }
+            let head = state.free_head;
+            let mut i = head;
+            for (n, segment) in segments.iter().enumerate() {
+                let desc = self.desc(i);
+                let next = unsafe { desc.read_volatile() }.next;
+                let last = n == segments.len() - 1;
+                let mut flags = if last { 0 } else { DESC_F_NEXT };
+                if segment.device_writable {
+                    flags |= DESC_F_WRITE;
+                }
+                unsafe { desc.write_volatile(Descriptor { addr: segment.phys, len: segment.len, flags, next }); }
+                if last {
+                    state.free_head = next;
+                } else {
+                    i = next;
+                }
+            }
+            state.num_free -= segments.len() as u16;
+            state.done[head as usize] = None;
+
+            let avail_idx = state.avail_idx;
+            unsafe { self.avail(2 + (avail_idx % self.size) as usize).write_volatile(head); }
+            // the descriptors and ring entry have to be visible before the index moves
+            fence(Ordering::SeqCst);
+            state.avail_idx = avail_idx.wrapping_add(1);
+            unsafe { self.avail(1).write_volatile(state.avail_idx); }
+            fence(Ordering::SeqCst);
+            Some(head)
+        })
+    }
+
+    /// Returns a future that resolves to the number of bytes the device wrote once the chain completes
+    pub fn wait(&self, head: u16) -> UsedFuture<'_> {
+        UsedFuture { queue: self, head }
+    }
+
+    /// Consume new entries from the used ring and wake whoever is waiting on them.
+    /// Called from the IRQ handler, or by waiting futures when interrupts aren't available.
+    pub fn service(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            loop {
+                let used_idx = unsafe { self.used_idx().read_volatile() };
+                if used_idx == state.last_used {
+                    break;
+                }
+                fence(Ordering::SeqCst);
+                let [id, len] = unsafe { self.used_elem(state.last_used % self.size).read_volatile() };
+                state.last_used = state.last_used.wrapping_add(1);
+                if let Some(done) = state.done.get_mut(id as usize) {
+                    *done = Some(len);
+                    // waking doesn't allocate or touch the queue, so it's fine under the lock
+                    self.wakers[id as usize].wake();
+                }
+            }
+        });
+    }
+
+    /// Take the result for a completed chain and free its descriptors. Returns `None` if it hasn't completed.
+    fn take_result(&self, head: u16) -> Option<u32> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let len = state.done[head as usize].take()?;
+            // walk to the end of the chain and put it back on the free list
+            let mut count = 1;
+            let mut tail = head;
+            loop {
+                let desc = unsafe { self.desc(tail).read_volatile() };
+                if desc.flags & DESC_F_NEXT == 0 {
+                    break;
+                }
+                tail = desc.next;
+                count += 1;
+            }
+            let last = self.desc(tail);
+            unsafe { last.write_volatile(Descriptor { next: state.free_head, ..Default::default() }); }
+            state.free_head = head;
+            state.num_free += count;
+            Some(len)
+        })
+    }
+}
+
+/// Future returned by [Virtqueue::wait]
+#[derive(Debug)]
+pub struct UsedFuture<'a> {
+    queue: &'a Virtqueue,
+    head: u16,
+}
+
+impl Future for UsedFuture<'_> {
+    type Output = u32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(len) = self.queue.take_result(self.head) {
+            return Poll::Ready(len);
+        }
+        self.queue.wakers[self.head as usize].register(cx.waker());
+
+        if !self.queue.irq_enabled.load(Ordering::SeqCst) {
+            // nobody else is going to look at the used ring
+            self.queue.service();
+            cx.waker().wake_by_ref();
+        }
+        match self.queue.take_result(self.head) {
+            Some(len) => Poll::Ready(len),
+            None => Poll::Pending,
+        }
+    }
+}
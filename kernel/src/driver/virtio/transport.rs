@@ -0,0 +1,304 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use super::queue::Virtqueue;
+use crate::driver::pci::{PciAddress, PciCommand, CAP_VENDOR};
+use crate::memory::mmio::{ioremap_region, CacheMode, IoMap};
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// Device conforms to the virtio 1.0 spec, required by the modern transport
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// ISR status bit for a used buffer notification
+pub const ISR_QUEUE: u8 = 1;
+
+// Legacy I/O port registers
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+/// Device specific config, when MSI-X is disabled
+const LEGACY_CONFIG: u16 = 0x14;
+
+// Modern vendor capability types
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Modern common config registers
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// How the device registers are reached. Legacy devices use an I/O port BAR,
+/// modern ones describe memory-mapped register blocks with vendor capabilities.
+#[derive(Debug)]
+pub enum Transport {
+    Legacy {
+        io_base: u16,
+    },
+    Modern {
+        /// Virtual addresses of each register block
+        common: u64,
+        notify: u64,
+        notify_multiplier: u32,
+        isr: u64,
+        device: u64,
+        /// Uncached mappings of the register blocks, the addresses above point into these
+        regions: Vec<IoMap<[u8]>>,
+    },
+}
+
+unsafe fn mmio_read<T: Copy>(addr: u64) -> T {
+    unsafe { (addr as *const T).read_volatile() }
+}
+
+unsafe fn mmio_write<T: Copy>(addr: u64, value: T) {
+    unsafe { (addr as *mut T).write_volatile(value) }
+}
+
+impl Transport {
+    /// Work out which transport a device supports, preferring the modern one.
+    /// Also enables I/O, memory and bus mastering for the function.
+    pub fn probe(pci_addr: PciAddress) -> Option<Self> {
+        pci_addr.set_command(pci_addr.command() | PciCommand::IoSpace | PciCommand::MemorySpace | PciCommand::BusMaster);
+
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        let mut regions = Vec::new();
+        for cap in pci_addr.find_capabilities(CAP_VENDOR) {
+            let cfg_type = pci_addr.read_u8(cap + 3);
+            let bar = pci_addr.read_u8(cap + 4);
+            if bar > 5 {
+                continue;
+            }
+            let wanted = match cfg_type {
+                CAP_COMMON_CFG => common.is_none(),
+                CAP_NOTIFY_CFG => notify.is_none(),
+                CAP_ISR_CFG => isr.is_none(),
+                CAP_DEVICE_CFG => device.is_none(),
+                _ => false,
+            };
+            // the first capability of each type is the preferred one
+            if !wanted {
+                continue;
+            }
+            let phys = PhysAddr::new(pci_addr.bar_address(bar) + pci_addr.read_u32(cap + 8) as u64);
+            let length = pci_addr.read_u32(cap + 12) as u64;
+            let region = match unsafe { ioremap_region(phys, length, CacheMode::Uncached) } {
+                Ok(region) => region,
+                Err(e) => {
+                    crate::serial_println!("virtio: failed to map capability at {:#x}: {}", phys.as_u64(), e);
+                    continue;
+                }
+            };
+            let addr = region.virt_addr().as_u64();
+            regions.push(region);
+            match cfg_type {
+                CAP_COMMON_CFG => common = Some(addr),
+                CAP_NOTIFY_CFG => notify = Some((addr, pci_addr.read_u32(cap + 16))),
+                CAP_ISR_CFG => isr = Some(addr),
+                _ => device = Some(addr),
+            }
+        }
+        if let (Some(common), Some((notify, notify_multiplier)), Some(isr), Some(device)) = (common, notify, isr, device) {
+            return Some(Transport::Modern { common, notify, notify_multiplier, isr, device, regions });
+        }
+
+        // transitional and legacy devices have their registers in I/O BAR 0
+        let bar0 = pci_addr.read_u32(0x10);
+        if bar0 & 1 == 1 && pci_addr.bar(0) != 0 {
+            return Some(Transport::Legacy { io_base: pci_addr.bar(0) as u16 });
+        }
+        None
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io_base } => unsafe { Port::<u8>::new(io_base + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => unsafe { mmio_read(common + COMMON_STATUS) },
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { io_base } => unsafe { Port::<u8>::new(io_base + LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => unsafe { mmio_write(common + COMMON_STATUS, status) },
+        }
+    }
+
+    /// Writing zero to the status register resets the device
+    pub fn reset(&self) {
+        self.set_status(0);
+        // modern devices finish resetting when the status reads back as zero
+        for _ in 0..1_000_000 {
+            if self.status() == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { io_base } => unsafe { Port::<u32>::new(io_base + LEGACY_DEVICE_FEATURES).read() as u64 },
+            Transport::Modern { common, .. } => unsafe {
+                mmio_write(common + COMMON_DEVICE_FEATURE_SELECT, 0u32);
+                let low: u32 = mmio_read(common + COMMON_DEVICE_FEATURE);
+                mmio_write(common + COMMON_DEVICE_FEATURE_SELECT, 1u32);
+                let high: u32 = mmio_read(common + COMMON_DEVICE_FEATURE);
+                low as u64 | (high as u64) << 32
+            },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy { io_base } => unsafe { Port::<u32>::new(io_base + LEGACY_DRIVER_FEATURES).write(features as u32) },
+            Transport::Modern { common, .. } => unsafe {
+                mmio_write(common + COMMON_DRIVER_FEATURE_SELECT, 0u32);
+                mmio_write(common + COMMON_DRIVER_FEATURE, features as u32);
+                mmio_write(common + COMMON_DRIVER_FEATURE_SELECT, 1u32);
+                mmio_write(common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            },
+        }
+    }
+
+    /// Reset the device, acknowledge it and accept the subset of `wanted` features it offers.
+    /// Returns the negotiated features.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, anyhow::Error> {
+        self.reset();
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let wanted = if self.is_modern() { wanted | FEATURE_VERSION_1 } else { wanted & 0xFFFF_FFFF };
+        let features = self.device_features() & wanted;
+        if self.is_modern() && features & FEATURE_VERSION_1 == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(anyhow::anyhow!("virtio: modern device doesn't offer VERSION_1"));
+        }
+        self.set_driver_features(features);
+
+        // legacy devices don't have FEATURES_OK
+        if self.is_modern() {
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(anyhow::anyhow!("virtio: device rejected features {:#X}", features));
+            }
+        }
+        Ok(features)
+    }
+
+    /// Tell the device the driver is ready. Queues have to be set up before this.
+    pub fn driver_ok(&self) {
+        let status = self.status();
+        self.set_status(status | STATUS_DRIVER_OK);
+    }
+
+    /// Largest size the given queue can have, zero if it doesn't exist
+    pub fn max_queue_size(&self, index: u16) -> u16 {
+        match *self {
+            Transport::Legacy { io_base } => unsafe {
+                Port::<u16>::new(io_base + LEGACY_QUEUE_SELECT).write(index);
+                Port::<u16>::new(io_base + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => unsafe {
+                mmio_write(common + COMMON_QUEUE_SELECT, index);
+                mmio_read(common + COMMON_QUEUE_SIZE)
+            },
+        }
+    }
+
+    /// Whether the queue size can be lowered from [Transport::max_queue_size]
+    pub fn can_resize_queues(&self) -> bool {
+        self.is_modern()
+    }
+
+    /// Hand a queue's memory to the device and enable it
+    pub fn setup_queue(&self, queue: &mut Virtqueue) {
+        let index = queue.index();
+        match *self {
+            Transport::Legacy { io_base } => unsafe {
+                Port::<u16>::new(io_base + LEGACY_QUEUE_SELECT).write(index);
+                // legacy queues are one contiguous block, given as a page number
+                Port::<u32>::new(io_base + LEGACY_QUEUE_PFN).write((queue.desc_phys() >> 12) as u32);
+            },
+            Transport::Modern { common, .. } => unsafe {
+                mmio_write(common + COMMON_QUEUE_SELECT, index);
+                mmio_write(common + COMMON_QUEUE_SIZE, queue.size());
+                for (reg, addr) in [(COMMON_QUEUE_DESC, queue.desc_phys()),
+                                    (COMMON_QUEUE_DRIVER, queue.avail_phys()),
+                                    (COMMON_QUEUE_DEVICE, queue.used_phys())].iter() {
+                    mmio_write(common + reg, *addr as u32);
+                    mmio_write(common + reg + 4, (*addr >> 32) as u32);
+                }
+                let notify_offset: u16 = mmio_read(common + COMMON_QUEUE_NOTIFY_OFF);
+                queue.set_notify_offset(notify_offset);
+                mmio_write(common + COMMON_QUEUE_ENABLE, 1u16);
+            },
+        }
+    }
+
+    /// Tell the device there are new buffers in the given queue
+    pub fn notify(&self, queue: &Virtqueue) {
+        match *self {
+            Transport::Legacy { io_base } => unsafe { Port::<u16>::new(io_base + LEGACY_QUEUE_NOTIFY).write(queue.index()) },
+            Transport::Modern { notify, notify_multiplier, .. } => unsafe {
+                mmio_write(notify + queue.notify_offset() as u64 * notify_multiplier as u64, queue.index());
+            },
+        }
+    }
+
+    /// Read and acknowledge the interrupt status
+    pub fn read_isr(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io_base } => unsafe { Port::<u8>::new(io_base + LEGACY_ISR).read() },
+            Transport::Modern { isr, .. } => unsafe { mmio_read(isr) },
+        }
+    }
+
+    /// Read a dword from the device specific config space
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        match *self {
+            Transport::Legacy { io_base } => unsafe { Port::<u32>::new(io_base + LEGACY_CONFIG + offset).read() },
+            Transport::Modern { device, .. } => unsafe { mmio_read(device + offset as u64) },
+        }
+    }
+
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+}
@@ -16,12 +16,24 @@ use num_traits::FromPrimitive;
 use crate::path::Path;
 use crate::util::UUID;
 use crate::device::block::BlockDevice;
-use crate::fs::{FsResult, FsError, Filesystem, VfsNodeType, VfsDirectoryEntry};
+use crate::fs::{FsResult, FsFuture, FsError, Filesystem, VfsNodeType, VfsDirectoryEntry};
 use core::iter::FromIterator;
+use core::ops::Range;
 use alloc::sync::Arc;
+use alloc::boxed::Box;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::memory::allocator::slab::SlabCache;
 
 const ROOT_INODE: u64 = 2;
 
+static INODE_CACHE: SlabCache = SlabCache::for_type::<Inode>("ext2_inode");
+static DIRECTORY_ENTRY_CACHE: SlabCache = SlabCache::for_type::<Ext2DirectoryEntry>("ext2_dentry");
+
+/// An inode read from disk, allocated from [INODE_CACHE]
+type CachedInode = Box<Inode, &'static SlabCache>;
+/// A directory entry, allocated from [DIRECTORY_ENTRY_CACHE]
+type CachedDirectoryEntry = Box<Ext2DirectoryEntry, &'static SlabCache>;
+
 pub type FsHandle = u32;
 
 #[derive(Debug, Clone, Copy)]
@@ -107,8 +119,8 @@ pub struct Ext2Filesystem {
     pub journal_info: Option<Ext2JournalInfo>,
 }
 impl Ext2Filesystem {
-    pub unsafe fn read_from(media: &Arc<BlockDevice>) -> FsResult<Self> {
-        let buffer = media.read(0)?;
+    pub async unsafe fn read_from(media: &Arc<BlockDevice>) -> FsResult<Self> {
+        let buffer = media.read(0).await?;
         let header = unsafe { (*(&buffer[0x400..0x454] as *const [u8] as *const SuperblockHeader)).clone() };
         if header.check_signature != 0xEF53 {
             return Err(FsError::NotValidFs);
@@ -177,7 +189,7 @@ impl Ext2Filesystem {
     }
 
     /// Reads the Block Group Descriptor for the given group number
-    fn read_bgd(&self, group_num: u32) -> FsResult<BlockGroupDescriptor> {
+    async fn read_bgd(&self, group_num: u32) -> FsResult<BlockGroupDescriptor> {
         if group_num >= self.total_groups {
             return Err(FsError::OutOfBounds);
         }
@@ -186,7 +198,7 @@ impl Ext2Filesystem {
         // offset into the table for the desired group's descriptor (32 bytes each)
         let bgd_byte_offset = group_num * 32;
         // read the block from media (table block + byte offset / block size (blocks rounded down))
-        let block = self.media.read(bgd_table_block + (bgd_byte_offset as u64 / self.block_size as u64))?;
+        let block = self.media.read(bgd_table_block + (bgd_byte_offset as u64 / self.block_size as u64)).await?;
         unsafe {
             // raw address for the start of the read block in memory
             let buf_addr = &block as *const [u8] as *const u8 as u64;
@@ -200,7 +212,7 @@ impl Ext2Filesystem {
     }
 
     /// Reads an Ext2 block from the FS (NOT a block device block, although they're often 4k as well)
-    fn read_block(&self, block_num: u64) -> FsResult<Vec<u8>> {
+    async fn read_block(&self, block_num: u64) -> FsResult<Vec<u8>> {
         if block_num >= self.total_blocks {
             return Err(FsError::OutOfBounds);
         }
@@ -209,17 +221,31 @@ impl Ext2Filesystem {
             // TODO: add support for other block sizes once ATA convenience functions are done
         }
         // assuming the 4k blocks line up is lazy but whatever, its temporary
-        let block = self.media.read(block_num)?;
+        let block = self.media.read(block_num).await?;
         Ok(Vec::from_iter(block.iter().cloned()))
     }
 
-    fn read_inode(&self, inode_num: u64) -> FsResult<Inode>  {
+    /// Tells the underlying disk that the given Ext2 blocks have been freed, so an SSD can reclaim them.
+    /// Should be called once the blocks are marked free in the block bitmap.
+    pub async fn discard_blocks(&self, block_range: Range<u64>) -> FsResult<()> {
+        if block_range.end > self.total_blocks {
+            return Err(FsError::OutOfBounds);
+        }
+        if self.block_size != 4096 {
+            panic!("Only block sizes of 4096 are currently supported");
+        }
+        // same 1:1 block mapping as read_block
+        self.media.discard(block_range).await?;
+        Ok(())
+    }
+
+    async fn read_inode(&self, inode_num: u64) -> FsResult<CachedInode>  {
         if self.block_size != 4096 {
             panic!("Only block sizes of 4096 are currently supported");
             // TODO: add support for other block sizes once ATA convenience functions are done
         }
         let group = self.block_group_containing_inode(inode_num)?;
-        let bgd = self.read_bgd(group)?;
+        let bgd = self.read_bgd(group).await?;
         let inode_index = self.inode_table_entry_index(inode_num)?;
         let inodes_per_block = self.block_size / self.inode_size;
         let group_block_offset = group * self.blocks_per_group * self.block_size;
@@ -227,15 +253,15 @@ impl Ext2Filesystem {
         let block_num = bgd.inode_table_start_block + group_block_offset + block_offset_in_inode_table;
         let inode_index = inode_index % inodes_per_block as u64;
         // assuming the 4k blocks line up is lazy but whatever, its temporary
-        let block = self.media.read(block_num as u64)?;
+        let block = self.media.read(block_num as u64).await?;
         assert!((inode_index * self.inode_size as u64) < self.block_size as u64);
         unsafe {
             let inode_addr = (&block as *const [u8] as *const u8 as u64) + (inode_index * self.inode_size as u64);
-            Ok((*(inode_addr as *const Inode)).clone())
+            Ok(Box::new_in((*(inode_addr as *const Inode)).clone(), &INODE_CACHE))
         }
     }
 
-    fn parse_directory_block(&self, block: &[u8]) -> FsResult<Vec<Ext2DirectoryEntry>> {
+    async fn parse_directory_block(&self, block: &[u8]) -> FsResult<Vec<CachedDirectoryEntry>> {
         let mut result = Vec::new();
         let buffer_addr = block as *const [u8] as *const u8 as u64;
         let mut offset = 0;
@@ -257,12 +283,12 @@ impl Ext2Filesystem {
                                                         Some(InvalidCharPolicy::ReplaceWithUnknownSymbol)
                 ).unwrap()
             };
-            let entry_node = self.read_inode(dir_entry.inode as u64)?;
-            result.push(Ext2DirectoryEntry {
+            let entry_node = self.read_inode(dir_entry.inode as u64).await?;
+            result.push(Box::new_in(Ext2DirectoryEntry {
                 file_name,
                 entry_type: DirectoryEntryType::from(InodeType::from_u16(entry_node.type_and_permissions).unwrap()),
                 inode: dir_entry.inode
-            });
+            }, &DIRECTORY_ENTRY_CACHE));
             offset += dir_entry.total_entry_size as usize;
         }
     }
@@ -291,29 +317,28 @@ impl Ext2Filesystem {
     }
 
     // TODO: support indirect pointers
-    fn list_single_directory_internal(&self, node: &Inode) -> FsResult<Vec<Ext2DirectoryEntry>> {
+    async fn list_single_directory_internal(&self, node: &Inode) -> FsResult<Vec<CachedDirectoryEntry>> {
         let mut result = Vec::new();
         for block_num in node.direct_block_pointers.iter() {
             if *block_num != 0 {
                 // assuming the 4k blocks line up is lazy but whatever, its temporary
-                let block = self.media.read(*block_num as u64)?;
-                result.append(&mut self.parse_directory_block(&block)?);
+                let block = self.media.read(*block_num as u64).await?;
+                result.append(&mut self.parse_directory_block(&block).await?);
             }
         }
         Ok(result)
     }
-}
-impl Filesystem for Ext2Filesystem {
-    fn list_directory(&self, path: &Path) -> FsResult<Vec<VfsDirectoryEntry>> {
+
+    async fn list_directory_internal(&self, path: &Path) -> FsResult<Vec<VfsDirectoryEntry>> {
         if self.block_size != 4096 {
             panic!("Only block sizes of 4096 are currently supported");
             // TODO: add support for other block sizes once ATA convenience functions are done
         }
 
-        let mut current_node = self.read_inode(ROOT_INODE).unwrap();
+        let mut current_node = self.read_inode(ROOT_INODE).await?;
         // skip root
         for segment in path.iter().skip(1) {
-            let entries = self.list_single_directory_internal(&current_node)?;
+            let entries = self.list_single_directory_internal(&current_node).await?;
             for e in entries {
                 if e.file_name.as_str() == segment {
                     // found matching entry
@@ -322,25 +347,130 @@ impl Filesystem for Ext2Filesystem {
                         return Err(FsError::PathContainsFileAsDirectory);
                     }
                     // set current node to this one, check next segment
-                    current_node = self.read_inode(e.inode as u64).unwrap();
+                    current_node = self.read_inode(e.inode as u64).await?;
                 }
             }
         }
         // if we've made it here, we've traversed the whole path,
         // and `current_node` points to the last segment in the path
-        let dir_contents = self.list_single_directory_internal(&current_node)?;
+        let dir_contents = self.list_single_directory_internal(&current_node).await?;
         // need to convert to generic vfs entries
         let mut result = Vec::new();
-        for e in dir_contents {
+        for e in dir_contents.iter() {
             result.push(VfsDirectoryEntry {
                 file_name: e.file_name.clone(),
-                full_path: path.clone() / Path::from(e.file_name),
-                entry_type: e.entry_type.into(),
+                full_path: path.clone() / Path::from(e.file_name.clone()),
+                entry_type: e.entry_type.clone().into(),
                 inode: e.inode
             });
         }
         Ok(result)
     }
+
+    /// Follow `path` from the root directory to the inode it names
+    async fn find_inode(&self, path: &Path) -> FsResult<CachedInode> {
+        let mut current_node = self.read_inode(ROOT_INODE).await?;
+        // skip root
+        for segment in path.iter().skip(1) {
+            if InodeType::from_u16(current_node.type_and_permissions) != Some(InodeType::Directory) {
+                return Err(FsError::PathContainsFileAsDirectory);
+            }
+            let entries = self.list_single_directory_internal(&current_node).await?;
+            let entry = entries.iter()
+                .find(|e| e.file_name.as_str() == segment)
+                .ok_or(FsError::FileNotFound)?;
+            current_node = self.read_inode(entry.inode as u64).await?;
+        }
+        Ok(current_node)
+    }
+
+    /// Find the filesystem block holding block number `index` of an inode's data, following the
+    /// indirect block pointers as needed. Resolves to 0 for holes in sparse files.
+    async fn data_block(&self, node: &Inode, index: u64) -> FsResult<u32> {
+        let pointers_per_block = self.block_size as u64 / 4;
+        if index < 12 {
+            return Ok(node.direct_block_pointers[index as usize]);
+        }
+        // find the table to start from and how many levels of tables there are below it
+        let mut index = index - 12;
+        let (mut pointer, levels) = if index < pointers_per_block {
+            (node.singly_indirect_block_pointer, 1)
+        }
+        else if index - pointers_per_block < pointers_per_block.pow(2) {
+            index -= pointers_per_block;
+            (node.doubly_indirect_block_pointer, 2)
+        }
+        else if index - pointers_per_block - pointers_per_block.pow(2) < pointers_per_block.pow(3) {
+            index -= pointers_per_block + pointers_per_block.pow(2);
+            (node.triply_indirect_block_pointer, 3)
+        }
+        else {
+            return Err(FsError::OutOfBounds);
+        };
+
+        for level in (0..levels).rev() {
+            if pointer == 0 {
+                return Ok(0);
+            }
+            let table = self.read_block(pointer as u64).await?;
+            let entry = ((index / pointers_per_block.pow(level)) % pointers_per_block) as usize;
+            pointer = LittleEndian::read_u32(&table[entry * 4..entry * 4 + 4]);
+        }
+        Ok(pointer)
+    }
+
+    async fn file_size_internal(&self, path: &Path) -> FsResult<u64> {
+        let node = self.find_inode(path).await?;
+        if InodeType::from_u16(node.type_and_permissions) != Some(InodeType::File) {
+            return Err(FsError::NotAFile);
+        }
+        Ok(node.size())
+    }
+
+    async fn read_file_internal(&self, path: &Path, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let node = self.find_inode(path).await?;
+        if InodeType::from_u16(node.type_and_permissions) != Some(InodeType::File) {
+            return Err(FsError::NotAFile);
+        }
+        let size = node.size();
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = core::cmp::min(buffer.len() as u64, size - offset) as usize;
+        let block_size = self.block_size as usize;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let offset_in_block = (pos % block_size as u64) as usize;
+            let count = core::cmp::min(len - done, block_size - offset_in_block);
+            match self.data_block(&node, pos / block_size as u64).await? {
+                // hole in a sparse file
+                0 => buffer[done..done + count].fill(0),
+                block_num => {
+                    let block = self.read_block(block_num as u64).await?;
+                    buffer[done..done + count].copy_from_slice(&block[offset_in_block..offset_in_block + count]);
+                }
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+}
+impl Filesystem for Ext2Filesystem {
+    fn type_as_str(&self) -> &'static str { "Ext2" }
+
+    fn list_directory<'a>(&'a self, path: &'a Path) -> FsFuture<'a, Vec<VfsDirectoryEntry>> {
+        Box::pin(self.list_directory_internal(path))
+    }
+
+    fn file_size<'a>(&'a self, path: &'a Path) -> FsFuture<'a, u64> {
+        Box::pin(self.file_size_internal(path))
+    }
+
+    fn read_file<'a>(&'a self, path: &'a Path, offset: u64, buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(self.read_file_internal(path, offset, buffer))
+    }
 }
 // TODO: NOT ACTUALLY THREAD SAFE
 unsafe impl Send for Ext2Filesystem {}
@@ -443,6 +573,12 @@ pub struct Inode {
     block_address_of_fragment: u32,
     os_specific_value_2: [u8; 12]
 }
+impl Inode {
+    /// Size of a regular file in bytes. The upper half is only used by regular files.
+    fn size(&self) -> u64 {
+        self.file_size_lower_half as u64 | (self.file_size_upper_half as u64) << 32
+    }
+}
 
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -0,0 +1,125 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Files read into memory so they can back file-backed areas.
+//!
+//! The page fault handler can't wait for a disk, so instead of reading pages when they're touched,
+//! a task reads the whole file into frames with [FilePages::read] and the area is filled from
+//! those. Use it as the source of [Backing::File].
+//!
+//! [Backing::File]: crate::memory::address_space::Backing::File
+
+use alloc::vec::Vec;
+use x86_64::structures::paging::PhysFrame;
+use crate::fs::Filesystem;
+use crate::memory::address_space::{PageSource, PAGE_SIZE};
+use crate::memory::frame;
+use crate::path::Path;
+use crate::PHYS_MEM_OFFSET;
+
+/// The contents of a file, a frame per page
+#[derive(Debug)]
+pub struct FilePages {
+    size: u64,
+    frames: Vec<PhysFrame>,
+}
+
+/// # Safety
+///
+/// Nothing else may be writing to the frame.
+unsafe fn contents<'a>(frame: PhysFrame) -> &'a mut [u8] {
+    let ptr = (PHYS_MEM_OFFSET + frame.start_address().as_u64()) as *mut u8;
+    unsafe { core::slice::from_raw_parts_mut(ptr, PAGE_SIZE as usize) }
+}
+
+impl FilePages {
+    /// Reads all of the file at `path`
+    pub async fn read(fs: &dyn Filesystem, path: &Path) -> anyhow::Result<Self> {
+        let size = fs.file_size(path).await.map_err(|e| anyhow::anyhow!("{}: {:?}", path, e))?;
+        // frames are freed on drop if reading fails half way
+        let mut pages = Self { size, frames: Vec::with_capacity(((size + PAGE_SIZE - 1) / PAGE_SIZE) as usize) };
+        for offset in (0..size).step_by(PAGE_SIZE as usize) {
+            let frame = frame::allocate_frame_or_reclaim().ok_or_else(|| anyhow::anyhow!("{}: out of memory", path))?;
+            pages.frames.push(frame);
+            let page = unsafe { contents(frame) };
+            let read = fs.read_file(path, offset, page).await.map_err(|e| anyhow::anyhow!("{}: {:?}", path, e))?;
+            page[read..].fill(0);
+        }
+        Ok(pages)
+    }
+
+    /// Size of the file in bytes
+    pub fn size(&self) -> u64 { self.size }
+}
+
+impl PageSource for FilePages {
+    fn read_page(&self, offset: u64, page: &mut [u8]) -> anyhow::Result<()> {
+        match self.frames.get((offset / PAGE_SIZE) as usize) {
+            Some(&frame) => page.copy_from_slice(unsafe { contents(frame) }),
+            None => page.fill(0),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FilePages {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            unsafe { frame::free_frame(frame) };
+        }
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+/// A filesystem with one file, whatever the path
+#[cfg(test)]
+#[derive(Debug)]
+struct MemoryFile(Vec<u8>);
+
+#[cfg(test)]
+impl Filesystem for MemoryFile {
+    fn type_as_str(&self) -> &'static str { "Memory" }
+
+    fn list_directory<'a>(&'a self, _path: &'a Path) -> crate::fs::FsFuture<'a, Vec<crate::fs::VfsDirectoryEntry>> {
+        alloc::boxed::Box::pin(async { Err(crate::fs::FsError::NotSupported) })
+    }
+
+    fn file_size<'a>(&'a self, _path: &'a Path) -> crate::fs::FsFuture<'a, u64> {
+        alloc::boxed::Box::pin(async move { Ok(self.0.len() as u64) })
+    }
+
+    fn read_file<'a>(&'a self, _path: &'a Path, offset: u64, buffer: &'a mut [u8]) -> crate::fs::FsFuture<'a, usize> {
+        alloc::boxed::Box::pin(async move {
+            let data = self.0.get(offset as usize..).unwrap_or(&[]);
+            let count = core::cmp::min(data.len(), buffer.len());
+            buffer[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        })
+    }
+}
+
+#[test_case]
+fn test_file_pages() {
+    use alloc::sync::Arc;
+    use futures_util::FutureExt;
+    use crate::memory::address_space::{with_kernel_space, Backing, Protection};
+    let data: Vec<u8> = (0..PAGE_SIZE as usize + 100).map(|i| (i / 7) as u8).collect();
+    let file = MemoryFile(data.clone());
+    let pages = FilePages::read(&file, &Path::from("/file")).now_or_never().unwrap().unwrap();
+    assert_eq!(pages.size(), data.len() as u64);
+    let source: Arc<dyn PageSource> = Arc::new(pages);
+
+    // a page longer than the file, which reads as zeros
+    let backing = Backing::File { source: source.clone(), offset: 0 };
+    let area = with_kernel_space(|space| space.allocate_lazy(3 * PAGE_SIZE, PAGE_SIZE, Protection::READ, backing, "test file pages"))
+        .unwrap();
+    let mapped = unsafe { core::slice::from_raw_parts(area.as_ptr::<u8>(), 3 * PAGE_SIZE as usize) };
+    assert_eq!(&mapped[..data.len()], &data[..]);
+    assert!(mapped[data.len()..].iter().all(|&b| b == 0));
+    with_kernel_space(|space| space.unmap(area)).unwrap();
+    crate::memory::page_cache::invalidate(&source);
+}
@@ -8,15 +8,22 @@ use crate::device::block::BlockDeviceError;
 use crate::path::Path;
 use alloc::vec::Vec;
 use alloc::string::String;
-use crate::fs::ext2::FsHandle;
+use crate::fs::ext2::{Ext2Filesystem, FsHandle};
+use crate::device::block::BlockDevice;
 use core::fmt::Debug;
+use futures_util::future::LocalBoxFuture;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 
 pub mod fat32;
 pub mod ext2;
 pub mod vfs;
 pub mod partition;
+/// Files read into memory ahead of time so they can be mapped
+pub mod file_pages;
 
 pub type FsResult<T> = Result<T, FsError>;
+pub type FsFuture<'a, T> = LocalBoxFuture<'a, FsResult<T>>;
 
 #[derive(Debug, Clone, Copy)]
 pub enum FsError {
@@ -39,7 +46,11 @@ pub enum FsError {
     /// Path is not mounted
     PathNotMounted,
     /// Tried to ls a file (e.g. `ls /a/b/c` where `b` is a file
-    PathContainsFileAsDirectory
+    PathContainsFileAsDirectory,
+    /// Tried to read or write something that isn't a regular file, like a directory
+    NotAFile,
+    /// The filesystem doesn't implement this operation
+    NotSupported,
 }
 impl From<BlockDeviceError> for FsError {
     fn from(e: BlockDeviceError) -> Self {
@@ -56,7 +67,32 @@ impl FilesystemStatic {
 
 /// Generic filesystem interface
 pub trait Filesystem: Send + Sync + Debug {
-    fn list_directory(&self, path: &Path) -> FsResult<Vec<VfsDirectoryEntry>>;
+    /// Short name of the filesystem type, like "Ext2"
+    fn type_as_str(&self) -> &'static str;
+    fn list_directory<'a>(&'a self, path: &'a Path) -> FsFuture<'a, Vec<VfsDirectoryEntry>>;
+    /// Size of the file at `path` in bytes
+    fn file_size<'a>(&'a self, _path: &'a Path) -> FsFuture<'a, u64> {
+        Box::pin(async { Err(FsError::NotSupported) })
+    }
+    /// Read from the file at `path` starting at byte `offset`. Resolves to the number of bytes read,
+    /// which is less than the length of `buffer` if the end of the file was reached.
+    fn read_file<'a>(&'a self, _path: &'a Path, _offset: u64, _buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::NotSupported) })
+    }
+    /// Write to the file at `path` starting at byte `offset`. Resolves to the number of bytes written.
+    fn write_file<'a>(&'a self, _path: &'a Path, _offset: u64, _buffer: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::NotSupported) })
+    }
+}
+
+/// Try each filesystem driver on `device`, returning the filesystem from the first one that recognizes it
+pub async fn probe(device: &Arc<BlockDevice>) -> Option<Arc<dyn Filesystem>> {
+    match unsafe { Ext2Filesystem::read_from(device) }.await {
+        Ok(fs) if fs.block_size == 4096 => return Some(Arc::new(fs)),
+        Ok(fs) => crate::serial_println!("ext2: block size {} isn't supported yet", fs.block_size),
+        Err(_) => {},
+    }
+    None
 }
 #[derive(Debug, Clone)]
 pub struct VfsDirectoryEntry {
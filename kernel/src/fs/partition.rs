@@ -4,11 +4,41 @@
 // See LICENSE.txt and CREDITS.txt for details
 ///////////////////////////////////////////////////////////////////////////////L
 
+use alloc::vec;
 use alloc::vec::Vec;
 use crate::util::UUID;
 use alloc::string::String;
 use core::ops::Range;
 use core::fmt::{Debug, Formatter};
+use byteorder::{ByteOrder, LittleEndian};
+use crate::device::block::BlockDeviceError;
+use crate::device::physical::SyncDisk;
+use crate::util::{crc32, random_u64};
+
+/// Offset of the partition entries in the MBR
+const MBR_ENTRIES_OFFSET: usize = 0x1BE;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE: u16 = 0xAA55;
+/// MBR partition type of the single partition covering a GPT disk
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// CHS address in an MBR entry that tells the reader to use the LBA fields instead
+const MBR_CHS_LBA: [u8; 3] = [0xFE, 0xFF, 0xFF];
+/// Entries in new tables, the minimum the spec allows
+const GPT_DEFAULT_ENTRIES: u32 = 128;
+/// Tables with more entries than this aren't supported
+const GPT_MAX_ENTRIES: u32 = 1024;
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Partition names are UTF-16, in 72 bytes
+const GPT_NAME_LENGTH: usize = 36;
+
+// GPT partition type GUIDs, in their on-disk mixed-endian byte order
+const GPT_TYPE_EFI_SYSTEM: [u8; 16] = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+const GPT_TYPE_BASIC_DATA: [u8; 16] = [0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7];
+const GPT_TYPE_LINUX_FS: [u8; 16] = [0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4];
+const GPT_TYPE_LINUX_SWAP: [u8; 16] = [0x6D, 0xFD, 0x57, 0x06, 0xAB, 0xA4, 0xC4, 0x43, 0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F];
 
 #[derive(Debug, Clone, Copy)]
 pub enum PartitionType {
@@ -27,11 +57,362 @@ pub enum PartitionType {
     Unknown,
 }
 
+impl PartitionType {
+    fn from_mbr_type(id: u8) -> Self {
+        match id {
+            0x00 => PartitionType::FreeSpace,
+            0x05 | 0x0F | 0x85 => PartitionType::Container,
+            0x82 => PartitionType::Swap,
+            0x01 | 0x04 | 0x06 | 0x07 | 0x0B | 0x0C | 0x0E | 0x83 => PartitionType::Filesystem,
+            0x11 | 0x14 | 0x16 | 0x17 | 0x1B | 0x1C | 0x1E => PartitionType::HiddenFilesystem,
+            0x27 => PartitionType::Recovery,
+            0xEF => PartitionType::Service,
+            _ => PartitionType::Unknown,
+        }
+    }
+
+    /// MBR type ID for new partitions of this kind
+    fn to_mbr_type(&self) -> Option<u8> {
+        match self {
+            PartitionType::Filesystem => Some(0x83),
+            PartitionType::Swap => Some(0x82),
+            PartitionType::Service => Some(0xEF),
+            _ => None,
+        }
+    }
+
+    /// GPT type GUID for new partitions of this kind
+    fn to_gpt_type(&self) -> Option<[u8; 16]> {
+        match self {
+            PartitionType::Filesystem => Some(GPT_TYPE_LINUX_FS),
+            PartitionType::Swap => Some(GPT_TYPE_LINUX_SWAP),
+            PartitionType::Service => Some(GPT_TYPE_EFI_SYSTEM),
+            _ => None,
+        }
+    }
+
+    fn from_gpt_type(guid: &[u8]) -> Self {
+        if guid.iter().all(|b| *b == 0) { PartitionType::FreeSpace }
+        else if guid == GPT_TYPE_EFI_SYSTEM { PartitionType::Service }
+        else if guid == GPT_TYPE_BASIC_DATA || guid == GPT_TYPE_LINUX_FS { PartitionType::Filesystem }
+        else if guid == GPT_TYPE_LINUX_SWAP { PartitionType::Swap }
+        else { PartitionType::Unknown }
+    }
+}
+
 #[derive(Debug)]
 pub enum PartitionTable {
     MBR(MbrPartitionTable),
     GPT(GptPartitionTable),
 }
+impl PartitionTable {
+    /// Read the partition table from the start of `disk`.
+    /// Resolves to `None` if the disk doesn't have a partition table we understand.
+    pub async fn read(disk: &SyncDisk) -> Result<Option<Self>, BlockDeviceError> {
+        Self::read_internal(disk, false).await
+    }
+
+    /// Like [PartitionTable::read], but keeps an MBR without any partitions, like one that was just created
+    pub async fn read_for_editing(disk: &SyncDisk) -> Result<Option<Self>, BlockDeviceError> {
+        Self::read_internal(disk, true).await
+    }
+
+    async fn read_internal(disk: &SyncDisk, keep_empty: bool) -> Result<Option<Self>, BlockDeviceError> {
+        let sector_size = disk.block_length().await
+            .map_err(|_| BlockDeviceError::MediaError)? as usize;
+        if sector_size < 512 {
+            return Ok(None);
+        }
+        let mut mbr = vec![0u8; sector_size];
+        read_sectors(disk, 0, &mut mbr).await?;
+        if LittleEndian::read_u16(&mbr[510..512]) != MBR_SIGNATURE {
+            return Ok(None);
+        }
+
+        let entries: Vec<&[u8]> = (0..4)
+            .map(|i| &mbr[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..MBR_ENTRIES_OFFSET + (i + 1) * MBR_ENTRY_SIZE])
+            .collect();
+        if entries.iter().any(|entry| entry[4] == MBR_TYPE_GPT_PROTECTIVE) {
+            return Ok(read_gpt(disk, sector_size).await?.map(PartitionTable::GPT));
+        }
+
+        let mut partitions = Vec::new();
+        for (index, entry) in entries.into_iter().enumerate() {
+            let partition_type = PartitionType::from_mbr_type(entry[4]);
+            let first_sector = LittleEndian::read_u32(&entry[8..12]);
+            let sector_count = LittleEndian::read_u32(&entry[12..16]);
+            if matches!(partition_type, PartitionType::FreeSpace) || sector_count == 0 {
+                continue;
+            }
+            partitions.push(MbrPartition {
+                media: disk.clone(),
+                first_sector,
+                last_sector: first_sector.saturating_add(sector_count - 1),
+                partition_type,
+                index: index as u8,
+                type_id: entry[4],
+                bootable: entry[0] & 0x80 != 0,
+            });
+        }
+        // a boot sector with the signature but no partitions, e.g. a FAT floppy
+        if partitions.is_empty() && !keep_empty {
+            return Ok(None);
+        }
+        Ok(Some(PartitionTable::MBR(MbrPartitionTable {
+            partitions,
+            disk_signature: LittleEndian::read_u32(&mbr[0x1B8..0x1BC]),
+            copy_protected: LittleEndian::read_u16(&mbr[0x1BC..0x1BE]) == 0x5A5A,
+            disk_sectors: disk_sectors(disk, sector_size),
+        })))
+    }
+
+    /// Take the partitions out of the table
+    pub fn into_partitions(self) -> Vec<Partition> {
+        match self {
+            PartitionTable::MBR(table) => table.partitions.into_iter().map(Partition::MBR).collect(),
+            PartitionTable::GPT(table) => table.partitions.into_iter().map(Partition::GPT).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            PartitionTable::MBR(table) => table.partitions.len(),
+            PartitionTable::GPT(table) => table.partitions.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// First and last sector of every partition, in table order
+    pub fn sector_ranges(&self) -> Vec<(u64, u64)> {
+        match self {
+            PartitionTable::MBR(table) => table.used(None),
+            PartitionTable::GPT(table) => table.used(None),
+        }
+    }
+
+    /// The furthest the end of partition `index` can be moved without running into something
+    pub fn max_end(&self, index: usize) -> Option<u64> {
+        let ranges = self.sector_ranges();
+        let (first, _) = *ranges.get(index)?;
+        let limit = match self {
+            PartitionTable::MBR(table) => table.max_sector(),
+            PartitionTable::GPT(table) => table.last_usable_lba,
+        };
+        Some(ranges.iter().map(|(start, _)| *start).filter(|start| *start > first).map(|start| start - 1).fold(limit, core::cmp::min))
+    }
+
+    /// See [MbrPartitionTable::free_space] and [GptPartitionTable::free_space]
+    pub fn free_space(&self, sectors: Option<u64>, align: u64) -> Option<(u64, u64)> {
+        match self {
+            PartitionTable::MBR(table) => table.free_space(sectors, align),
+            PartitionTable::GPT(table) => table.free_space(sectors, align),
+        }
+    }
+
+    /// Add a partition of the given kind. MBR partitions can't have names.
+    /// Returns the position of the new partition in the table.
+    pub fn create(&mut self, disk: &SyncDisk, first: u64, last: u64, kind: PartitionType, name: &str) -> Result<usize, anyhow::Error> {
+        match self {
+            PartitionTable::MBR(table) => {
+                if !name.is_empty() {
+                    return Err(anyhow::anyhow!("partition table: MBR partitions don't have names"));
+                }
+                let type_id = kind.to_mbr_type()
+                    .ok_or_else(|| anyhow::anyhow!("partition table: can't create {:?} partitions on MBR", kind))?;
+                table.create(disk, first, last, type_id)
+            },
+            PartitionTable::GPT(table) => {
+                let type_guid = kind.to_gpt_type()
+                    .ok_or_else(|| anyhow::anyhow!("partition table: can't create {:?} partitions on GPT", kind))?;
+                table.create(disk, first, last, type_guid, name)
+            },
+        }
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<(), anyhow::Error> {
+        match self {
+            PartitionTable::MBR(table) => table.delete(index).map(|_| ()),
+            PartitionTable::GPT(table) => table.delete(index).map(|_| ()),
+        }
+    }
+
+    /// Move the end of a partition. Its contents aren't touched, so shrink the filesystem first.
+    pub fn resize(&mut self, index: usize, last: u64) -> Result<(), anyhow::Error> {
+        match self {
+            PartitionTable::MBR(table) => table.resize(index, last),
+            PartitionTable::GPT(table) => table.resize(index, last),
+        }
+    }
+
+    pub fn rename(&mut self, index: usize, name: &str) -> Result<(), anyhow::Error> {
+        match self {
+            PartitionTable::MBR(_) => Err(anyhow::anyhow!("partition table: MBR partitions don't have names")),
+            PartitionTable::GPT(table) => table.rename(index, name),
+        }
+    }
+
+    /// Write the table to `disk`, replacing whatever table was there
+    pub async fn write(&self, disk: &SyncDisk) -> Result<(), anyhow::Error> {
+        match self {
+            PartitionTable::MBR(table) => table.write(disk).await,
+            PartitionTable::GPT(table) => table.write(disk).await,
+        }
+    }
+}
+
+/// Size of `disk` in sectors, or as big as anything can address if it doesn't know
+fn disk_sectors(disk: &SyncDisk, sector_size: usize) -> u64 {
+    disk.size().map_or(u64::MAX, |size| size / sector_size as u64)
+}
+
+/// Read whole sectors starting at `sector` into `buffer`
+async fn read_sectors(disk: &SyncDisk, sector: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+    disk.read(sector, buffer).await.map(|_| ()).map_err(|e| {
+        crate::serial_println!("Partition table read failed: {}", e);
+        BlockDeviceError::MediaError
+    })
+}
+
+/// Read the GPT, falling back to the backup at the end of the disk if the primary one is damaged
+async fn read_gpt(disk: &SyncDisk, sector_size: usize) -> Result<Option<GptPartitionTable>, BlockDeviceError> {
+    if let Some(table) = read_gpt_at(disk, sector_size, 1).await? {
+        return Ok(Some(table));
+    }
+    let disk_sectors = disk_sectors(disk, sector_size);
+    if disk_sectors != u64::MAX && disk_sectors > 2 {
+        if let Some(table) = read_gpt_at(disk, sector_size, disk_sectors - 1).await? {
+            crate::both_println!("WARNING: primary GPT is damaged, using the backup");
+            return Ok(Some(table));
+        }
+    }
+    crate::serial_println!("Disk has a protective MBR but no valid GPT header");
+    Ok(None)
+}
+
+/// Read the GPT header at `lba` and its partition entries. Resolves to `None` if either fails its checksum.
+async fn read_gpt_at(disk: &SyncDisk, sector_size: usize, lba: u64) -> Result<Option<GptPartitionTable>, BlockDeviceError> {
+    let mut header = vec![0u8; sector_size];
+    read_sectors(disk, lba, &mut header).await?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = LittleEndian::read_u32(&header[12..16]) as usize;
+    if header_size < GPT_HEADER_SIZE || header_size > sector_size || LittleEndian::read_u64(&header[24..32]) != lba {
+        return Ok(None);
+    }
+    let header_crc = LittleEndian::read_u32(&header[16..20]);
+    LittleEndian::write_u32(&mut header[16..20], 0);
+    if crc32(&header[..header_size]) != header_crc {
+        crate::serial_println!("GPT header at LBA {} has a bad checksum", lba);
+        return Ok(None);
+    }
+
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&header[56..72]);
+    let first_usable_lba = LittleEndian::read_u64(&header[40..48]);
+    let last_usable_lba = LittleEndian::read_u64(&header[48..56]);
+    let entries_lba = LittleEndian::read_u64(&header[72..80]);
+    let entry_count = LittleEndian::read_u32(&header[80..84]);
+    let entry_size = LittleEndian::read_u32(&header[84..88]) as usize;
+    if entry_size < GPT_ENTRY_SIZE || entry_count > GPT_MAX_ENTRIES {
+        return Err(BlockDeviceError::OutOfBounds);
+    }
+
+    let entries_len = entry_count as usize * entry_size;
+    let table_sectors = (entries_len + sector_size - 1) / sector_size;
+    let mut table = vec![0u8; table_sectors * sector_size];
+    read_sectors(disk, entries_lba, &mut table).await?;
+    if crc32(&table[..entries_len]) != LittleEndian::read_u32(&header[88..92]) {
+        crate::serial_println!("GPT partition entries at LBA {} have a bad checksum", entries_lba);
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in table.chunks_exact(entry_size).take(entry_count as usize).enumerate() {
+        let partition_type = PartitionType::from_gpt_type(&entry[0..16]);
+        if matches!(partition_type, PartitionType::FreeSpace) {
+            continue;
+        }
+        let mut type_guid = [0u8; 16];
+        type_guid.copy_from_slice(&entry[0..16]);
+        let mut part_uuid = [0u8; 16];
+        part_uuid.copy_from_slice(&entry[16..32]);
+        let first_lba = LittleEndian::read_u64(&entry[32..40]);
+        let last_lba = LittleEndian::read_u64(&entry[40..48]);
+        let name: String = core::char::decode_utf16(entry[56..128].chunks_exact(2).map(LittleEndian::read_u16))
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .take_while(|c| *c != '\0')
+            .collect();
+        partitions.push(GptPartition {
+            media: disk.clone(),
+            first_sector: first_lba as u32,
+            last_sector: last_lba as u32,
+            partition_type,
+            uuid: UUID(part_uuid),
+            first_lba,
+            last_lba,
+            flags: LittleEndian::read_u64(&entry[48..56]),
+            name,
+            index: index as u32,
+            type_guid,
+        });
+    }
+    Ok(Some(GptPartitionTable {
+        partitions,
+        uuid: UUID(uuid),
+        partition_entry_size: entry_size as u32,
+        entry_count,
+        first_usable_lba,
+        last_usable_lba,
+        disk_sectors: disk_sectors(disk, sector_size),
+    }))
+}
+
+/// Check that `first..=last` lies within `min..=max` and doesn't overlap any of `others`
+fn check_placement(first: u64, last: u64, min: u64, max: u64, others: &[(u64, u64)]) -> Result<(), anyhow::Error> {
+    if first > last || first < min || last > max {
+        return Err(anyhow::anyhow!("partition table: sectors {}-{} aren't inside the usable area {}-{}", first, last, min, max));
+    }
+    if let Some((a, b)) = others.iter().find(|(a, b)| first <= *b && *a <= last) {
+        return Err(anyhow::anyhow!("partition table: sectors {}-{} overlap the partition at {}-{}", first, last, a, b));
+    }
+    Ok(())
+}
+
+/// Find room for a partition of `sectors` sectors (or the biggest gap, if `None`) between `min` and `max`,
+/// starting on a multiple of `align`
+fn find_free_space(min: u64, max: u64, align: u64, mut used: Vec<(u64, u64)>, sectors: Option<u64>) -> Option<(u64, u64)> {
+    let align = core::cmp::max(align, 1);
+    used.sort_unstable();
+    let mut gaps = Vec::new();
+    let mut cursor = min;
+    for (first, last) in used {
+        if first > cursor {
+            gaps.push((cursor, first - 1));
+        }
+        cursor = core::cmp::max(cursor, last.saturating_add(1));
+    }
+    if cursor <= max {
+        gaps.push((cursor, max));
+    }
+
+    let mut best: Option<(u64, u64)> = None;
+    for (start, end) in gaps {
+        let start = (start + align - 1) / align * align;
+        if start > end || start < min {
+            continue;
+        }
+        match sectors {
+            Some(sectors) if end - start + 1 >= sectors => return Some((start, start + sectors - 1)),
+            Some(_) => {},
+            None => if best.map_or(true, |(a, b)| end - start > b - a) {
+                best = Some((start, end));
+            },
+        }
+    }
+    best
+}
 
 #[derive(Debug)]
 pub enum Partition {
@@ -39,12 +420,75 @@ pub enum Partition {
     GPT(GptPartition),
 }
 impl Partition {
-    pub fn read_bytes(&self, _addr_range: Range<u64>, _buffer: &mut [u8]) {
-        // TODO: there has to be a better way to do this
-        // match &self {
-        //     Partition::GPT(part) => part.media.read_bytes(addr_range, buffer),
-        //     Partition::MBR(part) => part.media.read_bytes(addr_range, buffer)
-        // }
+    /// The disk this partition is on
+    pub fn media(&self) -> &SyncDisk {
+        match &self {
+            Partition::GPT(part) => &part.media,
+            Partition::MBR(part) => &part.media,
+        }
+    }
+
+    /// First and last sector of the partition on its disk
+    pub fn sector_range(&self) -> (u64, u64) {
+        match &self {
+            Partition::GPT(part) => (part.first_lba, part.last_lba),
+            Partition::MBR(part) => (part.first_sector as u64, part.last_sector as u64),
+        }
+    }
+
+    /// Read the given byte range (relative to the start of the partition) into `buffer`
+    pub async fn read_bytes(&self, addr_range: Range<u64>, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let media = self.media();
+        let (first_sector, last_sector) = self.sector_range();
+        let sector_size = media.block_length().await
+            .map_err(|_| BlockDeviceError::MediaError)? as u64;
+
+        let start = first_sector * sector_size + addr_range.start;
+        let end = first_sector * sector_size + addr_range.end;
+        if addr_range.start >= addr_range.end || end > (last_sector + 1) * sector_size
+            || buffer.len() < (end - start) as usize {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        // the disk can only read whole sectors, so read everything that overlaps and copy out the middle
+        let first_read_sector = start / sector_size;
+        let last_read_sector = (end - 1) / sector_size;
+        let mut sectors = vec![0u8; ((last_read_sector - first_read_sector + 1) * sector_size) as usize];
+        if let Err(e) = media.read(first_read_sector, &mut sectors).await {
+            crate::serial_println!("Partition read failed: {}", e);
+            return Err(BlockDeviceError::MediaError);
+        }
+
+        let offset = (start - first_read_sector * sector_size) as usize;
+        let len = (end - start) as usize;
+        buffer[..len].copy_from_slice(&sectors[offset..offset + len]);
+        Ok(())
+    }
+
+    /// Tell the disk the given byte range (relative to the start of the partition) no longer holds data.
+    /// Only sectors that are completely inside the range are discarded.
+    pub async fn discard_bytes(&self, addr_range: Range<u64>) -> Result<(), BlockDeviceError> {
+        let media = self.media();
+        let (first_sector, last_sector) = self.sector_range();
+        let sector_size = media.block_length().await
+            .map_err(|_| BlockDeviceError::MediaError)? as u64;
+
+        let start = first_sector * sector_size + addr_range.start;
+        let end = first_sector * sector_size + addr_range.end;
+        if addr_range.start >= addr_range.end || end > (last_sector + 1) * sector_size {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        let first_discard_sector = (start + sector_size - 1) / sector_size;
+        let end_discard_sector = end / sector_size;
+        if first_discard_sector >= end_discard_sector {
+            return Ok(());
+        }
+        if let Err(e) = media.discard(first_discard_sector, end_discard_sector - first_discard_sector).await {
+            crate::serial_println!("Partition discard failed: {}", e);
+            return Err(BlockDeviceError::MediaError);
+        }
+        Ok(())
     }
 }
 
@@ -53,6 +497,99 @@ pub struct MbrPartitionTable {
     pub partitions: Vec<MbrPartition>,
     pub disk_signature: u32,
     pub copy_protected: bool,
+    /// Size of the disk in sectors
+    pub disk_sectors: u64,
+}
+impl MbrPartitionTable {
+    /// An empty table for a disk of `disk_sectors` sectors
+    pub fn new(disk_sectors: u64) -> Self {
+        Self { partitions: Vec::new(), disk_signature: random_u64() as u32, copy_protected: false, disk_sectors }
+    }
+
+    /// Last sector a partition can use. MBR can't describe anything past 2^32 sectors.
+    fn max_sector(&self) -> u64 {
+        core::cmp::min(self.disk_sectors, u32::MAX as u64).saturating_sub(1)
+    }
+
+    fn used(&self, except: Option<usize>) -> Vec<(u64, u64)> {
+        self.partitions.iter().enumerate()
+            .filter(|(i, _)| Some(*i) != except)
+            .map(|(_, part)| (part.first_sector as u64, part.last_sector as u64))
+            .collect()
+    }
+
+    /// Room for a partition of `sectors` sectors, or the biggest free space if `None`
+    pub fn free_space(&self, sectors: Option<u64>, align: u64) -> Option<(u64, u64)> {
+        find_free_space(1, self.max_sector(), align, self.used(None), sectors)
+    }
+
+    /// Add a partition in the first free slot. Returns its position in `partitions`.
+    pub fn create(&mut self, disk: &SyncDisk, first: u64, last: u64, type_id: u8) -> Result<usize, anyhow::Error> {
+        check_placement(first, last, 1, self.max_sector(), &self.used(None))?;
+        let index = (0..4u8).find(|i| !self.partitions.iter().any(|part| part.index == *i))
+            .ok_or_else(|| anyhow::anyhow!("partition table: all 4 MBR slots are taken"))?;
+        let position = self.partitions.iter().position(|part| part.index > index).unwrap_or(self.partitions.len());
+        self.partitions.insert(position, MbrPartition {
+            media: disk.clone(),
+            first_sector: first as u32,
+            last_sector: last as u32,
+            partition_type: PartitionType::from_mbr_type(type_id),
+            index,
+            type_id,
+            bootable: false,
+        });
+        Ok(position)
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<MbrPartition, anyhow::Error> {
+        if index >= self.partitions.len() {
+            return Err(anyhow::anyhow!("partition table: no partition {}", index));
+        }
+        Ok(self.partitions.remove(index))
+    }
+
+    pub fn resize(&mut self, index: usize, last: u64) -> Result<(), anyhow::Error> {
+        let first = self.partitions.get(index)
+            .ok_or_else(|| anyhow::anyhow!("partition table: no partition {}", index))?
+            .first_sector as u64;
+        check_placement(first, last, 1, self.max_sector(), &self.used(Some(index)))?;
+        self.partitions[index].last_sector = last as u32;
+        Ok(())
+    }
+
+    /// Write the table into the MBR, keeping the boot code that's there
+    pub async fn write(&self, disk: &SyncDisk) -> Result<(), anyhow::Error> {
+        let sector_size = disk.block_length().await? as usize;
+        let mut mbr = vec![0u8; sector_size];
+        disk.read(0, &mut mbr).await?;
+        for byte in mbr[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 4 * MBR_ENTRY_SIZE].iter_mut() {
+            *byte = 0;
+        }
+        for part in self.partitions.iter() {
+            let offset = MBR_ENTRIES_OFFSET + part.index as usize * MBR_ENTRY_SIZE;
+            let entry = &mut mbr[offset..offset + MBR_ENTRY_SIZE];
+            entry[0] = if part.bootable { 0x80 } else { 0 };
+            // CHS addresses that say "use the LBA fields"
+            entry[1..4].copy_from_slice(&MBR_CHS_LBA);
+            entry[4] = part.type_id;
+            entry[5..8].copy_from_slice(&MBR_CHS_LBA);
+            LittleEndian::write_u32(&mut entry[8..12], part.first_sector);
+            LittleEndian::write_u32(&mut entry[12..16], part.last_sector - part.first_sector + 1);
+        }
+        LittleEndian::write_u32(&mut mbr[0x1B8..0x1BC], self.disk_signature);
+        LittleEndian::write_u16(&mut mbr[0x1BC..0x1BE], if self.copy_protected { 0x5A5A } else { 0 });
+        LittleEndian::write_u16(&mut mbr[510..512], MBR_SIGNATURE);
+
+        // a GPT left behind would confuse anything that looks for one before the MBR
+        let mut header = vec![0u8; sector_size];
+        for lba in [1, self.disk_sectors.wrapping_sub(1)] {
+            if lba < self.disk_sectors && disk.read(lba, &mut header).await.is_ok() && &header[0..8] == GPT_SIGNATURE {
+                disk.write(lba, &vec![0u8; sector_size]).await?;
+            }
+        }
+        disk.write(0, &mbr).await?;
+        disk.flush().await
+    }
 }
 
 #[derive(Debug)]
@@ -60,23 +597,194 @@ pub struct GptPartitionTable {
     pub partitions: Vec<GptPartition>,
     pub uuid: UUID,
     pub partition_entry_size: u32,
+    /// Number of slots in the partition entry array
+    pub entry_count: u32,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// Size of the disk in sectors. The backup header is in the last one.
+    pub disk_sectors: u64,
+}
+impl GptPartitionTable {
+    /// An empty table for a disk of `disk_sectors` sectors
+    pub fn new(disk_sectors: u64, sector_size: usize) -> Result<Self, anyhow::Error> {
+        let table_sectors = Self::table_sectors_for(GPT_DEFAULT_ENTRIES, GPT_ENTRY_SIZE as u32, sector_size);
+        // protective MBR, two headers and two copies of the entries, plus at least one usable sector
+        if disk_sectors == u64::MAX || disk_sectors < 4 + 2 * table_sectors {
+            return Err(anyhow::anyhow!("partition table: disk is too small for GPT"));
+        }
+        Ok(Self {
+            partitions: Vec::new(),
+            uuid: UUID::random(),
+            partition_entry_size: GPT_ENTRY_SIZE as u32,
+            entry_count: GPT_DEFAULT_ENTRIES,
+            first_usable_lba: 2 + table_sectors,
+            last_usable_lba: disk_sectors - 2 - table_sectors,
+            disk_sectors,
+        })
+    }
+
+    fn table_sectors_for(entry_count: u32, entry_size: u32, sector_size: usize) -> u64 {
+        ((entry_count as usize * entry_size as usize + sector_size - 1) / sector_size) as u64
+    }
+
+    fn used(&self, except: Option<usize>) -> Vec<(u64, u64)> {
+        self.partitions.iter().enumerate()
+            .filter(|(i, _)| Some(*i) != except)
+            .map(|(_, part)| (part.first_lba, part.last_lba))
+            .collect()
+    }
+
+    fn get(&self, index: usize) -> Result<&GptPartition, anyhow::Error> {
+        self.partitions.get(index).ok_or_else(|| anyhow::anyhow!("partition table: no partition {}", index))
+    }
+
+    fn check_name(name: &str) -> Result<(), anyhow::Error> {
+        if name.encode_utf16().count() > GPT_NAME_LENGTH {
+            return Err(anyhow::anyhow!("partition table: names can be at most {} characters", GPT_NAME_LENGTH));
+        }
+        Ok(())
+    }
+
+    /// Room for a partition of `sectors` sectors, or the biggest free space if `None`
+    pub fn free_space(&self, sectors: Option<u64>, align: u64) -> Option<(u64, u64)> {
+        find_free_space(self.first_usable_lba, self.last_usable_lba, align, self.used(None), sectors)
+    }
+
+    /// Add a partition in the first free slot. Returns its position in `partitions`.
+    pub fn create(&mut self, disk: &SyncDisk, first: u64, last: u64, type_guid: [u8; 16], name: &str) -> Result<usize, anyhow::Error> {
+        check_placement(first, last, self.first_usable_lba, self.last_usable_lba, &self.used(None))?;
+        Self::check_name(name)?;
+        let index = (0..self.entry_count).find(|i| !self.partitions.iter().any(|part| part.index == *i))
+            .ok_or_else(|| anyhow::anyhow!("partition table: all {} GPT entries are taken", self.entry_count))?;
+        let position = self.partitions.iter().position(|part| part.index > index).unwrap_or(self.partitions.len());
+        self.partitions.insert(position, GptPartition {
+            media: disk.clone(),
+            first_sector: first as u32,
+            last_sector: last as u32,
+            partition_type: PartitionType::from_gpt_type(&type_guid),
+            uuid: UUID::random(),
+            first_lba: first,
+            last_lba: last,
+            flags: 0,
+            name: String::from(name),
+            index,
+            type_guid,
+        });
+        Ok(position)
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<GptPartition, anyhow::Error> {
+        self.get(index)?;
+        Ok(self.partitions.remove(index))
+    }
+
+    pub fn resize(&mut self, index: usize, last: u64) -> Result<(), anyhow::Error> {
+        let first = self.get(index)?.first_lba;
+        check_placement(first, last, self.first_usable_lba, self.last_usable_lba, &self.used(Some(index)))?;
+        self.partitions[index].last_lba = last;
+        self.partitions[index].last_sector = last as u32;
+        Ok(())
+    }
+
+    pub fn rename(&mut self, index: usize, name: &str) -> Result<(), anyhow::Error> {
+        self.get(index)?;
+        Self::check_name(name)?;
+        self.partitions[index].name = String::from(name);
+        Ok(())
+    }
+
+    /// The header that goes at `lba`, pointing at entries at `entries_lba`
+    fn header(&self, sector_size: usize, lba: u64, alternate_lba: u64, entries_lba: u64, entries_crc: u32) -> Vec<u8> {
+        let mut header = vec![0u8; sector_size];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        LittleEndian::write_u32(&mut header[8..12], GPT_REVISION);
+        LittleEndian::write_u32(&mut header[12..16], GPT_HEADER_SIZE as u32);
+        LittleEndian::write_u64(&mut header[24..32], lba);
+        LittleEndian::write_u64(&mut header[32..40], alternate_lba);
+        LittleEndian::write_u64(&mut header[40..48], self.first_usable_lba);
+        LittleEndian::write_u64(&mut header[48..56], self.last_usable_lba);
+        header[56..72].copy_from_slice(&self.uuid.0);
+        LittleEndian::write_u64(&mut header[72..80], entries_lba);
+        LittleEndian::write_u32(&mut header[80..84], self.entry_count);
+        LittleEndian::write_u32(&mut header[84..88], self.partition_entry_size);
+        LittleEndian::write_u32(&mut header[88..92], entries_crc);
+        let crc = crc32(&header[..GPT_HEADER_SIZE]);
+        LittleEndian::write_u32(&mut header[16..20], crc);
+        header
+    }
+
+    /// Write the protective MBR, both headers and both copies of the partition entries
+    pub async fn write(&self, disk: &SyncDisk) -> Result<(), anyhow::Error> {
+        let sector_size = disk.block_length().await? as usize;
+        let table_sectors = Self::table_sectors_for(self.entry_count, self.partition_entry_size, sector_size);
+        if self.disk_sectors == u64::MAX || self.disk_sectors < 4 + 2 * table_sectors {
+            return Err(anyhow::anyhow!("partition table: disk is too small for GPT"));
+        }
+        let backup_lba = self.disk_sectors - 1;
+        let backup_entries_lba = backup_lba - table_sectors;
+        if self.first_usable_lba < 2 + table_sectors || self.last_usable_lba >= backup_entries_lba {
+            return Err(anyhow::anyhow!("partition table: usable area overlaps the partition entries"));
+        }
+
+        let entry_size = self.partition_entry_size as usize;
+        let mut entries = vec![0u8; table_sectors as usize * sector_size];
+        for part in self.partitions.iter() {
+            let entry = &mut entries[part.index as usize * entry_size..(part.index as usize + 1) * entry_size];
+            entry[0..16].copy_from_slice(&part.type_guid);
+            entry[16..32].copy_from_slice(&part.uuid.0);
+            LittleEndian::write_u64(&mut entry[32..40], part.first_lba);
+            LittleEndian::write_u64(&mut entry[40..48], part.last_lba);
+            LittleEndian::write_u64(&mut entry[48..56], part.flags);
+            for (i, unit) in part.name.encode_utf16().take(GPT_NAME_LENGTH).enumerate() {
+                LittleEndian::write_u16(&mut entry[56 + i * 2..58 + i * 2], unit);
+            }
+        }
+        let entries_crc = crc32(&entries[..self.entry_count as usize * entry_size]);
+
+        // the protective MBR covers the whole disk, or as much of it as it can describe
+        let mut mbr = vec![0u8; sector_size];
+        disk.read(0, &mut mbr).await?;
+        for byte in mbr[MBR_ENTRIES_OFFSET..510].iter_mut() {
+            *byte = 0;
+        }
+        let entry = &mut mbr[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE];
+        entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+        entry[4] = MBR_TYPE_GPT_PROTECTIVE;
+        entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        LittleEndian::write_u32(&mut entry[8..12], 1);
+        LittleEndian::write_u32(&mut entry[12..16], core::cmp::min(self.disk_sectors - 1, u32::MAX as u64) as u32);
+        LittleEndian::write_u16(&mut mbr[510..512], MBR_SIGNATURE);
+
+        // backup first, so a crash halfway through still leaves one good copy to recover from
+        disk.write(backup_entries_lba, &entries).await?;
+        disk.write(backup_lba, &self.header(sector_size, backup_lba, 1, backup_entries_lba, entries_crc)).await?;
+        disk.flush().await?;
+        disk.write(2, &entries).await?;
+        disk.write(1, &self.header(sector_size, 1, backup_lba, 2, entries_crc)).await?;
+        disk.write(0, &mbr).await?;
+        disk.flush().await
+    }
 }
 
 pub struct MbrPartition {
-    //pub media: Arc<dyn StorageDriver>,
+    pub media: SyncDisk,
     pub first_sector: u32,
     pub last_sector: u32,
     pub partition_type: PartitionType,
+    /// Slot in the MBR (0-3)
+    pub index: u8,
+    pub type_id: u8,
+    pub bootable: bool,
 }
 impl Debug for MbrPartition {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "MbrPartition {{ media: Arc<dyn StorageDriver>, first_sector: {}, last_sector: {}, partition_type: {:?} }}",
-               self.first_sector, self.last_sector, self.partition_type)
+        write!(f, "MbrPartition {{ media: {:?}, first_sector: {}, last_sector: {}, partition_type: {:?} }}",
+               self.media, self.first_sector, self.last_sector, self.partition_type)
     }
 }
 
 pub struct GptPartition {
-    //pub media: Arc<dyn StorageDriver>,
+    pub media: SyncDisk,
     pub first_sector: u32,
     pub last_sector: u32,
     pub partition_type: PartitionType,
@@ -85,10 +793,119 @@ pub struct GptPartition {
     pub last_lba: u64,
     pub flags: u64,
     pub name: String,
+    /// Slot in the partition entry array
+    pub index: u32,
+    pub type_guid: [u8; 16],
 }
 impl Debug for GptPartition {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "GptPartition {{ media: Arc<dyn StorageDriver>, first_sector: {}, last_sector: {}, partition_type: {:?} }}",
-               self.first_sector, self.last_sector, self.partition_type)
+        write!(f, "GptPartition {{ media: {:?}, first_sector: {}, last_sector: {}, partition_type: {:?} }}",
+               self.media, self.first_sector, self.last_sector, self.partition_type)
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_read_mbr() {
+    use alloc::boxed::Box;
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    let mut image = vec![0u8; 512];
+    // one Linux partition at sector 8, 16 sectors long, and one empty entry
+    image[MBR_ENTRIES_OFFSET + 4] = 0x83;
+    LittleEndian::write_u32(&mut image[MBR_ENTRIES_OFFSET + 8..], 8);
+    LittleEndian::write_u32(&mut image[MBR_ENTRIES_OFFSET + 12..], 16);
+    LittleEndian::write_u16(&mut image[510..], MBR_SIGNATURE);
+    let disk = SyncDisk::new(Box::new(RamDisk::from_image(image, 32 * 512).unwrap()));
+
+    let table = PartitionTable::read(&disk).now_or_never().unwrap().unwrap().unwrap();
+    let partitions = table.into_partitions();
+    assert_eq!(partitions.len(), 1);
+    match &partitions[0] {
+        Partition::MBR(part) => {
+            assert_eq!((part.first_sector, part.last_sector), (8, 23));
+            assert!(matches!(part.partition_type, PartitionType::Filesystem));
+        },
+        Partition::GPT(_) => panic!("expected an MBR partition"),
+    }
+
+    let blank = SyncDisk::new(Box::new(RamDisk::new(32 * 512).unwrap()));
+    assert!(PartitionTable::read(&blank).now_or_never().unwrap().unwrap().is_none());
+}
+
+#[test_case]
+fn test_gpt_edit() {
+    use alloc::boxed::Box;
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    let disk = SyncDisk::new(Box::new(RamDisk::new(512 * 512).unwrap()));
+    let mut table = PartitionTable::GPT(GptPartitionTable::new(512, 512).unwrap());
+    // 34 sectors before the usable area and 33 after it
+    let (first, last) = table.free_space(Some(100), 8).unwrap();
+    assert_eq!((first, last), (40, 139));
+    table.create(&disk, first, last, PartitionType::Filesystem, "root").unwrap();
+    let (first, last) = table.free_space(None, 8).unwrap();
+    assert_eq!((first, last), (144, 478));
+    table.create(&disk, first, last, PartitionType::Swap, "swap").unwrap();
+    assert!(table.create(&disk, 100, 200, PartitionType::Filesystem, "").is_err());
+    table.write(&disk).now_or_never().unwrap().unwrap();
+
+    match PartitionTable::read(&disk).now_or_never().unwrap().unwrap().unwrap() {
+        PartitionTable::GPT(read) => {
+            assert_eq!(read.partitions.len(), 2);
+            assert_eq!((read.partitions[0].first_lba, read.partitions[0].last_lba), (40, 139));
+            assert_eq!(read.partitions[1].name, "swap");
+            assert!(matches!(read.partitions[1].partition_type, PartitionType::Swap));
+        },
+        PartitionTable::MBR(_) => panic!("expected GPT"),
+    }
+
+    table.delete(1).unwrap();
+    table.resize(0, 300).unwrap();
+    table.rename(0, "boot").unwrap();
+    table.write(&disk).now_or_never().unwrap().unwrap();
+    // wreck the primary header, the backup should take over
+    disk.write(1, &[0u8; 512]).now_or_never().unwrap().unwrap();
+    match PartitionTable::read(&disk).now_or_never().unwrap().unwrap().unwrap() {
+        PartitionTable::GPT(read) => {
+            assert_eq!(read.partitions.len(), 1);
+            assert_eq!((read.partitions[0].last_lba, read.partitions[0].name.as_str()), (300, "boot"));
+        },
+        PartitionTable::MBR(_) => panic!("expected GPT"),
+    }
+}
+
+#[test_case]
+fn test_mbr_edit() {
+    use alloc::boxed::Box;
+    use futures_util::FutureExt;
+    use crate::driver::ramdisk::RamDisk;
+
+    let disk = SyncDisk::new(Box::new(RamDisk::new(64 * 512).unwrap()));
+    let mut table = PartitionTable::MBR(MbrPartitionTable::new(64));
+    table.create(&disk, 8, 23, PartitionType::Filesystem, "").unwrap();
+    table.create(&disk, 24, 63, PartitionType::Swap, "").unwrap();
+    assert!(table.create(&disk, 30, 40, PartitionType::Filesystem, "").is_err());
+    assert!(table.rename(0, "nope").is_err());
+    table.delete(1).unwrap();
+    table.resize(0, 40).unwrap();
+    table.write(&disk).now_or_never().unwrap().unwrap();
+
+    match PartitionTable::read(&disk).now_or_never().unwrap().unwrap().unwrap() {
+        PartitionTable::MBR(read) => {
+            assert_eq!(read.partitions.len(), 1);
+            assert_eq!((read.partitions[0].first_sector, read.partitions[0].last_sector), (8, 40));
+            assert_eq!(read.partitions[0].type_id, 0x83);
+        },
+        PartitionTable::GPT(_) => panic!("expected MBR"),
     }
+    // an empty table is only kept when reading for editing
+    table.delete(0).unwrap();
+    table.write(&disk).now_or_never().unwrap().unwrap();
+    assert!(PartitionTable::read(&disk).now_or_never().unwrap().unwrap().is_none());
+    assert!(PartitionTable::read_for_editing(&disk).now_or_never().unwrap().unwrap().is_some());
 }
@@ -45,6 +45,12 @@ impl VFS {
         }
     }
 
+    /// True if `fs` is mounted anywhere
+    pub fn is_mounted(&self, fs: &Arc<dyn Filesystem>) -> bool {
+        // compare data pointers only, the same type can have more than one vtable
+        self.mounts.values().any(|mount| Arc::as_ptr(mount) as *const u8 == Arc::as_ptr(fs) as *const u8)
+    }
+
     pub fn fs_for_path(&self, path: &Path) -> FsResult<&Arc<dyn Filesystem>> {
         // store deepest match (we want to match a mount at /foo/bar with higher precedence than /)
         let mut deepest_path = Path::new();
@@ -69,12 +75,12 @@ impl VFS {
         }
     }
 
-    pub fn list_dir(&self, path: Path) -> FsResult<Vec<VfsDirectoryEntry>> {
+    pub async fn list_dir(&self, path: Path) -> FsResult<Vec<VfsDirectoryEntry>> {
         match self.fs_for_path(&path) {
             Ok(fs) => {
                 // we have a mount for this path.
                 // try to ls the path, forward any errors
-                fs.list_directory(&path)
+                fs.list_directory(&path).await
             },
             Err(e) => Err(e)
         }
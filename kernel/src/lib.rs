@@ -39,6 +39,7 @@
 #![feature(asm)]
 #![feature(const_mut_refs)]
 #![feature(const_fn_trait_bound)]
+#![feature(allocator_api)]
 
 #![cfg_attr(test, no_main)]
 #![test_runner(crate::test_runner)]
@@ -52,6 +53,8 @@ pub use chrono;
 pub mod acpi;
 /// Architecture-specific implementations
 pub mod arch;
+/// Software cryptography for disk encryption
+pub mod crypto;
 /// Devices that support reading and/or writing, physical or virtual
 pub mod device;
 /// Drivers for specific hardware
@@ -68,8 +71,8 @@ pub mod path;
 pub mod service;
 /// Interactive shell system and parser
 pub mod shell;
-/// Syncrhonization primitives
-//pub mod sync;
+/// Synchronization primitives
+pub mod sync;
 /// General utilities
 pub mod util;
 /// Text-mode VGA output
@@ -82,14 +85,14 @@ pub mod time;
 use core::panic::PanicInfo;
 use x86_64::VirtAddr;
 
-use crate::memory::{BootInfoFrameAllocator, AHCI_MEM_REGION};
-use x86_64::structures::paging::OffsetPageTable;
+use crate::memory::ISA_DMA_REGION;
+use crate::memory::frame::GlobalFrameAllocator;
 use x86_64::instructions::port::Port;
 use tinypci::PciDeviceInfo;
 use alloc::vec::Vec;
 use crate::vga_buffer::Color;
 use bootloader::bootinfo::{MemoryRegionType, MemoryRegion, FrameRange};
-use crate::driver::ahci::constants::AHCI_MEMORY_SIZE;
+use crate::driver::isa_dma::{ISA_DMA_BUFFER_SIZE, ISA_DMA_LIMIT};
 use spin::Mutex;
 use core::sync::atomic::Ordering;
 
@@ -215,21 +218,24 @@ impl Drop for StartupStep {
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct MemoryInitResults {
-    pub mapper: OffsetPageTable<'static>,
-    pub frame_allocator: BootInfoFrameAllocator,
+    pub frame_allocator: GlobalFrameAllocator,
 }
 
 pub fn init_memory(phys_mem_offset: VirtAddr) -> MemoryInitResults {
     let mut step = StartupStep::begin("Initializing heap");
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init() };
-    memory::allocator::init_heap(&mut mapper, &mut frame_allocator)
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init() };
+    let mut frame_allocator = GlobalFrameAllocator;
+    memory::allocator::init_heap(mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::mmio::init();
+    memory::address_space::init();
+    arch::gdt::init_stacks();
 
     memory::HAVE_ALLOC.store(true, Ordering::Relaxed);
 
     step.ok();
-    MemoryInitResults { mapper, frame_allocator }
+    MemoryInitResults { frame_allocator }
 }
 
 pub static PCI_DEVICES: Mutex<Vec<PciDeviceInfo>> = Mutex::new(Vec::new());
@@ -264,29 +270,32 @@ pub fn init_pci() {
 }
 
 pub fn init_memory_map(boot_info: &'static bootloader::BootInfo) {
-    // search memory map provided by bootloader for a free memory region for AHCI
-    let mut found_ahci_mem = None;
+    // search memory map provided by bootloader for an aligned buffer the ISA DMA controller can reach
+    let mut found_isa_dma_mem = None;
     {
         let mut step = StartupStep::begin("Building global memory map");
         let mut mmap_lock = memory::GLOBAL_MEMORY_MAP.lock();
         for region in boot_info.memory_map.iter() {
-            if found_ahci_mem.is_none() && region.region_type == MemoryRegionType::Usable &&
-                region.range.end_addr() - region.range.start_addr() >= AHCI_MEMORY_SIZE {
-                let ahci_region = MemoryRegion {
-                    range: FrameRange::new(region.range.start_addr(), region.range.start_addr() + AHCI_MEMORY_SIZE),
+            if let (None, Some(start)) = (found_isa_dma_mem, isa_dma_buffer_start(region)) {
+                let isa_dma_region = MemoryRegion {
+                    range: FrameRange::new(start, start + ISA_DMA_BUFFER_SIZE),
                     region_type: MemoryRegionType::InUse
                 };
-                let leftover = region.range.end_addr() - region.range.start_addr() - AHCI_MEMORY_SIZE;
-                let leftover_region = MemoryRegion {
-                    range: FrameRange::new(region.range.start_addr() + leftover, region.range.end_addr()),
-                    region_type: MemoryRegionType::Usable
-                };
-
-                mmap_lock.add_region(ahci_region);
-                mmap_lock.add_region(leftover_region);
-
-                found_ahci_mem = Some(ahci_region);
-                step.ok();
+                // keep whatever is left on either side usable
+                if start > region.range.start_addr() {
+                    mmap_lock.add_region(MemoryRegion {
+                        range: FrameRange::new(region.range.start_addr(), start),
+                        region_type: MemoryRegionType::Usable
+                    });
+                }
+                mmap_lock.add_region(isa_dma_region);
+                if start + ISA_DMA_BUFFER_SIZE < region.range.end_addr() {
+                    mmap_lock.add_region(MemoryRegion {
+                        range: FrameRange::new(start + ISA_DMA_BUFFER_SIZE, region.range.end_addr()),
+                        region_type: MemoryRegionType::Usable
+                    });
+                }
+                found_isa_dma_mem = Some(isa_dma_region);
             } else {
                 mmap_lock.add_region(*region);
             }
@@ -294,10 +303,26 @@ pub fn init_memory_map(boot_info: &'static bootloader::BootInfo) {
         for region in mmap_lock.iter() {
             crate::serial_println!("{:?}", region);
         }
+        step.ok();
+    }
+    // only the floppy driver needs this, so it's not fatal
+    if found_isa_dma_mem.is_none() {
+        crate::serial_println!("No memory below 16 MiB left for ISA DMA.");
+    }
+    *ISA_DMA_REGION.lock() = found_isa_dma_mem;
+}
+
+/// Start of an ISA DMA buffer inside `region`, if it's usable and reaches far enough below 16 MiB
+fn isa_dma_buffer_start(region: &MemoryRegion) -> Option<u64> {
+    if region.region_type != MemoryRegionType::Usable {
+        return None;
+    }
+    let start = (region.range.start_addr() + ISA_DMA_BUFFER_SIZE - 1) / ISA_DMA_BUFFER_SIZE * ISA_DMA_BUFFER_SIZE;
+    if start + ISA_DMA_BUFFER_SIZE <= core::cmp::min(region.range.end_addr(), ISA_DMA_LIMIT) {
+        Some(start)
+    } else {
+        None
     }
-    let found_ahci_mem = found_ahci_mem
-        .expect("Failed to find free space for AHCI memory.");
-    *AHCI_MEM_REGION.lock() = Some(found_ahci_mem);
 }
 
 //pub fn init_fs_service() {
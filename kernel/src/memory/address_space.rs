@@ -0,0 +1,609 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Virtual memory areas.
+//!
+//! An [AddressSpace] keeps track of which ranges of virtual memory are in use, what they're for
+//! and what's behind them, and maps them into its page table. The kernel has one, [KERNEL_SPACE],
+//! for mappings that used to be made by hand; processes will get their own from [AddressSpace::new_user],
+//! which share the kernel's part of the page table.
+//!
+//! Areas don't have to be mapped up front: the page fault handler calls [handle_page_fault], which
+//! maps the missing page if the access was allowed, zero-filled or read through the page cache.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt::{Debug, Display, Formatter};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::mapper::{MapToError, MapperFlush, UnmapError};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::frame::{self, FaultFrameAllocator, GlobalFrameAllocator, FRAME_SIZE};
+use crate::memory::mmio::{CacheMode, PAT_4KIB};
+use crate::memory::page_cache;
+use crate::PHYS_MEM_OFFSET;
+
+pub const PAGE_SIZE: u64 = FRAME_SIZE;
+/// Areas placed by [AddressSpace::allocate] have at least this much unmapped memory on either side,
+/// so running off the end of one faults instead of scribbling over the next
+pub const GUARD_SIZE: u64 = PAGE_SIZE;
+/// Virtual memory handed out by the kernel address space, out of the way of everything the bootloader maps
+pub const KERNEL_AREA_RANGE: Range<u64> = 0xFFFF_9000_0000_0000..0xFFFF_A000_0000_0000;
+/// Memory covered by one level 4 page table entry. User address spaces have to start and end on
+/// these, so they don't share any page tables with the kernel.
+const LEVEL_4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// The kernel's address space, set up by [init]
+pub static KERNEL_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+bitflags::bitflags! {
+    /// What an area may be used for
+    pub struct Protection: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+        /// Accessible from ring 3
+        const USER = 1 << 3;
+    }
+}
+
+/// Something that can fill the pages of a file-backed area. Pages are read from the page fault
+/// handler, so reading mustn't wait for anything, like a disk interrupt or a lock, and mustn't
+/// allocate. [crate::fs::file_pages::FilePages] reads a file in ahead of time for that.
+pub trait PageSource: Debug + Send + Sync {
+    /// Reads the page at `offset` bytes into the file. Anything past the end of the file reads as zeros.
+    fn read_page(&self, offset: u64, page: &mut [u8]) -> anyhow::Result<()>;
+}
+
+/// What's behind an area
+#[derive(Debug, Clone)]
+pub enum Backing {
+    /// Zero-filled memory from the frame allocator, freed along with the area
+    Anonymous,
+    /// A fixed range of physical memory starting at `base`, like device registers. It's left alone
+    /// when the area goes away.
+    Physical { base: PhysAddr, cache: CacheMode },
+    /// Private copies of the pages of a file, starting `offset` bytes into it
+    File { source: Arc<dyn PageSource>, offset: u64 },
+}
+
+/// What a faulting access was trying to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "instruction fetch",
+        })
+    }
+}
+
+/// A range of virtual memory in an [AddressSpace]
+#[derive(Debug, Clone)]
+pub struct Area {
+    pub start: VirtAddr,
+    /// Size in bytes, a multiple of [PAGE_SIZE]
+    pub size: u64,
+    pub protection: Protection,
+    pub backing: Backing,
+    /// What the area is for, for debugging
+    pub name: &'static str,
+}
+
+impl Area {
+    pub fn end(&self) -> VirtAddr { self.start + self.size }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    /// Whether the area's protection allows `access`, from user mode if `user` is set
+    pub fn allows(&self, access: Access, user: bool) -> bool {
+        let needed = match access {
+            Access::Read => Protection::READ,
+            Access::Write => Protection::WRITE,
+            Access::Execute => Protection::EXECUTE,
+        };
+        self.protection.contains(needed) && (!user || self.protection.contains(Protection::USER))
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end().as_u64() && self.start.as_u64() < end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let first = Page::containing_address(self.start);
+        (0..self.size / PAGE_SIZE).map(move |i| first + i)
+    }
+
+    /// Page table flags for mapping pages of this area
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.protection.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.protection.contains(Protection::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if self.protection.contains(Protection::USER) {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if let Backing::Physical { cache, .. } = self.backing {
+            flags |= cache.page_flags();
+        }
+        flags
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    /// Start or size isn't a multiple of the page size
+    Unaligned,
+    /// The range is outside the address space or overlaps an existing area
+    Overlap,
+    /// There's no free range big enough
+    OutOfVirtualMemory,
+    /// There are no free frames for the area's pages or their page tables
+    OutOfMemory,
+    /// There's no area at that address
+    NotFound,
+    /// Reading a page of a file-backed area failed
+    ReadFailed,
+    /// The area doesn't allow that kind of access
+    AccessDenied,
+    /// A page fault couldn't be handled because the address space was locked, so it happened
+    /// while the address space was being changed
+    Locked,
+}
+
+impl Display for AddressSpaceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            AddressSpaceError::Unaligned => "address or size isn't page aligned",
+            AddressSpaceError::Overlap => "range overlaps an existing area",
+            AddressSpaceError::OutOfVirtualMemory => "out of virtual address space",
+            AddressSpaceError::OutOfMemory => "out of physical memory",
+            AddressSpaceError::NotFound => "no area at that address",
+            AddressSpaceError::ReadFailed => "failed to read page from file",
+            AddressSpaceError::AccessDenied => "access not allowed by the area's protection",
+            AddressSpaceError::Locked => "address space was locked",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// A page table and the areas mapped with it
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    /// Where [allocate][AddressSpace::allocate] places areas
+    range: Range<u64>,
+    /// Areas by start address
+    areas: BTreeMap<u64, Area>,
+    /// True if the page table belongs to this address space and is freed along with it
+    owns_page_table: bool,
+}
+
+impl AddressSpace {
+    /// Address space for the currently active page table, which it doesn't take ownership of.
+    ///
+    /// # Safety
+    ///
+    /// Only one `AddressSpace` may manage `range` of the active page table.
+    pub unsafe fn current(range: Range<u64>) -> Self {
+        let (level_4_frame, _) = Cr3::read();
+        Self { level_4_frame, range, areas: BTreeMap::new(), owns_page_table: false }
+    }
+
+    /// A new address space for a process. It gets its own page table, which shares everything
+    /// outside of `range` with the active one. `range` has to start and end on 512 GiB boundaries
+    /// and may only contain user mappings.
+    pub fn new_user(range: Range<u64>) -> Result<Self, AddressSpaceError> {
+        if range.start % LEVEL_4_ENTRY_SIZE != 0 || range.end % LEVEL_4_ENTRY_SIZE != 0 || range.start >= range.end {
+            return Err(AddressSpaceError::Unaligned);
+        }
+        let frame = frame::allocate_frame_or_reclaim().ok_or(AddressSpaceError::OutOfMemory)?;
+        let table = unsafe { &mut *table_ptr(frame) };
+        let (current, _) = Cr3::read();
+        let current = unsafe { &*table_ptr(current) };
+        for (i, entry) in table.iter_mut().enumerate() {
+            let addr = level_4_entry_addr(i);
+            if range.contains(&addr) {
+                entry.set_unused();
+            } else {
+                *entry = current[i].clone();
+            }
+        }
+        Ok(Self { level_4_frame: frame, range, areas: BTreeMap::new(), owns_page_table: true })
+    }
+
+    /// Physical address of the level 4 page table, for loading into CR3
+    pub fn page_table(&self) -> PhysFrame { self.level_4_frame }
+
+    pub fn range(&self) -> Range<u64> { self.range.clone() }
+
+    /// The area containing `addr`
+    pub fn area(&self, addr: VirtAddr) -> Option<&Area> {
+        self.areas.range(..=addr.as_u64()).next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(addr))
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = &Area> {
+        self.areas.values()
+    }
+
+    /// Finds a free range of `size` bytes aligned to `align` (a power of two, at least a page),
+    /// with [GUARD_SIZE] bytes free on either side.
+    pub fn find_free(&self, size: u64, align: u64) -> Option<VirtAddr> {
+        let align = core::cmp::max(align, PAGE_SIZE);
+        let mut cursor = self.range.start + GUARD_SIZE;
+        let fits = |start: u64, end: u64| {
+            let end = core::cmp::min(end, self.range.end);
+            start.checked_add(size).and_then(|top| top.checked_add(GUARD_SIZE)).map_or(false, |top| top <= end)
+        };
+        for area in self.areas.values() {
+            let area_end = area.end().as_u64();
+            if area_end <= self.range.start {
+                continue;
+            }
+            let start = align_up(cursor, align);
+            if fits(start, area.start.as_u64()) {
+                return Some(VirtAddr::new(start));
+            }
+            cursor = core::cmp::max(cursor, area_end + GUARD_SIZE);
+        }
+        let start = align_up(cursor, align);
+        if fits(start, self.range.end) { Some(VirtAddr::new(start)) } else { None }
+    }
+
+    /// Adds an area at `start` without mapping anything. Its pages are mapped on first use by
+    /// the page fault handler, or with [populate][AddressSpace::populate].
+    pub fn reserve(&mut self, area: Area) -> Result<VirtAddr, AddressSpaceError> {
+        let start = area.start.as_u64();
+        if start % PAGE_SIZE != 0 || area.size % PAGE_SIZE != 0 || area.size == 0 {
+            return Err(AddressSpaceError::Unaligned);
+        }
+        match area.backing {
+            Backing::Physical { base, .. } if !base.is_aligned(PAGE_SIZE) => return Err(AddressSpaceError::Unaligned),
+            // the page cache works in whole pages
+            Backing::File { offset, .. } if offset % PAGE_SIZE != 0 => return Err(AddressSpaceError::Unaligned),
+            _ => {},
+        }
+        let end = start.checked_add(area.size).ok_or(AddressSpaceError::Overlap)?;
+        if self.owns_page_table && (start < self.range.start || end > self.range.end) {
+            // the rest of the page table is shared
+            return Err(AddressSpaceError::Overlap);
+        }
+        // only the area starting right before `end` can overlap, since areas don't overlap each other
+        if self.areas.range(..end).next_back().map_or(false, |(_, other)| other.overlaps(start, end)) {
+            return Err(AddressSpaceError::Overlap);
+        }
+        self.areas.insert(start, area);
+        Ok(VirtAddr::new(start))
+    }
+
+    /// Places a new area of `size` bytes somewhere free in the address space and maps all of it.
+    pub fn allocate(&mut self, size: u64, align: u64, protection: Protection, backing: Backing, name: &'static str)
+        -> Result<VirtAddr, AddressSpaceError>
+    {
+        let size = align_up(size, PAGE_SIZE);
+        let start = self.find_free(size, align).ok_or(AddressSpaceError::OutOfVirtualMemory)?;
+        self.map(Area { start, size, protection, backing, name })
+    }
+
+    /// Places a new area of `size` bytes somewhere free in the address space without mapping
+    /// anything, leaving that to the page fault handler.
+    pub fn allocate_lazy(&mut self, size: u64, align: u64, protection: Protection, backing: Backing, name: &'static str)
+        -> Result<VirtAddr, AddressSpaceError>
+    {
+        let size = align_up(size, PAGE_SIZE);
+        let start = self.find_free(size, align).ok_or(AddressSpaceError::OutOfVirtualMemory)?;
+        self.reserve(Area { start, size, protection, backing, name })
+    }
+
+    /// Adds an area and maps all of its pages. Nothing is left behind if that fails.
+    pub fn map(&mut self, area: Area) -> Result<VirtAddr, AddressSpaceError> {
+        let start = self.reserve(area)?;
+        let pages: alloc::vec::Vec<_> = self.areas[&start.as_u64()].pages().collect();
+        for page in pages {
+            if let Err(e) = self.populate(page.start_address()) {
+                self.unmap(start).expect("failed to roll back a partial mapping");
+                return Err(e);
+            }
+        }
+        Ok(start)
+    }
+
+    /// Removes the area starting at `start`, unmaps its pages and frees the memory behind them.
+    pub fn unmap(&mut self, start: VirtAddr) -> Result<Area, AddressSpaceError> {
+        let area = self.areas.remove(&start.as_u64()).ok_or(AddressSpaceError::NotFound)?;
+        let mut mapper = unsafe { self.mapper() };
+        let flags = area.page_flags();
+        for page in area.pages() {
+            if flags.contains(PAT_4KIB) {
+                // the mapper refuses to unmap entries with bit 7 set, the unmap flushes the TLB anyway
+                if let Ok(flush) = unsafe { mapper.update_flags(page, flags - PAT_4KIB) } {
+                    flush.ignore();
+                }
+            }
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    self.flush(flush);
+                    if !matches!(area.backing, Backing::Physical { .. }) {
+                        unsafe { frame::free_frame(frame) };
+                    }
+                },
+                // never touched
+                Err(UnmapError::PageNotMapped) => {},
+                Err(e) => panic!("failed to unmap {:?} of area {}: {:?}", page, area.name, e),
+            }
+        }
+        Ok(area)
+    }
+
+    /// Maps the page containing `addr` if it's part of an area and isn't mapped yet.
+    pub fn populate(&mut self, addr: VirtAddr) -> Result<(), AddressSpaceError> {
+        self.populate_page(addr, false)
+    }
+
+    /// [populate](Self::populate), or with `fault` set, what the page fault handler can do: no
+    /// waiting for locks, no reclaiming and no heap allocations, since the fault may have hit in
+    /// the middle of any of them.
+    fn populate_page(&mut self, addr: VirtAddr, fault: bool) -> Result<(), AddressSpaceError> {
+        let area = self.area(addr).ok_or(AddressSpaceError::NotFound)?;
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let mut mapper = unsafe { self.mapper() };
+        if mapper.translate_addr(page.start_address()).is_some() {
+            return Ok(());
+        }
+        let offset = page.start_address() - area.start;
+        let frame = match &area.backing {
+            Backing::Anonymous => {
+                let frame = allocate_page_frame(fault).ok_or(AddressSpaceError::OutOfMemory)?;
+                unsafe { frame_contents(frame).fill(0) };
+                frame
+            },
+            Backing::Physical { base, .. } => PhysFrame::containing_address(*base + offset),
+            Backing::File { source, offset: file_offset } => {
+                let frame = allocate_page_frame(fault).ok_or(AddressSpaceError::OutOfMemory)?;
+                let read = if fault { page_cache::read_page_in_fault } else { page_cache::read_page };
+                if read(source, file_offset + offset, unsafe { frame_contents(frame) }).is_err() {
+                    unsafe { frame::free_frame(frame) };
+                    return Err(AddressSpaceError::ReadFailed);
+                }
+                frame
+            },
+        };
+        let flags = area.page_flags();
+        // the mapper won't set bit 7 in a 4 KiB page's entry since it means huge page elsewhere
+        let pat = flags.contains(PAT_4KIB);
+        let flags = flags - PAT_4KIB;
+        let owned = !matches!(area.backing, Backing::Physical { .. });
+        let mut parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            parent_flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        let mapped = if fault {
+            unsafe { mapper.map_to_with_table_flags(page, frame, flags, parent_flags, &mut FaultFrameAllocator) }
+        } else {
+            unsafe { mapper.map_to_with_table_flags(page, frame, flags, parent_flags, &mut GlobalFrameAllocator) }
+        };
+        match mapped {
+            // the page wasn't mapped before, so there's nothing stale in the TLB
+            Ok(flush) => flush.ignore(),
+            Err(e) => {
+                if owned {
+                    unsafe { frame::free_frame(frame) };
+                }
+                return Err(match e {
+                    MapToError::FrameAllocationFailed => AddressSpaceError::OutOfMemory,
+                    MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => AddressSpaceError::Overlap,
+                });
+            }
+        }
+        if pat {
+            unsafe { mapper.update_flags(page, flags | PAT_4KIB) }
+                .expect("page vanished right after mapping it")
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// Maps the page containing `addr` after a fault on it, if it belongs to an area that allows
+    /// `access`. `user` is set for accesses from user mode.
+    pub fn handle_fault(&mut self, addr: VirtAddr, access: Access, user: bool) -> Result<(), AddressSpaceError> {
+        let area = self.area(addr).ok_or(AddressSpaceError::NotFound)?;
+        if !area.allows(access, user) {
+            return Err(AddressSpaceError::AccessDenied);
+        }
+        self.populate_page(addr, true)
+    }
+
+    /// Page table for this address space. Only one may be used at a time, which `&mut self` makes sure of.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        unsafe { OffsetPageTable::new(&mut *table_ptr(self.level_4_frame), VirtAddr::new(PHYS_MEM_OFFSET)) }
+    }
+
+    /// Flushes a TLB entry if this address space is the active one; otherwise it can't be cached.
+    fn flush(&self, flush: MapperFlush<Size4KiB>) {
+        if self.owns_page_table && Cr3::read().0 != self.level_4_frame {
+            flush.ignore();
+        } else {
+            flush.flush();
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if !self.owns_page_table {
+            return;
+        }
+        assert_ne!(Cr3::read().0, self.level_4_frame, "dropping the active address space");
+        let starts: alloc::vec::Vec<_> = self.areas.keys().copied().collect();
+        for start in starts {
+            let _ = self.unmap(VirtAddr::new(start));
+        }
+        // free the page tables of our part of the address space
+        let table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        for (i, entry) in table.iter_mut().enumerate() {
+            if self.range.contains(&level_4_entry_addr(i)) && !entry.is_unused() {
+                unsafe { free_table(PhysFrame::containing_address(entry.addr()), 3) };
+                entry.set_unused();
+            }
+        }
+        unsafe { frame::free_frame(self.level_4_frame) };
+    }
+}
+
+/// Frees the page table in `frame` and the lower level tables it points to, but not the pages they map
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    if level > 1 {
+        let table = unsafe { &*table_ptr(frame) };
+        for entry in table.iter() {
+            if !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                unsafe { free_table(PhysFrame::containing_address(entry.addr()), level - 1) };
+            }
+        }
+    }
+    unsafe { frame::free_frame(frame) };
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    (PHYS_MEM_OFFSET + frame.start_address().as_u64()) as *mut PageTable
+}
+
+/// A frame for an area's page, see [AddressSpace::populate_page] for `fault`
+fn allocate_page_frame(fault: bool) -> Option<PhysFrame> {
+    if fault { frame::try_allocate_frame() } else { frame::allocate_frame_or_reclaim() }
+}
+
+/// # Safety
+///
+/// Nothing else may be using the frame.
+unsafe fn frame_contents<'a>(frame: PhysFrame) -> &'a mut [u8] {
+    let ptr = (PHYS_MEM_OFFSET + frame.start_address().as_u64()) as *mut u8;
+    unsafe { core::slice::from_raw_parts_mut(ptr, PAGE_SIZE as usize) }
+}
+
+/// First address covered by a level 4 page table entry
+fn level_4_entry_addr(index: usize) -> u64 {
+    let addr = index as u64 * LEVEL_4_ENTRY_SIZE;
+    // sign extend the upper half
+    if index >= 256 { addr | 0xFFFF_0000_0000_0000 } else { addr }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Sets up [KERNEL_SPACE] for the active page table. Needs the heap and frame allocator.
+pub fn init() {
+    let space = unsafe { AddressSpace::current(KERNEL_AREA_RANGE) };
+    *KERNEL_SPACE.lock() = Some(space);
+}
+
+/// Why a page fault couldn't be handled
+#[derive(Debug, Clone)]
+pub struct PageFaultError {
+    pub error: AddressSpaceError,
+    /// The area the address is in, if there is one
+    pub area: Option<Area>,
+}
+
+/// Handles a page fault at `addr` in the kernel address space, mapping the page if that's what the
+/// access was missing. `present` is set if the page was mapped already, so the fault came from the
+/// page's protection and mapping it again won't help.
+///
+/// Called from the page fault handler, so it gives up rather than wait if the fault hit while the
+/// address space was locked.
+pub fn handle_page_fault(addr: VirtAddr, access: Access, user: bool, present: bool) -> Result<(), PageFaultError> {
+    let mut guard = KERNEL_SPACE.try_lock().ok_or(PageFaultError { error: AddressSpaceError::Locked, area: None })?;
+    let space = guard.as_mut().ok_or(PageFaultError { error: AddressSpaceError::NotFound, area: None })?;
+    let result = if present { Err(AddressSpaceError::AccessDenied) } else { space.handle_fault(addr, access, user) };
+    result.map_err(|error| PageFaultError { error, area: space.area(addr).cloned() })
+}
+
+/// Runs `f` with the kernel address space locked
+pub fn with_kernel_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+    without_interrupts(|| f(KERNEL_SPACE.lock().as_mut().expect("kernel address space isn't initialized")))
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_kernel_areas() {
+    let rw = Protection::READ | Protection::WRITE;
+    let (a, b) = with_kernel_space(|space| {
+        let a = space.allocate(3 * PAGE_SIZE, PAGE_SIZE, rw, Backing::Anonymous, "test a").unwrap();
+        let b = space.allocate(PAGE_SIZE, 64 * 1024, rw, Backing::Anonymous, "test b").unwrap();
+        (a, b)
+    });
+    assert_eq!(b.as_u64() % (64 * 1024), 0);
+    assert!(b >= a + 3 * PAGE_SIZE + GUARD_SIZE || b + PAGE_SIZE + GUARD_SIZE <= a);
+
+    // zero-filled and usable
+    let memory = unsafe { core::slice::from_raw_parts_mut(a.as_mut_ptr::<u8>(), 3 * PAGE_SIZE as usize) };
+    assert!(memory.iter().all(|&b| b == 0));
+    memory.fill(0x5A);
+    assert!(crate::memory::translate_addr(a + 2 * PAGE_SIZE).is_some());
+
+    with_kernel_space(|space| {
+        assert_eq!(space.area(a + 5u64).unwrap().name, "test a");
+        let overlapping = Area { start: a + PAGE_SIZE, size: PAGE_SIZE, protection: rw, backing: Backing::Anonymous, name: "overlap" };
+        assert_eq!(space.reserve(overlapping).unwrap_err(), AddressSpaceError::Overlap);
+
+        let free = frame::stats().free;
+        space.unmap(a).unwrap();
+        assert!(frame::stats().free >= free + 3);
+        assert!(space.area(a).is_none());
+        assert_eq!(space.unmap(a).unwrap_err(), AddressSpaceError::NotFound);
+        space.unmap(b).unwrap();
+    });
+    assert!(crate::memory::translate_addr(a).is_none());
+}
+
+#[test_case]
+fn test_demand_paging() {
+    use crate::memory::translate_addr;
+    let rw = Protection::READ | Protection::WRITE;
+    let lazy = with_kernel_space(|space| space.allocate_lazy(2 * PAGE_SIZE, PAGE_SIZE, rw, Backing::Anonymous, "test lazy"))
+        .unwrap();
+    assert!(translate_addr(lazy).is_none());
+
+    // the first access faults the page in, zero-filled, and leaves the others alone
+    assert_eq!(unsafe { lazy.as_ptr::<u64>().read_volatile() }, 0);
+    assert!(translate_addr(lazy).is_some());
+    assert!(translate_addr(lazy + PAGE_SIZE).is_none());
+    unsafe { (lazy + PAGE_SIZE).as_mut_ptr::<u64>().write_volatile(7) };
+    assert_eq!(unsafe { (lazy + PAGE_SIZE).as_ptr::<u64>().read_volatile() }, 7);
+
+    with_kernel_space(|space| {
+        let read_only = space.allocate_lazy(PAGE_SIZE, PAGE_SIZE, Protection::READ, Backing::Anonymous, "test read only")
+            .unwrap();
+        assert_eq!(space.handle_fault(read_only, Access::Write, false), Err(AddressSpaceError::AccessDenied));
+        assert_eq!(space.handle_fault(read_only, Access::Execute, false), Err(AddressSpaceError::AccessDenied));
+        assert_eq!(space.handle_fault(read_only, Access::Read, true), Err(AddressSpaceError::AccessDenied));
+        assert!(translate_addr(read_only).is_none());
+        assert_eq!(space.handle_fault(read_only, Access::Read, false), Ok(()));
+        assert!(translate_addr(read_only).is_some());
+        assert_eq!(space.handle_fault(read_only - GUARD_SIZE, Access::Read, false), Err(AddressSpaceError::NotFound));
+
+        space.unmap(read_only).unwrap();
+        space.unmap(lazy).unwrap();
+    });
+}
@@ -0,0 +1,354 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Heap debugging, built in with the `heap_debug` feature.
+//!
+//! Every allocation is padded with a header and a redzone on either side:
+//!
+//! ```text
+//! | padding | Header | redzone | allocation | redzone |
+//! ```
+//!
+//! New allocations are filled with [ALLOC_POISON] so reads of uninitialized memory stand out.
+//! On free the header is checked against the layout passed to `dealloc`, both redzones are
+//! checked for overruns, and the allocation is filled with [FREE_POISON] and kept in a
+//! quarantine for a while instead of being freed right away. That catches double frees, and
+//! writes through dangling pointers show up as damaged poison once the block leaves the
+//! quarantine. Live allocations are kept on a list along with the return addresses of their
+//! callers, so [dump_allocations] can list everything that's still outstanding.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::{Display, Formatter};
+use core::mem::size_of;
+use core::ptr;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use super::ALLOCATOR;
+
+const REDZONE: usize = 32;
+const REDZONE_BYTE: u8 = 0xBB;
+/// Fills new allocations
+pub const ALLOC_POISON: u8 = 0xA5;
+/// Fills freed allocations
+pub const FREE_POISON: u8 = 0x6B;
+const MAGIC_LIVE: u64 = 0x4556_494C_5041_4548; // "HEAPLIVE"
+const MAGIC_FREED: u64 = 0x4545_5246_5041_4548; // "HEAPFREE"
+/// Return addresses recorded per allocation
+const CALLERS: usize = 6;
+/// Frees that are held back before the memory really goes back to the allocator
+const QUARANTINE_SIZE: usize = 256;
+/// Frame pointers further apart than this are assumed to be garbage
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+    /// Counts up with every allocation, to tell old allocations from new ones in a dump
+    serial: u64,
+    /// Neighbours on the list of live allocations
+    next: *mut Header,
+    prev: *mut Header,
+    callers: [usize; CALLERS],
+}
+
+struct State {
+    /// Most recent live allocation
+    live: *mut Header,
+    count: usize,
+    bytes: usize,
+    serial: u64,
+    /// Freed allocations (user pointers) waiting to be really freed, used as a ring
+    quarantine: [usize; QUARANTINE_SIZE],
+    quarantine_next: usize,
+}
+
+// only touched with the lock held
+unsafe impl Send for State {}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    live: ptr::null_mut(),
+    count: 0,
+    bytes: 0,
+    serial: 0,
+    quarantine: [0; QUARANTINE_SIZE],
+    quarantine_next: 0,
+});
+
+/// Something wrong found while freeing. Reported after the lock is released, with a panic.
+#[derive(Debug)]
+enum HeapError {
+    /// No valid header in front of the pointer
+    InvalidFree { ptr: usize },
+    DoubleFree { ptr: usize, callers: [usize; CALLERS] },
+    LayoutMismatch { ptr: usize, allocated: (usize, usize), freed: (usize, usize), callers: [usize; CALLERS] },
+    /// Something wrote past either end of the allocation
+    RedzoneDamaged { ptr: usize, offset: isize, callers: [usize; CALLERS] },
+    /// Something wrote to the allocation after it was freed
+    UseAfterFree { ptr: usize, offset: usize, callers: [usize; CALLERS] },
+}
+
+impl Display for HeapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let callers = match self {
+            HeapError::InvalidFree { ptr } => {
+                return write!(f, "free of {:#x}, which isn't a live allocation", ptr);
+            },
+            HeapError::DoubleFree { ptr, callers } => {
+                write!(f, "double free of {:#x}", ptr)?;
+                callers
+            },
+            HeapError::LayoutMismatch { ptr, allocated, freed, callers } => {
+                write!(f, "{:#x} allocated with size {} align {} but freed with size {} align {}",
+                       ptr, allocated.0, allocated.1, freed.0, freed.1)?;
+                callers
+            },
+            HeapError::RedzoneDamaged { ptr, offset, callers } => {
+                write!(f, "write out of bounds of {:#x} at offset {}", ptr, offset)?;
+                callers
+            },
+            HeapError::UseAfterFree { ptr, offset, callers } => {
+                write!(f, "write to {:#x} at offset {} after it was freed", ptr, offset)?;
+                callers
+            },
+        };
+        write!(f, ", allocated from")?;
+        write_callers(f, callers)
+    }
+}
+
+fn write_callers(f: &mut impl core::fmt::Write, callers: &[usize]) -> core::fmt::Result {
+    for caller in callers.iter().take_while(|&&caller| caller != 0) {
+        write!(f, " {:#x}", caller)?;
+    }
+    Ok(())
+}
+
+/// Bytes in front of the allocation, so the allocation itself ends up aligned
+fn prefix(align: usize) -> usize {
+    let needed = size_of::<Header>() + REDZONE;
+    (needed + align - 1) / align * align
+}
+
+/// What's really allocated for an allocation with this size and alignment
+fn padded_layout(size: usize, align: usize) -> Option<Layout> {
+    let align = core::cmp::max(align, core::mem::align_of::<Header>());
+    Layout::from_size_align(prefix(align) + size + REDZONE, align).ok()
+}
+
+fn header(ptr: usize) -> *mut Header {
+    (ptr - REDZONE - size_of::<Header>()) as *mut Header
+}
+
+/// Return addresses of the functions that called the allocator, found by following the frame pointers
+#[inline(always)]
+fn callers() -> [usize; CALLERS] {
+    let mut callers = [0; CALLERS];
+    let mut frame: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+    for caller in callers.iter_mut() {
+        if frame == 0 || frame % 8 != 0 {
+            break;
+        }
+        let (next, return_address) = unsafe { (*(frame as *const usize), *((frame + 8) as *const usize)) };
+        *caller = return_address;
+        // stacks grow down, so every caller's frame is a little above ours
+        if next <= frame || next - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = next;
+    }
+    callers
+}
+
+/// Wraps the normal kernel allocator with the checks described in the module documentation
+#[derive(Debug)]
+pub struct DebugAllocator;
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let padded = match padded_layout(layout.size(), layout.align()) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let base = unsafe { ALLOCATOR.alloc(padded) };
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base as usize + prefix(padded.align());
+        unsafe {
+            ptr::write_bytes((ptr - REDZONE) as *mut u8, REDZONE_BYTE, REDZONE);
+            ptr::write_bytes(ptr as *mut u8, ALLOC_POISON, layout.size());
+            ptr::write_bytes((ptr + layout.size()) as *mut u8, REDZONE_BYTE, REDZONE);
+        }
+        let callers = callers();
+        without_interrupts(|| {
+            let mut state = STATE.lock();
+            state.serial += 1;
+            let header = header(ptr);
+            unsafe {
+                header.write(Header {
+                    magic: MAGIC_LIVE,
+                    size: layout.size(),
+                    align: layout.align(),
+                    serial: state.serial,
+                    next: state.live,
+                    prev: ptr::null_mut(),
+                    callers,
+                });
+                if !state.live.is_null() {
+                    (*state.live).prev = header;
+                }
+            }
+            state.live = header;
+            state.count += 1;
+            state.bytes += layout.size();
+        });
+        ptr as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let result = without_interrupts(|| {
+            let mut state = STATE.lock();
+            let ptr = ptr as usize;
+            unsafe { check_live(ptr, layout)? };
+            let header = header(ptr);
+            unsafe {
+                // off the live list
+                let Header { next, prev, .. } = *header;
+                if prev.is_null() {
+                    state.live = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if !next.is_null() {
+                    (*next).prev = prev;
+                }
+                (*header).magic = MAGIC_FREED;
+                ptr::write_bytes(ptr as *mut u8, FREE_POISON, layout.size());
+            }
+            state.count -= 1;
+            state.bytes -= layout.size();
+
+            // into the quarantine, pushing out the oldest entry
+            let slot = state.quarantine_next;
+            let evicted = core::mem::replace(&mut state.quarantine[slot], ptr);
+            state.quarantine_next = (slot + 1) % QUARANTINE_SIZE;
+            match evicted {
+                0 => Ok(None),
+                evicted => unsafe { check_quarantined(evicted).map(Some) },
+            }
+        });
+        match result {
+            Ok(Some((base, padded))) => unsafe { ALLOCATOR.dealloc(base as *mut u8, padded) },
+            Ok(None) => {},
+            Err(error) => panic!("heap: {}", error),
+        }
+    }
+}
+
+/// Make sure `ptr` is a live allocation made with `layout`, with its redzones intact
+unsafe fn check_live(ptr: usize, layout: Layout) -> Result<(), HeapError> {
+    let header = unsafe { &*header(ptr) };
+    match header.magic {
+        MAGIC_LIVE => {},
+        MAGIC_FREED => return Err(HeapError::DoubleFree { ptr, callers: header.callers }),
+        _ => return Err(HeapError::InvalidFree { ptr }),
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        return Err(HeapError::LayoutMismatch {
+            ptr,
+            allocated: (header.size, header.align),
+            freed: (layout.size(), layout.align()),
+            callers: header.callers,
+        });
+    }
+    let front = unsafe { core::slice::from_raw_parts((ptr - REDZONE) as *const u8, REDZONE) };
+    let back = unsafe { core::slice::from_raw_parts((ptr + header.size) as *const u8, REDZONE) };
+    if let Some(i) = front.iter().rposition(|&b| b != REDZONE_BYTE) {
+        return Err(HeapError::RedzoneDamaged { ptr, offset: i as isize - REDZONE as isize, callers: header.callers });
+    }
+    if let Some(i) = back.iter().position(|&b| b != REDZONE_BYTE) {
+        return Err(HeapError::RedzoneDamaged { ptr, offset: (header.size + i) as isize, callers: header.callers });
+    }
+    Ok(())
+}
+
+/// Make sure nothing wrote to a quarantined allocation, and return what to really free for it
+unsafe fn check_quarantined(ptr: usize) -> Result<(usize, Layout), HeapError> {
+    let header = unsafe { &*header(ptr) };
+    let data = unsafe { core::slice::from_raw_parts(ptr as *const u8, header.size) };
+    if let Some(offset) = data.iter().position(|&b| b != FREE_POISON) {
+        return Err(HeapError::UseAfterFree { ptr, offset, callers: header.callers });
+    }
+    // it was checked to be a valid layout when it was allocated
+    let padded = padded_layout(header.size, header.align).unwrap();
+    Ok((ptr - prefix(padded.align()), padded))
+}
+
+/// Number of live allocations and the bytes they hold, not counting debugging overhead
+pub fn outstanding() -> (usize, usize) {
+    without_interrupts(|| {
+        let state = STATE.lock();
+        (state.count, state.bytes)
+    })
+}
+
+/// Print every live allocation to the serial port, oldest last, along with the addresses it was
+/// allocated from. Use `addr2line` on the kernel binary to turn those into source lines.
+/// Returns the number of allocations and their total size.
+pub fn dump_allocations() -> (usize, usize) {
+    use core::fmt::Write;
+    without_interrupts(|| {
+        let state = STATE.lock();
+        // printing to serial doesn't allocate, so it's fine to hold the lock
+        let mut serial = crate::device::serial::SERIAL1.lock();
+        let mut header = state.live;
+        while !header.is_null() {
+            let h = unsafe { &*header };
+            let ptr = header as usize + size_of::<Header>() + REDZONE;
+            let _ = write!(serial, "#{} {:#x} size {} align {} from", h.serial, ptr, h.size, h.align);
+            let _ = write_callers(&mut *serial, &h.callers);
+            let _ = writeln!(serial);
+            header = h.next;
+        }
+        let _ = writeln!(serial, "{} allocations, {} bytes outstanding", state.count, state.bytes);
+        (state.count, state.bytes)
+    })
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_heap_debug_poison() {
+    use alloc::boxed::Box;
+    let (count, bytes) = outstanding();
+    let boxed = Box::new([0u8; 100]);
+    assert_eq!(outstanding(), (count + 1, bytes + 100));
+    let header = unsafe { &*header(boxed.as_ptr() as usize) };
+    assert_eq!(header.magic, MAGIC_LIVE);
+    assert_eq!(header.size, 100);
+    assert_ne!(header.callers[0], 0);
+    drop(boxed);
+    assert_eq!(outstanding(), (count, bytes));
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let ptr = unsafe { DebugAllocator.alloc(layout) };
+    let data = unsafe { core::slice::from_raw_parts_mut(ptr, 24) };
+    assert!(data.iter().all(|&b| b == ALLOC_POISON));
+    data[23] = 1;
+    unsafe { check_live(ptr as usize, layout) }.unwrap();
+    // one byte past the end
+    unsafe { *ptr.add(24) = 1 };
+    assert!(matches!(unsafe { check_live(ptr as usize, layout) }, Err(HeapError::RedzoneDamaged { offset: 24, .. })));
+    unsafe { *ptr.add(24) = REDZONE_BYTE };
+    assert!(matches!(unsafe { check_live(ptr as usize, Layout::from_size_align(32, 8).unwrap()) },
+                     Err(HeapError::LayoutMismatch { .. })));
+    unsafe { DebugAllocator.dealloc(ptr, layout) };
+    assert!(unsafe { core::slice::from_raw_parts(ptr, 24) }.iter().all(|&b| b == FREE_POISON));
+    assert!(matches!(unsafe { check_live(ptr as usize, layout) }, Err(HeapError::DoubleFree { .. })));
+}
@@ -6,25 +6,40 @@
 
 use core::alloc::{Layout, GlobalAlloc};
 use super::Locked;
-use core::{ptr, mem};
+use super::slab::SlabCache;
+use core::ptr;
 use core::ptr::NonNull;
 use core::fmt::{Debug, Formatter};
-
-#[derive(Debug)]
-struct ListNode {
-    next: Option<&'static mut ListNode>,
-}
+use linked_list_allocator::{HoleStats, Strategy};
 
 /// The block sizes to use.
 ///
-/// The sizes must each be power of 2 because they are also used as the block alignment (alignments
-/// must be always powers of 2). We don't define any block sizes smaller than 8 because each block
+/// Each size class is a slab cache aligned to the largest power of two dividing its size, so
+/// e.g. 48-byte blocks are 16-byte aligned. Sizes in between the powers of 2 keep the waste for
+/// odd-sized allocations down. We don't define any block sizes smaller than 8 because each block
 /// must be capable of storing a 64-bit pointer to the next block when freed. For allocations
 /// greater than 2048 bytes we fall back to a linked list memory.allocator.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048];
+
+static BLOCK_CACHES: [SlabCache; BLOCK_SIZES.len()] = [
+    SlabCache::new("kmalloc-8", 8, 8),
+    SlabCache::new("kmalloc-16", 16, 16),
+    SlabCache::new("kmalloc-32", 32, 32),
+    SlabCache::new("kmalloc-48", 48, 16),
+    SlabCache::new("kmalloc-64", 64, 64),
+    SlabCache::new("kmalloc-96", 96, 32),
+    SlabCache::new("kmalloc-128", 128, 128),
+    SlabCache::new("kmalloc-192", 192, 64),
+    SlabCache::new("kmalloc-256", 256, 256),
+    SlabCache::new("kmalloc-384", 384, 128),
+    SlabCache::new("kmalloc-512", 512, 512),
+    SlabCache::new("kmalloc-768", 768, 256),
+    SlabCache::new("kmalloc-1024", 1024, 1024),
+    SlabCache::new("kmalloc-1536", 1536, 512),
+    SlabCache::new("kmalloc-2048", 2048, 2048),
+];
 
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
 }
 
@@ -32,7 +47,6 @@ impl FixedSizeBlockAllocator {
     /// Creates an empty FixedSizeBlockAllocator.
     pub const fn new() -> Self {
         FixedSizeBlockAllocator {
-            list_heads: [None, None, None, None, None, None, None, None, None],
             fallback_allocator: linked_list_allocator::Heap::empty(),
         }
     }
@@ -45,76 +59,88 @@ impl FixedSizeBlockAllocator {
     /// and that the heap is unused. This method must be called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         unsafe { self.fallback_allocator.init(heap_start, heap_size); }
+        // only big allocations end up here, best fit keeps them from breaking up the free space
+        // at the top of the heap, which is what gets trimmed
+        self.fallback_allocator.set_strategy(Strategy::BestFit);
     }
 
-    /// Allocates using the fallback memory.allocator.
+    /// Allocates using the fallback memory.allocator, growing the heap if it's full.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
+        if let Ok(ptr) = self.fallback_allocator.allocate(layout) {
+            return ptr.as_ptr();
+        }
+        if !super::grow_heap(&mut self.fallback_allocator, layout) {
+            return ptr::null_mut();
+        }
+        match self.fallback_allocator.allocate(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+
+    /// Size of the heap and how much of it the fallback allocator has handed out, in bytes
+    pub fn heap_usage(&self) -> (usize, usize) {
+        (self.fallback_allocator.size(), self.fallback_allocator.used())
+    }
+
+    /// Free holes in the heap
+    pub fn heap_holes(&self) -> HoleStats {
+        self.fallback_allocator.hole_stats()
+    }
 }
 
 impl Debug for FixedSizeBlockAllocator {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "FixedSizeBlockAllocator {{ list_heads: {:?}, fallback_allocator: linked_list_allocator::Heap }}", self.list_heads)
+        write!(f, "FixedSizeBlockAllocator {{ block_caches: {:?}, fallback_allocator: linked_list_allocator::Heap }}", BLOCK_CACHES)
     }
 }
 
 /// Choose an appropriate block size for the given layout.
 ///
-/// Returns an index into the `BLOCK_SIZES` array.
-fn list_index(layout: &Layout) -> Option<usize> {
-    let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+/// Returns the slab cache for that size.
+fn block_cache(layout: &Layout) -> Option<&'static SlabCache> {
+    BLOCK_CACHES.iter().find(|cache| cache.fits(layout))
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align)
-                            .unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            }
-            None => {
-                allocator.fallback_alloc(layout)
-            },
+        match block_cache(&layout) {
+            Some(cache) => cache.allocate_object().map_or(ptr::null_mut(), NonNull::as_ptr),
+            None => self.lock().fallback_alloc(layout),
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                unsafe { new_node_ptr.write(new_node); }
-                allocator.list_heads[index] = unsafe { Some(&mut *new_node_ptr) };
-            }
+        let ptr = NonNull::new(ptr).unwrap();
+        match block_cache(&layout) {
+            Some(cache) => unsafe { cache.free_object(ptr) },
             None => {
-                let ptr = NonNull::new(ptr).unwrap();
+                let mut allocator = self.lock();
                 unsafe { allocator.fallback_allocator.deallocate(ptr, layout); }
+                super::trim_heap(&mut allocator.fallback_allocator);
+            }
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        if block_cache(&layout).is_none() && block_cache(&new_layout).is_none() {
+            // the heap can often resize in place
+            let mut allocator = self.lock();
+            let ptr = NonNull::new(ptr).unwrap();
+            if let Ok(new_ptr) = unsafe { allocator.fallback_allocator.reallocate(ptr, layout, new_size) } {
+                super::trim_heap(&mut allocator.fallback_allocator);
+                return new_ptr.as_ptr();
+            }
+        }
+        // moving between caches, or the heap has to grow first
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+                self.dealloc(ptr, layout);
             }
         }
+        new_ptr
     }
 }
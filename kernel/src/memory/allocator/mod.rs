@@ -5,13 +5,21 @@
 ///////////////////////////////////////////////////////////////////////////////L
 
 use x86_64::{
-    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB},
     VirtAddr,
 };
+use core::alloc::Layout;
 use core::fmt::Debug;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::memory::frame::{self, GlobalFrameAllocator, FRAME_SIZE};
 
 
 pub mod fixed_size_block;
+/// Slab caches for fixed-size kernel objects
+pub mod slab;
+/// Redzones, poisoning and leak tracking for the global allocator
+#[cfg(feature = "heap_debug")]
+pub mod debug;
 
 
 /// A wrapper around spin::Mutex to permit trait implementations.
@@ -25,12 +33,55 @@ impl<A: Debug> Locked<A> {
 }
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+/// Mapped at boot, the heap never shrinks below this
+pub const HEAP_INITIAL_SIZE: usize = 1024 * 1024; // 1 MiB
+/// Default for [set_heap_limit]
+pub const HEAP_DEFAULT_LIMIT: usize = 256 * 1024 * 1024; // 256 MiB
+/// The heap grows by at least this much at a time, and keeps this much free at the top when trimming
+const HEAP_GROWTH: usize = 256 * 1024;
+/// Free memory at the top of the heap is only given back once there's this much of it
+const HEAP_TRIM_THRESHOLD: usize = 1024 * 1024;
 
-pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), MapToError<Size4KiB>> {
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_DEFAULT_LIMIT);
+/// The page table from [super::init], handed over by [init_heap] for growing and trimming the heap.
+/// Only locked while the allocator is.
+static HEAP_MAPPER: spin::Mutex<Option<OffsetPageTable<'static>>> = spin::Mutex::new(None);
+
+/// Maximum size the heap may grow to. Lowering it below the current size doesn't shrink the heap,
+/// it only stops it from growing further.
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(core::cmp::max(bytes, HEAP_INITIAL_SIZE), Ordering::Relaxed);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Kernel heap usage, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Currently mapped
+    pub size: usize,
+    /// Handed out by the fallback allocator. Small allocations come from slab caches instead.
+    pub used: usize,
+    pub limit: usize,
+    /// Number of free holes in the heap, more of them for the same free space means more fragmentation
+    pub holes: usize,
+    pub largest_hole: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let allocator = ALLOCATOR.lock();
+    let (size, used) = allocator.heap_usage();
+    let holes = allocator.heap_holes();
+    HeapStats { size, used, limit: heap_limit(), holes: holes.holes, largest_hole: holes.largest }
+}
+
+/// Map the initial heap. Keeps `mapper` to map more of it later on.
+pub fn init_heap(mut mapper: OffsetPageTable<'static>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + (HEAP_SIZE - 1);
+        let heap_end = heap_start + (HEAP_INITIAL_SIZE - 1);
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -46,13 +97,102 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl
         }
     }
 
+    *HEAP_MAPPER.lock() = Some(mapper);
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, HEAP_INITIAL_SIZE);
     }
 
     Ok(())
 }
 
-#[global_allocator]
+/// Map more memory at the top of `heap`, enough for at least `layout`.
+/// Returns false if that would go over the limit or we're out of frames.
+fn grow_heap(heap: &mut linked_list_allocator::Heap, layout: Layout) -> bool {
+    // worst case the allocation needs alignment padding and a hole header in front of it
+    if heap.bottom() == 0 {
+        // not initialized yet
+        return false;
+    }
+    let needed = layout.size() + layout.align() + linked_list_allocator::hole::HoleList::min_size();
+    let by = align_up(core::cmp::max(needed, HEAP_GROWTH));
+    let by = core::cmp::min(by, align_down(heap_limit().saturating_sub(heap.size())));
+    if by < needed {
+        return false;
+    }
+
+    let top = heap.top();
+    let mut lock = HEAP_MAPPER.lock();
+    let mapper = match lock.as_mut() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    for offset in (0..by).step_by(FRAME_SIZE as usize) {
+        let page = Page::containing_address(VirtAddr::new((top + offset) as u64));
+        if !map_heap_page(mapper, page) {
+            unmap_heap(mapper, top, offset);
+            return false;
+        }
+    }
+    unsafe { heap.extend(by) };
+    true
+}
+
+fn map_heap_page(mapper: &mut impl Mapper<Size4KiB>, page: Page<Size4KiB>) -> bool {
+    let frame = match frame::allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        },
+        Err(_) => {
+            unsafe { frame::free_frame(frame) };
+            false
+        }
+    }
+}
+
+/// Give free memory at the top of `heap` back to the frame allocator once there's a lot of it
+fn trim_heap(heap: &mut linked_list_allocator::Heap) {
+    let free = heap.free_at_top();
+    if free < HEAP_TRIM_THRESHOLD {
+        return;
+    }
+    let by = align_down(core::cmp::min(free - HEAP_GROWTH, heap.size().saturating_sub(HEAP_INITIAL_SIZE)));
+    let mut lock = HEAP_MAPPER.lock();
+    let mapper = match lock.as_mut() {
+        Some(mapper) => mapper,
+        None => return,
+    };
+    if by == 0 || !heap.shrink(by) {
+        return;
+    }
+    unmap_heap(mapper, heap.top(), by);
+}
+
+/// Unmap `size` bytes of heap starting at `start` and free the frames behind them
+fn unmap_heap(mapper: &mut impl Mapper<Size4KiB>, start: usize, size: usize) {
+    for offset in (0..size).step_by(FRAME_SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new((start + offset) as u64));
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame::free_frame(frame) };
+        }
+    }
+}
+
+fn align_up(size: usize) -> usize { align_down(size + FRAME_SIZE as usize - 1) }
+
+fn align_down(size: usize) -> usize { size / FRAME_SIZE as usize * FRAME_SIZE as usize }
+
+#[cfg_attr(not(feature = "heap_debug"), global_allocator)]
 pub static ALLOCATOR: Locked<fixed_size_block::FixedSizeBlockAllocator> =
     Locked::new( fixed_size_block::FixedSizeBlockAllocator::new() );
+
+/// With `heap_debug`, every allocation goes through the checks in [debug] before reaching [ALLOCATOR]
+#[cfg(feature = "heap_debug")]
+#[global_allocator]
+static DEBUG_ALLOCATOR: debug::DebugAllocator = debug::DebugAllocator;
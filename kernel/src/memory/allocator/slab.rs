@@ -0,0 +1,385 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Slab caches: named pools of equally sized objects.
+//!
+//! Each slab is a naturally aligned block of frames straight from the frame allocator, accessed
+//! through the physical memory mapping. It starts with a [SlabHeader] and is followed by the
+//! objects, so freeing an object finds its slab by rounding the address down. Slabs with free
+//! objects are kept on a list; full slabs are only reachable through their objects. Once a cache
+//! has more than [MAX_EMPTY_SLABS] slabs without any objects in use, the extra ones go back to
+//! the frame allocator.
+//!
+//! Caches are `static`s. Use them through the [Allocator] implementation, e.g.
+//! `Box::new_in(inode, &INODE_CACHE)`.
+
+use alloc::alloc::Global;
+use core::alloc::{AllocError, Allocator, Layout};
+use core::fmt::{Debug, Formatter};
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::memory::frame::{self, AddressLimit, FRAME_SIZE, MAX_ORDER};
+
+/// Slabs are made big enough for at least this many objects, so big objects don't waste most of a slab
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+/// Empty slabs a cache holds on to, so allocating and freeing one object doesn't hit the frame allocator every time
+const MAX_EMPTY_SLABS: usize = 1;
+/// Free objects store a pointer to the next one
+const MIN_OBJECT_SIZE: usize = size_of::<usize>();
+
+/// Head of the list of every cache that has allocated at least one slab
+static CACHES: AtomicPtr<SlabCache> = AtomicPtr::new(ptr::null_mut());
+
+/// Start of every slab
+#[repr(C)]
+struct SlabHeader {
+    cache: *const SlabCache,
+    /// Neighbours on the cache's list of slabs with free objects
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct CacheInner {
+    /// Slabs with at least one free object, empty ones included
+    partial: *mut SlabHeader,
+    slabs: usize,
+    empty_slabs: usize,
+    in_use: usize,
+    allocations: u64,
+    frees: u64,
+}
+
+// the slabs are only ever touched with the cache's lock held
+unsafe impl Send for CacheInner {}
+
+impl CacheInner {
+    unsafe fn push_partial(&mut self, slab: *mut SlabHeader) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut SlabHeader) {
+        unsafe {
+            let SlabHeader { next, prev, .. } = *slab;
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+/// Statistics for one cache
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// Bytes per object, after rounding up for alignment
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slab_size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+impl CacheStats {
+    /// Bytes taken from the frame allocator
+    pub fn memory(&self) -> usize { self.slabs * self.slab_size }
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    /// Slabs are 2^order frames
+    order: usize,
+    /// Where the first object starts in a slab
+    offset: usize,
+    objects: usize,
+    inner: Mutex<CacheInner>,
+    /// Next cache in [CACHES]
+    next: AtomicPtr<SlabCache>,
+    registered: AtomicBool,
+}
+
+impl SlabCache {
+    /// A cache for objects of `size` bytes aligned to `align`, which must be a power of two
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < MIN_OBJECT_SIZE { MIN_OBJECT_SIZE } else { align };
+        let size = if size < MIN_OBJECT_SIZE { MIN_OBJECT_SIZE } else { size };
+        let size = (size + align - 1) / align * align;
+        let offset = (size_of::<SlabHeader>() + align - 1) / align * align;
+        let mut order = 0;
+        while order < MAX_ORDER && ((FRAME_SIZE as usize) << order) < offset + MIN_OBJECTS_PER_SLAB * size {
+            order += 1;
+        }
+        let slab_size = (FRAME_SIZE as usize) << order;
+        let objects = if slab_size > offset { (slab_size - offset) / size } else { 0 };
+        SlabCache {
+            name, size, align, order, offset, objects,
+            inner: Mutex::new(CacheInner {
+                partial: ptr::null_mut(),
+                slabs: 0,
+                empty_slabs: 0,
+                in_use: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+            next: AtomicPtr::new(ptr::null_mut()),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// A cache for values of type `T`
+    pub const fn for_type<T>(name: &'static str) -> Self {
+        Self::new(name, size_of::<T>(), core::mem::align_of::<T>())
+    }
+
+    pub fn name(&self) -> &'static str { self.name }
+
+    /// Whether an allocation with this layout can come from this cache
+    pub fn fits(&self, layout: &Layout) -> bool {
+        layout.size() <= self.size && layout.align() <= self.align
+    }
+
+    fn slab_size(&self) -> usize { (FRAME_SIZE as usize) << self.order }
+
+    /// Take one object from the cache, growing it by a slab if there are no free objects.
+    /// Returns `None` if there's no memory left for a new slab.
+    pub fn allocate_object(&'static self) -> Option<NonNull<u8>> {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            if inner.partial.is_null() {
+                let slab = self.new_slab()?;
+                unsafe { inner.push_partial(slab) };
+                inner.slabs += 1;
+                inner.empty_slabs += 1;
+            }
+            let slab = inner.partial;
+            let object = unsafe {
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                if (*slab).in_use == 0 {
+                    inner.empty_slabs -= 1;
+                }
+                (*slab).in_use += 1;
+                if (*slab).free.is_null() {
+                    // full, it comes back on the list when an object is freed
+                    inner.unlink(slab);
+                }
+                object
+            };
+            inner.in_use += 1;
+            inner.allocations += 1;
+            NonNull::new(object as *mut u8)
+        })
+    }
+
+    /// Return an object to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have come from [SlabCache::allocate_object] on this cache and must not be used anymore.
+    pub unsafe fn free_object(&self, object: NonNull<u8>) {
+        let slab = (object.as_ptr() as usize & !(self.slab_size() - 1)) as *mut SlabHeader;
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            unsafe {
+                assert!(ptr::eq((*slab).cache, self), "object {:p} freed to the wrong slab cache ({})", object, self.name);
+                if (*slab).free.is_null() {
+                    inner.push_partial(slab);
+                }
+                let object = object.as_ptr() as *mut FreeObject;
+                object.write(FreeObject { next: (*slab).free });
+                (*slab).free = object;
+                (*slab).in_use -= 1;
+                if (*slab).in_use == 0 {
+                    inner.empty_slabs += 1;
+                    if inner.empty_slabs > MAX_EMPTY_SLABS {
+                        inner.unlink(slab);
+                        inner.empty_slabs -= 1;
+                        inner.slabs -= 1;
+                        self.release_slab(slab);
+                    }
+                }
+            }
+            inner.in_use -= 1;
+            inner.frees += 1;
+        })
+    }
+
+    /// Give every empty slab back to the frame allocator. Returns how many there were.
+    pub fn shrink(&self) -> usize {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let mut released = 0;
+            let mut slab = inner.partial;
+            while !slab.is_null() {
+                unsafe {
+                    let next = (*slab).next;
+                    if (*slab).in_use == 0 {
+                        inner.unlink(slab);
+                        self.release_slab(slab);
+                        released += 1;
+                    }
+                    slab = next;
+                }
+            }
+            inner.slabs -= released;
+            inner.empty_slabs -= released;
+            released
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = without_interrupts(|| {
+            let inner = self.inner.lock();
+            (inner.slabs, inner.in_use, inner.allocations, inner.frees)
+        });
+        CacheStats {
+            name: self.name,
+            object_size: self.size,
+            objects_per_slab: self.objects,
+            slab_size: self.slab_size(),
+            slabs: inner.0,
+            in_use: inner.1,
+            allocations: inner.2,
+            frees: inner.3,
+        }
+    }
+
+    /// Get a fresh slab from the frame allocator with all of its objects on its free list
+    fn new_slab(&'static self) -> Option<*mut SlabHeader> {
+        if self.objects == 0 {
+            return None;
+        }
+        self.register();
+        let phys = frame::allocate_contiguous(1 << self.order, self.slab_size() as u64, AddressLimit::Any)?;
+        let start = (crate::PHYS_MEM_OFFSET + phys.as_u64()) as usize;
+        let mut free: *mut FreeObject = ptr::null_mut();
+        for i in (0..self.objects).rev() {
+            let object = (start + self.offset + i * self.size) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+        let slab = start as *mut SlabHeader;
+        unsafe {
+            slab.write(SlabHeader {
+                cache: self,
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    /// # Safety
+    ///
+    /// The slab must be empty and not on the partial list anymore.
+    unsafe fn release_slab(&self, slab: *mut SlabHeader) {
+        let phys = PhysAddr::new(slab as u64 - crate::PHYS_MEM_OFFSET);
+        unsafe { frame::free_contiguous(phys, 1 << self.order) };
+    }
+
+    /// Add this cache to [CACHES] the first time it's used
+    fn register(&'static self) {
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let me = self as *const SlabCache as *mut SlabCache;
+        let mut head = CACHES.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match CACHES.compare_exchange_weak(head, me, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+impl Debug for SlabCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SlabCache").field("stats", &self.stats()).finish()
+    }
+}
+
+unsafe impl Allocator for &'static SlabCache {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let cache: &'static SlabCache = *self;
+        if !cache.fits(&layout) {
+            return Err(AllocError);
+        }
+        let object = cache.allocate_object().ok_or(AllocError)?;
+        NonNull::new(ptr::slice_from_raw_parts_mut(object.as_ptr(), cache.size)).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        unsafe { self.free_object(ptr) }
+    }
+}
+
+/// Allocates from a cache when the layout fits and from the heap otherwise,
+/// for things whose size varies but is usually small
+#[derive(Debug, Clone, Copy)]
+pub struct SlabOrHeap(pub &'static SlabCache);
+
+unsafe impl Allocator for SlabOrHeap {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.0.fits(&layout) {
+            self.0.allocate(layout)
+        } else {
+            Global.allocate(layout)
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.0.fits(&layout) {
+            unsafe { self.0.deallocate(ptr, layout) }
+        } else {
+            unsafe { Global.deallocate(ptr, layout) }
+        }
+    }
+}
+
+/// Every cache that has allocated a slab so far, newest first
+pub fn caches() -> impl Iterator<Item = &'static SlabCache> {
+    let mut next = CACHES.load(Ordering::Acquire) as *const SlabCache;
+    core::iter::from_fn(move || {
+        // caches are statics and never leave the list
+        let cache = unsafe { next.as_ref() }?;
+        next = cache.next.load(Ordering::Acquire);
+        Some(cache)
+    })
+}
+
+/// Shrink every cache, returning how many slabs were given back
+pub fn shrink_all() -> usize {
+    caches().map(SlabCache::shrink).sum()
+}
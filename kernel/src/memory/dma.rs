@@ -0,0 +1,235 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Buffers for devices to DMA to and from.
+//!
+//! There are two kinds, named after their Linux counterparts:
+//!
+//! - A [DmaBuffer] is coherent memory: physically contiguous frames owned by the driver, which the
+//!   CPU and the device can both use at any time. Descriptor rings, command lists and the like.
+//! - A [DmaMapping] is a streaming mapping: memory that's already there, like the buffer passed to
+//!   a disk read, lent to the device for one transfer. If the device can't reach some of it, the
+//!   transfer goes through a bounce buffer instead.
+//!
+//! x86 keeps caches coherent with DMA, so neither needs cache maintenance. Buffers are reached
+//! through the bootloader's write-back map of physical memory, and handing memory over in either
+//! direction is just a fence.
+
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::frame::{self, AddressLimit, FRAME_SIZE};
+use crate::memory::translate_addr;
+use crate::PHYS_MEM_OFFSET;
+
+/// Physically contiguous, zeroed memory for a device, freed when dropped
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    size: usize,
+    frames: u64,
+}
+
+impl DmaBuffer {
+    /// Allocate `size` bytes aligned to `align`, which has to be a power of two. Alignments below a
+    /// page are rounded up to one. Returns `None` if there isn't enough contiguous memory within
+    /// `limit`.
+    pub fn new(size: usize, align: usize, limit: AddressLimit) -> Option<Self> {
+        assert!(align.is_power_of_two(), "DMA buffer alignment {} isn't a power of two", align);
+        let frames = (core::cmp::max(size, 1) as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+        let phys = frame::allocate_contiguous(frames, core::cmp::max(align as u64, FRAME_SIZE), limit)?;
+        let buffer = Self { phys, size, frames };
+        unsafe { core::ptr::write_bytes(buffer.as_mut_ptr(), 0, (frames * FRAME_SIZE) as usize) };
+        Some(buffer)
+    }
+
+    /// A single page anywhere in memory, the most common case
+    pub fn page() -> Option<Self> {
+        Self::new(FRAME_SIZE as usize, FRAME_SIZE as usize, AddressLimit::Any)
+    }
+
+    /// Address for the device
+    pub fn phys(&self) -> PhysAddr { self.phys }
+
+    /// Address for the CPU
+    pub fn virt(&self) -> VirtAddr { VirtAddr::new(PHYS_MEM_OFFSET + self.phys.as_u64()) }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 { self.virt().as_mut_ptr() }
+
+    pub fn len(&self) -> usize { self.size }
+
+    pub fn is_empty(&self) -> bool { self.size == 0 }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { frame::free_contiguous(self.phys, self.frames) };
+    }
+}
+
+impl Debug for DmaBuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "DmaBuffer {{ phys: {:#x}, size: {:#x} }}", self.phys.as_u64(), self.size)
+    }
+}
+
+/// Which way the data of a [DmaMapping] moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the memory, like for a disk write
+    ToDevice,
+    /// The device writes the memory, like for a disk read
+    FromDevice,
+    Bidirectional,
+}
+
+impl DmaDirection {
+    fn to_device(self) -> bool { self != DmaDirection::FromDevice }
+    fn from_device(self) -> bool { self != DmaDirection::ToDevice }
+}
+
+/// A physically contiguous part of a [DmaMapping]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaSegment {
+    pub phys: PhysAddr,
+    pub len: usize,
+}
+
+/// Memory lent to a device for a transfer.
+///
+/// The memory belongs to the device from [DmaMapping::new] until the mapping is dropped, and the
+/// CPU shouldn't touch it in between, except around [DmaMapping::sync_for_cpu] and
+/// [DmaMapping::sync_for_device] when reusing one mapping for several transfers.
+#[derive(Debug)]
+pub struct DmaMapping {
+    buf: *mut u8,
+    len: usize,
+    direction: DmaDirection,
+    /// Where the device really goes if it can't reach `buf`
+    bounce: Option<DmaBuffer>,
+    segments: Vec<DmaSegment>,
+}
+
+impl DmaMapping {
+    /// Map `len` bytes at `buf` for a device that can reach memory within `limit`.
+    /// Returns `None` if part of the buffer isn't mapped, or a bounce buffer was needed and there
+    /// wasn't enough memory for one.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for `len` bytes until the mapping is dropped, and writable unless
+    /// `direction` is [DmaDirection::ToDevice].
+    pub unsafe fn new(buf: *const u8, len: usize, direction: DmaDirection, limit: AddressLimit) -> Option<Self> {
+        let mut segments: Vec<DmaSegment> = Vec::new();
+        let mut reachable = true;
+        let mut addr = buf as u64;
+        let end = addr + len as u64;
+        while addr < end {
+            let chunk = (core::cmp::min((addr & !(FRAME_SIZE - 1)) + FRAME_SIZE, end) - addr) as usize;
+            let phys = translate_addr(VirtAddr::new(addr))?;
+            if limit == AddressLimit::Below4GiB && phys.as_u64() + chunk as u64 > 1 << 32 {
+                reachable = false;
+            }
+            match segments.last_mut() {
+                Some(last) if last.phys + last.len as u64 == phys => last.len += chunk,
+                _ => segments.push(DmaSegment { phys, len: chunk }),
+            }
+            addr += chunk as u64;
+        }
+
+        let mut mapping = Self { buf: buf as *mut u8, len, direction, bounce: None, segments };
+        if !reachable {
+            let bounce = DmaBuffer::new(len, 1, limit)?;
+            mapping.segments = alloc::vec![DmaSegment { phys: bounce.phys(), len }];
+            mapping.bounce = Some(bounce);
+        }
+        mapping.sync_for_device();
+        Some(mapping)
+    }
+
+    /// Where the device should go, in order
+    pub fn segments(&self) -> &[DmaSegment] { &self.segments }
+
+    /// Whether the transfer goes through a bounce buffer
+    pub fn is_bounced(&self) -> bool { self.bounce.is_some() }
+
+    pub fn direction(&self) -> DmaDirection { self.direction }
+
+    /// Hand the memory back to the device after the CPU changed it
+    pub fn sync_for_device(&mut self) {
+        if let Some(bounce) = &self.bounce {
+            if self.direction.to_device() {
+                unsafe { core::ptr::copy_nonoverlapping(self.buf, bounce.as_mut_ptr(), self.len) };
+            }
+        }
+        // the device mustn't see the memory before our writes to it
+        fence(Ordering::SeqCst);
+    }
+
+    /// Make what the device wrote visible to the CPU, once the transfer has completed
+    pub fn sync_for_cpu(&mut self) {
+        fence(Ordering::SeqCst);
+        if let Some(bounce) = &self.bounce {
+            if self.direction.from_device() {
+                unsafe { core::ptr::copy_nonoverlapping(bounce.as_mut_ptr(), self.buf, self.len) };
+            }
+        }
+    }
+}
+
+impl Drop for DmaMapping {
+    fn drop(&mut self) {
+        self.sync_for_cpu();
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_dma_buffer() {
+    let buffer = DmaBuffer::new(3 * FRAME_SIZE as usize + 1, 16 * FRAME_SIZE as usize, AddressLimit::Below4GiB).unwrap();
+    assert!(buffer.phys().is_aligned(16 * FRAME_SIZE));
+    assert!(buffer.phys().as_u64() + 4 * FRAME_SIZE <= 1 << 32);
+    assert_eq!(translate_addr(buffer.virt()), Some(buffer.phys()));
+    let bytes = unsafe { core::slice::from_raw_parts(buffer.as_mut_ptr(), 4 * FRAME_SIZE as usize) };
+    assert!(bytes.iter().all(|&b| b == 0));
+}
+
+#[test_case]
+fn test_dma_mapping() {
+    // a heap buffer spanning a page boundary gets every byte covered, in order
+    let data = alloc::vec![0x5Au8; 2 * FRAME_SIZE as usize];
+    let start = unsafe { data.as_ptr().add(100) };
+    let mapping = unsafe { DmaMapping::new(start, FRAME_SIZE as usize, DmaDirection::ToDevice, AddressLimit::Any) }.unwrap();
+    assert!(!mapping.is_bounced());
+    assert_eq!(mapping.segments().iter().map(|s| s.len).sum::<usize>(), FRAME_SIZE as usize);
+    assert_eq!(mapping.segments()[0].phys, translate_addr(VirtAddr::from_ptr(start)).unwrap());
+    drop(mapping);
+
+    // memory above 4 GiB goes through a bounce buffer for 32-bit devices
+    let high = match frame::allocate_contiguous(1, FRAME_SIZE, AddressLimit::Any) {
+        Some(phys) if phys.as_u64() >= 1 << 32 => phys,
+        Some(phys) => {
+            // not enough memory to test that
+            unsafe { frame::free_contiguous(phys, 1) };
+            return;
+        }
+        None => panic!("out of memory"),
+    };
+    let ptr = (PHYS_MEM_OFFSET + high.as_u64()) as *mut u8;
+    unsafe { core::ptr::write_bytes(ptr, 0x11, 16) };
+    let mut mapping = unsafe { DmaMapping::new(ptr, 16, DmaDirection::Bidirectional, AddressLimit::Below4GiB) }.unwrap();
+    assert!(mapping.is_bounced());
+    let bounce = (PHYS_MEM_OFFSET + mapping.segments()[0].phys.as_u64()) as *mut u8;
+    assert_eq!(unsafe { bounce.read() }, 0x11);
+    // pretend the device wrote something
+    unsafe { bounce.write(0x22) };
+    mapping.sync_for_cpu();
+    assert_eq!(unsafe { ptr.read() }, 0x22);
+    drop(mapping);
+    unsafe { frame::free_contiguous(high, 1) };
+    drop(data);
+}
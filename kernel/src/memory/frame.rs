@@ -0,0 +1,433 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Buddy allocator for physical memory.
+//!
+//! Free blocks of 2^order frames are kept in one doubly linked list per order and zone, with the
+//! list nodes stored in the free frames themselves. The only other bookkeeping is one byte per
+//! frame, carved out of usable memory at boot, that says whether the frame starts a free block
+//! and of which order. That's enough to find and merge buddies in constant time, so nothing here
+//! needs the heap (which is itself built out of frames from here).
+
+use core::fmt::{Debug, Formatter};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use bootloader::bootinfo::MemoryRegionType;
+
+pub const FRAME_SIZE: u64 = 4096;
+/// Largest block is 2^MAX_ORDER frames (4 MiB), which also limits contiguous allocations
+pub const MAX_ORDER: usize = 10;
+/// Anything below this is reachable by devices that only do 32-bit DMA
+const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Marks the first frame of a free block in the state map, the low bits hold the order
+const FREE: u8 = 0x80;
+const ORDER_MASK: u8 = 0x1F;
+/// End of a free list
+const NONE: u64 = u64::MAX;
+
+const ZONE_DMA32: usize = 0;
+const ZONE_NORMAL: usize = 1;
+const ZONE_COUNT: usize = 2;
+
+static FRAME_ALLOCATOR: Mutex<Option<PhysicalMemoryManager>> = Mutex::new(None);
+
+/// Which physical addresses an allocation may come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressLimit {
+    /// Anywhere, preferring memory above 4 GiB to keep the low memory for devices that need it
+    Any,
+    /// Entirely below 4 GiB, for devices limited to 32-bit DMA addresses
+    Below4GiB,
+}
+
+/// Physical memory usage, in frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames managed by the allocator (usable memory minus its own bookkeeping)
+    pub total: u64,
+    pub free: u64,
+    pub free_below_4gib: u64,
+    /// Largest contiguous allocation that would currently succeed
+    pub largest_free_block: u64,
+}
+
+impl FrameStats {
+    pub fn used(&self) -> u64 { self.total - self.free }
+}
+
+/// List node at the start of every free block
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+pub struct PhysicalMemoryManager {
+    /// Virtual address where physical memory is mapped
+    phys_offset: u64,
+    /// First frame number covered by `state`
+    base: u64,
+    /// One byte per frame from `base` on, `FREE | order` for the first frame of a free block, 0 otherwise
+    state: &'static mut [u8],
+    /// Frame number of the first block in each free list, or `NONE`
+    free_lists: [[u64; MAX_ORDER + 1]; ZONE_COUNT],
+    total: u64,
+    free: [u64; ZONE_COUNT],
+}
+
+impl Debug for PhysicalMemoryManager {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PhysicalMemoryManager")
+            .field("base", &self.base)
+            .field("frames", &self.state.len())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl PhysicalMemoryManager {
+    /// Build the allocator out of the given usable physical memory ranges. The state map is
+    /// placed in the first range with enough room and everything else becomes free.
+    /// Returns `None` if there's no room for the state map.
+    ///
+    /// # Safety
+    ///
+    /// The ranges must really be unused and must not overlap, and physical memory has to be
+    /// mapped at `phys_offset`.
+    pub unsafe fn new(regions: &[Range<u64>], phys_offset: u64) -> Option<Self> {
+        let frames = |r: &Range<u64>| {
+            // frame 0 is never handed out, so a physical address of zero can't be mistaken for "none"
+            let start = core::cmp::max((r.start + FRAME_SIZE - 1) / FRAME_SIZE, 1);
+            let end = r.end / FRAME_SIZE;
+            start..core::cmp::max(start, end)
+        };
+        let base = regions.iter().map(frames).filter(|f| !f.is_empty()).map(|f| f.start).min()?;
+        let end = regions.iter().map(frames).map(|f| f.end).max()?;
+        let state_frames = (end - base + FRAME_SIZE - 1) / FRAME_SIZE;
+        let state_start = regions.iter().map(frames).find(|f| f.end - f.start >= state_frames)?.start;
+
+        let state_ptr = (phys_offset + state_start * FRAME_SIZE) as *mut u8;
+        let state = unsafe { core::slice::from_raw_parts_mut(state_ptr, (end - base) as usize) };
+        state.iter_mut().for_each(|s| *s = 0);
+
+        let mut manager = Self {
+            phys_offset,
+            base,
+            state,
+            free_lists: [[NONE; MAX_ORDER + 1]; ZONE_COUNT],
+            total: 0,
+            free: [0; ZONE_COUNT],
+        };
+        let state_range = state_start..state_start + state_frames;
+        for f in regions.iter().map(frames) {
+            if f.start < state_range.end && state_range.start < f.end {
+                manager.add_range(f.start..state_range.start);
+                manager.add_range(state_range.end..f.end);
+            } else {
+                manager.add_range(f);
+            }
+        }
+        Some(manager)
+    }
+
+    /// Like [allocate_frame], but gives up instead of waiting if the allocator is locked. For the page
+/// fault handler, which may have interrupted whoever holds the lock, and so mustn't reclaim either.
+pub fn try_allocate_frame() -> Option<PhysFrame> {
+    without_interrupts(|| FRAME_ALLOCATOR.try_lock()?.as_mut()?.allocate(1, FRAME_SIZE, AddressLimit::Any))
+        .map(PhysFrame::containing_address)
+}
+
+/// Allocate `count` physically contiguous frames, aligned to `align` bytes.
+    /// Blocks are naturally aligned to their size, so alignment only ever rounds the order up.
+    pub fn allocate(&mut self, count: u64, align: u64, limit: AddressLimit) -> Option<PhysAddr> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let order = core::cmp::max(order_for(count), order_for(align / FRAME_SIZE));
+        if order > MAX_ORDER {
+            return None;
+        }
+        let zones: &[usize] = match limit {
+            AddressLimit::Any => &[ZONE_NORMAL, ZONE_DMA32],
+            AddressLimit::Below4GiB => &[ZONE_DMA32],
+        };
+        let frame = zones.iter().find_map(|&zone| self.take(zone, order))?;
+        // give back what was only taken to round up to a power of two
+        self.free_range(frame + count..frame + (1 << order));
+        Some(PhysAddr::new(frame * FRAME_SIZE))
+    }
+
+    /// Return `count` frames starting at `start`, which don't have to be what one `allocate` returned.
+    ///
+    /// # Safety
+    ///
+    /// The frames must have come from this allocator and must not be in use anymore.
+    pub unsafe fn free(&mut self, start: PhysAddr, count: u64) {
+        assert!(start.is_aligned(FRAME_SIZE), "freeing unaligned frame {:?}", start);
+        let frame = start.as_u64() / FRAME_SIZE;
+        assert!(frame >= self.base && frame + count <= self.end(), "freeing frames {:?} that were never managed", start);
+        self.free_range(frame..frame + count);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let largest = self.free_lists.iter()
+            .filter_map(|lists| lists.iter().rposition(|&head| head != NONE))
+            .max()
+            .map(|order| 1 << order)
+            .unwrap_or(0);
+        FrameStats {
+            total: self.total,
+            free: self.free.iter().sum(),
+            free_below_4gib: self.free[ZONE_DMA32],
+            largest_free_block: largest,
+        }
+    }
+
+    fn end(&self) -> u64 { self.base + self.state.len() as u64 }
+
+    /// Add never-before-seen frames to the free lists
+    fn add_range(&mut self, frames: Range<u64>) {
+        if frames.start < frames.end {
+            self.total += frames.end - frames.start;
+            self.free_range(frames);
+        }
+    }
+
+    /// Free a range by splitting it into the largest aligned blocks that fit
+    fn free_range(&mut self, frames: Range<u64>) {
+        let mut frame = frames.start;
+        while frame < frames.end {
+            let mut order = core::cmp::min(frame.trailing_zeros() as usize, MAX_ORDER);
+            while frame + (1 << order) > frames.end {
+                order -= 1;
+            }
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Free one block and merge it with its buddy for as long as the buddy is free too
+    fn free_block(&mut self, mut frame: u64, mut order: usize) {
+        debug_assert_eq!(self.state(frame) & FREE, 0, "double free of frame {:#x}", frame * FRAME_SIZE);
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.unlink(buddy, order);
+            frame = core::cmp::min(frame, buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Pop a block of at least `order` from `zone` and split it down to size
+    fn take(&mut self, zone: usize, order: usize) -> Option<u64> {
+        let mut found = (order..=MAX_ORDER).find(|&o| self.free_lists[zone][o] != NONE)?;
+        let frame = self.free_lists[zone][found];
+        self.unlink(frame, found);
+        while found > order {
+            found -= 1;
+            self.push(frame + (1 << found), found);
+        }
+        Some(frame)
+    }
+
+    fn is_free_block(&self, frame: u64, order: usize) -> bool {
+        frame >= self.base && frame < self.end() && self.state(frame) == FREE | order as u8
+    }
+
+    fn push(&mut self, frame: u64, order: usize) {
+        let zone = zone_of(frame);
+        let head = self.free_lists[zone][order];
+        unsafe {
+            self.node(frame).write(FreeBlock { next: head, prev: NONE });
+            if head != NONE {
+                (*self.node(head)).prev = frame;
+            }
+        }
+        self.free_lists[zone][order] = frame;
+        self.set_state(frame, FREE | order as u8);
+        self.free[zone] += 1 << order;
+    }
+
+    fn unlink(&mut self, frame: u64, order: usize) {
+        debug_assert_eq!(self.state(frame) & ORDER_MASK, order as u8);
+        let zone = zone_of(frame);
+        let FreeBlock { next, prev } = unsafe { self.node(frame).read() };
+        unsafe {
+            if prev != NONE {
+                (*self.node(prev)).next = next;
+            } else {
+                self.free_lists[zone][order] = next;
+            }
+            if next != NONE {
+                (*self.node(next)).prev = prev;
+            }
+        }
+        self.set_state(frame, 0);
+        self.free[zone] -= 1 << order;
+    }
+
+    fn node(&self, frame: u64) -> *mut FreeBlock {
+        (self.phys_offset + frame * FRAME_SIZE) as *mut FreeBlock
+    }
+
+    fn state(&self, frame: u64) -> u8 { self.state[(frame - self.base) as usize] }
+
+    fn set_state(&mut self, frame: u64, value: u8) { self.state[(frame - self.base) as usize] = value; }
+}
+
+/// Smallest order whose blocks hold `count` frames
+fn order_for(count: u64) -> usize {
+    if count <= 1 { 0 } else { (64 - (count - 1).leading_zeros()) as usize }
+}
+
+/// Blocks never straddle 4 GiB since it's a multiple of the largest block size
+fn zone_of(frame: u64) -> usize {
+    if frame * FRAME_SIZE < DMA32_LIMIT { ZONE_DMA32 } else { ZONE_NORMAL }
+}
+
+/// Set up the global frame allocator from the usable regions in [super::GLOBAL_MEMORY_MAP].
+///
+/// # Safety
+///
+/// All frames marked usable in the memory map must really be unused, and this must only be called once.
+pub unsafe fn init() {
+    // no heap yet, and the bootloader's memory map has room for 64 regions too
+    const EMPTY: Range<u64> = 0..0;
+    let mut regions = [EMPTY; 64];
+    let mut count = 0;
+    for region in super::GLOBAL_MEMORY_MAP.lock().iter().filter(|r| r.region_type == MemoryRegionType::Usable) {
+        if count == regions.len() {
+            break;
+        }
+        regions[count] = region.range.start_addr()..region.range.end_addr();
+        count += 1;
+    }
+    let manager = unsafe { PhysicalMemoryManager::new(&regions[..count], crate::PHYS_MEM_OFFSET) }
+        .expect("no usable memory for the frame allocator");
+    *FRAME_ALLOCATOR.lock() = Some(manager);
+}
+
+fn with_allocator<T>(f: impl FnOnce(&mut PhysicalMemoryManager) -> T) -> T {
+    // page faults and interrupt handlers may need frames too
+    without_interrupts(|| f(FRAME_ALLOCATOR.lock().as_mut().expect("frame allocator isn't initialized")))
+}
+
+/// Allocate a single frame from anywhere in physical memory
+pub fn allocate_frame() -> Option<PhysFrame> {
+    allocate_contiguous(1, FRAME_SIZE, AddressLimit::Any).map(PhysFrame::containing_address)
+}
+
+/// Like [allocate_frame], but if there's none left, frees what caches hold with [super::reclaim]
+/// and tries again. Mustn't be called with any of the locks that takes held.
+pub fn allocate_frame_or_reclaim() -> Option<PhysFrame> {
+    allocate_frame().or_else(|| if super::reclaim() { allocate_frame() } else { None })
+}
+
+/// Like [allocate_frame], but gives up instead of waiting if the allocator is locked. For the page
+/// fault handler, which may have interrupted whoever holds the lock, and so mustn't reclaim either.
+pub fn try_allocate_frame() -> Option<PhysFrame> {
+    without_interrupts(|| FRAME_ALLOCATOR.try_lock()?.as_mut()?.allocate(1, FRAME_SIZE, AddressLimit::Any))
+        .map(PhysFrame::containing_address)
+}
+
+/// Allocate `count` physically contiguous frames aligned to `align` bytes, at most 2^MAX_ORDER frames
+pub fn allocate_contiguous(count: u64, align: u64, limit: AddressLimit) -> Option<PhysAddr> {
+    with_allocator(|a| a.allocate(count, align, limit))
+}
+
+/// # Safety
+///
+/// The frame must have come from this allocator and must not be in use anymore.
+pub unsafe fn free_frame(frame: PhysFrame) {
+    unsafe { free_contiguous(frame.start_address(), 1) }
+}
+
+/// # Safety
+///
+/// The frames must have come from this allocator and must not be in use anymore.
+pub unsafe fn free_contiguous(start: PhysAddr, count: u64) {
+    with_allocator(|a| unsafe { a.free(start, count) })
+}
+
+/// Current physical memory usage, or all zeroes before [init]
+pub fn stats() -> FrameStats {
+    without_interrupts(|| FRAME_ALLOCATOR.lock().as_ref().map(|a| a.stats()).unwrap_or_default())
+}
+
+/// Hands out frames from the global frame allocator, for use with the page table mapper
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
+/// [GlobalFrameAllocator] for the page fault handler, see [try_allocate_frame]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for FaultFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        try_allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe { free_frame(frame) }
+    }
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_buddy_allocator() {
+    const FRAMES: usize = 64;
+    #[repr(C, align(4096))]
+    struct Memory([u8; FRAMES * FRAME_SIZE as usize]);
+    static mut MEMORY: Memory = Memory([0; FRAMES * FRAME_SIZE as usize]);
+
+    // pretend the buffer sits right below and above 4 GiB, so both zones get used
+    let phys_start = DMA32_LIMIT - 32 * FRAME_SIZE;
+    let virt_start = unsafe { core::ptr::addr_of_mut!(MEMORY) as u64 };
+    let phys_offset = virt_start.wrapping_sub(phys_start);
+    let region = phys_start..phys_start + FRAMES as u64 * FRAME_SIZE;
+    let mut manager = unsafe { PhysicalMemoryManager::new(&[region], phys_offset) }.unwrap();
+
+    // one frame goes to the state map
+    let initial = manager.stats();
+    assert_eq!(initial.total, FRAMES as u64 - 1);
+    assert_eq!(initial.free, initial.total);
+    assert_eq!(initial.free_below_4gib, 31);
+    assert_eq!(initial.largest_free_block, 32);
+
+    let low = manager.allocate(3, 4 * FRAME_SIZE, AddressLimit::Below4GiB).unwrap();
+    assert!(low.as_u64() + 3 * FRAME_SIZE <= DMA32_LIMIT);
+    assert!(low.is_aligned(4 * FRAME_SIZE));
+    let high = manager.allocate(16, FRAME_SIZE, AddressLimit::Any).unwrap();
+    assert!(high.as_u64() >= DMA32_LIMIT);
+    assert_eq!(manager.stats().free, initial.free - 19);
+    assert!(manager.allocate(32, FRAME_SIZE, AddressLimit::Below4GiB).is_none());
+    assert!(manager.allocate(1 << (MAX_ORDER + 1), FRAME_SIZE, AddressLimit::Any).is_none());
+
+    // freeing in pieces merges everything back together
+    unsafe {
+        manager.free(high + 8 * FRAME_SIZE, 8);
+        manager.free(high, 8);
+        manager.free(low, 3);
+    }
+    assert_eq!(manager.stats(), initial);
+    assert_eq!(manager.allocate(32, 32 * FRAME_SIZE, AddressLimit::Any), Some(PhysAddr::new(DMA32_LIMIT)));
+}
@@ -0,0 +1,181 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Mapping device memory with the right cache attributes.
+//!
+//! The bootloader's map of physical memory is write-back cacheable, which is fine for RAM but not
+//! for device registers: reads can come from the cache and writes can be delayed or merged.
+//! [ioremap] maps physical memory into the kernel address space with a chosen [CacheMode]
+//! instead, using the page attribute table for write combining.
+
+use core::fmt::{Debug, Formatter};
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::address_space::{self, AddressSpaceError, Backing, Protection, PAGE_SIZE};
+
+const IA32_PAT: u32 = 0x277;
+/// Page attribute table entries, one per byte from entry 0 up. 0-3 are the power-on defaults (write
+/// back, write through, uncached overridable by MTRRs, uncached), so mappings made without the PAT
+/// bit mean the same thing as before. 4-7 would repeat them, but entry 4 is write combining instead.
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+/// For 4 KiB pages bit 7 selects the upper half of the PAT, it's only the huge page bit at higher levels
+pub const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// How accesses to a mapping are cached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal memory
+    WriteBack,
+    /// Reads are cached, writes go straight to memory
+    WriteThrough,
+    /// Writes are buffered and may be merged, reads aren't cached. For framebuffers and the like.
+    WriteCombining,
+    /// Every access goes to the device, in order. For device registers.
+    Uncached,
+}
+
+impl CacheMode {
+    /// Page table flags selecting this mode with the PAT set up by [init]
+    pub fn page_flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PAT_4KIB,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Program the page attribute table. Has to run on every CPU before [CacheMode::WriteCombining] is used.
+pub fn init() {
+    let mut pat = Msr::new(IA32_PAT);
+    unsafe { pat.write(PAT_VALUE) };
+}
+
+/// Physical memory mapped into the kernel address space, unmapped again when dropped
+pub struct IoMap<T: ?Sized> {
+    ptr: NonNull<T>,
+    phys: PhysAddr,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ?Sized> IoMap<T> {
+    pub fn as_ptr(&self) -> *mut T { self.ptr.as_ptr() }
+
+    pub fn virt_addr(&self) -> VirtAddr { VirtAddr::from_ptr(self.ptr.as_ptr() as *const u8) }
+
+    pub fn phys_addr(&self) -> PhysAddr { self.phys }
+
+    /// Keeps the mapping forever, for devices that stay around as long as the kernel does
+    pub fn leak(self) -> &'static mut T {
+        let ptr = self.ptr.as_ptr();
+        core::mem::forget(self);
+        unsafe { &mut *ptr }
+    }
+}
+
+// the mapping is owned like a `Box` is, so it can move and be shared the same way
+unsafe impl<T: ?Sized + Send> Send for IoMap<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for IoMap<T> {}
+
+impl<T: ?Sized> Deref for IoMap<T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { self.ptr.as_ref() } }
+}
+
+impl<T: ?Sized> DerefMut for IoMap<T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { self.ptr.as_mut() } }
+}
+
+impl<T: ?Sized> Drop for IoMap<T> {
+    fn drop(&mut self) {
+        unsafe { iounmap(self.virt_addr()) };
+    }
+}
+
+impl<T: ?Sized> Debug for IoMap<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "IoMap {{ virt: {:#x}, phys: {:#x} }}", self.virt_addr().as_u64(), self.phys.as_u64())
+    }
+}
+
+/// Maps a `T` at physical address `phys`.
+///
+/// # Safety
+///
+/// There must be a `T` at `phys`, and nothing else may be using it as anything else.
+pub unsafe fn ioremap<T>(phys: PhysAddr, cache: CacheMode) -> Result<IoMap<T>, AddressSpaceError> {
+    let virt = map(phys, size_of::<T>() as u64, cache)?;
+    Ok(IoMap { ptr: NonNull::new(virt.as_mut_ptr()).unwrap(), phys, _marker: PhantomData })
+}
+
+/// Maps `size` bytes of physical memory starting at `phys`.
+///
+/// # Safety
+///
+/// Reading or writing the memory mustn't interfere with anything else using it.
+pub unsafe fn ioremap_region(phys: PhysAddr, size: u64, cache: CacheMode) -> Result<IoMap<[u8]>, AddressSpaceError> {
+    let virt = map(phys, size, cache)?;
+    let ptr = NonNull::new(core::ptr::slice_from_raw_parts_mut(virt.as_mut_ptr::<u8>(), size as usize)).unwrap();
+    Ok(IoMap { ptr, phys, _marker: PhantomData })
+}
+
+/// Maps the pages covering `size` bytes at `phys`, returns where `phys` ended up
+fn map(phys: PhysAddr, size: u64, cache: CacheMode) -> Result<VirtAddr, AddressSpaceError> {
+    let base = phys.align_down(PAGE_SIZE);
+    let offset = phys - base;
+    let pages_size = (offset + core::cmp::max(size, 1) + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let protection = Protection::READ | Protection::WRITE;
+    let start = address_space::with_kernel_space(|space| {
+        space.allocate(pages_size, PAGE_SIZE, protection, Backing::Physical { base, cache }, "mmio")
+    })?;
+    Ok(start + offset)
+}
+
+/// Unmaps memory mapped with [ioremap] or [ioremap_region], given any address inside the mapping.
+///
+/// # Safety
+///
+/// Nothing may use the mapping afterwards.
+pub unsafe fn iounmap(addr: VirtAddr) {
+    address_space::with_kernel_space(|space| {
+        let start = space.area(addr).expect("iounmap of memory that isn't mapped").start;
+        space.unmap(start).expect("iounmap failed");
+    });
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_ioremap() {
+    // map a page of RAM a second time and check both mappings see the same memory
+    let frame = crate::memory::frame::allocate_frame().unwrap();
+    let phys = frame.start_address();
+    let direct = (crate::PHYS_MEM_OFFSET + phys.as_u64()) as *mut u32;
+    unsafe { direct.add(4).write_volatile(0x1234_5678) };
+
+    let mut mapped = unsafe { ioremap::<[u32; 8]>(phys, CacheMode::Uncached) }.unwrap();
+    assert_eq!(unsafe { core::ptr::read_volatile(&mapped[4]) }, 0x1234_5678);
+    unsafe { core::ptr::write_volatile(&mut mapped[5], 0x9ABC_DEF0) };
+    assert_eq!(unsafe { direct.add(5).read_volatile() }, 0x9ABC_DEF0);
+
+    // unaligned regions keep their offset
+    let region = unsafe { ioremap_region(phys + 16u64, 8, CacheMode::WriteCombining) }.unwrap();
+    assert_eq!(region.len(), 8);
+    assert_eq!(region.virt_addr().as_u64() % PAGE_SIZE, 16);
+    assert_eq!(crate::memory::translate_addr(region.virt_addr()), Some(phys + 16u64));
+
+    let virt = mapped.virt_addr();
+    drop(mapped);
+    drop(region);
+    assert!(crate::memory::translate_addr(virt).is_none());
+    unsafe { crate::memory::frame::free_frame(frame) };
+}
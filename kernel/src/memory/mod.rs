@@ -4,24 +4,46 @@
 // See LICENSE.txt and CREDITS.txt for details
 ///////////////////////////////////////////////////////////////////////////////L
 
+/// Virtual memory areas and address spaces
+pub mod address_space;
 pub mod allocator;
+/// Buffers for devices to DMA to and from
+pub mod dma;
+/// Physical frame allocator
+pub mod frame;
+/// Mapping device memory with the right cache attributes
+pub mod mmio;
+/// Pages of files kept in memory
+pub mod page_cache;
+/// Kernel stacks with guard pages
+pub mod stack;
 
 use x86_64::{structures::paging::PageTable, VirtAddr, PhysAddr};
-use x86_64::structures::paging::{OffsetPageTable, FrameAllocator, Size4KiB, PhysFrame};
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType, MemoryRegion};
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
+use bootloader::bootinfo::{MemoryMap, MemoryRegion};
 use lazy_static::lazy_static;
 use spin::Mutex;
-use bitflags::_core::sync::atomic::AtomicU64;
-use core::sync::atomic::{Ordering, AtomicBool};
+use core::sync::atomic::AtomicBool;
 
 lazy_static! {
     pub static ref GLOBAL_MEMORY_MAP: Mutex<MemoryMap> = Mutex::new(MemoryMap::new());
 }
 
 pub static HAVE_ALLOC: AtomicBool = AtomicBool::new(false);
-pub static AHCI_MEM_REGION: Mutex<Option<MemoryRegion>> = Mutex::new(None);
+/// Buffer below 16 MiB for ISA DMA transfers, see [crate::driver::isa_dma]
+pub static ISA_DMA_REGION: Mutex<Option<MemoryRegion>> = Mutex::new(None);
 
 
+/// Give back memory that's only kept around to save work later: the page cache and empty slabs.
+/// Returns false if there was nothing to free.
+///
+/// This takes the page cache, slab cache and heap locks, so it mustn't be called with any of them held.
+pub fn reclaim() -> bool {
+    let pages = page_cache::shrink();
+    let slabs = allocator::slab::shrink_all();
+    pages + slabs > 0
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// # Safety
@@ -42,31 +64,31 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
     unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) }
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-#[derive(Debug)]
-pub struct BootInfoFrameAllocator {
-    next: AtomicU64,
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the global memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the global
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init() -> Self {
-        BootInfoFrameAllocator { next: AtomicU64::new(0) }
-    }
-}
+/// Translate a virtual address to the physical address it's mapped to by walking the active page tables.
+/// Returns `None` if the address isn't mapped.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags;
 
-// TODO: deallocate frames
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        GLOBAL_MEMORY_MAP.lock().iter()
-            .filter(|r| r.region_type == MemoryRegionType::Usable)
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            .flat_map(|r| r.step_by(4096))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-            .nth(self.next.fetch_add(1, Ordering::Relaxed) as usize)
+    let (mut frame, _) = Cr3::read();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, &index) in indexes.iter().enumerate() {
+        let virt = crate::PHYS_MEM_OFFSET + frame.start_address().as_u64();
+        let table = unsafe { &*(virt as *const PageTable) };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // in the last level that bit selects the PAT entry instead
+        if level < 3 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let offset_mask = match level {
+                1 => 0x3FFF_FFFF, // 1 GiB page
+                2 => 0x1F_FFFF,   // 2 MiB page
+                _ => return None,
+            };
+            return Some(entry.addr() + (addr.as_u64() & offset_mask));
+        }
+        frame = PhysFrame::containing_address(entry.addr());
     }
+    Some(frame.start_address() + u64::from(addr.page_offset()))
 }
@@ -0,0 +1,231 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Pages of files kept in memory.
+//!
+//! File-backed areas get private copies of a file's pages when they're first touched. The cache
+//! sits between them and the [PageSource], so every page is only read once no matter how many
+//! areas map it or how often they're unmapped and faulted in again.
+//!
+//! The page fault handler ends up in here, so nothing in this module may fault, and the cache is
+//! never locked while reading from a source. Pages read by the fault handler aren't added to the
+//! cache, see [read_page_in_fault].
+//!
+//! Filesystem reads are async and wait for disk interrupts, which the page fault handler can't do,
+//! so files are mapped through [crate::fs::file_pages::FilePages], which a task reads in first.
+//!
+//! The cache is emptied by [crate::memory::reclaim] when physical memory runs out.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PhysFrame;
+use crate::memory::address_space::{PageSource, PAGE_SIZE};
+use crate::memory::frame;
+use crate::PHYS_MEM_OFFSET;
+
+/// Cached pages of every source, by the source's address
+static PAGE_CACHE: Mutex<Vec<CachedFile>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct CachedFile {
+    /// Kept alive so its address can't be reused by another source while it's in the cache
+    source: Arc<dyn PageSource>,
+    /// Frames holding the file's contents, by offset into the file
+    pages: BTreeMap<u64, PhysFrame>,
+}
+
+/// How much the cache holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageCacheStats {
+    pub files: usize,
+    pub pages: usize,
+}
+
+fn key(source: &Arc<dyn PageSource>) -> usize {
+    Arc::as_ptr(source) as *const u8 as usize
+}
+
+/// # Safety
+///
+/// Nothing else may be using the frame.
+unsafe fn contents<'a>(frame: PhysFrame) -> &'a mut [u8] {
+    let ptr = (PHYS_MEM_OFFSET + frame.start_address().as_u64()) as *mut u8;
+    unsafe { core::slice::from_raw_parts_mut(ptr, PAGE_SIZE as usize) }
+}
+
+/// Copies the cached page at `offset` of `source` into `page`, returns false if it isn't cached
+fn copy_cached(cache: &[CachedFile], source: &Arc<dyn PageSource>, offset: u64, page: &mut [u8]) -> bool {
+    let frame = cache.iter()
+        .find(|file| key(&file.source) == key(source))
+        .and_then(|file| file.pages.get(&offset));
+    match frame {
+        Some(&frame) => {
+            page.copy_from_slice(unsafe { contents(frame) });
+            true
+        },
+        None => false,
+    }
+}
+
+fn check_alignment(offset: u64, page: &[u8]) -> anyhow::Result<()> {
+    if offset % PAGE_SIZE != 0 || page.len() != PAGE_SIZE as usize {
+        return Err(anyhow::anyhow!("page cache: unaligned read at {:#x}", offset));
+    }
+    Ok(())
+}
+
+/// Fills `page` with the page at `offset` into `source`, which has to be page aligned.
+/// The page is read from the source and kept if it isn't cached yet.
+pub fn read_page(source: &Arc<dyn PageSource>, offset: u64, page: &mut [u8]) -> anyhow::Result<()> {
+    check_alignment(offset, page)?;
+    if without_interrupts(|| copy_cached(&PAGE_CACHE.lock(), source, offset, page)) {
+        return Ok(());
+    }
+
+    let frame = frame::allocate_frame_or_reclaim().ok_or_else(|| anyhow::anyhow!("page cache: out of memory"))?;
+    if let Err(e) = source.read_page(offset, unsafe { contents(frame) }) {
+        unsafe { frame::free_frame(frame) };
+        return Err(e);
+    }
+    page.copy_from_slice(unsafe { contents(frame) });
+
+    without_interrupts(|| {
+        let mut cache = PAGE_CACHE.lock();
+        let index = match cache.iter().position(|file| key(&file.source) == key(source)) {
+            Some(index) => index,
+            None => {
+                cache.push(CachedFile { source: source.clone(), pages: BTreeMap::new() });
+                cache.len() - 1
+            }
+        };
+        if cache[index].pages.contains_key(&offset) {
+            // read by someone else in the meantime
+            unsafe { frame::free_frame(frame) };
+        } else {
+            cache[index].pages.insert(offset, frame);
+        }
+    });
+    Ok(())
+}
+
+/// [read_page] for the page fault handler. The cache is skipped if it's locked, and pages that
+/// aren't cached are read straight into `page` without being kept, since keeping them allocates.
+pub fn read_page_in_fault(source: &Arc<dyn PageSource>, offset: u64, page: &mut [u8]) -> anyhow::Result<()> {
+    check_alignment(offset, page)?;
+    let cached = without_interrupts(|| match PAGE_CACHE.try_lock() {
+        Some(cache) => copy_cached(&cache, source, offset, page),
+        None => false,
+    });
+    if cached {
+        return Ok(());
+    }
+    source.read_page(offset, page)
+}
+
+/// Forgets every cached page of `source`, for when the file changes or goes away.
+/// Areas that already have their copies keep them.
+pub fn invalidate(source: &Arc<dyn PageSource>) {
+    let removed = without_interrupts(|| {
+        let mut cache = PAGE_CACHE.lock();
+        let index = cache.iter().position(|file| key(&file.source) == key(source))?;
+        Some(cache.swap_remove(index))
+    });
+    if let Some(file) = removed {
+        free_pages(file);
+    }
+}
+
+/// Empties the cache, returning the number of frames freed
+pub fn shrink() -> usize {
+    let files = without_interrupts(|| core::mem::take(&mut *PAGE_CACHE.lock()));
+    files.into_iter().map(free_pages).sum()
+}
+
+fn free_pages(file: CachedFile) -> usize {
+    let count = file.pages.len();
+    for frame in file.pages.into_values() {
+        unsafe { frame::free_frame(frame) };
+    }
+    count
+}
+
+pub fn stats() -> PageCacheStats {
+    without_interrupts(|| {
+        let cache = PAGE_CACHE.lock();
+        PageCacheStats { files: cache.len(), pages: cache.iter().map(|file| file.pages.len()).sum() }
+    })
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+/// Pages filled with their page number, counting how often they're read
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct CountingSource {
+    reads: core::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl PageSource for CountingSource {
+    fn read_page(&self, offset: u64, page: &mut [u8]) -> anyhow::Result<()> {
+        self.reads.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        page.fill((offset / PAGE_SIZE) as u8);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_page_cache() {
+    use core::sync::atomic::Ordering;
+    use crate::memory::address_space::{with_kernel_space, Backing, Protection};
+    let counting = Arc::new(CountingSource::default());
+    let source: Arc<dyn PageSource> = counting.clone();
+    let mut page = alloc::vec![0u8; PAGE_SIZE as usize];
+
+    read_page(&source, 3 * PAGE_SIZE, &mut page).unwrap();
+    assert!(page.iter().all(|&b| b == 3));
+    page.fill(0);
+    read_page(&source, 3 * PAGE_SIZE, &mut page).unwrap();
+    assert!(page.iter().all(|&b| b == 3));
+    assert_eq!(counting.reads.load(Ordering::Relaxed), 1);
+    assert!(read_page(&source, 5, &mut page).is_err());
+
+    invalidate(&source);
+    read_page(&source, 3 * PAGE_SIZE, &mut page).unwrap();
+    assert_eq!(counting.reads.load(Ordering::Relaxed), 2);
+
+    // file-backed areas fault their pages in through the cache, without adding to it
+    let backing = Backing::File { source: source.clone(), offset: 2 * PAGE_SIZE };
+    let area = with_kernel_space(|space| space.allocate_lazy(2 * PAGE_SIZE, PAGE_SIZE, Protection::READ, backing, "test file"))
+        .unwrap();
+    assert_eq!(unsafe { (area + PAGE_SIZE).as_ptr::<u8>().read_volatile() }, 3);
+    assert_eq!(counting.reads.load(Ordering::Relaxed), 2);
+    assert_eq!(unsafe { area.as_ptr::<u8>().read_volatile() }, 2);
+    assert_eq!(counting.reads.load(Ordering::Relaxed), 3);
+    assert_eq!(stats().pages, 1);
+    with_kernel_space(|space| space.unmap(area)).unwrap();
+
+    invalidate(&source);
+    // the cache was the only other owner
+    assert_eq!(Arc::strong_count(&counting), 2);
+}
+
+#[test_case]
+fn test_page_cache_reclaim() {
+    let counting = Arc::new(CountingSource::default());
+    let source: Arc<dyn PageSource> = counting.clone();
+    let mut page = alloc::vec![0u8; PAGE_SIZE as usize];
+    read_page(&source, 0, &mut page).unwrap();
+    assert!(stats().pages > 0);
+
+    // what running out of frames does
+    assert!(crate::memory::reclaim());
+    assert_eq!(stats(), PageCacheStats::default());
+    assert_eq!(Arc::strong_count(&counting), 2);
+}
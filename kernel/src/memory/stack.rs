@@ -0,0 +1,146 @@
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
+
+//! Kernel stacks with guard pages.
+//!
+//! A [KernelStack] is an area of the kernel address space, and [AddressSpace::allocate] keeps the
+//! page below every area unmapped, so running off the bottom of a stack faults instead of quietly
+//! overwriting whatever comes next. Stacks are registered along with that guard page, which lets
+//! the fault handlers tell a stack overflow from any other bad access and say whose it was.
+//! Usually that's the double fault handler: with the stack pointer in the guard page, the CPU
+//! can't push the page fault's frame.
+//!
+//! [AddressSpace::allocate]: crate::memory::address_space::AddressSpace::allocate
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+use crate::memory::address_space::{self, AddressSpaceError, Backing, Protection, GUARD_SIZE, PAGE_SIZE};
+use crate::memory::translate_addr;
+
+/// Size of the stack the executor runs on. Tasks are polled on it one after another rather than
+/// getting stacks of their own, so this is the deepest any of them can go.
+pub const KERNEL_STACK_SIZE: u64 = 256 * 1024;
+
+/// Every stack with a guard page we know of
+static STACKS: Mutex<Vec<StackInfo>> = Mutex::new(Vec::new());
+
+/// Where a stack is and what it's for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackInfo {
+    /// Lowest address of the stack, the guard page is right below
+    pub bottom: VirtAddr,
+    /// Address just past the stack, where the stack pointer starts
+    pub top: VirtAddr,
+    pub name: &'static str,
+}
+
+impl StackInfo {
+    /// Whether `addr` is in the guard page
+    pub fn guards(&self, addr: VirtAddr) -> bool {
+        addr < self.bottom && addr >= self.bottom - GUARD_SIZE
+    }
+}
+
+/// A mapped stack with an unmapped guard page below it, unmapped again when dropped
+#[derive(Debug)]
+pub struct KernelStack {
+    info: StackInfo,
+}
+
+impl KernelStack {
+    /// Allocate a stack of `size` bytes, rounded up to whole pages
+    pub fn new(size: u64, name: &'static str) -> Result<Self, AddressSpaceError> {
+        let protection = Protection::READ | Protection::WRITE;
+        let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let bottom = address_space::with_kernel_space(|space| space.allocate(size, PAGE_SIZE, protection, Backing::Anonymous, name))?;
+        let info = StackInfo { bottom, top: bottom + size, name };
+        register(info);
+        Ok(Self { info })
+    }
+
+    /// Initial stack pointer, stacks grow down from here
+    pub fn top(&self) -> VirtAddr { self.info.top }
+
+    pub fn bottom(&self) -> VirtAddr { self.info.bottom }
+
+    pub fn info(&self) -> StackInfo { self.info }
+
+    /// Keeps the stack forever, for stacks used as long as the kernel runs. Returns its top.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let bottom = self.info.bottom;
+        without_interrupts(|| STACKS.lock().retain(|stack| stack.bottom != bottom));
+        address_space::with_kernel_space(|space| space.unmap(self.info.bottom))
+            .expect("kernel stack wasn't mapped");
+    }
+}
+
+/// Switch to `stack` and call `f` on it. There's no way back, the old stack is simply abandoned.
+pub fn run_on(stack: KernelStack, f: fn() -> !) -> ! {
+    let top = stack.leak();
+    // the top is page aligned, so the return address `call` pushes leaves `f` with the alignment it expects
+    unsafe {
+        asm!("mov rsp, {top}", "xor ebp, ebp", "call {f}", "ud2",
+             top = in(reg) top.as_u64(), f = in(reg) f, options(noreturn));
+    }
+}
+
+/// Remember a stack so overflowing it gets reported. The page below `bottom` has to be unmapped.
+pub fn register(info: StackInfo) {
+    without_interrupts(|| STACKS.lock().push(info));
+}
+
+/// Register the stack we're running on, whose guard page is the first unmapped page below the
+/// stack pointer. For the stack the bootloader set up.
+pub fn register_current(name: &'static str) {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let mapped = |page: u64| translate_addr(VirtAddr::new(page)).is_some();
+    let mut bottom = rsp & !(PAGE_SIZE - 1);
+    while mapped(bottom - PAGE_SIZE) {
+        bottom -= PAGE_SIZE;
+    }
+    let mut top = (rsp & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+    while mapped(top) {
+        top += PAGE_SIZE;
+    }
+    register(StackInfo { bottom: VirtAddr::new(bottom), top: VirtAddr::new(top), name });
+}
+
+/// The stack whose guard page `addr` is in. Called from the fault handlers, so it gives up rather
+/// than wait if the fault hit while the list was locked.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<StackInfo> {
+    STACKS.try_lock()?.iter().find(|stack| stack.guards(addr)).copied()
+}
+
+// Tests ///////////////////////////////////////////////////////////////////////
+
+#[test_case]
+fn test_kernel_stack() {
+    let stack = KernelStack::new(3 * PAGE_SIZE + 1, "test stack").unwrap();
+    assert_eq!(stack.top() - stack.bottom(), 4 * PAGE_SIZE);
+    assert!(translate_addr(stack.bottom()).is_some());
+    assert!(translate_addr(stack.top() - 8u64).is_some());
+    assert!(translate_addr(stack.bottom() - 8u64).is_none());
+
+    assert_eq!(overflowed_stack(stack.bottom() - 8u64), Some(stack.info()));
+    assert_eq!(overflowed_stack(stack.bottom() - GUARD_SIZE - 8u64), None);
+    assert_eq!(overflowed_stack(stack.bottom()), None);
+
+    let bottom = stack.bottom();
+    drop(stack);
+    assert_eq!(overflowed_stack(bottom - 8u64), None);
+    assert!(translate_addr(bottom).is_none());
+}
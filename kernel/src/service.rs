@@ -4,18 +4,37 @@
 // See LICENSE.txt and CREDITS.txt for details
 ///////////////////////////////////////////////////////////////////////////////L
 
-//use crate::driver::ata::{AtaDrive, ide_identify};
 //use crate::fs::ext2::Ext2Filesystem;
 use hashbrown::HashMap;
 use spin::Mutex;
-use crate::device::physical::SyncDisk;
+use crate::device::physical::{PhysicalDeviceType, SyncDisk};
+use crate::device::block::{BlockDevice, BlockDeviceMedia};
+use crate::fs::Filesystem;
+use crate::fs::partition::PartitionTable;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
 
 pub static DISK_SERVICE: Mutex<Option<DiskService>> = Mutex::new(None);
 //pub static ref FS_SERVICE: Mutex<FsService> = Mutex::new(FsService::new());
 
 
+/// A partition, or a whole disk without a partition table, and the filesystem found on it
+#[derive(Debug)]
+pub struct Volume {
+    /// Disk service ID of the disk this volume is on
+    pub disk_id: u32,
+    /// Index of the partition in the partition table, `None` for an unpartitioned disk
+    pub partition: Option<usize>,
+    pub device: Arc<BlockDevice>,
+    pub filesystem: Option<Arc<dyn Filesystem>>,
+    /// Holds an encrypted volume, which shows up as a disk of its own once it's unlocked
+    pub encrypted: bool,
+}
+
 pub struct DiskService {
     disks: HashMap<u32, SyncDisk, ahash::RandomState>,
+    volumes: Vec<Volume>,
     next_id: u32,
 }
 impl DiskService {
@@ -23,28 +42,65 @@ impl DiskService {
         if DISK_SERVICE.lock().is_some() {
             crate::both_println!("ERROR: Disk service is already initialized");
         }
-        // TODO: IDE drives
-        // for bus in 0..2 {
-        //     for device in 0..2 {
-        //         unsafe {
-        //             if let Some(info) = ide_identify(bus, device) {
-        //                 self.drives.insert(self.next_id, Arc::new(AtaDrive::from_identify(info, bus, device)));
-        //                 self.next_id += 1;
-        //             }
-        //         }
-        //     }
-        // }
-
-        let mut next_id = 0;
-        let mut disks = HashMap::default();
-        let scanned_disks = crate::driver::ahci::scan_disks().await;
-        for disk in scanned_disks.iter() {
-            disks.insert(next_id, disk.clone());
-            next_id += 1;
+        let mut service = Self { disks: HashMap::default(), volumes: Vec::new(), next_id: 0 };
+        for disk in crate::driver::ahci::scan_disks().await {
+            service.register(disk);
+        }
+        for disk in crate::driver::ide::scan_disks().await {
+            service.register(disk);
+        }
+        for disk in crate::driver::nvme::scan_disks().await {
+            service.register(disk);
+        }
+        for disk in crate::driver::virtio::scan_disks().await {
+            service.register(disk);
+        }
+        for disk in crate::driver::floppy::scan_disks().await {
+            service.register(disk);
         }
 
-        *DISK_SERVICE.lock() = Some(Self { disks, next_id });
+        let mut disks: Vec<(u32, SyncDisk)> = service.iter().map(|(id, disk)| (*id, disk.clone())).collect();
+        disks.sort_by_key(|(id, _)| *id);
+        // RAID members only hold the array's data, so scan the arrays instead of them
+        let mut members: Vec<u32> = Vec::new();
+        for (array, mut ids) in crate::driver::raid1::scan_disks(&disks).await {
+            members.append(&mut ids);
+            let id = service.register(array.clone());
+            disks.push((id, array));
+        }
+        disks.retain(|(id, _)| !members.contains(id));
+        for (id, disk) in disks {
+            let mut volumes = scan_volumes(id, &disk).await;
+            service.volumes.append(&mut volumes);
+        }
+
+        let locked: Vec<(u32, Option<usize>)> = service.volumes.iter()
+            .filter(|volume| volume.encrypted)
+            .map(|volume| (volume.disk_id, volume.partition))
+            .collect();
+        *DISK_SERVICE.lock() = Some(service);
         crate::both_println!("Disk service initialized");
+        for (disk_id, partition) in locked {
+            crate::shell::request_passphrase(crate::shell::PassphraseRequest::Unlock { disk_id, partition });
+        }
+    }
+    /// Add a disk, returning the ID it can be looked up with
+    pub fn register(&mut self, disk: SyncDisk) -> u32 {
+        let id = self.next_id;
+        self.disks.insert(id, disk);
+        self.next_id += 1;
+        id
+    }
+    /// Remove a disk and its volumes, e.g. because it was unplugged
+    pub fn unregister(&mut self, id: u32) -> Option<SyncDisk> {
+        self.volumes.retain(|volume| volume.disk_id != id);
+        self.disks.remove(&id)
+    }
+    /// Find the service ID of the disk with the given type and driver-specific ID
+    pub fn find(&self, kind: PhysicalDeviceType, device_id: usize) -> Option<u32> {
+        self.disks.iter()
+            .find(|(_, disk)| disk.kind() == kind && disk.id() == device_id)
+            .map(|(id, _)| *id)
     }
     pub fn get(&self, id: u32) -> Option<SyncDisk> {
         match self.disks.get(&id) {
@@ -58,6 +114,147 @@ impl DiskService {
     pub fn iter_mut(&mut self) -> hashbrown::hash_map::IterMut<'_, u32, SyncDisk> {
         self.disks.iter_mut()
     }
+    /// Partitions and filesystems found on the registered disks
+    pub fn volumes(&self) -> &[Volume] {
+        &self.volumes
+    }
+}
+
+/// Register a disk that showed up after boot, e.g. a hotplugged drive, a RAM disk or a loop device,
+/// and scan it for partitions and filesystems like the disks found at boot.
+/// Returns `None` if the disk service isn't initialized yet.
+pub async fn add_disk(disk: SyncDisk) -> Option<u32> {
+    let id = DISK_SERVICE.lock().as_mut()?.register(disk.clone());
+    // don't hold the service lock while reading from the disk
+    let mut volumes = scan_volumes(id, &disk).await;
+    if let Some(service) = DISK_SERVICE.lock().as_mut() {
+        // it might have been removed again in the meantime
+        if service.disks.contains_key(&id) {
+            service.volumes.append(&mut volumes);
+        }
+    }
+    Some(id)
+}
+
+/// Scan a disk for partitions and filesystems again, e.g. after its partition table was changed,
+/// replacing the volumes found on it before
+pub async fn rescan_disk(id: u32) -> Option<()> {
+    let disk = DISK_SERVICE.lock().as_ref()?.get(id)?;
+    let mut volumes = scan_volumes(id, &disk).await;
+    let mut lock = DISK_SERVICE.lock();
+    let service = lock.as_mut()?;
+    if service.disks.contains_key(&id) {
+        service.volumes.retain(|volume| volume.disk_id != id);
+        service.volumes.append(&mut volumes);
+    }
+    Some(())
+}
+
+/// Make sure nothing is using a disk before its partition table is rewritten or it's turned into
+/// something else: none of its filesystems are mounted, it isn't part of a RAID array and none of
+/// its encrypted volumes are unlocked
+pub async fn check_disk_unused(id: u32) -> Result<SyncDisk, anyhow::Error> {
+    let (disk, volumes): (SyncDisk, Vec<(Arc<BlockDevice>, Option<Arc<dyn Filesystem>>)>) = {
+        let lock = DISK_SERVICE.lock();
+        let service = lock.as_ref().ok_or_else(|| anyhow::anyhow!("Disk service is not initialized"))?;
+        let disk = service.get(id).ok_or_else(|| anyhow::anyhow!("No disk {}", id))?;
+        let volumes = service.volumes.iter()
+            .filter(|volume| volume.disk_id == id)
+            .map(|volume| (volume.device.clone(), volume.filesystem.clone()))
+            .collect();
+        (disk, volumes)
+    };
+
+    let mounted = unsafe {
+        crate::fs::vfs::GLOBAL_VFS.lock().as_ref().map_or(false, |vfs| volumes.iter()
+            .any(|(_, fs)| fs.as_ref().map_or(false, |fs| vfs.is_mounted(fs))))
+    };
+    if mounted {
+        return Err(anyhow::anyhow!("Disk {} has a mounted filesystem", id));
+    }
+    if crate::driver::raid1::is_member(&disk) {
+        return Err(anyhow::anyhow!("Disk {} is part of a RAID array", id));
+    }
+    for (device, _) in volumes.iter() {
+        if crate::device::crypt::is_unlocked(device).await {
+            return Err(anyhow::anyhow!("Disk {} has an unlocked encrypted volume", id));
+        }
+    }
+    Ok(disk)
+}
+
+/// Read the partition table on a disk and probe every partition for a filesystem.
+/// A disk without a partition table is probed as a whole.
+async fn scan_volumes(disk_id: u32, disk: &SyncDisk) -> Vec<Volume> {
+    let media: Vec<(Option<usize>, BlockDeviceMedia)> = match PartitionTable::read(disk).await {
+        Ok(Some(table)) => table.into_partitions().into_iter()
+            .enumerate()
+            .map(|(i, part)| (Some(i), BlockDeviceMedia::Partition(part)))
+            .collect(),
+        Ok(None) => alloc::vec![(None, BlockDeviceMedia::Disk(disk.clone()))],
+        Err(err) => {
+            // e.g. an empty optical drive
+            crate::serial_println!("Disk {}: couldn't read partition table: {:?}", disk_id, err);
+            return Vec::new();
+        }
+    };
+
+    let mut volumes = Vec::new();
+    for (partition, media) in media {
+        let device = Arc::new(BlockDevice::new(media));
+        let encrypted = crate::device::crypt::is_encrypted(&device).await;
+        let filesystem = if encrypted { None } else { crate::fs::probe(&device).await };
+        match (partition, &filesystem) {
+            (Some(i), None) if encrypted => crate::both_println!("Disk {} partition {}: encrypted", disk_id, i),
+            (None, None) if encrypted => crate::both_println!("Disk {}: encrypted", disk_id),
+            (Some(i), Some(fs)) => crate::both_println!("Disk {} partition {}: {} filesystem", disk_id, i, fs.type_as_str()),
+            (None, Some(fs)) => crate::both_println!("Disk {}: {} filesystem", disk_id, fs.type_as_str()),
+            (Some(i), None) => crate::serial_println!("Disk {} partition {}: no known filesystem", disk_id, i),
+            // only keep whole disks that turn out to hold a filesystem
+            (None, None) => continue,
+        }
+        volumes.push(Volume { disk_id, partition, device, filesystem, encrypted });
+    }
+    volumes
+}
+
+/// How often [disk_health_task] checks the disks
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically reads SMART data from every disk that supports it and warns about any that look like they're dying
+pub async fn disk_health_task() {
+    loop {
+        // don't hold the service lock while talking to the disks
+        let disks: Vec<(u32, SyncDisk)> = match DISK_SERVICE.lock().as_ref() {
+            Some(service) => service.iter()
+                .filter(|(_, disk)| disk.info().map_or(false, |info| info.smart_enabled))
+                .map(|(id, disk)| (*id, disk.clone()))
+                .collect(),
+            None => {
+                // the disk scan hasn't finished yet
+                crate::task::sleep::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        for (id, disk) in disks {
+            match disk.smart().await {
+                Ok(report) => {
+                    if !report.healthy {
+                        crate::both_println!("WARNING: disk {} reports SMART failure, back up your data", id);
+                    }
+                    for attr in report.failing() {
+                        crate::both_println!("WARNING: disk {} SMART attribute {} ({}) is failing: {} (threshold {})",
+                                             id, attr.id, attr.name(), attr.current, attr.threshold);
+                    }
+                    crate::serial_println!("Disk {} SMART: healthy: {} temperature: {:?}", id, report.healthy, report.temperature());
+                },
+                Err(err) => crate::serial_println!("Disk {} SMART check failed: {}", id, err),
+            }
+        }
+
+        crate::task::sleep::sleep(HEALTH_CHECK_INTERVAL).await;
+    }
 }
 
 // #[derive(Debug)]
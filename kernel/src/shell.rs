@@ -4,34 +4,87 @@
 // See LICENSE.txt and CREDITS.txt for details
 ///////////////////////////////////////////////////////////////////////////////L
 
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use core::future::Future;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::{print, println};
 //use crate::fs::vfs::VFS;
 //use crate::fs::VfsNodeType;
 use crate::path::Path;
+use crate::device::block::BlockDevice;
+use crate::device::crypt::CryptTarget;
+use crate::device::physical::SyncDisk;
+use crate::fs::partition::{GptPartitionTable, MbrPartitionTable, PartitionTable, PartitionType};
+use crate::task::Task;
 
 
 lazy_static! {
     pub static ref SHELL: Mutex<Shell> = Mutex::new(Shell {
         command_str: String::new(),
-        working_directory: Path::from("/")
+        working_directory: Path::from("/"),
+        passphrase_requests: VecDeque::new(),
     });
 }
 
+/// Something the shell is asking for a passphrase for, instead of reading a command
+#[derive(Clone)]
+pub enum PassphraseRequest {
+    /// Unlock the encrypted volume on a disk or partition
+    Unlock { disk_id: u32, partition: Option<usize> },
+    /// Set up encryption on a disk or partition. Asked twice to catch typos.
+    Encrypt { disk_id: u32, partition: Option<usize>, first: Option<String> },
+}
+impl PassphraseRequest {
+    fn prompt(&self) -> String {
+        let (action, disk_id, partition) = match self {
+            PassphraseRequest::Unlock { disk_id, partition } => ("unlock", disk_id, partition),
+            PassphraseRequest::Encrypt { disk_id, partition, first: None } => ("encrypt", disk_id, partition),
+            PassphraseRequest::Encrypt { disk_id, partition, first: Some(_) } => ("repeat it for", disk_id, partition),
+        };
+        match partition {
+            Some(i) => alloc::format!("Passphrase to {} disk {} partition {} (empty to skip): ", action, disk_id, i),
+            None => alloc::format!("Passphrase to {} disk {} (empty to skip): ", action, disk_id),
+        }
+    }
+}
+impl Debug for PassphraseRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        // never print the passphrase that's waiting to be confirmed
+        match self {
+            PassphraseRequest::Unlock { disk_id, partition } => write!(f, "Unlock({}, {:?})", disk_id, partition),
+            PassphraseRequest::Encrypt { disk_id, partition, .. } => write!(f, "Encrypt({}, {:?})", disk_id, partition),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Shell {
     command_str: String,
     working_directory: Path,
+    /// Passphrases to ask for before going back to reading commands
+    passphrase_requests: VecDeque<PassphraseRequest>,
 }
 impl Shell {
     pub fn add_char(&mut self, c: char) {
         self.command_str.push(c);
     }
 
+    /// False while typing a passphrase, which shouldn't show up on the screen
+    pub fn echo(&self) -> bool {
+        self.passphrase_requests.is_empty()
+    }
+
     pub fn prompt(&self) {
-        print!("[{}] > ", self.working_directory);
+        match self.passphrase_requests.front() {
+            Some(request) => print!("{}", request.prompt()),
+            None => print!("[{}] > ", self.working_directory),
+        }
     }
 
     pub fn submit(&mut self) {
@@ -52,11 +105,58 @@ impl Shell {
         //         };
         //     }
         // }
+        if let Some(request) = self.passphrase_requests.pop_front() {
+            let passphrase = core::mem::take(&mut self.command_str);
+            self.submit_passphrase(request, passphrase);
+            self.prompt();
+            return;
+        }
         let s = self.command_str.trim();
         if !s.is_empty() {
             if s == "shutdown" {
                 crate::shutdown();
             }
+            else if s == "disks" {
+                list_disks();
+            }
+            else if s == "mem" {
+                print_memory();
+            }
+            else if s == "slabs" {
+                print_slab_caches();
+            }
+            else if s == "heapdump" {
+                dump_heap();
+            }
+            else if s == "vmas" {
+                print_kernel_areas();
+            }
+            else if let Some(args) = s.strip_prefix("heaplimit ") {
+                set_heap_limit(args);
+            }
+            else if let Some(args) = s.strip_prefix("raid1 ") {
+                create_raid1(args);
+            }
+            else if let Some(args) = s.strip_prefix("ramdisk ") {
+                create_ramdisk(args);
+            }
+            else if let Some(args) = s.strip_prefix("losetup ") {
+                create_loop_device(args);
+            }
+            else if let Some(args) = s.strip_prefix("part ") {
+                partition_command(args);
+            }
+            else if let Some(args) = s.strip_prefix("unlock ") {
+                if let Some((disk_id, partition)) = parse_volume(args) {
+                    self.passphrase_requests.push_back(PassphraseRequest::Unlock { disk_id, partition });
+                }
+            }
+            else if let Some(args) = s.strip_prefix("encrypt ") {
+                if let Some((disk_id, partition)) = parse_volume(args) {
+                    println!("WARNING: this erases everything on the volume");
+                    self.passphrase_requests.push_back(PassphraseRequest::Encrypt { disk_id, partition, first: None });
+                }
+            }
             // else if s == "list disks" {
             //     let mut loops = 0;
             //     let lock = loop {
@@ -166,4 +266,463 @@ impl Shell {
     pub fn backspace(&mut self) -> bool {
         self.command_str.pop().is_some()
     }
+
+    fn submit_passphrase(&mut self, request: PassphraseRequest, mut passphrase: String) {
+        if passphrase.is_empty() {
+            println!("Skipped.");
+            return;
+        }
+        match request {
+            PassphraseRequest::Unlock { disk_id, partition } => spawn(unlock_volume(disk_id, partition, passphrase)),
+            PassphraseRequest::Encrypt { disk_id, partition, first: None } => {
+                self.passphrase_requests.push_front(PassphraseRequest::Encrypt { disk_id, partition, first: Some(passphrase) });
+            },
+            PassphraseRequest::Encrypt { disk_id, partition, first: Some(mut first) } => {
+                if crate::crypto::constant_time_eq(first.as_bytes(), passphrase.as_bytes()) {
+                    spawn(encrypt_volume(disk_id, partition, passphrase));
+                } else {
+                    println!("Passphrases don't match.");
+                    self.passphrase_requests.push_front(PassphraseRequest::Encrypt { disk_id, partition, first: None });
+                    clear_passphrase(&mut passphrase);
+                }
+                clear_passphrase(&mut first);
+            },
+        }
+    }
+}
+
+/// Ask for a passphrase, e.g. for an encrypted volume found at boot. Anything typed so far is dropped.
+pub fn request_passphrase(request: PassphraseRequest) {
+    let mut shell = SHELL.lock();
+    shell.passphrase_requests.push_back(request);
+    if shell.passphrase_requests.len() == 1 {
+        clear_passphrase(&mut shell.command_str);
+        println!();
+        shell.prompt();
+    }
+}
+
+fn clear_passphrase(passphrase: &mut String) {
+    // zeroes are still valid UTF-8
+    crate::crypto::zeroize(unsafe { passphrase.as_bytes_mut() });
+    passphrase.clear();
+}
+
+/// Run a command that has to wait on something in the background, since the shell can't
+fn spawn(future: impl Future<Output = ()> + 'static) {
+    crate::task::executor::GLOBAL_EXECUTOR.get().expect("GLOBAL_EXECUTOR is not initialized")
+        .spawn(Task::new(future));
+}
+
+/// Parse `<disk> [partition]`
+fn parse_volume(args: &str) -> Option<(u32, Option<usize>)> {
+    let mut args = args.split_whitespace();
+    let disk_id = args.next().and_then(|id| id.parse().ok());
+    let partition = args.next().map(|i| i.parse().ok());
+    match (disk_id, partition, args.next()) {
+        (Some(disk_id), None, None) => Some((disk_id, None)),
+        (Some(disk_id), Some(Some(partition)), None) => Some((disk_id, Some(partition))),
+        _ => {
+            println!("usage: <disk> [partition]  (see `disks` for IDs)");
+            None
+        }
+    }
+}
+
+/// The volume on a disk or partition, if the disk service knows about it
+fn find_volume(disk_id: u32, partition: Option<usize>) -> Option<Arc<BlockDevice>> {
+    crate::service::DISK_SERVICE.lock().as_ref()?.volumes().iter()
+        .find(|volume| volume.disk_id == disk_id && volume.partition == partition)
+        .map(|volume| volume.device.clone())
+}
+
+async fn unlock_volume(disk_id: u32, partition: Option<usize>, mut passphrase: String) {
+    let result = async {
+        let device = find_volume(disk_id, partition)
+            .ok_or_else(|| anyhow::anyhow!("No encrypted volume there."))?;
+        let target = CryptTarget::from_media(&device.media).await?;
+        crate::device::crypt::unlock(target, passphrase.as_bytes()).await
+    }.await;
+    clear_passphrase(&mut passphrase);
+    match result {
+        Ok(disk) => {
+            if let Some(id) = crate::service::add_disk(SyncDisk::new(Box::new(disk))).await {
+                println!("Unlocked volume is disk {}", id);
+            }
+        },
+        Err(err) => {
+            println!("{}", err);
+            request_passphrase(PassphraseRequest::Unlock { disk_id, partition });
+        },
+    }
+}
+
+async fn encrypt_volume(disk_id: u32, partition: Option<usize>, mut passphrase: String) {
+    let result = async {
+        let target = match partition {
+            Some(_) => {
+                let device = find_volume(disk_id, partition)
+                    .ok_or_else(|| anyhow::anyhow!("No such partition."))?;
+                CryptTarget::from_media(&device.media).await?
+            },
+            None => {
+                let disk = crate::service::DISK_SERVICE.lock().as_ref().and_then(|service| service.get(disk_id))
+                    .ok_or_else(|| anyhow::anyhow!("No such disk."))?;
+                CryptTarget::whole_disk(disk).await?
+            },
+        };
+        // don't pull a filesystem out from under whoever's using it
+        let in_use = crate::service::DISK_SERVICE.lock().as_ref().map_or(false, |service| service.volumes().iter()
+            .any(|volume| volume.disk_id == disk_id && (partition.is_none() || volume.partition == partition)
+                && volume.filesystem.is_some()));
+        if in_use {
+            return Err(anyhow::anyhow!("There's a filesystem on it, refusing to erase it."));
+        }
+        crate::device::crypt::format(&target, passphrase.as_bytes()).await
+    }.await;
+    clear_passphrase(&mut passphrase);
+    match result {
+        Ok(_) => {
+            // a whole disk only becomes a volume once the service sees the header
+            crate::service::rescan_disk(disk_id).await;
+            request_passphrase(PassphraseRequest::Unlock { disk_id, partition });
+        },
+        Err(err) => println!("{}", err),
+    }
+}
+
+/// `raid1 <disk> <disk>...`: mirror the given disks, erasing whatever is on them
+fn create_raid1(args: &str) {
+    let ids = match args.split_whitespace().map(|id| id.parse().ok()).collect::<Option<Vec<u32>>>() {
+        Some(ids) => ids,
+        None => {
+            println!("usage: raid1 <disk> <disk>...  (see `disks` for IDs)");
+            return;
+        }
+    };
+    if let Some(id) = ids.iter().enumerate().find(|(i, id)| ids[..*i].contains(id)).map(|(_, id)| id) {
+        println!("Disk {} is given more than once.", id);
+        return;
+    }
+    spawn(async move {
+        let result = async {
+            let mut disks = Vec::new();
+            // don't erase anything that's mounted or unlocked
+            for id in ids.iter() {
+                disks.push(crate::service::check_disk_unused(*id).await?);
+            }
+            crate::driver::raid1::Array::create(disks).await
+        }.await;
+        match result {
+            Ok(array) => {
+                // the members' old partitions and filesystems are gone
+                for id in ids {
+                    crate::service::rescan_disk(id).await;
+                }
+                let disk = SyncDisk::new(Box::new(crate::driver::raid1::Raid1Disk::new(array)));
+                if let Some(id) = crate::service::add_disk(disk).await {
+                    println!("RAID-1 array added as disk {}", id);
+                }
+            },
+            Err(err) => println!("{}", err),
+        }
+    });
+}
+
+/// `ramdisk <MiB>`: add an empty RAM disk
+fn create_ramdisk(args: &str) {
+    let size = match args.trim().parse::<usize>().ok().and_then(|mib| mib.checked_mul(1024 * 1024)) {
+        Some(size) if size > 0 => size,
+        _ => {
+            println!("usage: ramdisk <MiB>");
+            return;
+        }
+    };
+    let disk = match crate::driver::ramdisk::RamDisk::new(size) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    spawn(async move {
+        if let Some(id) = crate::service::add_disk(SyncDisk::new(Box::new(disk))).await {
+            println!("RAM disk added as disk {}", id);
+        }
+    });
+}
+
+/// `losetup <path>`: add a read only disk backed by a file
+fn create_loop_device(args: &str) {
+    let path = args.trim();
+    if path.is_empty() || path.contains(char::is_whitespace) {
+        println!("usage: losetup <path>");
+        return;
+    }
+    let path = Path::from(path);
+    spawn(async move {
+        match crate::driver::loop_device::LoopDevice::open(path).await {
+            Ok(device) => {
+                if let Some(id) = crate::service::add_disk(SyncDisk::new(Box::new(device))).await {
+                    println!("Loop device added as disk {}", id);
+                }
+            },
+            Err(err) => println!("{}", err),
+        }
+    });
+}
+
+/// A change to a disk's partition table, see [partition_command]
+#[derive(Debug)]
+enum PartitionEdit {
+    List,
+    /// Replace the table with an empty one, GPT if true
+    Label(bool),
+    /// Add a partition of the given size in MiB (all the free space if `None`) in the first place it fits
+    Create { size: Option<u64>, kind: PartitionType, name: String },
+    Delete(usize),
+    /// Grow or shrink a partition to the given size in MiB (as far as it can go if `None`)
+    Resize(usize, Option<u64>),
+    Rename(usize, String),
+}
+
+const PART_USAGE: &str = "usage: part <disk> [label gpt|mbr | create <MiB|max> [fs|swap|efi] [name] | delete <n> | resize <n> <MiB|max> | rename <n> <name>]";
+
+/// `part <disk> ...`: show or change the partition table of a disk
+fn partition_command(args: &str) {
+    let mut words = args.split_whitespace();
+    let disk_id = words.next().and_then(|id| id.parse::<u32>().ok());
+    let size = |word: Option<&str>| match word {
+        Some("max") => Some(None),
+        Some(mb) => mb.parse::<u64>().ok().filter(|mb| *mb > 0).map(Some),
+        None => None,
+    };
+    let index = |word: Option<&str>| word.and_then(|n| n.parse::<usize>().ok());
+    let edit = match words.next() {
+        None => Some(PartitionEdit::List),
+        Some("label") => match words.next() {
+            Some("gpt") => Some(PartitionEdit::Label(true)),
+            Some("mbr") => Some(PartitionEdit::Label(false)),
+            _ => None,
+        },
+        Some("create") => size(words.next()).and_then(|size| {
+            let kind = match words.next() {
+                None | Some("fs") => PartitionType::Filesystem,
+                Some("swap") => PartitionType::Swap,
+                Some("efi") => PartitionType::Service,
+                Some(_) => return None,
+            };
+            let name = words.by_ref().collect::<Vec<&str>>().join(" ");
+            Some(PartitionEdit::Create { size, kind, name })
+        }),
+        Some("delete") => index(words.next()).map(PartitionEdit::Delete),
+        Some("resize") => index(words.next()).and_then(|i| Some(PartitionEdit::Resize(i, size(words.next())?))),
+        Some("rename") => index(words.next()).map(|i| PartitionEdit::Rename(i, words.by_ref().collect::<Vec<&str>>().join(" "))),
+        Some(_) => None,
+    };
+    match (disk_id, edit, words.next()) {
+        (Some(disk_id), Some(edit), None) => spawn(edit_partitions(disk_id, edit)),
+        _ => println!("{}", PART_USAGE),
+    }
+}
+
+async fn edit_partitions(disk_id: u32, edit: PartitionEdit) {
+    let result = async {
+        if let PartitionEdit::List = edit {
+            let disk = crate::service::DISK_SERVICE.lock().as_ref().and_then(|service| service.get(disk_id))
+                .ok_or_else(|| anyhow::anyhow!("No disk {}", disk_id))?;
+            match PartitionTable::read_for_editing(&disk).await.map_err(|e| anyhow::anyhow!("Couldn't read the partition table: {:?}", e))? {
+                Some(table) => print_partitions(&table),
+                None => println!("Disk {} has no partition table", disk_id),
+            }
+            return Ok(());
+        }
+
+        let disk = crate::service::check_disk_unused(disk_id).await?;
+        let sector_size = disk.block_length().await? as u64;
+        let disk_sectors = disk.size().ok_or_else(|| anyhow::anyhow!("Disk {} has no known size", disk_id))? / sector_size;
+        let mib = (1024 * 1024) / sector_size;
+        let mut table = match edit {
+            PartitionEdit::Label(true) => PartitionTable::GPT(GptPartitionTable::new(disk_sectors, sector_size as usize)?),
+            PartitionEdit::Label(false) => PartitionTable::MBR(MbrPartitionTable::new(disk_sectors)),
+            _ => PartitionTable::read_for_editing(&disk).await
+                .map_err(|e| anyhow::anyhow!("Couldn't read the partition table: {:?}", e))?
+                .ok_or_else(|| anyhow::anyhow!("Disk {} has no partition table, make one with `part {} label gpt`", disk_id, disk_id))?,
+        };
+        match edit {
+            PartitionEdit::Create { size, kind, name } => {
+                let sectors = match size {
+                    Some(size) => Some(size.checked_mul(mib).ok_or_else(|| anyhow::anyhow!("{} MiB is too big", size))?),
+                    None => None,
+                };
+                let (first, last) = table.free_space(sectors, mib)
+                    .ok_or_else(|| anyhow::anyhow!("Not enough free space"))?;
+                let i = table.create(&disk, first, last, kind, &name)?;
+                println!("Created partition {}: sectors {}-{}", i, first, last);
+            },
+            PartitionEdit::Delete(i) => table.delete(i)?,
+            PartitionEdit::Resize(i, size) => {
+                let first = table.sector_ranges().get(i).ok_or_else(|| anyhow::anyhow!("No partition {}", i))?.0;
+                let last = match size {
+                    Some(size) => size.checked_mul(mib)
+                        .and_then(|sectors| first.checked_add(sectors))
+                        .and_then(|end| end.checked_sub(1))
+                        .ok_or_else(|| anyhow::anyhow!("{} MiB is not a valid size", size))?,
+                    None => table.max_end(i).unwrap_or(first),
+                };
+                table.resize(i, last)?;
+            },
+            PartitionEdit::Rename(i, name) => table.rename(i, &name)?,
+            PartitionEdit::Label(_) | PartitionEdit::List => {},
+        }
+        table.write(&disk).await?;
+        crate::service::rescan_disk(disk_id).await;
+        print_partitions(&table);
+        Ok::<(), anyhow::Error>(())
+    }.await;
+    if let Err(err) = result {
+        println!("{}", err);
+    }
+}
+
+fn print_partitions(table: &PartitionTable) {
+    match table {
+        PartitionTable::MBR(table) => {
+            println!("MBR, disk signature {:08X}", table.disk_signature);
+            for (i, part) in table.partitions.iter().enumerate() {
+                println!("    {}: sectors {}-{}  type {:02X} ({:?}){}", i, part.first_sector, part.last_sector,
+                         part.type_id, part.partition_type, if part.bootable { "  bootable" } else { "" });
+            }
+        },
+        PartitionTable::GPT(table) => {
+            println!("GPT, disk {}", table.uuid);
+            for (i, part) in table.partitions.iter().enumerate() {
+                println!("    {}: sectors {}-{}  {:?}  \"{}\"", i, part.first_lba, part.last_lba, part.partition_type, part.name);
+            }
+        },
+    }
+}
+
+/// Print how much physical memory is in use
+fn print_memory() {
+    use crate::memory::frame::{self, FRAME_SIZE};
+    let stats = frame::stats();
+    let kib = |frames: u64| frames * FRAME_SIZE / 1024;
+    println!("Physical memory: {} KiB total, {} KiB used, {} KiB free ({} KiB below 4 GiB)",
+             kib(stats.total), kib(stats.used()), kib(stats.free), kib(stats.free_below_4gib));
+    println!("    largest free block: {} KiB", kib(stats.largest_free_block));
+    let heap = crate::memory::allocator::heap_stats();
+    println!("Kernel heap: {} KiB mapped, {} KiB used, limit {} KiB", heap.size / 1024, heap.used / 1024, heap.limit / 1024);
+    println!("    {} holes, largest {} KiB", heap.holes, heap.largest_hole / 1024);
+    let cache = crate::memory::page_cache::stats();
+    println!("Page cache: {} KiB of {} files", kib(cache.pages as u64), cache.files);
+}
+
+/// `heaplimit <MiB>`: change how big the kernel heap may grow
+fn set_heap_limit(args: &str) {
+    use crate::memory::allocator;
+    match args.trim().parse::<usize>().ok().and_then(|mib| mib.checked_mul(1024 * 1024)) {
+        Some(limit) => {
+            allocator::set_heap_limit(limit);
+            println!("Kernel heap limit is now {} KiB", allocator::heap_limit() / 1024);
+        },
+        None => println!("usage: heaplimit <MiB>"),
+    }
+}
+
+/// Print statistics for every slab cache that's been used
+fn print_slab_caches() {
+    use crate::memory::allocator::slab;
+    let mut caches: Vec<_> = slab::caches().map(|cache| cache.stats()).collect();
+    caches.sort_by_key(|stats| stats.name);
+    println!("{:<16} {:>6} {:>8} {:>8} {:>6} {:>8} {:>10} {:>10}",
+             "cache", "size", "in use", "objects", "slabs", "KiB", "allocs", "frees");
+    for stats in caches {
+        println!("{:<16} {:>6} {:>8} {:>8} {:>6} {:>8} {:>10} {:>10}",
+                 stats.name, stats.object_size, stats.in_use, stats.slabs * stats.objects_per_slab,
+                 stats.slabs, stats.memory() / 1024, stats.allocations, stats.frees);
+    }
+}
+
+/// Print the areas in the kernel's address space
+fn print_kernel_areas() {
+    use crate::memory::address_space::{self, Backing};
+    address_space::with_kernel_space(|space| {
+        for area in space.areas() {
+            let backing = match &area.backing {
+                Backing::Anonymous => String::from("anonymous"),
+                Backing::Physical { base, cache } => alloc::format!("physical {:#x} {:?}", base.as_u64(), cache),
+                Backing::File { offset, .. } => alloc::format!("file +{:#x}", offset),
+            };
+            println!("{:#018x}-{:#018x} {:?} {} ({})", area.start.as_u64(), area.end().as_u64(),
+                     area.protection, area.name, backing);
+        }
+    });
+}
+
+/// List outstanding heap allocations on the serial port, if heap debugging is built in
+#[cfg(feature = "heap_debug")]
+fn dump_heap() {
+    let (count, bytes) = crate::memory::allocator::debug::dump_allocations();
+    println!("{} allocations ({} bytes) outstanding, listed on the serial port", count, bytes);
+}
+
+#[cfg(not(feature = "heap_debug"))]
+fn dump_heap() {
+    println!("Heap debugging isn't enabled, build with `--features heap_debug`.");
+}
+
+/// Print every disk known to the disk service along with what it reported about itself
+fn list_disks() {
+    let lock = crate::service::DISK_SERVICE.lock();
+    let service = match lock.as_ref() {
+        Some(service) => service,
+        None => {
+            println!("Disk service is not initialized.");
+            return;
+        }
+    };
+
+    let mut disks: Vec<_> = service.iter().collect();
+    disks.sort_by_key(|(id, _)| **id);
+    for (id, disk) in disks {
+        let size_mb = disk.size().unwrap_or(0) / (1024 * 1024);
+        println!("Disk {}  type: {:?}  size: {} MB  queue depth: {}{}", id, disk.kind(), size_mb, disk.queue_depth(),
+                 if disk.read_only() { "  read only" } else { "" });
+        let stats = disk.queue_stats();
+        if stats.submitted > 0 {
+            println!("    requests: {} ({} merged)  commands: {}  queued: {} (max {})  latency: {} ms avg, {} ms max",
+                     stats.submitted, stats.merged, stats.dispatched, stats.queued, stats.max_queued,
+                     stats.average_latency().as_millis(), stats.max_latency.as_millis());
+        }
+        if let Some(info) = disk.info() {
+            let on_off = |supported: bool, enabled: bool| match (supported, enabled) {
+                (false, _) => "unsupported",
+                (true, false) => "off",
+                (true, true) => "on",
+            };
+            println!("    model: {}  serial: {}  firmware: {}", info.model, info.serial, info.firmware);
+            println!("    sectors: {} x {} bytes (physical {})  LBA: {}-bit", info.sectors,
+                     info.logical_sector_size, info.physical_sector_size, if info.lba48 { 48 } else { 28 });
+            match info.ncq_depth {
+                Some(depth) => print!("    NCQ: {}", depth),
+                None => print!("    NCQ: unsupported"),
+            }
+            println!("  TRIM: {}  write cache: {}  SMART: {}",
+                     if info.trim { "yes" } else { "no" },
+                     on_off(info.write_cache, info.write_cache_enabled),
+                     on_off(info.smart, info.smart_enabled));
+        }
+        for volume in service.volumes().iter().filter(|volume| volume.disk_id == *id) {
+            let fs = match &volume.filesystem {
+                Some(fs) => fs.type_as_str(),
+                None if volume.encrypted => "encrypted",
+                None => "unknown",
+            };
+            match volume.partition {
+                Some(i) => println!("    partition {}: {}", i, fs),
+                None => println!("    filesystem: {}", fs),
+            }
+        }
+    }
 }
@@ -1,68 +1,134 @@
-// ///////////////////////////////////////////////////////////////////////////////L
-// ///////////////////////////////////////////////////////////////////////////////L
+///////////////////////////////////////////////////////////////////////////////L
+// The MIT License (MIT)
+// Copyright (c) 2021 [untitled os] Team
+// See LICENSE.txt and CREDITS.txt for details
+///////////////////////////////////////////////////////////////////////////////L
 
-use alloc::boxed::Box;
-use alloc::sync::Arc;
-use core::cell::RefCell;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::{Debug, Formatter};
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
 use core::sync::atomic::{AtomicBool, Ordering};
-use crossbeam::queue::ArrayQueue;
-use futures_util::task::AtomicWaker;
-use lock_api::{RawMutex, Mutex, GuardSend};
+use core::task::{Context, Poll, Waker};
 
-type Waker = Arc<AtomicWaker>;
-type MaybeQueue = Option<Box<ArrayQueue<Waker>>>;
+/// A mutex that parks the waiting task instead of spinning.
+///
+/// A spin lock held across an `.await` will deadlock the cooperative executor as soon as a
+/// second task tries to take it, so anything that needs to stay locked while waiting on
+/// hardware (like a disk) should use this instead.
+pub struct AsyncMutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: spin::Mutex<Vec<Waker>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: spin::Mutex::new(Vec::new()),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    /// Returns a future that resolves to a guard once the lock has been acquired.
+    pub fn lock(&self) -> AsyncMutexLockFuture<'_, T> {
+        AsyncMutexLockFuture { mutex: self }
+    }
+
+    /// Attempts to acquire the lock without waiting.
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            Some(AsyncMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the mutex is currently held by someone.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        // wake everyone, the first one to be polled gets the lock and the rest re-register
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
 
-pub struct RawSpinMutex {
-     pub locked: AtomicBool
+impl<T: ?Sized> Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AsyncMutex {{ locked: {} }}", self.is_locked())
+    }
 }
 
-impl RawSpinMutex {
-    pub const fn new() -> Self { Self { locked: AtomicBool::new(false) } }
+/// Future returned by [AsyncMutex::lock]
+pub struct AsyncMutexLockFuture<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
 }
 
-unsafe impl RawMutex for RawSpinMutex {
-    const INIT: Self = Self { locked: AtomicBool::new(false) };
+impl<'a, T: ?Sized> Future for AsyncMutexLockFuture<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
 
-    type GuardMarker = GuardSend;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // fast path
+        if let Some(guard) = self.mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
 
-    fn lock(&self) {
-        //let waker = Arc::new(AtomicWaker::new());
-        //self.enqueue(waker.clone());
-        //AsyncMutexFuture { waker, mutex: &self }
-        while !self.try_lock() {}
+        self.mutex.waiters.lock().push(cx.waker().clone());
+        // check again in case the lock was released before we registered
+        match self.mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
     }
+}
 
-    fn try_lock(&self) -> bool {
-        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+impl<T: ?Sized> Debug for AsyncMutexLockFuture<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AsyncMutexLockFuture {{ mutex: {:?} }}", self.mutex)
     }
+}
 
-    unsafe fn unlock(&self) {
-        self.locked.store(false, Ordering::Release);
-        // if let Some(waker) = self.queue.borrow_mut().as_mut().unwrap().pop() {
-        //     waker.wake();
-        // }
+/// Scoped lock on an [AsyncMutex]. The lock is released when this is dropped.
+pub struct AsyncMutexGuard<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<T: ?Sized> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
     }
 }
 
-// pub struct AsyncMutexFuture<'a, T> {
-//     waker: Waker,
-//     mutex: &'a AsyncMutex<T>,
-// }
-//
-// impl<'a, T> Future for AsyncMutexFuture<'a, T> {
-//     type Output = AsyncMutexLock<'a, 'a, T>;
-//
-//     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-//         match self.mutex.try_lock() {
-//             Some(lock) => Poll::Ready(lock),
-//             None => {
-//                 self.waker.take();
-//                 self.waker.register(cx.waker());
-//                 Poll::Pending
-//             }
-//         }
-//     }
-// }
-
-pub type SpinMutex<T> = lock_api::Mutex<RawSpinMutex, T>;
-pub type SpinMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinMutex, T>;
+impl<T: ?Sized> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl<T: ?Sized> Debug for AsyncMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AsyncMutexGuard {{ mutex: {:?} }}", self.mutex)
+    }
+}
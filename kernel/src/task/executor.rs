@@ -6,10 +6,10 @@
 
 use super::{Task, TaskId};
 use alloc::{collections::BTreeMap, sync::Arc};
-use core::task::{Waker, Context, Poll};
+use core::task::{Waker, RawWaker, RawWakerVTable, Context, Poll};
 use crossbeam::queue::ArrayQueue;
-use alloc::task::Wake;
-use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
 use core::future::Future;
@@ -17,6 +17,7 @@ use core::pin::Pin;
 use futures_util::task::AtomicWaker;
 use crate::util::DoubleArrayQueue;
 use crate::time::Instant;
+use crate::memory::allocator::slab::SlabCache;
 
 // TODO: return error or something when queues are full instead of panicking
 
@@ -183,7 +184,10 @@ impl ExecutorInner {
                 .or_insert_with(|| TaskWaker::new(task_id));
 
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            super::CURRENT_TASK.store(task_id.0, Ordering::Relaxed);
+            let poll = task.poll(&mut context);
+            super::CURRENT_TASK.store(u64::MAX, Ordering::Relaxed);
+            match poll {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks_lock.remove(&task_id);
@@ -216,16 +220,23 @@ impl Future for ExecutorSpawnFuture {
     }
 }
 
+static TASK_WAKER_CACHE: SlabCache = SlabCache::for_type::<TaskWaker>("task_waker");
+
+static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    TaskWaker::clone_raw, TaskWaker::wake_raw, TaskWaker::wake_by_ref_raw, TaskWaker::drop_raw);
+
+/// Reference counted by hand instead of using `Arc` and `Wake`, so it can live in a slab cache
 struct TaskWaker {
-    task_id: TaskId
+    task_id: TaskId,
+    refs: AtomicUsize,
 }
 
 impl TaskWaker {
     #[allow(clippy::new_ret_no_self)]
     fn new(task_id: TaskId) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id
-        }))
+        let waker = Box::new_in(TaskWaker { task_id, refs: AtomicUsize::new(1) }, &TASK_WAKER_CACHE);
+        let (ptr, _) = Box::into_raw_with_allocator(waker);
+        unsafe { Waker::from_raw(RawWaker::new(ptr as *const (), &TASK_WAKER_VTABLE)) }
     }
 
     fn wake_task(&self) {
@@ -233,14 +244,29 @@ impl TaskWaker {
             .wake_task(self.task_id)
             .expect("woken task queue full (do you need to increase queue size in task::executor?)");
     }
-}
 
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
+    unsafe fn clone_raw(data: *const ()) -> RawWaker {
+        let waker = unsafe { &*(data as *const TaskWaker) };
+        waker.refs.fetch_add(1, Ordering::Relaxed);
+        RawWaker::new(data, &TASK_WAKER_VTABLE)
     }
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+    unsafe fn wake_raw(data: *const ()) {
+        unsafe {
+            Self::wake_by_ref_raw(data);
+            Self::drop_raw(data);
+        }
+    }
+
+    unsafe fn wake_by_ref_raw(data: *const ()) {
+        unsafe { &*(data as *const TaskWaker) }.wake_task();
+    }
+
+    unsafe fn drop_raw(data: *const ()) {
+        let waker = unsafe { &*(data as *const TaskWaker) };
+        if waker.refs.fetch_sub(1, Ordering::Release) == 1 {
+            core::sync::atomic::fence(Ordering::Acquire);
+            drop(unsafe { Box::from_raw_in(data as *mut TaskWaker, &TASK_WAKER_CACHE) });
+        }
     }
 }
@@ -78,14 +78,18 @@ pub async fn process_scancodes() {
             if let Some(key) = keyboard.process_keyevent(key_event.clone()) {
                 match key {
                     DecodedKey::Unicode(character) => {
+                        // passphrases aren't echoed
+                        let echo = (*crate::shell::SHELL.lock()).echo();
                         if character == '\x08' {
                             // true if a character was erased (so we can't go past the start of the string)
-                            if (*crate::shell::SHELL.lock()).backspace() {
+                            if (*crate::shell::SHELL.lock()).backspace() && echo {
                                 crate::vga_buffer::_backspace();
                             }
                         }
                         else {
-                            crate::print!("{}", character);
+                            if echo || character == '\n' {
+                                crate::print!("{}", character);
+                            }
                             if character == '\n' {
                                 (*crate::shell::SHELL.lock()).submit();
                             }
@@ -9,6 +9,7 @@ use alloc::boxed::Box;
 use core::task::{Poll, Context};
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::fmt::{Debug, Formatter};
+use crate::memory::allocator::slab::{SlabCache, SlabOrHeap};
 
 pub mod keyboard;
 pub mod executor;
@@ -24,16 +25,45 @@ impl TaskId {
     }
 }
 
+/// Raw ID of the task being polled, `u64::MAX` when the executor isn't polling anything
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// ID of the task that's running right now, or `None` outside of the executor (e.g. during early init).
+/// IDs are never reused, so this can be used to tell tasks apart.
+pub fn current_task_id() -> Option<u64> {
+    match CURRENT_TASK.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        id => Some(id),
+    }
+}
+
+/// Lets the executor run other tasks before this one continues, for long computations that would otherwise
+/// hold up everything else
+pub async fn yield_now() {
+    let mut yielded = false;
+    futures_util::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }).await
+}
+
+/// Task futures up to this size come from a slab cache, bigger ones from the heap
+static TASK_CACHE: SlabCache = SlabCache::new("task", 512, 16);
+
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    future: Pin<Box<dyn Future<Output = ()>, SlabOrHeap>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
-            future: Box::pin(future),
+            future: Box::pin_in(future, SlabOrHeap(&TASK_CACHE)),
         }
     }
 
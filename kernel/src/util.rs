@@ -116,6 +116,46 @@ pub unsafe fn read_c_str_with_len(addr: VirtAddr, len: usize) -> String {
     string
 }
 
+/// A random number from RDRAND, or `None` if the CPU doesn't have it or it failed
+pub fn rdrand_u64() -> Option<u64> {
+    x86_64::instructions::random::RdRand::new()?.get_u64()
+}
+
+/// A random number from RDRAND if possible, otherwise mixed from the timestamp counter.
+/// Good enough to tell things apart, but don't use it for keys.
+pub fn random_u64() -> u64 {
+    rdrand_u64().unwrap_or_else(|| {
+        // splitmix64 finalizer
+        let mut x = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^ (x >> 31)
+    })
+}
+
+const CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC-32 used by GPT, zlib and Ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| (crc >> 8) ^ CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize])
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 #[repr(transparent)]
 pub struct UUID(pub [u8; 16]);
@@ -133,6 +173,16 @@ impl UUID {
         }
         uuid_str
     }
+    /// Generate a random (version 4) UUID
+    pub fn random() -> Self {
+        let mut bytes = [0u8; 16];
+        for half in bytes.chunks_exact_mut(8) {
+            half.copy_from_slice(&random_u64().to_le_bytes());
+        }
+        bytes[6] = (bytes[6] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        UUID(bytes)
+    }
     pub fn version(&self) -> u32 {
         ((self.0[6] >> 4) & 0xF) as u32
     }
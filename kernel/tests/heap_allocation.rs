@@ -7,6 +7,7 @@
 #![no_std]
 #![no_main]
 #![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
 #![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -25,7 +26,7 @@ fn panic(info: &PanicInfo) -> ! {
 
 entry_point!(main);
 fn main(boot_info: &'static BootInfo) -> ! {
-    use memory::BootInfoFrameAllocator;
+    use memory::frame::GlobalFrameAllocator;
     use x86_64::VirtAddr;
 
     kernel::arch::gdt::init();
@@ -39,11 +40,10 @@ fn main(boot_info: &'static BootInfo) -> ! {
     }
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init()
-    };
-    memory::allocator::init_heap(&mut mapper, &mut frame_allocator)
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init() };
+    let mut frame_allocator = GlobalFrameAllocator;
+    memory::allocator::init_heap(mapper, &mut frame_allocator)
         .expect("heap initialization failed");
 
     test_main();
@@ -80,3 +80,83 @@ fn many_boxes() {
     }
     serial_println!("[ok]");
 }
+
+#[test_case]
+fn frame_allocation() {
+    use memory::frame::{self, AddressLimit, FRAME_SIZE};
+    serial_print!("frame_allocation... ");
+    let before = frame::stats();
+    assert!(before.free > 0 && before.free <= before.total);
+
+    let single = frame::allocate_frame().unwrap();
+    let dma = frame::allocate_contiguous(5, 8 * FRAME_SIZE, AddressLimit::Below4GiB).unwrap();
+    assert!(dma.is_aligned(8 * FRAME_SIZE));
+    assert!(dma.as_u64() + 5 * FRAME_SIZE <= 1 << 32);
+    assert_ne!(single.start_address(), dma);
+    assert_eq!(frame::stats().free, before.free - 6);
+
+    unsafe {
+        frame::free_contiguous(dma, 5);
+        frame::free_frame(single);
+    }
+    assert_eq!(frame::stats(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn heap_growth() {
+    use memory::allocator::heap_stats;
+    serial_print!("heap_growth... ");
+    let big = alloc::vec![1u8; 4 * 1024 * 1024];
+    let grown = heap_stats().size;
+    assert!(grown > big.len());
+    assert_eq!(big.iter().map(|&b| b as usize).sum::<usize>(), big.len());
+
+    // freeing it gives most of the memory back to the frame allocator
+    let free_frames = memory::frame::stats().free;
+    drop(big);
+    assert!(heap_stats().size < grown - 2 * 1024 * 1024);
+    assert!(memory::frame::stats().free > free_frames);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn heap_realloc() {
+    serial_print!("heap_realloc... ");
+    // too big for the slab caches, so these are resized by the heap
+    let mut buffer = alloc::vec![7u8; 4096];
+    let holes = memory::allocator::heap_stats().holes;
+    buffer.reserve_exact(64 * 1024);
+    buffer.resize(64 * 1024, 9);
+    buffer.shrink_to_fit();
+    buffer.truncate(8192);
+    buffer.shrink_to_fit();
+    assert!(buffer[..4096].iter().all(|&b| b == 7));
+    assert!(buffer[4096..].iter().all(|&b| b == 9));
+    drop(buffer);
+    assert!(memory::allocator::heap_stats().holes <= holes + 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn slab_cache() {
+    use memory::allocator::slab::{self, SlabCache};
+    static CACHE: SlabCache = SlabCache::new("test", 100, 8);
+    serial_print!("slab_cache... ");
+    let objects: Vec<_> = (0..100).map(|i| Box::new_in([i as u8; 100], &CACHE)).collect();
+    let stats = CACHE.stats();
+    assert_eq!(stats.object_size, 104);
+    assert_eq!(stats.in_use, 100);
+    assert!(stats.slabs * stats.objects_per_slab >= 100);
+    assert!(objects.iter().enumerate().all(|(i, object)| object.iter().all(|&b| b == i as u8)));
+    assert!(slab::caches().any(|cache| cache.name() == "test"));
+
+    // all but one of the empty slabs go straight back to the frame allocator
+    drop(objects);
+    let stats = CACHE.stats();
+    assert_eq!((stats.in_use, stats.allocations, stats.frees), (0, 100, 100));
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(CACHE.shrink(), 1);
+    assert_eq!(CACHE.stats().slabs, 0);
+    serial_println!("[ok]");
+}
@@ -20,6 +20,7 @@ use kernel::{MemoryInitResults, both_println};
 use kernel::time::DateTimeError;
 use x86_64::instructions::port::Port;
 use kernel::task::Task;
+use kernel::memory::stack::{KernelStack, KERNEL_STACK_SIZE};
 //use pest::Parser;
 
 
@@ -44,7 +45,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     kernel::arch::interrupts::early_init_interrupts();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let MemoryInitResults { mapper: _mapper, frame_allocator: _frame_allocator } = kernel::init_memory(phys_mem_offset);
+    let MemoryInitResults { frame_allocator: _frame_allocator } = kernel::init_memory(phys_mem_offset);
 
     kernel::acpi::init();
     kernel::arch::interrupts::late_init_interrupts();
@@ -61,6 +62,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     kernel::init_pci();
     kernel::driver::ahci::init();
+    kernel::driver::ide::init();
+    kernel::driver::nvme::init();
+    kernel::driver::virtio::init();
+    kernel::driver::floppy::init();
 
     kernel::arch::rtc::init_rtc();
 
@@ -86,6 +91,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(feature = "ci")]
     kernel::shutdown();
 
+    // run everything from here on, tasks included, on a stack with a guard page of our own
+    let stack = KernelStack::new(KERNEL_STACK_SIZE, "executor stack").expect("failed to allocate the executor stack");
+    kernel::memory::stack::run_on(stack, run_executor)
+}
+
+fn run_executor() -> ! {
     let exec = kernel::task::executor::Executor::init();
     exec.run(kernel::task::Task::new(async_main())) // -> !
 }
@@ -93,6 +104,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 async fn async_main() {
     let executor = kernel::task::executor::GLOBAL_EXECUTOR.get().unwrap().clone();
     executor.spawn(Task::new(kernel::service::DiskService::init())).await;
+    executor.spawn(Task::new(kernel::driver::ahci::hotplug::hotplug_task())).await;
+    executor.spawn(Task::new(kernel::service::disk_health_task())).await;
+    executor.spawn(Task::new(kernel::driver::floppy::motor_task())).await;
+    executor.spawn(Task::new(kernel::driver::raid1::raid_task())).await;
     executor.spawn(Task::new(kernel::task::keyboard::process_scancodes())).await;
 
     both_println!("async_main exit");